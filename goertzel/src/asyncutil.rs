@@ -3,6 +3,8 @@ use std::future::Future;
 
 use anyhow::Result;
 use tokio::select;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
 use tracing::error;
 
 pub async fn and_log_err(
@@ -20,3 +22,21 @@ pub async fn race<T>(fut1: impl Future<Output = T>, fut2: impl Future<Output = T
         r = fut2 => r,
     }
 }
+
+// Never finishes if there's nothing to wait for
+pub async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+// Same for a task that might not be running
+pub async fn join<T>(handle: &mut Option<JoinHandle<T>>) -> Result<T> {
+    let Some(running) = handle else {
+        return std::future::pending().await;
+    };
+    let joined = running.await;
+    *handle = None;
+    Ok(joined?)
+}
@@ -13,7 +13,10 @@ use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::{self, fmt, EnvFilter};
 
 use goertzel::contacts::CONTACTS;
-use goertzel::sip::{assert_status, tlssocket, SERVER_NAME, SERVER_PORT};
+use goertzel::rtp::port::PortAllocator;
+use goertzel::rtp::socket::Socket;
+use goertzel::sip::transport::TransportKind;
+use goertzel::sip::{assert_status, conn, SERVER_NAME, SERVER_PORT};
 
 #[tokio::main]
 async fn main() {
@@ -47,7 +50,7 @@ async fn main() {
 async fn post_handler() -> Result<&'static str, AppError> {
     let ip = public_ip::addr_v4().await.ok_or(anyhow!("no ip"))?;
 
-    let tls_conn = tlssocket::TlsSipConn::new(ip, SERVER_NAME, SERVER_PORT).await?;
+    let tls_conn = conn::SipConn::new(TransportKind::Tls, ip, SERVER_NAME, SERVER_PORT).await?;
 
     let password = env::var("SIP_PASSWORD")?;
     let mut dialog = tls_conn.dialog(String::from("1103")).await;
@@ -57,18 +60,27 @@ async fn post_handler() -> Result<&'static str, AppError> {
     let to = (*CONTACTS)
         .get("1102")
         .ok_or(anyhow!("contact is missing after I EXPLICITLY checked it"))?;
+    let rtp_sock = Socket::bind(&PortAllocator::default()).await?;
+    dialog.session.set_port(rtp_sock.port());
     dialog.invite(password.clone(), to.clone()).await?;
 
-    let resp_200 = dialog.recv().await?;
-    assert_status(&resp_200.clone().try_into()?)?;
+    let resp_200 = loop {
+        let resp: rsip::Response = dialog.recv().await?.try_into()?;
+        if !dialog.retry_invite(&password, &resp).await? {
+            break resp;
+        }
+    };
+    assert_status(&resp_200)?;
     dialog.set_to(resp_200.to_header()?.typed()?);
 
     let msg = dialog.recv().await?;
     dialog.ack(msg.try_into()?).await?;
 
-    let invite = dialog.recv().await?;
-    let sdp = dialog.sdp_from(invite.clone().try_into()?)?;
-    let ref resp = dialog.sdp_response_to(invite.clone().try_into()?, rsip::StatusCode::OK, sdp)?;
+    let invite: rsip::Request = dialog.recv().await?.try_into()?;
+    let (sdp, _) = dialog
+        .negotiate(&invite)?
+        .ok_or(anyhow!("no media in common with remote"))?;
+    let ref resp = dialog.sdp_response_to(invite, rsip::StatusCode::OK, sdp)?;
     dialog.send(resp.clone()).await?;
 
     let _ack = dialog.recv().await?;
@@ -2,7 +2,8 @@ use std::env;
 use std::time::Duration;
 
 use anyhow::{anyhow, Result};
-use goertzel::sip::{tlssocket, SERVER_NAME, SERVER_PORT};
+use goertzel::sip::transport::TransportKind;
+use goertzel::sip::{conn, SERVER_NAME, SERVER_PORT};
 use rsip::StatusCode;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
@@ -16,7 +17,7 @@ pub async fn main() -> Result<()> {
 
     let ip = public_ip::addr_v4().await.ok_or(anyhow!("no ip"))?;
 
-    let mut tls_conn = tlssocket::TlsSipConn::new(ip, SERVER_NAME, SERVER_PORT).await?;
+    let mut tls_conn = conn::SipConn::new(TransportKind::Tls, ip, SERVER_NAME, SERVER_PORT).await?;
 
     let password = env::var("SIP_PASSWORD")?;
     let mut dialog = tls_conn.dialog(String::from("1103")).await;
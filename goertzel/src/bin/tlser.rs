@@ -3,7 +3,10 @@ use std::time::Duration;
 
 use anyhow::{anyhow, Result};
 use goertzel::contacts::CONTACTS;
-use goertzel::sip::{tlssocket, SERVER_NAME, SERVER_PORT};
+use goertzel::rtp::port::PortAllocator;
+use goertzel::rtp::socket::Socket;
+use goertzel::sip::transport::TransportKind;
+use goertzel::sip::{conn, SERVER_NAME, SERVER_PORT};
 use rsip::StatusCode;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
@@ -17,7 +20,7 @@ pub async fn main() -> Result<()> {
 
     let ip = public_ip::addr_v4().await.ok_or(anyhow!("no ip"))?;
 
-    let mut tls_conn = tlssocket::TlsSipConn::new(ip, SERVER_NAME, SERVER_PORT).await?;
+    let mut tls_conn = conn::SipConn::new(TransportKind::Tls, ip, SERVER_NAME, SERVER_PORT).await?;
 
     let password = env::var("SIP_PASSWORD")?;
     let mut dialog_1103 = tls_conn.dialog(String::from("1103")).await;
@@ -30,6 +33,8 @@ pub async fn main() -> Result<()> {
     let to = (*CONTACTS)
         .get("1103")
         .ok_or(anyhow!("contact is missing after I EXPLICITLY checked it"))?;
+    let rtp_sock = Socket::bind(&PortAllocator::default()).await?;
+    dialog_1102.session.set_port(rtp_sock.port());
     dialog_1102.invite(password.clone(), to.clone()).await?;
 
     let new_msg = tls_conn
@@ -9,6 +9,7 @@ pub mod phone;
 pub mod pulse;
 pub mod ring;
 pub mod rtp;
+pub mod sdp;
 pub mod sip;
 pub mod state;
 pub mod tone;
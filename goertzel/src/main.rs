@@ -4,6 +4,8 @@ use std::{env, panic, process};
 use anyhow::Result;
 use goertzel::phone::Phone;
 use goertzel::ring;
+use goertzel::rtp::port::PortAllocator;
+use goertzel::sip::account::Account;
 use tokio::time::sleep;
 use tracing::{error, info};
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
@@ -22,9 +24,21 @@ async fn main() -> Result<()> {
         process::exit(1);
     }));
 
-    let username = env::var("SIP_USERNAME")?;
-    let password = env::var("SIP_PASSWORD")?;
-    let phone = Phone::new(username, password).await?;
+    let mut account = Account::new(env::var("SIP_USERNAME")?, env::var("SIP_PASSWORD")?);
+    if let Ok(mode) = env::var("SIP_DTMF_MODE") {
+        account.dtmf_mode = mode.parse()?;
+    }
+    if let Ok(policy) = env::var("SIP_SRTP") {
+        account.srtp_policy = policy.parse()?;
+    }
+    if let Ok(transport) = env::var("SIP_TRANSPORT") {
+        account.transport = Some(transport.parse()?);
+    }
+    let rtp_ports = match env::var("RTP_PORT_RANGE") {
+        Ok(range) => range.parse()?,
+        Err(_) => PortAllocator::default(),
+    };
+    let phone = Phone::new(account, rtp_ports).await?;
     info!("Got mic, listening...");
 
     //{
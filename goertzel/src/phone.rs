@@ -1,27 +1,28 @@
-use std::net::SocketAddr;
-use std::str::FromStr;
 use std::time::Duration;
 
+use rsip::prelude::*;
 use rsip::SipMessage;
-use sdp_rs::lines::media::MediaType;
 use sdp_rs::SessionDescription;
 use tokio::process::Command;
 use tokio::select;
-use tokio::sync::{broadcast, mpsc};
+use tokio::sync::{broadcast, mpsc, watch};
 use tokio::time::sleep;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
 use crate::contacts::CONTACTS;
 use crate::hook::{self, SwitchHook};
 use crate::nettest::do_i_have_internet;
-use crate::sip::tlssocket::TlsSipConn;
+use crate::sdp::MediaParams;
+use crate::sip::conn::{Flow, SipConn};
+use crate::sip::locate::DnsResolver;
+use crate::sip::registration::Registration;
 use crate::tone::TwoToneGen;
-use crate::{audio, deco, ring, rtp, sip};
+use crate::{asyncutil, audio, deco, ring, rtp, sip};
 use crate::{dtmf, pulse};
 use anyhow::{anyhow, Result};
 
 pub enum State {
-    Connected(TlsSipConn, Dial),
+    Connected(SipConn, Dial),
     Disconnected(WiFi),
 }
 
@@ -31,15 +32,36 @@ pub enum WiFi {
     Error(anyhow::Error),
 }
 
-// TODO(peter): SIP registration steps
 pub enum Dial {
     OnHook,
-    Ringing(sip::Dialog, rtp::socket::Socket, SipMessage),
+    Ringing(
+        sip::Dialog,
+        rtp::socket::Socket,
+        SipMessage,
+        SessionDescription,
+        // None if they left the offer to us and the answer's still to come in the ACK
+        Option<MediaParams>,
+    ),
     Await,
     DialOut(sip::Dialog, rtp::socket::Socket),
-    Dialing(sip::Dialog, rtp::socket::Socket),
+    // With the provisional that brought early media, if there was one
+    Dialing(sip::Dialog, rtp::socket::Socket, Option<rsip::Response>),
     Connected(sip::Dialog, rtp::socket::Socket),
+    // Far end's on hold while we dial who to hand them to
+    Transfer(sip::Dialog, rtp::socket::Socket),
+    // Far end's on hold while we call who we're handing them to
+    Consulting(
+        sip::Dialog,
+        rtp::socket::Socket,
+        sip::Dialog,
+        rtp::socket::Socket,
+        rsip::typed::To,
+    ),
+    // Hung up on a transfer, and hearing how it went with what's left of the consulting call
+    Transferring(sip::Dialog, Option<sip::Dialog>),
     Busy,
+    // The far end went quiet and we hung up on them
+    Dropped,
     Error(anyhow::Error),
 }
 
@@ -61,30 +83,33 @@ pub struct Phone {
 
     pub hook_ch: broadcast::Sender<SwitchHook>,
     pub pulse_ch: broadcast::Sender<u8>,
+    pub flash_ch: broadcast::Sender<()>,
 
-    username: String,
-    password: String,
+    account: sip::account::Account,
+    registration: watch::Receiver<Registration>,
+    // Whoever transferred us to the call we're placing, and wants to hear how it goes
+    referrer: Option<sip::Dialog>,
+
+    rtp_ports: rtp::port::PortAllocator,
 }
 
 impl Phone {
-    pub async fn new(username: String, password: String) -> Result<Self> {
+    pub async fn new(
+        account: sip::account::Account,
+        rtp_ports: rtp::port::PortAllocator,
+    ) -> Result<Self> {
         let (mic_ch, mic_stream, mic_cfg) = audio::get_input_channel()?;
         let (spk_ch, spk_stream, spk_cfg) = audio::get_output_channel()?;
 
         let (shk_pin, _, shk_ch) = hook::try_register_shk()?;
-        let (pulse_ch, _, hook_ch, _) = pulse::notgoertzelme(shk_ch);
+        let ((pulse_ch, _), (hook_ch, _), (flash_ch, _)) = pulse::notgoertzelme(shk_ch);
 
-        let tls_conn = if do_i_have_internet().await? {
+        let (sip_conn, registration) = if do_i_have_internet().await? {
             debug!("Registering to SIP server");
-            let ip = public_ip::addr_v4().await.ok_or(anyhow!("no ip"))?;
-            let username = username.clone();
-            let password = password.clone();
-            let tls_conn =
-                sip::tlssocket::TlsSipConn::new(ip, sip::SERVER_NAME, sip::SERVER_PORT).await?;
-            tls_conn.dialog(username).await.register(password).await?;
-            Some(tls_conn)
+            let (sip_conn, registration) = connect(&account).await?;
+            (Some(sip_conn), registration)
         } else {
-            None
+            (None, watch::channel(Registration::Pending).1)
         };
 
         #[cfg(not(target_arch = "arm"))]
@@ -92,9 +117,9 @@ impl Phone {
         #[cfg(target_arch = "arm")]
         let is_on_hook = shk_pin.is_low();
 
-        let state = match (tls_conn, is_on_hook) {
-            (Some(tls_conn), true) => State::Connected(tls_conn, Dial::OnHook),
-            (Some(tls_conn), false) => State::Connected(tls_conn, Dial::Await),
+        let state = match (sip_conn, is_on_hook) {
+            (Some(sip_conn), true) => State::Connected(sip_conn, Dial::OnHook),
+            (Some(sip_conn), false) => State::Connected(sip_conn, Dial::Await),
             (None, true) => State::Disconnected(WiFi::OnHook),
             (None, false) => State::Disconnected(WiFi::Await),
         };
@@ -114,9 +139,13 @@ impl Phone {
 
             hook_ch,
             pulse_ch,
+            flash_ch,
+
+            account,
+            registration,
+            referrer: None,
 
-            username,
-            password,
+            rtp_ports,
         })
     }
 
@@ -174,47 +203,58 @@ impl Phone {
     pub async fn begin_life(mut self) -> Result<()> {
         loop {
             self.state = match self.state {
-                State::Connected(mut tls_conn, Dial::OnHook) => {
+                State::Connected(mut sip_conn, Dial::OnHook) => {
                     debug!("phone on hook");
+                    end_referral(&mut self.referrer, rsip::StatusCode::RequestTerminated).await;
                     let mut hook_ch = self.hook_ch.subscribe();
 
                     loop {
                         select! {
-                            recvd = tls_conn.new_msg_ch.recv() => {
+                            recvd = sip_conn.new_msg_ch.recv() => {
                                 let invite = recvd.ok_or(anyhow!("new msg chan closed"))?;
-                                let dialog = tls_conn.dialog_from_req(&invite).await?;
-                                let rtp_sock = rtp::socket::Socket::bind().await?;
-                                break State::Connected(tls_conn, Dial::Ringing(dialog, rtp_sock, invite));
+                                let mut dialog = sip_conn.dialog_from_req(&invite).await?;
+                                dialog.dtmf_mode = self.account.dtmf_mode;
+                                dialog.session.srtp_policy = self.account.srtp_policy;
+                                let req: rsip::Request = invite.clone().try_into()?;
+                                let rtp_sock = rtp::socket::Socket::bind(&self.rtp_ports).await?;
+                                dialog.session.set_port(rtp_sock.port());
+                                match dialog.negotiate_invite(&req) {
+                                    Ok(Some((answer, params))) => {
+                                        break State::Connected(sip_conn, Dial::Ringing(dialog, rtp_sock, invite, answer, params));
+                                    },
+                                    negotiated => {
+                                        if let Err(e) = negotiated {
+                                            warn!("couldn't make sense of the offer: {}", e);
+                                        }
+                                        let resp = dialog.response_to(req, rsip::StatusCode::NotAcceptableHere, vec![])?;
+                                        dialog.send(resp).await?;
+                                    },
+                                }
                             },
                             hook_evt = hook_ch.recv() => match hook_evt {
                                 Ok(SwitchHook::ON) => {},
-                                Ok(SwitchHook::OFF) => break State::Connected(tls_conn, Dial::Await),
-                                Err(e) => break State::Connected(tls_conn, Dial::Error(e.into())),
+                                Ok(SwitchHook::OFF) => break State::Connected(sip_conn, Dial::Await),
+                                Err(e) => break State::Connected(sip_conn, Dial::Error(e.into())),
                             },
                         }
                     }
                 }
-                State::Connected(tls_conn, Dial::Ringing(mut dialog, rtp_sock, invite)) => {
+                State::Connected(
+                    sip_conn,
+                    Dial::Ringing(mut dialog, mut rtp_sock, invite, answer, params),
+                ) => {
                     debug!("ringing");
                     let mut hook_ch = self.hook_ch.subscribe();
                     let _ring = ring::ring_phone()?;
 
-                    let resp = dialog.response_to(
-                        invite.clone().try_into()?,
-                        rsip::StatusCode::Ringing,
-                        vec![],
-                    )?;
-                    dialog.send(resp).await?;
+                    dialog.ringing(invite.clone().try_into()?).await?;
 
                     loop {
                         select! {
                             msg = dialog.recv() => match msg? {
                                 SipMessage::Request(req) => match req.method() {
-                                    rsip::Method::Cancel => {
-                                        let resp = dialog.response_to(req, rsip::StatusCode::RequestTerminated, vec![])?;
-                                        dialog.send(resp).await?;
-                                        break State::Connected(tls_conn, Dial::OnHook);
-                                    },
+                                    // The transaction layer already answered it and the INVITE
+                                    rsip::Method::Cancel => break State::Connected(sip_conn, Dial::OnHook),
                                     _ => Err(anyhow!("got non-cancel request during ringing: {}", req))?,
                                 },
                                 SipMessage::Response(_) => Err(anyhow!("got unexpected response during ringing"))?,
@@ -222,224 +262,568 @@ impl Phone {
                             hook_evt = hook_ch.recv() => match hook_evt {
                                 Ok(SwitchHook::ON) => {},
                                 Ok(SwitchHook::OFF) => {
-                                    // TODO(peter): Include appropriate SDP params in OK
-                                    let sdp = dialog.sdp_from(invite.clone().try_into()?)?;
-                                    let ref resp = dialog.sdp_response_to(invite.clone().try_into()?, rsip::StatusCode::OK, sdp)?;
-                                    dialog.send(resp.clone()).await?;
+                                    let resp = dialog.sdp_response_to(invite.clone().try_into()?, rsip::StatusCode::OK, answer.clone())?;
+                                    dialog.send(resp).await?;
                                     match dialog.recv().await? {
                                         SipMessage::Request(req) => match req.method() {
-                                            rsip::Method::Ack => break State::Connected(tls_conn, Dial::Connected(dialog, rtp_sock)),
+                                            rsip::Method::Ack => {
+                                                let params = match params {
+                                                    Some(params) => params,
+                                                    None => match dialog.accept_delayed_answer(&req) {
+                                                        Ok(params) => params,
+                                                        // Too late to turn it down, so hang up on it
+                                                        Err(e) => {
+                                                            warn!("couldn't take the answer in the ack: {}", e);
+                                                            dialog.bye().await?;
+                                                            break State::Connected(sip_conn, Dial::OnHook);
+                                                        },
+                                                    },
+                                                };
+                                                rtp_sock.connect(&params, self.audio_in_ch.subscribe(), self.audio_out_ch.clone()).await?;
+                                                break State::Connected(sip_conn, Dial::Connected(dialog, rtp_sock));
+                                            },
                                             _ => Err(anyhow!("got non-ack request during ringing: {}", req))?,
                                         }
                                         _ => Err(anyhow!("got unexpected response during ringing"))?,
                                     }
                                 },
-                                Err(e) => break State::Connected(tls_conn, Dial::Error(e.into())),
+                                Err(e) => break State::Connected(sip_conn, Dial::Error(e.into())),
                             },
                         }
                     }
                 }
-                State::Connected(tls_conn, Dial::Await) => {
+                State::Connected(sip_conn, Dial::Await) => {
                     debug!("phone picked up");
                     let audio_out_ch = self.audio_out_ch.clone();
                     let pulse_ch = self.pulse_ch.subscribe();
                     let goertzel_ch = dtmf::goertzelme(self.audio_in_ch.subscribe());
                     let mut hook_ch = self.hook_ch.subscribe();
 
-                    let mut tone = TwoToneGen::off_hook(self.audio_out_sample_rate);
+                    // Nobody can call us back if we're not registered, so let whoever picked up know
+                    let registration = *self.registration.borrow_and_update();
+                    let mut tone = match registration {
+                        Registration::Registered => {
+                            TwoToneGen::off_hook(self.audio_out_sample_rate)
+                        }
+                        Registration::Pending | Registration::Failed => {
+                            debug!("no service: {:?}", registration);
+                            TwoToneGen::no_service(self.audio_out_sample_rate)
+                        }
+                    };
                     tone.play(audio_out_ch);
 
                     let mut dig_ch = deco::de_digs(goertzel_ch, pulse_ch);
-                    let mut dialog = tls_conn.dialog(self.username.clone()).await;
+                    let mut dialog = sip_conn.dialog(self.account.username.clone()).await;
+                    dialog.dtmf_mode = self.account.dtmf_mode;
+                    dialog.session.srtp_policy = self.account.srtp_policy;
 
                     let mut number = String::new();
                     loop {
                         select! {
-                            _ = sleep(Duration::from_secs(1)), if (*CONTACTS).contains_key(&number) && number != self.username => {
+                            _ = sleep(Duration::from_secs(1)), if (*CONTACTS).contains_key(&number) && number != self.account.username => {
                                 let to = (*CONTACTS).get(&number).ok_or(anyhow!("contact is missing after I EXPLICITLY checked it"))?;
-                                dialog.invite(self.password.clone(), to.clone()).await?;
-                                let rtp_sock = rtp::socket::Socket::bind().await?;
-                                break State::Connected(tls_conn, Dial::DialOut(dialog, rtp_sock));
+                                let rtp_sock = rtp::socket::Socket::bind(&self.rtp_ports).await?;
+                                dialog.session.set_port(rtp_sock.port());
+                                dialog.invite(self.account.password.clone(), to.clone()).await?;
+                                break State::Connected(sip_conn, Dial::DialOut(dialog, rtp_sock));
                             },
                             dig = dig_ch.recv() => match dig {
                                 Some(dig) => {
                                     debug!("GOT DIG: {}", dig);
                                     number.push((dig + b'0').into());
                                 },
-                                None => break State::Connected(tls_conn, Dial::Error(anyhow!("dig channel died :("))),
+                                None => break State::Connected(sip_conn, Dial::Error(anyhow!("dig channel died :("))),
+                            },
+                            Ok(()) = self.registration.changed(), if number.is_empty() => {
+                                if *self.registration.borrow() != registration {
+                                    break State::Connected(sip_conn, Dial::Await);
+                                }
                             },
                             hook_evt = hook_ch.recv() => match hook_evt {
-                                Ok(SwitchHook::ON) => break State::Connected(tls_conn, Dial::OnHook),
+                                Ok(SwitchHook::ON) => break State::Connected(sip_conn, Dial::OnHook),
                                 Ok(SwitchHook::OFF) => {},
-                                Err(e) => break State::Connected(tls_conn, Dial::Error(e.into())),
+                                Err(e) => break State::Connected(sip_conn, Dial::Error(e.into())),
                             },
                         }
                     }
                 }
-                State::Connected(mut tls_conn, Dial::DialOut(mut dialog, rtp_sock)) => {
+                State::Connected(mut sip_conn, Dial::DialOut(mut dialog, mut rtp_sock)) => {
                     debug!("dialed out");
                     let mut hook_ch = self.hook_ch.subscribe();
+                    // From when the INVITE went out, however many challenges it takes
+                    let give_up = sleep(Duration::from_secs(5));
+                    tokio::pin!(give_up);
 
                     loop {
                         select! {
-                            _ = sleep(Duration::from_secs(5)) => {
+                            _ = &mut give_up => {
                                 dialog.cancel().await?;
                                 // TODO(peter): Assert what this should be
                                 dialog.recv().await?;
-                                break State::Connected(tls_conn, Dial::Busy)
+                                end_referral(&mut self.referrer, rsip::StatusCode::RequestTimeout).await;
+                                break State::Connected(sip_conn, Dial::Busy)
+                            },
+                            recvd = sip_conn.new_msg_ch.recv() => {
+                                reject_busy(&sip_conn, recvd.ok_or(anyhow!("new msg chan closed"))?).await?;
                             },
-                            msg = tls_conn.new_msg_ch.recv() => match msg {
-                                Some(SipMessage::Request(_)) => Err(anyhow!("unexpected request during dial out"))?,
-                                Some(SipMessage::Response(resp)) => match resp.status_code {
+                            msg = dialog.recv() => match msg? {
+                                SipMessage::Request(_) => Err(anyhow!("unexpected request during dial out"))?,
+                                SipMessage::Response(resp) => match resp.status_code {
+                                    // The transaction layer already ACKed it
                                     rsip::StatusCode::BusyHere |
                                     rsip::StatusCode::Decline => {
-                                        dialog.ack(resp).await?;
-                                        break State::Connected(tls_conn, Dial::Busy)
+                                        end_referral(&mut self.referrer, resp.status_code).await;
+                                        break State::Connected(sip_conn, Dial::Busy)
+                                    },
+                                    rsip::StatusCode::Unauthorized |
+                                    rsip::StatusCode::ProxyAuthenticationRequired |
+                                    rsip::StatusCode::SessionIntervalTooSmall
+                                        if !dialog.retry_invite(&self.account.password, &resp).await? => {
+                                        end_referral(&mut self.referrer, resp.status_code.clone()).await;
+                                        break State::Connected(sip_conn, Dial::Error(anyhow!("invite wasn't accepted: {}", resp.status_code)))
                                     },
-                                    rsip::StatusCode::Ringing => {
-                                        break State::Connected(tls_conn, Dial::Dialing(dialog, rtp_sock))
+                                    rsip::StatusCode::Ringing | rsip::StatusCode::SessionProgress => {
+                                        let early = match dialog.early_media(&resp)? {
+                                            Some(params) => {
+                                                rtp_sock.connect(&params, self.audio_in_ch.subscribe(), self.audio_out_ch.clone()).await?;
+                                                Some(resp)
+                                            },
+                                            None => None,
+                                        };
+                                        break State::Connected(sip_conn, Dial::Dialing(dialog, rtp_sock, early))
                                     },
                                     _ => {},
                                 },
-                                None => Err(anyhow!("tls conn closed"))?,
                             },
                             hook_evt = hook_ch.recv() => match hook_evt {
-                                Ok(SwitchHook::ON) => break State::Connected(tls_conn, Dial::OnHook),
-                                Ok(SwitchHook::OFF) => break State::Connected(tls_conn, Dial::Error(anyhow!("got off hook during dial out"))),
-                                Err(e) => break State::Connected(tls_conn, Dial::Error(e.into())),
+                                Ok(SwitchHook::ON) => break State::Connected(sip_conn, Dial::OnHook),
+                                Ok(SwitchHook::OFF) => break State::Connected(sip_conn, Dial::Error(anyhow!("got off hook during dial out"))),
+                                Err(e) => break State::Connected(sip_conn, Dial::Error(e.into())),
                             },
                         }
                     }
                 }
-                State::Connected(mut tls_conn, Dial::Dialing(mut dialog, rtp_sock)) => {
+                State::Connected(
+                    mut sip_conn,
+                    Dial::Dialing(mut dialog, mut rtp_sock, mut early),
+                ) => {
                     debug!("dialing");
                     let audio_out_ch = self.audio_out_ch.clone();
                     let mut hook_ch = self.hook_ch.subscribe();
 
-                    let mut tone = TwoToneGen::ring(self.audio_out_sample_rate);
-                    tone.play(audio_out_ch);
+                    // Whatever the far end's playing us beats our own ringback
+                    let mut tone = match early {
+                        Some(_) => None,
+                        None => {
+                            let mut tone = TwoToneGen::ring(self.audio_out_sample_rate);
+                            tone.play(audio_out_ch);
+                            Some(tone)
+                        }
+                    };
 
                     loop {
                         select! {
-                            msg = tls_conn.new_msg_ch.recv() => match msg {
-                                Some(SipMessage::Request(_)) => Err(anyhow!("unexpected request during dial out"))?,
-                                Some(SipMessage::Response(resp)) => match resp.status_code {
+                            recvd = sip_conn.new_msg_ch.recv() => {
+                                reject_busy(&sip_conn, recvd.ok_or(anyhow!("new msg chan closed"))?).await?;
+                            },
+                            msg = dialog.recv() => match msg? {
+                                SipMessage::Request(_) => Err(anyhow!("unexpected request during dial out"))?,
+                                SipMessage::Response(resp) => match resp.status_code {
                                     rsip::StatusCode::OK => {
                                         dialog.ack(resp.clone()).await?;
-                                        break State::Connected(tls_conn, Dial::Connected(dialog, rtp_sock));
+                                        // They don't have to repeat the answer they gave with early media
+                                        let answer = match &early {
+                                            Some(early) if resp.body.is_empty() => early,
+                                            _ => &resp,
+                                        };
+                                        let params = dialog.accept_answer(answer)?;
+                                        rtp_sock.connect(&params, self.audio_in_ch.subscribe(), self.audio_out_ch.clone()).await?;
+                                        end_referral(&mut self.referrer, rsip::StatusCode::OK).await;
+                                        break State::Connected(sip_conn, Dial::Connected(dialog, rtp_sock));
+                                    },
+                                    _ if resp.status_code.kind() == rsip::StatusCodeKind::Provisional => {
+                                        if let Some(params) = dialog.early_media(&resp)? {
+                                            drop(tone.take());
+                                            rtp_sock.connect(&params, self.audio_in_ch.subscribe(), self.audio_out_ch.clone()).await?;
+                                            early = Some(resp);
+                                        }
+                                    },
+                                    // Only a call we were consulting with can still be waiting on credentials
+                                    rsip::StatusCode::Unauthorized |
+                                    rsip::StatusCode::ProxyAuthenticationRequired |
+                                    rsip::StatusCode::SessionIntervalTooSmall
+                                        if dialog.retry_invite(&self.account.password, &resp).await? => {},
+                                    // The transaction layer already ACKed it
+                                    _ if matches!(
+                                        resp.status_code.kind(),
+                                        rsip::StatusCodeKind::RequestFailure | rsip::StatusCodeKind::ServerFailure | rsip::StatusCodeKind::GlobalFailure
+                                    ) => {
+                                        info!("call didn't go through: {}", resp.status_code);
+                                        end_referral(&mut self.referrer, resp.status_code).await;
+                                        break State::Connected(sip_conn, Dial::Busy)
                                     },
                                     _ => {},
                                 },
-                                None => Err(anyhow!("tls conn closed"))?,
                             },
                             hook_evt = hook_ch.recv() => match hook_evt {
-                                Ok(SwitchHook::ON) => break State::Connected(tls_conn, Dial::OnHook),
+                                Ok(SwitchHook::ON) => break State::Connected(sip_conn, Dial::OnHook),
                                 Ok(SwitchHook::OFF) => {},
-                                Err(e) => break State::Connected(tls_conn, Dial::Error(e.into())),
+                                Err(e) => break State::Connected(sip_conn, Dial::Error(e.into())),
                             },
                         }
                     }
                 }
-                State::Connected(mut tls_conn, Dial::Connected(mut dialog, mut rtp_sock)) => {
+                State::Connected(mut sip_conn, Dial::Connected(mut dialog, mut rtp_sock)) => {
                     debug!("connected yay");
                     let mut hook_ch = self.hook_ch.subscribe();
+                    let mut flash_ch = self.flash_ch.subscribe();
+                    let goertzel_ch = dtmf::goertzelme(self.audio_in_ch.subscribe());
+                    let mut dig_ch = deco::de_digs(goertzel_ch, self.pulse_ch.subscribe());
+                    let mut flow = sip_conn.flow();
 
                     loop {
+                        let session_deadline = dialog.session_deadline();
                         select! {
-                            msg = tls_conn.new_msg_ch.recv() => match msg {
-                                Some(SipMessage::Request(req)) => match req.method {
-                                    rsip::Method::Invite => {
-                                        let req_sdp = SessionDescription::from_str(std::str::from_utf8(&req.body)?)?;
-                                        let sdp_ip = {
-                                            let connection = req_sdp.connection.clone().ok_or(anyhow!("connection line doesn't exist"))?;
-                                            connection.connection_address.base
-                                        };
-                                        let mut sdp_port = None;
-                                        for desc in &req_sdp.media_descriptions {
-                                            if desc.media.media == MediaType::Audio {
-                                                sdp_port = Some(desc.media.port);
-                                            }
-                                        }
-                                        let sdp_port = sdp_port.ok_or(anyhow!("missing audio SDP port"))?;
-                                        let sdp_addr = SocketAddr::new(sdp_ip, sdp_port);
-
-                                        let audio_in_ch = self.audio_in_ch.subscribe();
-                                        let audio_out_ch = self.audio_out_ch.clone();
-                                        rtp_sock.connect(sdp_addr, audio_in_ch, audio_out_ch).await?;
-
-                                        let sdp = dialog.sdp_from(req.clone())?;
-                                        let resp = dialog.sdp_response_to(req.clone(), rsip::StatusCode::OK, sdp)?;
-                                        dialog.send(resp.clone()).await?;
-
-                                        match dialog.recv().await? {
-                                            SipMessage::Request(req) => match req.method() {
-                                                rsip::Method::Ack => continue,
-                                                _ => Err(anyhow!("got non-ack request during connected: {}", req))?,
-                                            }
-                                            _ => Err(anyhow!("got unexpected response during connected"))?,
-                                        }
-                                    },
-                                    _ => Err(anyhow!("got unexpected request method during connected"))?,
+                            // The call won't survive on a new connection, so let whoever's on the line know
+                            Ok(()) = flow.changed() => if *flow.borrow() == Flow::Down {
+                                info!(stats = ?rtp_sock.stats(), "lost the connection mid-call");
+                                break State::Connected(sip_conn, Dial::Dropped);
+                            },
+                            recvd = sip_conn.new_msg_ch.recv() => {
+                                let msg = recvd.ok_or(anyhow!("new msg chan closed"))?;
+                                if !dialog.replaced_by(&msg)? {
+                                    reject_busy(&sip_conn, msg).await?;
+                                    continue;
+                                }
+                                // Someone we were transferred to is taking this call over
+                                let mut replacement = sip_conn.dialog_from_req(&msg).await?;
+                                replacement.dtmf_mode = self.account.dtmf_mode;
+                                replacement.session.srtp_policy = self.account.srtp_policy;
+                                replacement.session.set_port(rtp_sock.port());
+                                if let Some(params) = replacement.answer_reinvite(msg.try_into()?).await? {
+                                    rtp_sock.connect(&params, self.audio_in_ch.subscribe(), self.audio_out_ch.clone()).await?;
+                                    dialog.bye().await?;
+                                    dialog = replacement;
+                                    info!("call replaced");
+                                }
+                            },
+                            _ = asyncutil::sleep_until(session_deadline) => match dialog.refresh_session(self.account.password.clone()).await {
+                                Ok(Some(params)) => rtp_sock.connect(&params, self.audio_in_ch.subscribe(), self.audio_out_ch.clone()).await?,
+                                Ok(None) => {},
+                                // Nothing's coming back from the far end, so don't wait on the BYE either
+                                Err(e) => {
+                                    warn!("session timer ran out: {}", e);
+                                    if let Err(e) = dialog.bye().await {
+                                        warn!("couldn't send BYE: {}", e);
+                                    }
+                                    info!(stats = ?rtp_sock.stats(), "call dropped");
+                                    break State::Connected(sip_conn, Dial::Dropped);
                                 },
-                                Some(SipMessage::Response(_)) => Err(anyhow!("unexpected response during connected"))?,
-                                None => Err(anyhow!("tls conn closed"))?,
                             },
                             msg = dialog.recv() => match msg {
                                 Ok(SipMessage::Request(req)) => match req.method {
-                                    rsip::Method::Invite => {
-                                        let req_sdp = SessionDescription::from_str(std::str::from_utf8(&req.body)?)?;
-                                        let sdp_ip = {
-                                            let connection = req_sdp.connection.clone().ok_or(anyhow!("connection line doesn't exist"))?;
-                                            connection.connection_address.base
-                                        };
-                                        let mut sdp_port = None;
-                                        for desc in &req_sdp.media_descriptions {
-                                            if desc.media.media == MediaType::Audio {
-                                                sdp_port = Some(desc.media.port);
+                                    rsip::Method::Invite => match dialog.answer_reinvite(req).await? {
+                                        Some(params) => {
+                                            if !params.direction.recvs() {
+                                                info!("far end put us on hold");
                                             }
+                                            rtp_sock.connect(&params, self.audio_in_ch.subscribe(), self.audio_out_ch.clone()).await?;
+                                        },
+                                        None => warn!("turned down a re-INVITE, keeping the media as it was"),
+                                    },
+                                    rsip::Method::Refer => if let Some((target, replaces)) = dialog.answer_refer(req).await? {
+                                        info!(%target, "transferred");
+                                        let mut transferred = sip_conn.dialog(self.account.username.clone()).await;
+                                        transferred.dtmf_mode = self.account.dtmf_mode;
+                                        transferred.session.srtp_policy = self.account.srtp_policy;
+                                        transferred.session.set_port(rtp_sock.port());
+                                        let to = rsip::typed::To { display_name: None, uri: target, params: vec![] };
+                                        transferred.invite_replacing(self.account.password.clone(), to, replaces).await?;
+                                        self.referrer = Some(dialog);
+                                        break State::Connected(sip_conn, Dial::DialOut(transferred, rtp_sock));
+                                    },
+                                    rsip::Method::Update => {
+                                        if let Some(params) = dialog.answer_update(req).await? {
+                                            rtp_sock.connect(&params, self.audio_in_ch.subscribe(), self.audio_out_ch.clone()).await?;
                                         }
-                                        let sdp_port = sdp_port.ok_or(anyhow!("missing audio SDP port"))?;
-                                        let sdp_addr = SocketAddr::new(sdp_ip, sdp_port);
-
-                                        let audio_in_ch = self.audio_in_ch.subscribe();
-                                        let audio_out_ch = self.audio_out_ch.clone();
-                                        rtp_sock.connect(sdp_addr, audio_in_ch, audio_out_ch).await?;
-
-                                        let sdp = dialog.sdp_from(req.clone())?;
-                                        let resp = dialog.sdp_response_to(req.clone(), rsip::StatusCode::OK, sdp)?;
-                                        dialog.send(resp.clone()).await?;
-
-                                        match dialog.recv().await? {
-                                            SipMessage::Request(req) => match req.method() {
-                                                rsip::Method::Ack => continue,
-                                                _ => Err(anyhow!("got non-ack request during connected: {}", req))?,
-                                            }
-                                            _ => Err(anyhow!("got unexpected response during connected"))?,
+                                    },
+                                    rsip::Method::Info => {
+                                        if let Some(dig) = dialog.answer_info(req).await? {
+                                            debug!("remote sent digit over INFO: {}", dig);
                                         }
                                     },
                                     rsip::Method::Bye => {
                                         let resp = dialog.response_to(req.clone(), rsip::StatusCode::OK, vec![])?;
                                         dialog.send(resp).await?;
-                                        break State::Connected(tls_conn, Dial::Error(anyhow!("other party hung up")));
+                                        info!(stats = ?rtp_sock.stats(), "call ended by remote");
+                                        break State::Connected(sip_conn, Dial::Error(anyhow!("other party hung up")));
                                     },
                                     _ => Err(anyhow!("got unexpected request method during connected"))?,
                                 },
+                                // Only our INFOs should be waiting on a response mid-call
+                                Ok(SipMessage::Response(resp)) if resp.cseq_header()?.method()? == rsip::Method::Info => {
+                                    if let Err(e) = sip::assert_status(&resp) {
+                                        warn!("far end rejected INFO digit: {}", e);
+                                    }
+                                },
                                 Ok(SipMessage::Response(_)) => Err(anyhow!("unexpected response during connected"))?,
                                 Err(e) => Err(e)?,
                             },
+                            dig = dig_ch.recv() => match dig {
+                                Some(dig) => {
+                                    let sent = match dialog.dtmf_mode {
+                                        sip::account::DtmfMode::Rfc4733 => rtp_sock.send_digit(dig).await,
+                                        sip::account::DtmfMode::Info => dialog.info_digit(dig).await,
+                                    };
+                                    if let Err(e) = sent {
+                                        warn!("couldn't send digit {}: {}", dig, e);
+                                    }
+                                },
+                                None => Err(anyhow!("digs chan closed"))?,
+                            },
                             hook_evt = hook_ch.recv() => match hook_evt {
                                 Ok(SwitchHook::ON) => {
                                     dialog.bye().await?;
                                     sip::assert_status(&dialog.recv().await?.try_into()?)?;
-                                    break State::Connected(tls_conn, Dial::OnHook);
+                                    info!(stats = ?rtp_sock.stats(), "call ended");
+                                    break State::Connected(sip_conn, Dial::OnHook);
                                 },
                                 Ok(SwitchHook::OFF) => {},
-                                Err(e) => break State::Connected(tls_conn, Dial::Error(e.into())),
+                                Err(e) => break State::Connected(sip_conn, Dial::Error(e.into())),
+                            },
+                            flash = flash_ch.recv() => match flash {
+                                Ok(()) => match dialog.hold(self.account.password.clone(), true).await? {
+                                    Some(params) => {
+                                        rtp_sock.connect(&params, self.audio_in_ch.subscribe(), self.audio_out_ch.clone()).await?;
+                                        break State::Connected(sip_conn, Dial::Transfer(dialog, rtp_sock));
+                                    },
+                                    None => warn!("far end wouldn't go on hold, can't transfer them"),
+                                },
+                                Err(e) => break State::Connected(sip_conn, Dial::Error(e.into())),
                             },
                         }
                     }
                 }
-                State::Connected(tls_conn, Dial::Busy) => {
+                State::Connected(mut sip_conn, Dial::Transfer(mut held, mut held_rtp)) => {
+                    debug!("dialing a transfer");
+                    let audio_out_ch = self.audio_out_ch.clone();
+                    let mut hook_ch = self.hook_ch.subscribe();
+                    let mut flash_ch = self.flash_ch.subscribe();
+                    let goertzel_ch = dtmf::goertzelme(self.audio_in_ch.subscribe());
+                    let mut dig_ch = deco::de_digs(goertzel_ch, self.pulse_ch.subscribe());
+
+                    let mut tone = TwoToneGen::off_hook(self.audio_out_sample_rate);
+                    tone.play(audio_out_ch);
+
+                    let mut number = String::new();
+                    loop {
+                        select! {
+                            _ = sleep(Duration::from_secs(1)), if (*CONTACTS).contains_key(&number) && number != self.account.username => {
+                                let to = (*CONTACTS).get(&number).ok_or(anyhow!("contact is missing after I EXPLICITLY checked it"))?;
+                                let mut consult = sip_conn.dialog(self.account.username.clone()).await;
+                                consult.dtmf_mode = self.account.dtmf_mode;
+                                consult.session.srtp_policy = self.account.srtp_policy;
+                                let consult_rtp = rtp::socket::Socket::bind(&self.rtp_ports).await?;
+                                consult.session.set_port(consult_rtp.port());
+                                consult.invite(self.account.password.clone(), to.clone()).await?;
+                                break State::Connected(sip_conn, Dial::Consulting(held, held_rtp, consult, consult_rtp, to.clone()));
+                            },
+                            dig = dig_ch.recv() => match dig {
+                                Some(dig) => number.push((dig + b'0').into()),
+                                None => break State::Connected(sip_conn, Dial::Error(anyhow!("dig channel died :("))),
+                            },
+                            recvd = sip_conn.new_msg_ch.recv() => {
+                                reject_busy(&sip_conn, recvd.ok_or(anyhow!("new msg chan closed"))?).await?;
+                            },
+                            msg = held.recv() => match on_hold(&mut held, &mut held_rtp, msg?, self.audio_in_ch.subscribe(), self.audio_out_ch.clone()).await? {
+                                true => {},
+                                false => break State::Connected(sip_conn, Dial::Await),
+                            },
+                            // Changed our mind, back to who we had
+                            flash = flash_ch.recv() => match flash {
+                                Ok(()) => {
+                                    resume(&mut held, &mut held_rtp, &self.account, self.audio_in_ch.subscribe(), self.audio_out_ch.clone()).await?;
+                                    break State::Connected(sip_conn, Dial::Connected(held, held_rtp));
+                                },
+                                Err(e) => break State::Connected(sip_conn, Dial::Error(e.into())),
+                            },
+                            hook_evt = hook_ch.recv() => match hook_evt {
+                                Ok(SwitchHook::ON) => {
+                                    held.bye().await?;
+                                    info!(stats = ?held_rtp.stats(), "call ended");
+                                    break State::Connected(sip_conn, Dial::OnHook);
+                                },
+                                Ok(SwitchHook::OFF) => {},
+                                Err(e) => break State::Connected(sip_conn, Dial::Error(e.into())),
+                            },
+                        }
+                    }
+                }
+                State::Connected(
+                    mut sip_conn,
+                    Dial::Consulting(mut held, mut held_rtp, mut consult, mut consult_rtp, target),
+                ) => {
+                    debug!("consulting on a transfer");
+                    let mut hook_ch = self.hook_ch.subscribe();
+                    let mut flash_ch = self.flash_ch.subscribe();
+
+                    let mut tone: Option<TwoToneGen> = None;
+                    let mut answered = false;
+                    loop {
+                        select! {
+                            msg = consult.recv() => match msg? {
+                                SipMessage::Response(resp) => match resp.status_code.kind() {
+                                    rsip::StatusCodeKind::Provisional => {
+                                        if let Some(params) = consult.early_media(&resp)? {
+                                            drop(tone.take());
+                                            consult_rtp.connect(&params, self.audio_in_ch.subscribe(), self.audio_out_ch.clone()).await?;
+                                        } else if tone.is_none() && resp.status_code == rsip::StatusCode::Ringing {
+                                            let mut ring = TwoToneGen::ring(self.audio_out_sample_rate);
+                                            ring.play(self.audio_out_ch.clone());
+                                            tone = Some(ring);
+                                        }
+                                    },
+                                    rsip::StatusCodeKind::Successful => {
+                                        drop(tone.take());
+                                        consult.ack(resp.clone()).await?;
+                                        let params = consult.accept_answer(&resp)?;
+                                        consult_rtp.connect(&params, self.audio_in_ch.subscribe(), self.audio_out_ch.clone()).await?;
+                                        answered = true;
+                                    },
+                                    // The transaction layer already ACKed it
+                                    _ => {
+                                        if consult.retry_invite(&self.account.password, &resp).await? {
+                                            continue;
+                                        }
+                                        warn!("couldn't reach who we're transferring to: {}", resp.status_code);
+                                        resume(&mut held, &mut held_rtp, &self.account, self.audio_in_ch.subscribe(), self.audio_out_ch.clone()).await?;
+                                        break State::Connected(sip_conn, Dial::Connected(held, held_rtp));
+                                    },
+                                },
+                                SipMessage::Request(req) => match req.method {
+                                    rsip::Method::Bye => {
+                                        let resp = consult.response_to(req, rsip::StatusCode::OK, vec![])?;
+                                        consult.send(resp).await?;
+                                        resume(&mut held, &mut held_rtp, &self.account, self.audio_in_ch.subscribe(), self.audio_out_ch.clone()).await?;
+                                        break State::Connected(sip_conn, Dial::Connected(held, held_rtp));
+                                    },
+                                    rsip::Method::Invite => if let Some(params) = consult.answer_reinvite(req).await? {
+                                        consult_rtp.connect(&params, self.audio_in_ch.subscribe(), self.audio_out_ch.clone()).await?;
+                                    },
+                                    _ => warn!("ignoring {} while consulting", req.method),
+                                },
+                            },
+                            msg = held.recv() => if !on_hold(&mut held, &mut held_rtp, msg?, self.audio_in_ch.subscribe(), self.audio_out_ch.clone()).await? {
+                                // Nobody left to transfer, so this is just a call now
+                                break match answered {
+                                    true => State::Connected(sip_conn, Dial::Connected(consult, consult_rtp)),
+                                    false => State::Connected(sip_conn, Dial::Dialing(consult, consult_rtp, None)),
+                                };
+                            },
+                            recvd = sip_conn.new_msg_ch.recv() => {
+                                reject_busy(&sip_conn, recvd.ok_or(anyhow!("new msg chan closed"))?).await?;
+                            },
+                            // Drop who we called and go back to who we had
+                            flash = flash_ch.recv() => match flash {
+                                Ok(()) => {
+                                    match answered {
+                                        true => consult.bye().await?,
+                                        false => consult.cancel().await?,
+                                    }
+                                    resume(&mut held, &mut held_rtp, &self.account, self.audio_in_ch.subscribe(), self.audio_out_ch.clone()).await?;
+                                    break State::Connected(sip_conn, Dial::Connected(held, held_rtp));
+                                },
+                                Err(e) => break State::Connected(sip_conn, Dial::Error(e.into())),
+                            },
+                            // Hanging up hands them over. Once the consult's answered they take its
+                            // place, before that it's a blind transfer
+                            // https://www.rfc-editor.org/rfc/rfc5589 (6, 7)
+                            hook_evt = hook_ch.recv() => match hook_evt {
+                                Ok(SwitchHook::ON) => {
+                                    let password = self.account.password.clone();
+                                    let consult = match answered {
+                                        true => {
+                                            held.refer(password, target.uri.clone(), Some(consult.replaces()?)).await?;
+                                            Some(consult)
+                                        },
+                                        false => {
+                                            consult.cancel().await?;
+                                            held.refer(password, target.uri.clone(), None).await?;
+                                            None
+                                        },
+                                    };
+                                    info!(answered, "transfer sent");
+                                    break State::Connected(sip_conn, Dial::Transferring(held, consult));
+                                },
+                                Ok(SwitchHook::OFF) => {},
+                                Err(e) => break State::Connected(sip_conn, Dial::Error(e.into())),
+                            },
+                        }
+                    }
+                }
+                State::Connected(mut sip_conn, Dial::Transferring(mut held, mut consult)) => {
+                    debug!("transferring");
+                    let timeout = sleep(sip::transaction::T1 * 64);
+                    tokio::pin!(timeout);
+
+                    let held_hung_up = loop {
+                        select! {
+                            _ = &mut timeout => {
+                                warn!("never heard how the transfer went");
+                                break false;
+                            },
+                            msg = held.recv() => match msg? {
+                                SipMessage::Request(req) => match req.method {
+                                    rsip::Method::Notify => match held.answer_notify(req).await? {
+                                        Some(status) if status.kind() != rsip::StatusCodeKind::Provisional => {
+                                            match status.kind() {
+                                                rsip::StatusCodeKind::Successful => info!("transfer went through"),
+                                                _ => warn!("transfer failed: {}", status),
+                                            }
+                                            break false;
+                                        },
+                                        _ => {},
+                                    },
+                                    rsip::Method::Bye => {
+                                        let resp = held.response_to(req, rsip::StatusCode::OK, vec![])?;
+                                        held.send(resp).await?;
+                                        break true;
+                                    },
+                                    _ => {
+                                        let resp = held.response_to(req, rsip::StatusCode::MethodNotAllowed, vec![])?;
+                                        held.send(resp).await?;
+                                    },
+                                },
+                                SipMessage::Response(_) => {},
+                            },
+                            // Whoever we transferred to hangs up on us once they've got them
+                            msg = recv_from(&mut consult) => if let (SipMessage::Request(req), Some(dialog)) = (msg?, consult.as_mut()) {
+                                let status_code = match req.method {
+                                    rsip::Method::Bye => rsip::StatusCode::OK,
+                                    _ => rsip::StatusCode::MethodNotAllowed,
+                                };
+                                let hung_up = req.method == rsip::Method::Bye;
+                                let resp = dialog.response_to(req, status_code, vec![])?;
+                                dialog.send(resp).await?;
+                                if hung_up {
+                                    consult = None;
+                                }
+                            },
+                            recvd = sip_conn.new_msg_ch.recv() => {
+                                reject_busy(&sip_conn, recvd.ok_or(anyhow!("new msg chan closed"))?).await?;
+                            },
+                        }
+                    };
+                    // We're out of it however it went
+                    if !held_hung_up {
+                        held.bye().await?;
+                    }
+                    if let Some(consult) = &mut consult {
+                        consult.bye().await?;
+                    }
+                    State::Connected(sip_conn, Dial::OnHook)
+                }
+                State::Connected(sip_conn, Dial::Busy) => {
                     debug!("busy");
                     let audio_out_ch = self.audio_out_ch.clone();
                     let mut hook_ch = self.hook_ch.subscribe();
@@ -449,19 +833,35 @@ impl Phone {
 
                     loop {
                         match hook_ch.recv().await {
-                            Ok(SwitchHook::ON) => break State::Connected(tls_conn, Dial::OnHook),
+                            Ok(SwitchHook::ON) => break State::Connected(sip_conn, Dial::OnHook),
                             Ok(SwitchHook::OFF) => {}
-                            Err(e) => break State::Connected(tls_conn, Dial::Error(e.into())),
+                            Err(e) => break State::Connected(sip_conn, Dial::Error(e.into())),
                         }
                     }
                 }
-                State::Connected(tls_conn, Dial::Error(e)) => {
+                State::Connected(sip_conn, Dial::Dropped) => {
+                    debug!("dropped");
+                    let audio_out_ch = self.audio_out_ch.clone();
+                    let mut hook_ch = self.hook_ch.subscribe();
+
+                    let mut tone = TwoToneGen::no_service(self.audio_out_sample_rate);
+                    tone.play(audio_out_ch);
+
+                    loop {
+                        match hook_ch.recv().await {
+                            Ok(SwitchHook::ON) => break State::Connected(sip_conn, Dial::OnHook),
+                            Ok(SwitchHook::OFF) => {}
+                            Err(e) => break State::Connected(sip_conn, Dial::Error(e.into())),
+                        }
+                    }
+                }
+                State::Connected(sip_conn, Dial::Error(e)) => {
                     error!("{:?}", e);
                     let mut hook_ch = self.hook_ch.subscribe();
 
                     loop {
                         match hook_ch.recv().await {
-                            Ok(SwitchHook::ON) => break State::Connected(tls_conn, Dial::OnHook),
+                            Ok(SwitchHook::ON) => break State::Connected(sip_conn, Dial::OnHook),
                             Ok(SwitchHook::OFF) => {}
                             Err(e) => break State::Disconnected(WiFi::Error(e.into())),
                         }
@@ -483,11 +883,9 @@ impl Phone {
                         has_internet = do_i_have_internet() => {
                             match has_internet {
                                 Ok(true) => {
-                                    let ip = public_ip::addr_v4().await.ok_or(anyhow!("no ip"))?;
-                                    let tls_conn =
-                                        sip::tlssocket::TlsSipConn::new(ip, sip::SERVER_NAME, sip::SERVER_PORT).await?;
-                                    tls_conn.dialog(self.username.clone()).await.register(self.password.clone()).await?;
-                                    Some(State::Connected(tls_conn, Dial::OnHook))
+                                    let (sip_conn, registration) = connect(&self.account).await?;
+                                    self.registration = registration;
+                                    Some(State::Connected(sip_conn, Dial::OnHook))
                                 },
                                 Ok(false) => None,
                                 Err(e) => Some(State::Disconnected(WiFi::Error(e))),
@@ -517,11 +915,9 @@ impl Phone {
                     select! {
                         wifi_evt = self.get_wifi_creds() => match wifi_evt {
                             Ok(_) => {
-                                let ip = public_ip::addr_v4().await.ok_or(anyhow!("no ip"))?;
-                                let tls_conn =
-                                    sip::tlssocket::TlsSipConn::new(ip, sip::SERVER_NAME, sip::SERVER_PORT).await?;
-                                tls_conn.dialog(self.username.clone()).await.register(self.password.clone()).await?;
-                                State::Connected(tls_conn, Dial::Await)
+                                let (sip_conn, registration) = connect(&self.account).await?;
+                                self.registration = registration;
+                                State::Connected(sip_conn, Dial::Await)
                             },
                             Err(e) => State::Disconnected(WiFi::Error(e)),
                         },
@@ -548,3 +944,100 @@ impl Phone {
         }
     }
 }
+
+// Registration keeps itself alive in the background from here on
+async fn connect(
+    account: &sip::account::Account,
+) -> Result<(SipConn, watch::Receiver<Registration>)> {
+    let ip = public_ip::addr_v4().await.ok_or(anyhow!("no ip"))?;
+    let resolver = DnsResolver::system()?;
+    let sip_conn = SipConn::locate(&resolver, account.transport, ip, sip::SERVER_NAME).await?;
+    let dialog = sip_conn.dialog(account.username.clone()).await;
+    let registration = sip::registration::spawn(dialog, account.password.clone(), sip_conn.flow());
+    Ok((sip_conn, registration))
+}
+
+// Someone new is calling while we're busy with a call of our own
+async fn reject_busy(sip_conn: &SipConn, msg: SipMessage) -> Result<()> {
+    let mut dialog = sip_conn.dialog_from_req(&msg).await?;
+    let req: rsip::Request = msg.try_into()?;
+    debug!("rejecting {} while busy", req.method);
+    let resp = dialog.response_to(req, rsip::StatusCode::BusyHere, vec![])?;
+    dialog.send(resp).await
+}
+
+// Deals with whatever the far end sends while they're on hold, and returns false once they've
+// hung up
+async fn on_hold(
+    held: &mut sip::Dialog,
+    held_rtp: &mut rtp::socket::Socket,
+    msg: SipMessage,
+    audio_in_ch: broadcast::Receiver<i16>,
+    audio_out_ch: mpsc::Sender<i16>,
+) -> Result<bool> {
+    let SipMessage::Request(req) = msg else {
+        return Ok(true);
+    };
+    match req.method {
+        rsip::Method::Bye => {
+            let resp = held.response_to(req, rsip::StatusCode::OK, vec![])?;
+            held.send(resp).await?;
+            info!(stats = ?held_rtp.stats(), "call on hold ended by remote");
+            return Ok(false);
+        }
+        rsip::Method::Invite => {
+            if let Some(params) = held.answer_reinvite(req).await? {
+                held_rtp.connect(&params, audio_in_ch, audio_out_ch).await?;
+            }
+        }
+        rsip::Method::Update => {
+            if let Some(params) = held.answer_update(req).await? {
+                held_rtp.connect(&params, audio_in_ch, audio_out_ch).await?;
+            }
+        }
+        _ => {
+            let resp = held.response_to(req, rsip::StatusCode::MethodNotAllowed, vec![])?;
+            held.send(resp).await?;
+        }
+    }
+    Ok(true)
+}
+
+async fn resume(
+    held: &mut sip::Dialog,
+    held_rtp: &mut rtp::socket::Socket,
+    account: &sip::account::Account,
+    audio_in_ch: broadcast::Receiver<i16>,
+    audio_out_ch: mpsc::Sender<i16>,
+) -> Result<()> {
+    match held.hold(account.password.clone(), false).await? {
+        Some(params) => held_rtp.connect(&params, audio_in_ch, audio_out_ch).await,
+        None => Err(anyhow!("far end wouldn't come off hold")),
+    }
+}
+
+// Never finishes once there's no dialog left to hear from
+async fn recv_from(dialog: &mut Option<sip::Dialog>) -> Result<SipMessage> {
+    match dialog {
+        Some(dialog) => dialog.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+// Lets whoever transferred us know how the call they sent us to went, and hangs up on them if it
+// went through
+// https://www.rfc-editor.org/rfc/rfc3515 (2.4.5)
+async fn end_referral(referrer: &mut Option<sip::Dialog>, status_code: rsip::StatusCode) {
+    let Some(mut referrer) = referrer.take() else {
+        return;
+    };
+    let succeeded = status_code.kind() == rsip::StatusCodeKind::Successful;
+    if let Err(e) = referrer.notify_refer(status_code).await {
+        warn!("couldn't tell the transferrer how it went: {}", e);
+    }
+    if succeeded {
+        if let Err(e) = referrer.bye().await {
+            warn!("couldn't hang up on the transferrer: {}", e);
+        }
+    }
+}
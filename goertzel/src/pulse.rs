@@ -8,19 +8,21 @@ use crate::asyncutil::and_log_err;
 use crate::hook::SwitchHook;
 
 const PULSE_TIMEOUT_MS: u64 = 150;
+// Back off hook before this and it was a flash, not a hang up
+const FLASH_MAX_MS: u64 = 1000;
 
+type Channel<T> = (broadcast::Sender<T>, broadcast::Receiver<T>);
+
+// Splits the switch hook into dialed digits, hang ups and flashes
 pub fn notgoertzelme(
     mut shk_ch: broadcast::Receiver<SwitchHook>,
-) -> (
-    broadcast::Sender<u8>,
-    broadcast::Receiver<u8>,
-    broadcast::Sender<SwitchHook>,
-    broadcast::Receiver<SwitchHook>,
-) {
+) -> (Channel<u8>, Channel<SwitchHook>, Channel<()>) {
     let (digit_send_ch, digit_recv_ch) = broadcast::channel(1);
     let digit_send_ch2 = digit_send_ch.clone();
     let (onhook_send_ch, onhook_recv_ch) = broadcast::channel(1);
     let onhook_send_ch2 = onhook_send_ch.clone();
+    let (flash_send_ch, flash_recv_ch) = broadcast::channel(1);
+    let flash_send_ch2 = flash_send_ch.clone();
 
     tokio::spawn(and_log_err("pulse_detect", async move {
         loop {
@@ -34,8 +36,16 @@ pub fn notgoertzelme(
             loop {
                 tokio::select! {
                     _ = sleep(Duration::from_millis(PULSE_TIMEOUT_MS)) => {
-                        trace!("I hang up");
-                        let _ = onhook_send_ch.send(SwitchHook::ON);
+                        tokio::select! {
+                            _ = sleep(Duration::from_millis(FLASH_MAX_MS - PULSE_TIMEOUT_MS)) => {
+                                trace!("I hang up");
+                                let _ = onhook_send_ch.send(SwitchHook::ON);
+                            }
+                            _ = shk_ch.recv() => {
+                                trace!("I flash");
+                                let _ = flash_send_ch.send(());
+                            }
+                        };
                         break;
                     }
                     _ = shk_ch.recv() => {
@@ -60,9 +70,8 @@ pub fn notgoertzelme(
     }));
 
     (
-        digit_send_ch2,
-        digit_recv_ch,
-        onhook_send_ch2,
-        onhook_recv_ch,
+        (digit_send_ch2, digit_recv_ch),
+        (onhook_send_ch2, onhook_recv_ch),
+        (flash_send_ch2, flash_recv_ch),
     )
 }
@@ -0,0 +1,116 @@
+use anyhow::{anyhow, Result};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+// Comfort noise, so silence suppression doesn't sound like the line went dead
+// https://www.rfc-editor.org/rfc/rfc3389
+pub const CN_PAYLOAD_TYPE: u8 = 13;
+pub const CN_ENCODING_NAME: &str = "CN";
+pub const CN_CLOCK_RATE: u32 = 8000;
+
+// Noise level is in -dBov, 127 being as quiet as it gets
+// https://www.rfc-editor.org/rfc/rfc3389 (3.1)
+const MAX_LEVEL: u8 = 127;
+const FULL_SCALE: f64 = 32768.0;
+
+// Anything quieter than this is background noise
+const SILENCE_LEVEL: u8 = 50;
+// Frames of quiet before we stop sending audio, so we don't clip the ends of words
+const HANGOVER_FRAMES: usize = 10;
+
+// Silence Insertion Descriptor, we only send the level and ignore any spectral info
+// https://www.rfc-editor.org/rfc/rfc3389 (3)
+pub fn parse(payload: &[u8]) -> Result<u8> {
+    let level = payload
+        .first()
+        .ok_or(anyhow!("empty comfort noise payload"))?;
+    Ok(level & MAX_LEVEL)
+}
+
+pub fn to_bytes(level: u8) -> Vec<u8> {
+    vec![level.min(MAX_LEVEL)]
+}
+
+pub fn level(samples: &[i16]) -> u8 {
+    if samples.is_empty() {
+        return MAX_LEVEL;
+    }
+    let power = samples.iter().map(|s| (*s as f64).powi(2)).sum::<f64>() / samples.len() as f64;
+    let dbov = 10.0 * (power / FULL_SCALE.powi(2)).log10();
+    (-dbov).clamp(0.0, MAX_LEVEL as f64) as u8
+}
+
+pub struct NoiseGenerator {
+    amplitude: f64,
+    rng: StdRng,
+}
+
+impl NoiseGenerator {
+    pub fn new(level: u8) -> Self {
+        let rms = FULL_SCALE * 10f64.powf(-(level as f64) / 20.0);
+        Self {
+            // Uniform noise over [-a, a] has an RMS of a/sqrt(3)
+            amplitude: rms * 3f64.sqrt(),
+            rng: StdRng::from_rng(&mut rand::rng()),
+        }
+    }
+
+    pub fn generate(&mut self, n_samples: usize) -> Vec<i16> {
+        (0..n_samples)
+            .map(|_| self.rng.random_range(-self.amplitude..=self.amplitude) as i16)
+            .collect()
+    }
+}
+
+#[derive(Default)]
+pub struct SilenceDetector {
+    quiet_frames: usize,
+}
+
+impl SilenceDetector {
+    // Returns the background noise level once it's been quiet long enough to stop sending audio
+    pub fn update(&mut self, samples: &[i16]) -> Option<u8> {
+        let level = level(samples);
+        if level >= SILENCE_LEVEL {
+            self.quiet_frames += 1;
+        } else {
+            self.quiet_frames = 0;
+        }
+        (self.quiet_frames > HANGOVER_FRAMES).then_some(level)
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+
+    #[test]
+    fn successfully_generate_noise_at_level() {
+        for level in [20, 40, 60] {
+            let noise = NoiseGenerator::new(level).generate(8000);
+            let measured = super::level(&noise);
+            assert!(measured.abs_diff(level) <= 1, "{} -> {}", level, measured);
+        }
+        assert_eq!(super::level(&[0; 160]), MAX_LEVEL);
+    }
+
+    #[test]
+    fn successfully_detect_silence_after_hangover() {
+        let mut detector = SilenceDetector::default();
+        let speech = NoiseGenerator::new(10).generate(160);
+        let quiet = NoiseGenerator::new(70).generate(160);
+
+        assert_eq!(detector.update(&speech), None);
+        for _ in 0..HANGOVER_FRAMES {
+            assert_eq!(detector.update(&quiet), None);
+        }
+        assert!(detector.update(&quiet).is_some_and(|level| level >= 69));
+        assert_eq!(detector.update(&speech), None);
+    }
+
+    #[test]
+    fn fail_to_parse_empty_payload() {
+        assert!(parse(&[]).is_err());
+        assert_eq!(parse(&to_bytes(200)).unwrap(), MAX_LEVEL);
+    }
+}
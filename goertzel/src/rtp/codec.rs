@@ -0,0 +1,199 @@
+// G.711 companding, adapted from the Sun reference implementation
+// https://www.itu.int/rec/T-REC-G.711
+pub const PCMU_PAYLOAD_TYPE: u8 = 0;
+pub const PCMA_PAYLOAD_TYPE: u8 = 8;
+
+// In order of preference when we're making an offer
+pub const SUPPORTED_PAYLOAD_TYPES: [u8; 2] = [PCMU_PAYLOAD_TYPE, PCMA_PAYLOAD_TYPE];
+
+const G711_CLOCK_RATE: u32 = 8000;
+
+const ULAW_BIAS: i32 = 0x84;
+const ULAW_CLIP: i32 = 32635;
+
+const ALAW_XOR: u8 = 0x55;
+const ALAW_MAX_MAGNITUDE: i32 = 0x0fff;
+
+pub trait Codec: Send + Sync {
+    fn payload_type(&self) -> u8;
+    fn encoding_name(&self) -> &'static str;
+    fn clock_rate(&self) -> u32;
+
+    fn encode(&self, samples: &[i16]) -> Vec<u8>;
+    fn decode(&self, payload: &[u8]) -> Vec<i16>;
+}
+
+pub struct Pcmu;
+
+impl Codec for Pcmu {
+    fn payload_type(&self) -> u8 {
+        PCMU_PAYLOAD_TYPE
+    }
+
+    fn encoding_name(&self) -> &'static str {
+        "PCMU"
+    }
+
+    fn clock_rate(&self) -> u32 {
+        G711_CLOCK_RATE
+    }
+
+    fn encode(&self, samples: &[i16]) -> Vec<u8> {
+        samples.iter().map(|s| linear_to_ulaw(*s)).collect()
+    }
+
+    fn decode(&self, payload: &[u8]) -> Vec<i16> {
+        payload.iter().map(|b| ulaw_to_linear(*b)).collect()
+    }
+}
+
+pub struct Pcma;
+
+impl Codec for Pcma {
+    fn payload_type(&self) -> u8 {
+        PCMA_PAYLOAD_TYPE
+    }
+
+    fn encoding_name(&self) -> &'static str {
+        "PCMA"
+    }
+
+    fn clock_rate(&self) -> u32 {
+        G711_CLOCK_RATE
+    }
+
+    fn encode(&self, samples: &[i16]) -> Vec<u8> {
+        samples.iter().map(|s| linear_to_alaw(*s)).collect()
+    }
+
+    fn decode(&self, payload: &[u8]) -> Vec<i16> {
+        payload.iter().map(|b| alaw_to_linear(*b)).collect()
+    }
+}
+
+pub fn from_payload_type(payload_type: u8) -> Option<Box<dyn Codec>> {
+    match payload_type {
+        PCMU_PAYLOAD_TYPE => Some(Box::new(Pcmu)),
+        PCMA_PAYLOAD_TYPE => Some(Box::new(Pcma)),
+        _ => None,
+    }
+}
+
+fn linear_to_ulaw(sample: i16) -> u8 {
+    let mut pcm = sample as i32;
+    let sign = if pcm < 0 {
+        pcm = -pcm;
+        0x80
+    } else {
+        0x00
+    };
+    pcm = pcm.min(ULAW_CLIP) + ULAW_BIAS;
+
+    let mut exponent = 7;
+    let mut mask = 0x4000;
+    while exponent > 0 && pcm & mask == 0 {
+        exponent -= 1;
+        mask >>= 1;
+    }
+    let mantissa = (pcm >> (exponent + 3)) & 0x0f;
+    !(sign | (exponent << 4) | mantissa) as u8
+}
+
+fn ulaw_to_linear(byte: u8) -> i16 {
+    let byte = !byte;
+    let exponent = (byte >> 4) & 0x07;
+    let mantissa = (byte & 0x0f) as i32;
+    let magnitude = (((mantissa << 3) + ULAW_BIAS) << exponent) - ULAW_BIAS;
+    if byte & 0x80 != 0 {
+        -magnitude as i16
+    } else {
+        magnitude as i16
+    }
+}
+
+fn linear_to_alaw(sample: i16) -> u8 {
+    let pcm = sample as i32;
+    let (sign, magnitude) = if pcm >= 0 {
+        (0x80, pcm >> 3)
+    } else {
+        (0x00, (-pcm - 1) >> 3)
+    };
+    let magnitude = magnitude.min(ALAW_MAX_MAGNITUDE);
+
+    let code = if magnitude < 32 {
+        magnitude >> 1
+    } else {
+        let segment = (i32::BITS - magnitude.leading_zeros()) as i32 - 5;
+        (segment << 4) | ((magnitude >> segment) & 0x0f)
+    };
+    (sign | code) as u8 ^ ALAW_XOR
+}
+
+fn alaw_to_linear(byte: u8) -> i16 {
+    let byte = byte ^ ALAW_XOR;
+    let segment = (byte >> 4) & 0x07;
+    let mantissa = (byte & 0x0f) as i32;
+    let magnitude = if segment == 0 {
+        (mantissa << 4) + 8
+    } else {
+        ((mantissa << 4) + 0x108) << (segment - 1)
+    };
+    if byte & 0x80 != 0 {
+        magnitude as i16
+    } else {
+        -magnitude as i16
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+
+    fn assert_close(codec: &dyn Codec, sample: i16) {
+        let decoded = codec.decode(&codec.encode(&[sample]))[0];
+        // Companding error grows with magnitude, it's ~1/16th of the segment at worst
+        let tolerance = (sample as i32).abs() / 16 + 16;
+        assert!(
+            (decoded as i32 - sample as i32).abs() <= tolerance,
+            "{} -> {} via {}",
+            sample,
+            decoded,
+            codec.encoding_name(),
+        );
+    }
+
+    #[test]
+    fn successfully_encode_known_values() {
+        assert_eq!(
+            Pcmu.encode(&[0, -1, i16::MAX, i16::MIN]),
+            vec![0xff, 0x7f, 0x80, 0x00]
+        );
+        assert_eq!(
+            Pcma.encode(&[0, -1, i16::MAX, i16::MIN]),
+            vec![0xd5, 0x55, 0xaa, 0x2a]
+        );
+        assert_eq!(Pcmu.decode(&[0xff, 0x7f]), vec![0, 0]);
+        assert_eq!(Pcma.decode(&[0xd5, 0x55]), vec![8, -8]);
+    }
+
+    #[test]
+    fn successfully_round_trip_samples() {
+        for sample in (i16::MIN..=i16::MAX).step_by(97) {
+            assert_close(&Pcmu, sample);
+            assert_close(&Pcma, sample);
+        }
+    }
+
+    #[test]
+    fn successfully_pick_codec_by_payload_type() {
+        assert_eq!(
+            from_payload_type(0).map(|c| c.encoding_name()),
+            Some("PCMU")
+        );
+        assert_eq!(
+            from_payload_type(8).map(|c| c.encoding_name()),
+            Some("PCMA")
+        );
+        assert!(from_payload_type(96).is_none());
+    }
+}
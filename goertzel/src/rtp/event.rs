@@ -0,0 +1,209 @@
+use anyhow::{anyhow, Result};
+
+use crate::dtmf::{OCTOTHORPE, SEXTILE};
+
+// DTMF digits as RTP events instead of in-band tones the codec could mangle
+// https://www.rfc-editor.org/rfc/rfc4733
+pub const TELEPHONE_EVENT_PAYLOAD_TYPE: u8 = 101;
+pub const TELEPHONE_EVENT_ENCODING_NAME: &str = "telephone-event";
+pub const TELEPHONE_EVENT_CLOCK_RATE: u32 = 8000;
+// Digits, * and #, and A-D
+pub const TELEPHONE_EVENT_FMTP: &str = "0-15";
+
+const EVENT_SIZE: usize = 4;
+const END_BIT: u8 = 0x80;
+const VOLUME_MASK: u8 = 0x3f;
+
+// How long we hold a digit down for, and how loud
+const DIGIT_DURATION_MS: u32 = 100;
+const DIGIT_VOLUME: u8 = 10;
+// https://www.rfc-editor.org/rfc/rfc4733 (2.5.1.4)
+const END_PACKETS: usize = 3;
+
+const STAR_EVENT: u8 = 10;
+const POUND_EVENT: u8 = 11;
+const MAX_DTMF_EVENT: u8 = 15;
+
+// https://www.rfc-editor.org/rfc/rfc4733 (2.3)
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Event {
+    pub code: u8,
+    pub end: bool,
+    // In -dBm0
+    pub volume: u8,
+    // In timestamp units since the start of the event
+    pub duration: u16,
+}
+
+impl Event {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(EVENT_SIZE);
+        buf.push(self.code);
+        buf.push(if self.end { END_BIT } else { 0 } | (self.volume & VOLUME_MASK));
+        buf.extend_from_slice(&self.duration.to_be_bytes());
+        buf
+    }
+
+    pub fn parse(buf: &[u8]) -> Result<Self> {
+        if buf.len() < EVENT_SIZE {
+            return Err(anyhow!("telephone event too short: {}", buf.len()));
+        }
+        Ok(Self {
+            code: buf[0],
+            end: buf[1] & END_BIT != 0,
+            volume: buf[1] & VOLUME_MASK,
+            duration: u16::from_be_bytes([buf[2], buf[3]]),
+        })
+    }
+}
+
+// Our digits come out of dtmf/pulse with * and # as SEXTILE and OCTOTHORPE
+// https://www.rfc-editor.org/rfc/rfc4733 (3.2)
+pub fn from_digit(digit: u8) -> Option<u8> {
+    match digit {
+        0..=9 => Some(digit),
+        SEXTILE => Some(STAR_EVENT),
+        OCTOTHORPE => Some(POUND_EVENT),
+        _ => None,
+    }
+}
+
+pub fn to_digit(code: u8) -> Option<u8> {
+    match code {
+        0..=9 => Some(code),
+        STAR_EVENT => Some(SEXTILE),
+        POUND_EVENT => Some(OCTOTHORPE),
+        _ => None,
+    }
+}
+
+// Every packet of an event shares its timestamp, and the end gets sent more than once, so
+// only the first packet we see for each one counts
+#[derive(Default)]
+pub struct EventDetector {
+    last_timestamp: Option<u32>,
+}
+
+impl EventDetector {
+    pub fn update(&mut self, timestamp: u32, event: &Event) -> Option<u8> {
+        if event.code > MAX_DTMF_EVENT || self.last_timestamp == Some(timestamp) {
+            return None;
+        }
+        self.last_timestamp = Some(timestamp);
+        to_digit(event.code)
+    }
+}
+
+// Generates the packets for one digit, one per packetization interval in place of audio
+// https://www.rfc-editor.org/rfc/rfc4733 (2.5.1)
+pub struct EventSender {
+    code: u8,
+    pub timestamp: u32,
+    duration: u32,
+    end_duration: u32,
+    ends_sent: usize,
+}
+
+impl EventSender {
+    pub fn new(code: u8, timestamp: u32) -> Self {
+        Self {
+            code,
+            timestamp,
+            duration: 0,
+            end_duration: TELEPHONE_EVENT_CLOCK_RATE * DIGIT_DURATION_MS / 1000,
+            ends_sent: 0,
+        }
+    }
+
+    // The next event payload, and whether it's the first one so it needs the marker bit
+    pub fn next(&mut self, n_samples: usize) -> (Event, bool) {
+        let first = self.duration == 0;
+        // The end gets repeated as is, duration and all
+        if self.duration < self.end_duration {
+            self.duration = (self.duration + n_samples as u32).min(self.end_duration);
+        }
+        let end = self.duration >= self.end_duration;
+        if end {
+            self.ends_sent += 1;
+        }
+        let event = Event {
+            code: self.code,
+            end,
+            volume: DIGIT_VOLUME,
+            duration: self.duration as u16,
+        };
+        (event, first)
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.ends_sent >= END_PACKETS
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+
+    #[test]
+    fn successfully_round_trip_an_event() -> Result<()> {
+        let event = Event {
+            code: POUND_EVENT,
+            end: true,
+            volume: 10,
+            duration: 800,
+        };
+        let bytes = event.to_bytes();
+        assert_eq!(bytes, vec![11, 0x8a, 0x03, 0x20]);
+        assert_eq!(Event::parse(&bytes)?, event);
+        assert!(Event::parse(&bytes[..3]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn successfully_map_digits() {
+        for digit in [0, 5, 9, SEXTILE, OCTOTHORPE] {
+            assert_eq!(from_digit(digit).and_then(to_digit), Some(digit));
+        }
+        assert_eq!(from_digit(SEXTILE), Some(10));
+        assert_eq!(from_digit(OCTOTHORPE), Some(11));
+        assert_eq!(from_digit(11), None);
+    }
+
+    #[test]
+    fn successfully_report_each_event_once() {
+        let mut detector = EventDetector::default();
+        let event = |duration, end| Event {
+            code: 7,
+            end,
+            volume: 10,
+            duration,
+        };
+        assert_eq!(detector.update(1000, &event(160, false)), Some(7));
+        assert_eq!(detector.update(1000, &event(320, false)), None);
+        assert_eq!(detector.update(1000, &event(480, true)), None);
+        assert_eq!(detector.update(1000, &event(480, true)), None);
+        assert_eq!(detector.update(2000, &event(160, false)), Some(7));
+    }
+
+    #[test]
+    fn successfully_send_an_event_and_repeat_the_end() {
+        let mut sender = EventSender::new(5, 1000);
+        let mut sent = vec![];
+        while !sender.is_done() {
+            sent.push(sender.next(320));
+        }
+        let durations: Vec<_> = sent.iter().map(|(e, _)| (e.duration, e.end)).collect();
+        assert_eq!(
+            durations,
+            vec![
+                (320, false),
+                (640, false),
+                (800, true),
+                (800, true),
+                (800, true)
+            ]
+        );
+        assert!(sent[0].1);
+        assert!(sent[1..].iter().all(|(_, marker)| !marker));
+    }
+}
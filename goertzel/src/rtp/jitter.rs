@@ -0,0 +1,284 @@
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+use tracing::debug;
+
+use super::packet::Packet;
+
+// Adaptive playout buffer for a single remote source. Packets go in as they arrive and come
+// out once per packetization interval, reordered and with the depth following measured jitter.
+//
+// Target depth is a few jitter estimates' worth of packets, plus one for the packet in flight
+const JITTER_MULTIPLE: f64 = 3.0;
+const MIN_DEPTH: usize = 1;
+const MAX_DEPTH: usize = 10;
+// How far over target we let the buffer get before dropping audio to catch back up
+const SHRINK_SLACK: usize = 2;
+// Interarrival jitter smoothing
+// https://www.rfc-editor.org/rfc/rfc3550 (6.4.1)
+const JITTER_GAIN: f64 = 1.0 / 16.0;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct JitterStats {
+    pub received: u64,
+    // Arrived after their turn to play had passed
+    pub late: u64,
+    // Never arrived in time to play
+    pub lost: u64,
+    // Thrown away to bring latency down
+    pub discarded: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Playout {
+    Packet(Packet),
+    Lost,
+    // Nothing to play yet, still filling up
+    Buffering,
+}
+
+#[derive(Debug)]
+pub struct JitterBuffer {
+    clock_rate: u32,
+    samples_per_packet: u32,
+
+    // Keyed on extended sequence number so wraps sort correctly
+    packets: BTreeMap<u64, Packet>,
+    last_seq: Option<u64>,
+    // Unset until we start playing
+    next_seq: Option<u64>,
+    buffering: bool,
+
+    first_arrival: Option<Instant>,
+    last_transit: Option<u32>,
+    // In timestamp units
+    jitter: f64,
+
+    stats: JitterStats,
+}
+
+impl JitterBuffer {
+    pub fn new(clock_rate: u32, ptime: u32) -> Self {
+        Self {
+            clock_rate,
+            samples_per_packet: clock_rate * ptime / 1000,
+
+            packets: BTreeMap::new(),
+            last_seq: None,
+            next_seq: None,
+            buffering: true,
+
+            first_arrival: None,
+            last_transit: None,
+            jitter: 0.0,
+
+            stats: JitterStats::default(),
+        }
+    }
+
+    // Starts over for a new source, keeping the counts and jitter estimate
+    pub fn reset(&mut self) {
+        self.packets.clear();
+        self.last_seq = None;
+        self.next_seq = None;
+        self.buffering = true;
+        self.last_transit = None;
+    }
+
+    pub fn push(&mut self, packet: Packet, arrival: Instant) {
+        self.stats.received += 1;
+        self.update_jitter(packet.header.timestamp, arrival);
+
+        // Start a cycle up so reordering across the first packet can't go below zero
+        let seq = match self.last_seq {
+            Some(last_seq) => extend_seq(last_seq, packet.header.seq),
+            None => packet.header.seq as u64 + (1 << 16),
+        };
+        self.last_seq = Some(seq);
+        if self.next_seq.is_some_and(|next_seq| seq < next_seq) {
+            debug!("rtp: jitter buffer got seq {} too late", packet.header.seq);
+            self.stats.late += 1;
+            return;
+        }
+        // Source keeps duplicates from getting this far, but don't count on it
+        self.packets.entry(seq).or_insert(packet);
+    }
+
+    // Called once per packetization interval
+    pub fn pop(&mut self) -> Playout {
+        let target = self.target_depth();
+        if self.buffering {
+            if self.packets.len() < target {
+                return Playout::Buffering;
+            }
+            self.buffering = false;
+            // Start from whatever's oldest, the first packet to arrive might not have been
+            if self.next_seq.is_none() {
+                self.next_seq = self.packets.keys().next().copied();
+            }
+        }
+
+        while self.packets.len() > target + SHRINK_SLACK {
+            if let Some((seq, _)) = self.packets.pop_first() {
+                debug!(
+                    "rtp: jitter buffer over target {}, dropping {}",
+                    target, seq
+                );
+                self.stats.discarded += 1;
+                self.next_seq = Some(seq + 1);
+            }
+        }
+
+        let Some(next_seq) = self.next_seq else {
+            return Playout::Buffering;
+        };
+        if self.packets.is_empty() {
+            // Ran dry, wait for the depth to build back up rather than treating everything
+            // from here on as lost
+            debug!("rtp: jitter buffer underrun");
+            self.buffering = true;
+            return Playout::Buffering;
+        }
+
+        self.next_seq = Some(next_seq + 1);
+        match self.packets.remove(&next_seq) {
+            Some(packet) => Playout::Packet(packet),
+            None => {
+                self.stats.lost += 1;
+                Playout::Lost
+            }
+        }
+    }
+
+    pub fn stats(&self) -> JitterStats {
+        self.stats
+    }
+
+    // Interarrival jitter in timestamp units, as RTCP reports it
+    pub fn jitter(&self) -> u32 {
+        self.jitter as u32
+    }
+
+    pub fn target_depth(&self) -> usize {
+        let packets = (JITTER_MULTIPLE * self.jitter / self.samples_per_packet as f64).ceil();
+        (packets as usize + 1).clamp(MIN_DEPTH, MAX_DEPTH)
+    }
+
+    // https://www.rfc-editor.org/rfc/rfc3550 (A.8)
+    fn update_jitter(&mut self, timestamp: u32, arrival: Instant) {
+        let first_arrival = *self.first_arrival.get_or_insert(arrival);
+        let arrival = ((arrival - first_arrival).as_secs_f64() * self.clock_rate as f64) as u64;
+        // Both clocks wrap, differences between them don't care
+        let transit = (arrival as u32).wrapping_sub(timestamp);
+        if let Some(last_transit) = self.last_transit {
+            let d = (transit.wrapping_sub(last_transit) as i32).unsigned_abs() as f64;
+            self.jitter += (d - self.jitter) * JITTER_GAIN;
+        }
+        self.last_transit = Some(transit);
+    }
+}
+
+// Picks the extended sequence number closest to the last one, so wraps go either way
+fn extend_seq(last_seq: u64, seq: u16) -> u64 {
+    let delta = seq.wrapping_sub(last_seq as u16) as i16;
+    last_seq.saturating_add_signed(delta as i64)
+}
+
+#[cfg(test)]
+mod should {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::rtp::packet::Header;
+
+    const CLOCK_RATE: u32 = 8000;
+    const PTIME: u32 = 20;
+    // Close enough to the top that tests cross the wrap
+    const FIRST_SEQ: u16 = 65534;
+
+    // The nth packet from the remote, with its timestamp and on-time arrival
+    fn packet(n: u16) -> (Packet, Duration) {
+        let header = Header {
+            marker: false,
+            payload_type: 0,
+            seq: FIRST_SEQ.wrapping_add(n),
+            timestamp: n as u32 * CLOCK_RATE * PTIME / 1000,
+            ssrc: 1,
+            csrcs: vec![],
+        };
+        let arrival = Duration::from_millis(n as u64 * PTIME as u64);
+        (Packet::new(header, vec![n as u8]), arrival)
+    }
+
+    fn push(jb: &mut JitterBuffer, start: Instant, n: u16, delay: Duration) {
+        let (packet, arrival) = packet(n);
+        jb.push(packet, start + arrival + delay);
+    }
+
+    fn played(playout: Playout) -> Option<u8> {
+        match playout {
+            Playout::Packet(packet) => Some(packet.payload[0]),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn successfully_reorder_packets() {
+        let mut jb = JitterBuffer::new(CLOCK_RATE, PTIME);
+        let start = Instant::now();
+        push(&mut jb, start, 1, Duration::ZERO);
+        push(&mut jb, start, 0, Duration::ZERO);
+        assert_eq!(played(jb.pop()), Some(0));
+        push(&mut jb, start, 3, Duration::ZERO);
+        push(&mut jb, start, 2, Duration::ZERO);
+        for n in 1..4 {
+            assert_eq!(played(jb.pop()), Some(n));
+        }
+        assert_eq!(jb.pop(), Playout::Buffering);
+    }
+
+    #[test]
+    fn successfully_count_lost_and_late_packets() {
+        let mut jb = JitterBuffer::new(CLOCK_RATE, PTIME);
+        let start = Instant::now();
+        push(&mut jb, start, 0, Duration::ZERO);
+        push(&mut jb, start, 2, Duration::ZERO);
+        assert_eq!(played(jb.pop()), Some(0));
+        assert_eq!(jb.pop(), Playout::Lost);
+        push(&mut jb, start, 1, Duration::ZERO);
+        assert_eq!(played(jb.pop()), Some(2));
+        assert_eq!(
+            jb.stats(),
+            JitterStats {
+                received: 3,
+                late: 1,
+                lost: 1,
+                discarded: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn successfully_adapt_depth_to_jitter() {
+        let mut jb = JitterBuffer::new(CLOCK_RATE, PTIME);
+        let start = Instant::now();
+        for n in 0..50 {
+            push(&mut jb, start, n, Duration::ZERO);
+        }
+        assert_eq!(jb.target_depth(), MIN_DEPTH);
+
+        // Every other packet shows up 60ms late
+        for n in 50..100 {
+            let delay = Duration::from_millis(if n % 2 == 0 { 60 } else { 0 });
+            push(&mut jb, start, n, delay);
+        }
+        assert!(jb.target_depth() >= 4, "depth {}", jb.target_depth());
+
+        // Anything piled up past the target gets dropped to catch up
+        assert!(played(jb.pop()).is_some());
+        assert_eq!(
+            jb.stats().discarded as usize,
+            100 - jb.target_depth() - SHRINK_SLACK
+        );
+    }
+}
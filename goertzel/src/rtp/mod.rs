@@ -1 +1,13 @@
+pub mod cn;
+pub mod codec;
+pub mod event;
+pub mod jitter;
+pub mod packet;
+pub mod plc;
+pub mod port;
+pub mod resample;
+pub mod rtcp;
 pub mod socket;
+pub mod source;
+pub mod srtp;
+pub mod stats;
@@ -0,0 +1,170 @@
+use anyhow::{anyhow, Result};
+
+// https://www.rfc-editor.org/rfc/rfc3550 (5.1)
+pub const RTP_VERSION: u8 = 2;
+pub const HEADER_SIZE: usize = 12;
+
+const VERSION_SHIFT: u8 = 6;
+const PADDING_BIT: u8 = 0x20;
+const EXTENSION_BIT: u8 = 0x10;
+const CSRC_COUNT_MASK: u8 = 0x0f;
+const MARKER_BIT: u8 = 0x80;
+const PAYLOAD_TYPE_MASK: u8 = 0x7f;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Header {
+    pub marker: bool,
+    pub payload_type: u8,
+    pub seq: u16,
+    pub timestamp: u32,
+    pub ssrc: u32,
+    pub csrcs: Vec<u32>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Packet {
+    pub header: Header,
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    pub fn new(header: Header, payload: Vec<u8>) -> Self {
+        Self { header, payload }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf =
+            Vec::with_capacity(HEADER_SIZE + 4 * self.header.csrcs.len() + self.payload.len());
+        buf.push(
+            (RTP_VERSION << VERSION_SHIFT) | (self.header.csrcs.len() as u8 & CSRC_COUNT_MASK),
+        );
+        buf.push(
+            if self.header.marker { MARKER_BIT } else { 0 }
+                | (self.header.payload_type & PAYLOAD_TYPE_MASK),
+        );
+        buf.extend_from_slice(&self.header.seq.to_be_bytes());
+        buf.extend_from_slice(&self.header.timestamp.to_be_bytes());
+        buf.extend_from_slice(&self.header.ssrc.to_be_bytes());
+        for csrc in self.header.csrcs.iter().take(CSRC_COUNT_MASK as usize) {
+            buf.extend_from_slice(&csrc.to_be_bytes());
+        }
+        buf.extend_from_slice(&self.payload);
+        buf
+    }
+
+    pub fn parse(buf: &[u8]) -> Result<Self> {
+        if buf.len() < HEADER_SIZE {
+            return Err(anyhow!("rtp packet too short: {}", buf.len()));
+        }
+        let version = buf[0] >> VERSION_SHIFT;
+        if version != RTP_VERSION {
+            return Err(anyhow!("unsupported rtp version: {}", version));
+        }
+        let has_padding = buf[0] & PADDING_BIT != 0;
+        let has_extension = buf[0] & EXTENSION_BIT != 0;
+        let csrc_count = (buf[0] & CSRC_COUNT_MASK) as usize;
+
+        let mut header = Header {
+            marker: buf[1] & MARKER_BIT != 0,
+            payload_type: buf[1] & PAYLOAD_TYPE_MASK,
+            seq: u16::from_be_bytes([buf[2], buf[3]]),
+            timestamp: u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]),
+            ssrc: u32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]]),
+            csrcs: Vec::with_capacity(csrc_count),
+        };
+
+        let mut idx = HEADER_SIZE;
+        if buf.len() < idx + 4 * csrc_count {
+            return Err(anyhow!("rtp packet truncated in csrc list"));
+        }
+        for _ in 0..csrc_count {
+            header.csrcs.push(u32::from_be_bytes([
+                buf[idx],
+                buf[idx + 1],
+                buf[idx + 2],
+                buf[idx + 3],
+            ]));
+            idx += 4;
+        }
+
+        // Extension contents aren't used by anyone we talk to, so just skip over them
+        // https://www.rfc-editor.org/rfc/rfc3550 (5.3.1)
+        if has_extension {
+            if buf.len() < idx + 4 {
+                return Err(anyhow!("rtp packet truncated in extension header"));
+            }
+            let ext_words = u16::from_be_bytes([buf[idx + 2], buf[idx + 3]]) as usize;
+            idx += 4 + 4 * ext_words;
+            if buf.len() < idx {
+                return Err(anyhow!("rtp packet truncated in extension"));
+            }
+        }
+
+        let mut end = buf.len();
+        if has_padding {
+            let padding = buf[end - 1] as usize;
+            if padding == 0 || end - idx < padding {
+                return Err(anyhow!("invalid rtp padding length: {}", padding));
+            }
+            end -= padding;
+        }
+
+        Ok(Self {
+            header,
+            payload: buf[idx..end].to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+
+    fn dummy_packet() -> Packet {
+        Packet::new(
+            Header {
+                marker: true,
+                payload_type: 0,
+                seq: 0xbeef,
+                timestamp: 0xdeadbeef,
+                ssrc: 0x12345678,
+                csrcs: vec![],
+            },
+            vec![1, 2, 3, 4],
+        )
+    }
+
+    #[test]
+    fn successfully_round_trip_a_packet() -> Result<()> {
+        let packet = dummy_packet();
+        let bytes = packet.to_bytes();
+        assert_eq!(bytes.len(), HEADER_SIZE + 4);
+        assert_eq!(bytes[0], 0x80);
+        assert_eq!(bytes[1], 0x80);
+        assert_eq!(Packet::parse(&bytes)?, packet);
+        Ok(())
+    }
+
+    #[test]
+    fn successfully_strip_csrcs_extension_and_padding() -> Result<()> {
+        let mut bytes = vec![0xb1, 0x00, 0x00, 0x01, 0, 0, 0, 160, 0, 0, 0, 7];
+        bytes.extend_from_slice(&[0, 0, 0, 9]); // CSRC
+        bytes.extend_from_slice(&[0xbe, 0xde, 0, 1, 0xaa, 0xbb, 0xcc, 0xdd]); // Extension
+        bytes.extend_from_slice(&[5, 6]); // Payload
+        bytes.extend_from_slice(&[0, 2]); // Padding
+
+        let packet = Packet::parse(&bytes)?;
+        assert_eq!(packet.header.seq, 1);
+        assert_eq!(packet.header.timestamp, 160);
+        assert_eq!(packet.header.ssrc, 7);
+        assert_eq!(packet.header.csrcs, vec![9]);
+        assert_eq!(packet.payload, vec![5, 6]);
+        Ok(())
+    }
+
+    #[test]
+    fn fail_to_parse_garbage() {
+        assert!(Packet::parse(&[0x80, 0x00]).is_err());
+        assert!(Packet::parse(&[0x40; HEADER_SIZE]).is_err());
+    }
+}
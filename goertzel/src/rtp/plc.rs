@@ -0,0 +1,99 @@
+// Packet loss concealment by waveform repetition, loosely after G.711 Appendix I. Lost
+// frames replay the last good audio while fading it out, and audio after a loss fades back in.
+// https://www.itu.int/rec/T-REC-G.711
+const FADE_OUT_MS: u32 = 60;
+const FADE_IN_MS: u32 = 2;
+
+pub struct Concealer {
+    last: Vec<i16>,
+    // Where we are in replaying last
+    pos: usize,
+    concealed: usize,
+
+    fade_out_len: usize,
+    fade_in_len: usize,
+}
+
+impl Concealer {
+    pub fn new(clock_rate: u32) -> Self {
+        Self {
+            last: vec![],
+            pos: 0,
+            concealed: 0,
+
+            fade_out_len: (clock_rate * FADE_OUT_MS / 1000) as usize,
+            fade_in_len: (clock_rate * FADE_IN_MS / 1000) as usize,
+        }
+    }
+
+    // Whether we've had any audio worth repeating yet
+    pub fn is_primed(&self) -> bool {
+        !self.last.is_empty()
+    }
+
+    // Call with every frame of good audio, smooths over the seam after a loss
+    pub fn good(&mut self, mut samples: Vec<i16>) -> Vec<i16> {
+        if self.concealed > 0 {
+            let fade_in_len = self.fade_in_len.min(samples.len());
+            for (i, sample) in samples.iter_mut().take(fade_in_len).enumerate() {
+                *sample = (*sample as f32 * i as f32 / fade_in_len as f32) as i16;
+            }
+        }
+        self.last = samples.clone();
+        self.pos = 0;
+        self.concealed = 0;
+        samples
+    }
+
+    pub fn conceal(&mut self, n_samples: usize) -> Vec<i16> {
+        if self.last.is_empty() {
+            return vec![0; n_samples];
+        }
+        let samples = (0..n_samples)
+            .map(|i| {
+                let gain = 1.0 - (self.concealed + i) as f32 / self.fade_out_len as f32;
+                let sample = self.last[(self.pos + i) % self.last.len()];
+                (sample as f32 * gain.max(0.0)) as i16
+            })
+            .collect();
+        self.pos = (self.pos + n_samples) % self.last.len();
+        self.concealed += n_samples;
+        samples
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+
+    const CLOCK_RATE: u32 = 8000;
+    const FRAME_LEN: usize = 160;
+
+    #[test]
+    fn successfully_repeat_and_fade_out() {
+        let mut plc = Concealer::new(CLOCK_RATE);
+        assert!(!plc.is_primed());
+        assert_eq!(plc.conceal(FRAME_LEN), vec![0; FRAME_LEN]);
+
+        plc.good(vec![1000; FRAME_LEN]);
+        let first = plc.conceal(FRAME_LEN);
+        assert_eq!(first[0], 1000);
+        assert!(first.windows(2).all(|w| w[1] <= w[0]));
+        assert!(first[FRAME_LEN - 1] < 1000);
+
+        // Gone quiet by FADE_OUT_MS
+        plc.conceal(FRAME_LEN);
+        plc.conceal(FRAME_LEN);
+        assert_eq!(plc.conceal(FRAME_LEN), vec![0; FRAME_LEN]);
+    }
+
+    #[test]
+    fn successfully_fade_back_in_after_loss() {
+        let mut plc = Concealer::new(CLOCK_RATE);
+        assert_eq!(plc.good(vec![1000; FRAME_LEN]), vec![1000; FRAME_LEN]);
+        plc.conceal(FRAME_LEN);
+        let resumed = plc.good(vec![1000; FRAME_LEN]);
+        assert_eq!(resumed[0], 0);
+        assert_eq!(resumed[FRAME_LEN - 1], 1000);
+    }
+}
@@ -0,0 +1,169 @@
+use std::collections::HashSet;
+use std::ops::Range;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+use tokio::net::UdpSocket;
+use tracing::{debug, trace};
+
+// RTP gets the even port and RTCP the odd one right above it
+// https://www.rfc-editor.org/rfc/rfc3550 (11)
+pub const DEFAULT_PORT_RANGE: Range<u16> = 19512..19600;
+
+#[derive(Debug)]
+struct Ports {
+    in_use: HashSet<u16>,
+    next: u16,
+}
+
+#[derive(Clone, Debug)]
+pub struct PortAllocator {
+    range: Range<u16>,
+    ports: Arc<Mutex<Ports>>,
+}
+
+impl PortAllocator {
+    pub fn new(range: Range<u16>) -> Result<Self> {
+        let no_pairs = || anyhow!("port range {:?} has no even/odd pairs", range);
+        let start = range
+            .start
+            .checked_add(range.start % 2)
+            .ok_or_else(no_pairs)?;
+        if range.end < start.checked_add(2).ok_or_else(no_pairs)? {
+            return Err(no_pairs());
+        }
+        Ok(Self {
+            range: start..range.end,
+            ports: Arc::new(Mutex::new(Ports {
+                in_use: HashSet::new(),
+                next: start,
+            })),
+        })
+    }
+
+    // Hands out ports round-robin so a new call doesn't pick up stragglers from the last one
+    fn reserve(&self) -> Option<u16> {
+        let mut ports = self.ports.lock().unwrap();
+        let n_pairs = (self.range.end - self.range.start) / 2;
+        for _ in 0..n_pairs {
+            let port = ports.next;
+            ports.next = if port + 3 < self.range.end {
+                port + 2
+            } else {
+                self.range.start
+            };
+            if ports.in_use.insert(port) {
+                return Some(port);
+            }
+        }
+        None
+    }
+
+    fn release(&self, port: u16) {
+        self.ports.lock().unwrap().in_use.remove(&port);
+    }
+
+    // Binds an RTP socket and the RTCP one next to it
+    pub async fn bind(&self) -> Result<(UdpSocket, UdpSocket, PortLease)> {
+        let n_pairs = (self.range.end - self.range.start) / 2;
+        for _ in 0..n_pairs {
+            let port = self
+                .reserve()
+                .ok_or(anyhow!("all RTP ports in {:?} are in use", self.range))?;
+            let rtp_sock = UdpSocket::bind(("0.0.0.0", port)).await;
+            let rtcp_sock = UdpSocket::bind(("0.0.0.0", port + 1)).await;
+            match (rtp_sock, rtcp_sock) {
+                (Ok(rtp_sock), Ok(rtcp_sock)) => {
+                    debug!("rtp: bound ports {}-{}", port, port + 1);
+                    let lease = PortLease {
+                        port,
+                        allocator: self.clone(),
+                    };
+                    return Ok((rtp_sock, rtcp_sock, lease));
+                }
+                // Something outside of us has it, move along
+                (Err(e), _) | (_, Err(e)) => {
+                    trace!("rtp: couldn't bind ports {}-{}: {}", port, port + 1, e);
+                    self.release(port);
+                }
+            }
+        }
+        Err(anyhow!("no bindable RTP ports in {:?}", self.range))
+    }
+}
+
+impl Default for PortAllocator {
+    fn default() -> Self {
+        Self::new(DEFAULT_PORT_RANGE).unwrap()
+    }
+}
+
+// Parses ranges like "19512-19600", end exclusive
+impl FromStr for PortAllocator {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or(anyhow!("port range should look like start-end: {}", s))?;
+        Self::new(start.trim().parse()?..end.trim().parse()?)
+    }
+}
+
+#[derive(Debug)]
+pub struct PortLease {
+    pub port: u16,
+    allocator: PortAllocator,
+}
+
+impl Drop for PortLease {
+    fn drop(&mut self) {
+        debug!("rtp: releasing port {}", self.port);
+        self.allocator.release(self.port);
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+
+    // Two pairs just under a port the OS was happy to hand out, so it's likely free around there
+    fn test_range() -> Result<Range<u16>> {
+        let port = std::net::UdpSocket::bind(("0.0.0.0", 0))?
+            .local_addr()?
+            .port();
+        let start = port
+            .checked_sub(6)
+            .ok_or(anyhow!("ephemeral port {} too low", port))?
+            | 1;
+        Ok(start..start + 5)
+    }
+
+    #[tokio::test]
+    async fn successfully_hand_out_even_ports_until_exhausted() -> Result<()> {
+        let range = test_range()?;
+        let ports = PortAllocator::new(range.clone())?;
+        let (rtp1, rtcp1, lease1) = ports.bind().await?;
+        let (_rtp2, _rtcp2, lease2) = ports.bind().await?;
+        assert_eq!(lease1.port, range.start + 1);
+        assert_eq!(lease2.port, range.start + 3);
+        assert!(ports.bind().await.is_err());
+
+        drop((rtp1, rtcp1, lease1));
+        let (_rtp3, _rtcp3, lease3) = ports.bind().await?;
+        assert_eq!(lease3.port, range.start + 1);
+        Ok(())
+    }
+
+    #[test]
+    fn successfully_parse_ranges() -> Result<()> {
+        assert_eq!("10000-20000".parse::<PortAllocator>()?.range, 10000..20000);
+        assert_eq!("10001-10004".parse::<PortAllocator>()?.range, 10002..10004);
+        assert!("10001-10003".parse::<PortAllocator>().is_err());
+        assert_eq!("65531-65535".parse::<PortAllocator>()?.range, 65532..65535);
+        assert!("65535-65535".parse::<PortAllocator>().is_err());
+        assert!("10000".parse::<PortAllocator>().is_err());
+        Ok(())
+    }
+}
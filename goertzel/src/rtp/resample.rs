@@ -0,0 +1,154 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+// Integer-factor resampling between the 48k audio stream and codec clocks.
+// Both directions share a Hamming-windowed sinc low-pass just below the
+// narrower rate's Nyquist so we don't alias on the way down or image on the way up.
+const TAPS_PER_PHASE: usize = 8;
+const CUTOFF_RATIO: f64 = 0.9;
+
+fn lowpass(factor: usize) -> Vec<f64> {
+    let n_taps = factor * TAPS_PER_PHASE;
+    let center = (n_taps - 1) as f64 / 2.;
+    let cutoff = CUTOFF_RATIO / (2. * factor as f64);
+    let taps: Vec<f64> = (0..n_taps)
+        .map(|n| {
+            let x = n as f64 - center;
+            let sinc = if x == 0. {
+                2. * cutoff
+            } else {
+                (2. * PI * cutoff * x).sin() / (PI * x)
+            };
+            let ham = 0.54 - 0.46 * (2. * PI * n as f64 / (n_taps - 1) as f64).cos();
+            sinc * ham
+        })
+        .collect();
+    let gain: f64 = taps.iter().sum();
+    taps.into_iter().map(|t| t / gain).collect()
+}
+
+fn to_i16(sample: f64) -> i16 {
+    sample.round().clamp(i16::MIN as f64, i16::MAX as f64) as i16
+}
+
+pub struct Downsampler {
+    factor: usize,
+    taps: Vec<f64>,
+    history: VecDeque<f64>,
+    phase: usize,
+}
+
+impl Downsampler {
+    pub fn new(factor: usize) -> Self {
+        let taps = lowpass(factor);
+        Self {
+            factor,
+            history: VecDeque::from(vec![0.; taps.len()]),
+            taps,
+            phase: 0,
+        }
+    }
+
+    pub fn process(&mut self, samples: &[i16]) -> Vec<i16> {
+        let mut out = Vec::with_capacity(samples.len() / self.factor + 1);
+        for sample in samples {
+            self.history.pop_front();
+            self.history.push_back(*sample as f64);
+            self.phase += 1;
+            if self.phase == self.factor {
+                self.phase = 0;
+                let acc: f64 = self
+                    .history
+                    .iter()
+                    .zip(self.taps.iter().rev())
+                    .map(|(s, t)| s * t)
+                    .sum();
+                out.push(to_i16(acc));
+            }
+        }
+        out
+    }
+}
+
+pub struct Upsampler {
+    factor: usize,
+    taps: Vec<f64>,
+    history: VecDeque<f64>,
+}
+
+impl Upsampler {
+    pub fn new(factor: usize) -> Self {
+        Self {
+            factor,
+            taps: lowpass(factor),
+            history: VecDeque::from(vec![0.; TAPS_PER_PHASE]),
+        }
+    }
+
+    pub fn process(&mut self, samples: &[i16]) -> Vec<i16> {
+        let mut out = Vec::with_capacity(samples.len() * self.factor);
+        for sample in samples {
+            self.history.pop_front();
+            self.history.push_back(*sample as f64);
+            // Zero-stuffing means only every factor-th tap lands on a real sample,
+            // so each output phase is a short dot product against the input history
+            for phase in 0..self.factor {
+                let acc: f64 = self
+                    .history
+                    .iter()
+                    .rev()
+                    .enumerate()
+                    .map(|(k, s)| s * self.taps[phase + k * self.factor])
+                    .sum();
+                out.push(to_i16(acc * self.factor as f64));
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+
+    const FACTOR: usize = 6;
+
+    fn sine(rate: f64, freq: f64, len: usize) -> Vec<i16> {
+        (0..len)
+            .map(|n| (8000. * (2. * PI * freq * n as f64 / rate).sin()) as i16)
+            .collect()
+    }
+
+    fn rms(samples: &[i16]) -> f64 {
+        (samples.iter().map(|s| (*s as f64).powi(2)).sum::<f64>() / samples.len() as f64).sqrt()
+    }
+
+    #[test]
+    fn successfully_preserve_length() {
+        let mut down = Downsampler::new(FACTOR);
+        let mut up = Upsampler::new(FACTOR);
+        let narrow = down.process(&[0; 960]);
+        assert_eq!(narrow.len(), 160);
+        assert_eq!(up.process(&narrow).len(), 960);
+    }
+
+    #[test]
+    fn successfully_pass_voice_band() {
+        let mut down = Downsampler::new(FACTOR);
+        let mut up = Upsampler::new(FACTOR);
+        let input = sine(48000., 1000., 9600);
+        let output = up.process(&down.process(&input));
+        // Skip the filter warm-up before comparing levels
+        let ratio = rms(&output[960..]) / rms(&input[960..]);
+        assert!((0.9..1.1).contains(&ratio), "ratio {}", ratio);
+    }
+
+    #[test]
+    fn successfully_reject_aliases() {
+        let mut down = Downsampler::new(FACTOR);
+        let input = sine(48000., 12000., 9600);
+        let output = down.process(&input);
+        let ratio = rms(&output[160..]) / rms(&input);
+        assert!(ratio < 0.05, "ratio {}", ratio);
+    }
+}
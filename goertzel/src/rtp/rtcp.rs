@@ -0,0 +1,337 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+
+// https://www.rfc-editor.org/rfc/rfc3550 (6.4)
+const RTCP_VERSION: u8 = 2;
+const HEADER_SIZE: usize = 4;
+const REPORT_BLOCK_SIZE: usize = 24;
+const SENDER_INFO_SIZE: usize = 20;
+
+const VERSION_SHIFT: u8 = 6;
+const PADDING_BIT: u8 = 0x20;
+const COUNT_MASK: u8 = 0x1f;
+
+const SR_PACKET_TYPE: u8 = 200;
+const RR_PACKET_TYPE: u8 = 201;
+const SDES_PACKET_TYPE: u8 = 202;
+const BYE_PACKET_TYPE: u8 = 203;
+
+const SDES_END: u8 = 0;
+const SDES_CNAME: u8 = 1;
+
+// Seconds between the NTP epoch (1900) and the Unix one
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SenderInfo {
+    pub ntp_timestamp: u64,
+    pub rtp_timestamp: u32,
+    pub packet_count: u32,
+    pub octet_count: u32,
+}
+
+// https://www.rfc-editor.org/rfc/rfc3550 (6.4.1)
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReportBlock {
+    pub ssrc: u32,
+    pub fraction_lost: u8,
+    // Only 24 bits on the wire, and can go negative with duplicates
+    pub cumulative_lost: i32,
+    pub extended_max_seq: u32,
+    pub jitter: u32,
+    // Middle 32 bits of the NTP timestamp from the last SR we got, 0 if none
+    pub last_sr: u32,
+    // In 1/65536ths of a second
+    pub delay_since_last_sr: u32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Rtcp {
+    SenderReport {
+        ssrc: u32,
+        info: SenderInfo,
+        reports: Vec<ReportBlock>,
+    },
+    ReceiverReport {
+        ssrc: u32,
+        reports: Vec<ReportBlock>,
+    },
+    SourceDescription {
+        ssrc: u32,
+        cname: String,
+    },
+    Bye {
+        ssrcs: Vec<u32>,
+    },
+    // Anything we don't care about (APP, feedback, ...)
+    Other {
+        packet_type: u8,
+    },
+}
+
+impl Rtcp {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (count, packet_type, body) = match self {
+            Self::SenderReport {
+                ssrc,
+                info,
+                reports,
+            } => {
+                let mut body = ssrc.to_be_bytes().to_vec();
+                body.extend_from_slice(&info.ntp_timestamp.to_be_bytes());
+                body.extend_from_slice(&info.rtp_timestamp.to_be_bytes());
+                body.extend_from_slice(&info.packet_count.to_be_bytes());
+                body.extend_from_slice(&info.octet_count.to_be_bytes());
+                reports.iter().for_each(|report| report.write(&mut body));
+                (reports.len(), SR_PACKET_TYPE, body)
+            }
+            Self::ReceiverReport { ssrc, reports } => {
+                let mut body = ssrc.to_be_bytes().to_vec();
+                reports.iter().for_each(|report| report.write(&mut body));
+                (reports.len(), RR_PACKET_TYPE, body)
+            }
+            Self::SourceDescription { ssrc, cname } => {
+                let mut body = ssrc.to_be_bytes().to_vec();
+                let cname = &cname.as_bytes()[..cname.len().min(u8::MAX as usize)];
+                body.push(SDES_CNAME);
+                body.push(cname.len() as u8);
+                body.extend_from_slice(cname);
+                // Chunks end with at least one null and get padded out to a word
+                body.push(SDES_END);
+                body.resize(body.len().next_multiple_of(4), SDES_END);
+                (1, SDES_PACKET_TYPE, body)
+            }
+            Self::Bye { ssrcs } => {
+                let body = ssrcs.iter().flat_map(|ssrc| ssrc.to_be_bytes()).collect();
+                (ssrcs.len(), BYE_PACKET_TYPE, body)
+            }
+            Self::Other { packet_type } => (0, *packet_type, vec![]),
+        };
+
+        let mut buf = Vec::with_capacity(HEADER_SIZE + body.len());
+        buf.push((RTCP_VERSION << VERSION_SHIFT) | (count as u8 & COUNT_MASK));
+        buf.push(packet_type);
+        // Length is in words, minus one
+        buf.extend_from_slice(&((body.len() / 4) as u16).to_be_bytes());
+        buf.extend_from_slice(&body);
+        buf
+    }
+
+    // Parses a compound packet, which is several RTCP packets back to back
+    // https://www.rfc-editor.org/rfc/rfc3550 (6.1)
+    pub fn parse_compound(mut buf: &[u8]) -> Result<Vec<Self>> {
+        let mut packets = vec![];
+        while !buf.is_empty() {
+            if buf.len() < HEADER_SIZE {
+                return Err(anyhow!("rtcp packet too short: {}", buf.len()));
+            }
+            let version = buf[0] >> VERSION_SHIFT;
+            if version != RTCP_VERSION {
+                return Err(anyhow!("unsupported rtcp version: {}", version));
+            }
+            let count = (buf[0] & COUNT_MASK) as usize;
+            let packet_type = buf[1];
+            let len = (u16::from_be_bytes([buf[2], buf[3]]) as usize + 1) * 4;
+            if buf.len() < len {
+                return Err(anyhow!("rtcp length {} overruns packet {}", len, buf.len()));
+            }
+            let mut body = &buf[HEADER_SIZE..len];
+            if buf[0] & PADDING_BIT != 0 {
+                let padding = *body.last().ok_or(anyhow!("padding in empty rtcp packet"))?;
+                body = &body[..body.len().saturating_sub(padding as usize)];
+            }
+            packets.push(Self::parse_one(count, packet_type, body)?);
+            buf = &buf[len..];
+        }
+        Ok(packets)
+    }
+
+    fn parse_one(count: usize, packet_type: u8, body: &[u8]) -> Result<Self> {
+        match packet_type {
+            SR_PACKET_TYPE => {
+                if body.len() < 4 + SENDER_INFO_SIZE {
+                    return Err(anyhow!("sender report too short: {}", body.len()));
+                }
+                let info = SenderInfo {
+                    ntp_timestamp: u64::from_be_bytes(body[4..12].try_into()?),
+                    rtp_timestamp: read_u32(body, 12),
+                    packet_count: read_u32(body, 16),
+                    octet_count: read_u32(body, 20),
+                };
+                Ok(Self::SenderReport {
+                    ssrc: read_u32(body, 0),
+                    info,
+                    reports: ReportBlock::parse_all(count, &body[4 + SENDER_INFO_SIZE..])?,
+                })
+            }
+            RR_PACKET_TYPE => {
+                if body.len() < 4 {
+                    return Err(anyhow!("receiver report too short: {}", body.len()));
+                }
+                Ok(Self::ReceiverReport {
+                    ssrc: read_u32(body, 0),
+                    reports: ReportBlock::parse_all(count, &body[4..])?,
+                })
+            }
+            SDES_PACKET_TYPE => {
+                if body.len() < 4 {
+                    return Err(anyhow!("source description too short: {}", body.len()));
+                }
+                // We only ever care about the first chunk's CNAME
+                let mut items = &body[4..];
+                let mut cname = String::new();
+                while let [item_type, len, rest @ ..] = items {
+                    if *item_type == SDES_END || rest.len() < *len as usize {
+                        break;
+                    }
+                    let (text, rest) = rest.split_at(*len as usize);
+                    if *item_type == SDES_CNAME {
+                        cname = String::from_utf8_lossy(text).into_owned();
+                        break;
+                    }
+                    items = rest;
+                }
+                Ok(Self::SourceDescription {
+                    ssrc: read_u32(body, 0),
+                    cname,
+                })
+            }
+            BYE_PACKET_TYPE => {
+                if body.len() < count * 4 {
+                    return Err(anyhow!("bye too short for {} sources", count));
+                }
+                Ok(Self::Bye {
+                    ssrcs: (0..count).map(|i| read_u32(body, i * 4)).collect(),
+                })
+            }
+            packet_type => Ok(Self::Other { packet_type }),
+        }
+    }
+}
+
+impl ReportBlock {
+    fn write(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.ssrc.to_be_bytes());
+        let lost = self.cumulative_lost.clamp(-0x80_0000, 0x7f_ffff) as u32 & 0xff_ffff;
+        buf.extend_from_slice(&((self.fraction_lost as u32) << 24 | lost).to_be_bytes());
+        buf.extend_from_slice(&self.extended_max_seq.to_be_bytes());
+        buf.extend_from_slice(&self.jitter.to_be_bytes());
+        buf.extend_from_slice(&self.last_sr.to_be_bytes());
+        buf.extend_from_slice(&self.delay_since_last_sr.to_be_bytes());
+    }
+
+    fn parse_all(count: usize, buf: &[u8]) -> Result<Vec<Self>> {
+        if buf.len() < count * REPORT_BLOCK_SIZE {
+            return Err(anyhow!("rtcp too short for {} report blocks", count));
+        }
+        Ok(buf
+            .chunks_exact(REPORT_BLOCK_SIZE)
+            .take(count)
+            .map(|block| {
+                let lost = read_u32(block, 4);
+                Self {
+                    ssrc: read_u32(block, 0),
+                    fraction_lost: (lost >> 24) as u8,
+                    // Sign extend the 24 bit count
+                    cumulative_lost: ((lost << 8) as i32) >> 8,
+                    extended_max_seq: read_u32(block, 8),
+                    jitter: read_u32(block, 12),
+                    last_sr: read_u32(block, 16),
+                    delay_since_last_sr: read_u32(block, 20),
+                }
+            })
+            .collect())
+    }
+}
+
+fn read_u32(buf: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+// 32.32 fixed point seconds since 1900
+pub fn ntp_timestamp(time: SystemTime) -> u64 {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs() + NTP_UNIX_OFFSET;
+    let frac = ((since_epoch.subsec_nanos() as u64) << 32) / 1_000_000_000;
+    (secs << 32) | frac
+}
+
+// The middle 32 bits, which is what LSR and round trip times are measured in
+pub fn ntp_middle(ntp_timestamp: u64) -> u32 {
+    (ntp_timestamp >> 16) as u32
+}
+
+#[cfg(test)]
+mod should {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn report_block() -> ReportBlock {
+        ReportBlock {
+            ssrc: 0xdeadbeef,
+            fraction_lost: 64,
+            cumulative_lost: -3,
+            extended_max_seq: 0x1_0005,
+            jitter: 80,
+            last_sr: 0x1234_5678,
+            delay_since_last_sr: 0x0001_8000,
+        }
+    }
+
+    #[test]
+    fn successfully_round_trip_a_compound_packet() -> Result<()> {
+        let packets = vec![
+            Rtcp::SenderReport {
+                ssrc: 1,
+                info: SenderInfo {
+                    ntp_timestamp: 0x0102_0304_0506_0708,
+                    rtp_timestamp: 160,
+                    packet_count: 10,
+                    octet_count: 1600,
+                },
+                reports: vec![report_block()],
+            },
+            Rtcp::SourceDescription {
+                ssrc: 1,
+                cname: "frandline".into(),
+            },
+            Rtcp::ReceiverReport {
+                ssrc: 2,
+                reports: vec![],
+            },
+            Rtcp::Bye { ssrcs: vec![1, 2] },
+        ];
+        let bytes: Vec<u8> = packets
+            .iter()
+            .flat_map(|packet| packet.to_bytes())
+            .collect();
+        assert_eq!(bytes.len() % 4, 0);
+        assert_eq!(Rtcp::parse_compound(&bytes)?, packets);
+        Ok(())
+    }
+
+    #[test]
+    fn fail_to_parse_truncated_packets() {
+        let bytes = Rtcp::ReceiverReport {
+            ssrc: 2,
+            reports: vec![report_block()],
+        }
+        .to_bytes();
+        assert!(Rtcp::parse_compound(&bytes[..bytes.len() - 4]).is_err());
+        assert!(Rtcp::parse_compound(&bytes[..3]).is_err());
+    }
+
+    #[test]
+    fn successfully_convert_to_ntp() {
+        let time = UNIX_EPOCH + Duration::from_millis(1500);
+        let ntp = ntp_timestamp(time);
+        assert_eq!(ntp >> 32, NTP_UNIX_OFFSET + 1);
+        assert_eq!(ntp as u32, 1 << 31);
+        assert_eq!(
+            ntp_middle(ntp),
+            ((NTP_UNIX_OFFSET + 1) as u32) << 16 | 0x8000
+        );
+    }
+}
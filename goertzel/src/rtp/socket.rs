@@ -1,18 +1,40 @@
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::sync::{Arc, LazyLock};
-use std::time::Duration;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use tokio::net::UdpSocket;
+use tokio::select;
 use tokio::sync::{broadcast, mpsc};
 use tokio::task::AbortHandle;
-use tokio::time::sleep;
-use tracing::{debug, warn};
+use tokio::time::{interval, sleep_until, MissedTickBehavior};
+use tracing::{debug, info, warn};
 
 use crate::asyncutil::and_log_err;
+use crate::sdp::MediaParams;
 
-const SAMPLES_PER_BUF: usize = 960; // 20ms of samples @ 48k
-const BUF_SIZE: usize = 2 * SAMPLES_PER_BUF;
+use super::cn::{self, NoiseGenerator, SilenceDetector};
+use super::codec::{self, Codec};
+use super::event::{self, Event, EventDetector, EventSender};
+use super::jitter::{JitterBuffer, Playout};
+use super::packet::{Header, Packet};
+use super::plc::Concealer;
+use super::port::{PortAllocator, PortLease};
+use super::resample::{Downsampler, Upsampler};
+use super::rtcp::Rtcp;
+use super::source::{Arrival, Source};
+use super::srtp::{SrtcpContext, SrtpContext};
+use super::stats::{CallStats, Reporter};
+
+const AUDIO_SAMPLE_RATE: u32 = 48000;
+const SAMPLES_PER_MS: usize = (AUDIO_SAMPLE_RATE / 1000) as usize;
+const RECV_BUF_SIZE: usize = 1500;
+// How often to refresh the noise level while we're quiet
+const SID_INTERVAL_FRAMES: usize = 50;
+// Until we know the codec, everything we support is narrowband anyway
+const DEFAULT_CLOCK_RATE: u32 = 8000;
+const RTCP_INTERVAL: Duration = Duration::from_secs(5);
+const DIGITS_CHANNEL_SIZE: usize = 16;
 
 static NET_ADDR: LazyLock<Ipv4Addr> = LazyLock::new(|| "10.100.0.0".parse().unwrap());
 const NET_MASK: u32 = 0xffff0000;
@@ -20,26 +42,59 @@ const NET_MASK: u32 = 0xffff0000;
 #[derive(Clone)]
 pub struct Socket {
     sock: Arc<UdpSocket>,
+    rtcp_sock: Arc<UdpSocket>,
+    // Goes back to the allocator once the last clone is dropped
+    lease: Arc<PortLease>,
     in_handle: Option<AbortHandle>,
     out_handle: Option<AbortHandle>,
+    rtcp_handle: Option<AbortHandle>,
 
     remote: Option<SocketAddr>,
+    reporter: Arc<Mutex<Reporter>>,
+
+    // Digits to send as telephone events, if the other side takes them
+    digits_ch: Option<mpsc::Sender<u8>>,
+    remote_digits_ch: broadcast::Sender<u8>,
 }
 
 impl Socket {
-    pub async fn bind() -> Result<Self> {
-        let sock = UdpSocket::bind("0.0.0.0:19512").await?;
+    pub async fn bind(ports: &PortAllocator) -> Result<Self> {
+        let (sock, rtcp_sock, lease) = ports.bind().await?;
         Ok(Self {
             sock: Arc::new(sock),
+            rtcp_sock: Arc::new(rtcp_sock),
+            lease: Arc::new(lease),
             in_handle: None,
             out_handle: None,
+            rtcp_handle: None,
 
             remote: None,
+            reporter: Arc::new(Mutex::new(Reporter::new(DEFAULT_CLOCK_RATE))),
+
+            digits_ch: None,
+            remote_digits_ch: broadcast::channel(DIGITS_CHANNEL_SIZE).0,
         })
     }
 
-    pub async fn port(&self) -> Result<u16> {
-        Ok(self.sock.local_addr().map(|addr| addr.port())?)
+    pub fn port(&self) -> u16 {
+        self.lease.port
+    }
+
+    pub fn stats(&self) -> CallStats {
+        self.reporter.lock().unwrap().stats()
+    }
+
+    pub async fn send_digit(&self, digit: u8) -> Result<()> {
+        let digits_ch = self
+            .digits_ch
+            .as_ref()
+            .ok_or(anyhow!("remote didn't negotiate telephone events"))?;
+        Ok(digits_ch.send(digit).await?)
+    }
+
+    // Digits the other side pressed
+    pub fn remote_digits(&self) -> broadcast::Receiver<u8> {
+        self.remote_digits_ch.subscribe()
     }
 
     pub fn is_in_net(&self, ip: IpAddr) -> bool {
@@ -51,86 +106,393 @@ impl Socket {
 
     pub async fn connect(
         &mut self,
-        addr: SocketAddr,
-        mut audio_in: broadcast::Receiver<i16>,
+        params: &MediaParams,
+        audio_in: broadcast::Receiver<i16>,
         audio_out: mpsc::Sender<i16>,
     ) -> Result<()> {
+        self.abort();
+
+        let addr = params.remote;
+        let payload_type = params.payload_type;
+        let encoder = codec::from_payload_type(payload_type)
+            .ok_or(anyhow!("unsupported payload type: {}", payload_type))?;
+        let decoder = codec::from_payload_type(payload_type)
+            .ok_or(anyhow!("unsupported payload type: {}", payload_type))?;
+
         self.remote = Some(addr);
-        debug!("rtp: connecting to remote at {}", addr);
+        debug!(
+            "rtp: connecting to remote at {} with {} ({:?}, {})",
+            addr,
+            encoder.encoding_name(),
+            params.direction,
+            if params.srtp.is_some() {
+                "srtp"
+            } else {
+                "unencrypted"
+            },
+        );
         self.sock.connect(addr).await?;
         debug!("rtp: connected to remote");
+        self.reporter
+            .lock()
+            .unwrap()
+            .set_clock_rate(encoder.clock_rate());
 
+        // RTCP goes to the port right above RTP
+        // https://www.rfc-editor.org/rfc/rfc3550 (11)
+        match addr.port().checked_add(1) {
+            Some(rtcp_port) => {
+                let rtcp_addr = SocketAddr::new(addr.ip(), rtcp_port);
+                self.rtcp_sock.connect(rtcp_addr).await?;
+                self.spawn_rtcp(params, rtcp_addr);
+            }
+            None => warn!("rtcp: no port above {} for rtcp, going without", addr),
+        }
+
+        if params.direction.recvs() {
+            self.spawn_recv(params, decoder, audio_out);
+        }
+        if params.direction.sends() {
+            self.spawn_send(params, encoder, audio_in);
+        }
+
+        Ok(())
+    }
+
+    fn spawn_recv(
+        &mut self,
+        params: &MediaParams,
+        mut decoder: Box<dyn Codec>,
+        audio_out: mpsc::Sender<i16>,
+    ) {
+        let addr = params.remote;
+        let ptime = params.ptime;
+        let telephone_event = params.telephone_event;
+        let mut srtp = params
+            .srtp
+            .as_ref()
+            .map(|keys| SrtpContext::new(&keys.remote));
         let sock = self.sock.clone();
+        let reporter = self.reporter.clone();
+        let remote_digits_ch = self.remote_digits_ch.clone();
         let in_handle = tokio::spawn(and_log_err(
             format!("rtp recv socket {}", addr),
             async move {
-                let mut got_first_packet = false;
+                let mut source: Option<Source> = None;
+                let mut jitter_buf = JitterBuffer::new(decoder.clock_rate(), ptime);
+                let mut upsampler = Upsampler::new(resample_factor(decoder.as_ref()));
+                let mut concealer = Concealer::new(decoder.clock_rate());
+                // Set while the far end has gone quiet and sent us a noise level
+                let mut noise: Option<NoiseGenerator> = None;
+                let mut events = EventDetector::default();
 
-                let mut buf = vec![0; BUF_SIZE];
+                let mut playout = interval(Duration::from_millis(ptime as u64));
+                playout.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+                let mut buf = vec![0; RECV_BUF_SIZE];
                 loop {
-                    let n = sock.recv(&mut buf).await?;
-                    if !got_first_packet {
-                        sleep(Duration::from_millis(30)).await;
-                        got_first_packet = true;
-                    }
-                    if n != buf.len() {
-                        warn!("got unexpected packet length: {}", n);
-                    }
-                    for buf_idx in (0..buf.len()).step_by(2) {
-                        let sample = i16::from_be_bytes([buf[buf_idx], buf[buf_idx + 1]]);
-                        audio_out.send(sample).await?;
+                    select! {
+                        n = sock.recv(&mut buf) => {
+                            let packet = match &mut srtp {
+                                Some(srtp) => srtp.unprotect(&buf[..n?]).and_then(|buf| Packet::parse(&buf)),
+                                None => Packet::parse(&buf[..n?]),
+                            };
+                            let packet = match packet {
+                                Ok(packet) => packet,
+                                Err(e) => {
+                                    warn!("rtp: dropping bad packet: {}", e);
+                                    continue;
+                                }
+                            };
+                            let header = &packet.header;
+
+                            let arrival = match &mut source {
+                                Some(source) if source.ssrc == header.ssrc => source.update(header.seq),
+                                _ => {
+                                    info!("rtp: new remote ssrc {:08x}", header.ssrc);
+                                    source = Some(Source::new(header.ssrc, header.seq));
+                                    jitter_buf.reset();
+                                    Arrival::InOrder
+                                }
+                            };
+                            match arrival {
+                                Arrival::InOrder | Arrival::Late => {}
+                                Arrival::Restart => {
+                                    debug!("rtp: remote seq jumped to {}", header.seq);
+                                    jitter_buf.reset();
+                                }
+                                Arrival::Duplicate => {
+                                    debug!("rtp: dropping duplicate packet seq {}", header.seq);
+                                    continue;
+                                }
+                                Arrival::Stray => {
+                                    debug!("rtp: dropping stray packet seq {}", header.seq);
+                                    continue;
+                                }
+                            }
+                            if header.payload_type != cn::CN_PAYLOAD_TYPE
+                                && Some(header.payload_type) != telephone_event
+                                && codec::from_payload_type(header.payload_type).is_none()
+                            {
+                                warn!("rtp: unexpected payload type: {}", header.payload_type);
+                                continue;
+                            }
+                            jitter_buf.push(packet, Instant::now());
+                        },
+                        _ = playout.tick() => {
+                            let samples_per_packet = (decoder.clock_rate() * ptime / 1000) as usize;
+                            let samples = match (jitter_buf.pop(), &mut noise) {
+                                (Playout::Packet(packet), _) if packet.header.payload_type == cn::CN_PAYLOAD_TYPE => {
+                                    match cn::parse(&packet.payload) {
+                                        Ok(level) => {
+                                            debug!("rtp: remote went quiet, comfort noise at -{} dBov", level);
+                                            noise.insert(NoiseGenerator::new(level)).generate(samples_per_packet)
+                                        }
+                                        Err(e) => {
+                                            warn!("rtp: dropping bad comfort noise packet: {}", e);
+                                            concealer.conceal(samples_per_packet)
+                                        }
+                                    }
+                                }
+                                (Playout::Packet(packet), _) if Some(packet.header.payload_type) == telephone_event => {
+                                    match Event::parse(&packet.payload) {
+                                        Ok(event) => {
+                                            if let Some(digit) = events.update(packet.header.timestamp, &event) {
+                                                debug!("rtp: remote pressed {}", digit);
+                                                let _ = remote_digits_ch.send(digit);
+                                            }
+                                        }
+                                        Err(e) => warn!("rtp: dropping bad telephone event: {}", e),
+                                    }
+                                    // They stop sending audio while a key's down
+                                    concealer.conceal(samples_per_packet)
+                                }
+                                (Playout::Packet(packet), _) => {
+                                    noise = None;
+                                    if packet.header.payload_type != decoder.payload_type() {
+                                        // Checked on the way in
+                                        let codec = codec::from_payload_type(packet.header.payload_type).unwrap();
+                                        debug!("rtp: remote switched to {}", codec.encoding_name());
+                                        upsampler = Upsampler::new(resample_factor(codec.as_ref()));
+                                        concealer = Concealer::new(codec.clock_rate());
+                                        decoder = codec;
+                                    }
+                                    concealer.good(decoder.decode(&packet.payload))
+                                }
+                                // Silence suppression means we'll run dry on purpose, keep the noise going
+                                (Playout::Lost | Playout::Buffering, Some(noise)) => noise.generate(samples_per_packet),
+                                (Playout::Lost, None) => concealer.conceal(samples_per_packet),
+                                (Playout::Buffering, None) if concealer.is_primed() => concealer.conceal(samples_per_packet),
+                                (Playout::Buffering, None) => continue,
+                            };
+                            if let Some(source) = &source {
+                                reporter.lock().unwrap().on_received(source, jitter_buf.jitter(), jitter_buf.stats());
+                            }
+                            for sample in upsampler.process(&samples) {
+                                audio_out.send(sample).await?;
+                            }
+                        },
                     }
                 }
             },
         ))
         .abort_handle();
         self.in_handle = Some(in_handle);
+    }
+
+    fn spawn_send(
+        &mut self,
+        params: &MediaParams,
+        encoder: Box<dyn Codec>,
+        mut audio_in: broadcast::Receiver<i16>,
+    ) {
+        let addr = params.remote;
+        let samples_per_buf = params.ptime as usize * SAMPLES_PER_MS;
+        let comfort_noise = params.comfort_noise;
+        let telephone_event = params.telephone_event;
+        let (digits_ch, mut digits_recv_ch) = mpsc::channel(DIGITS_CHANNEL_SIZE);
+        self.digits_ch = telephone_event.map(|_| digits_ch);
+        let mut srtp = params
+            .srtp
+            .as_ref()
+            .map(|keys| SrtpContext::new(&keys.local));
 
         let sock = self.sock.clone();
+        let reporter = self.reporter.clone();
         let out_handle = tokio::spawn(and_log_err(
             format!("rtp send socket {}", addr),
             async move {
-                let mut buf = vec![0; BUF_SIZE];
-                let mut buf_idx = 0;
+                // Random starting points make known-plaintext attacks on encryption harder
+                // https://www.rfc-editor.org/rfc/rfc3550 (5.1)
+                let mut header = Header {
+                    marker: true,
+                    payload_type: encoder.payload_type(),
+                    seq: rand::random(),
+                    timestamp: rand::random(),
+                    ssrc: reporter.lock().unwrap().ssrc,
+                    csrcs: vec![],
+                };
+                debug!("rtp: sending as ssrc {:08x}", header.ssrc);
+
+                let mut downsampler = Downsampler::new(resample_factor(encoder.as_ref()));
+                // Only suppress silence if the other side can fill it back in
+                let mut silence = comfort_noise.then(SilenceDetector::default);
+                let mut quiet_frames = 0;
+                let mut event: Option<EventSender> = None;
+
+                let mut buf = Vec::with_capacity(samples_per_buf);
                 loop {
-                    let sample = audio_in.recv().await?;
-                    let bytes = sample.to_be_bytes();
-                    buf[buf_idx] = bytes[0];
-                    buf[buf_idx + 1] = bytes[1];
-                    buf_idx += 2;
-                    if buf_idx == BUF_SIZE {
-                        sock.send(&buf).await?;
-                        buf_idx = 0;
+                    select! {
+                        sample = audio_in.recv() => buf.push(sample?),
+                        Some(digit) = digits_recv_ch.recv() => {
+                            match (event::from_digit(digit), &event) {
+                                (Some(code), None) => event = Some(EventSender::new(code, header.timestamp)),
+                                (Some(_), Some(_)) => warn!("rtp: dropping digit {}, still sending the last one", digit),
+                                (None, _) => warn!("rtp: no telephone event for digit {}", digit),
+                            }
+                            continue;
+                        },
+                    }
+                    if buf.len() == samples_per_buf {
+                        let samples = downsampler.process(&buf);
+                        buf.clear();
+
+                        let level = silence.as_mut().and_then(|s| s.update(&samples));
+                        let packet = match (&mut event, telephone_event, level) {
+                            // Key presses go out in place of audio
+                            (Some(sender), Some(payload_type), _) => {
+                                let (payload, first) = sender.next(samples.len());
+                                let header = Header {
+                                    marker: first,
+                                    payload_type,
+                                    timestamp: sender.timestamp,
+                                    ..header.clone()
+                                };
+                                if sender.is_done() {
+                                    event = None;
+                                }
+                                Packet::new(header, payload.to_bytes())
+                            }
+                            (_, _, Some(level)) => {
+                                quiet_frames += 1;
+                                if quiet_frames % SID_INTERVAL_FRAMES != 1 {
+                                    header.timestamp =
+                                        header.timestamp.wrapping_add(samples.len() as u32);
+                                    continue;
+                                }
+                                let header = Header {
+                                    payload_type: cn::CN_PAYLOAD_TYPE,
+                                    ..header.clone()
+                                };
+                                Packet::new(header, cn::to_bytes(level))
+                            }
+                            (_, _, None) => {
+                                // First packet of a talkspurt gets the marker
+                                // https://www.rfc-editor.org/rfc/rfc3551 (4.1)
+                                if quiet_frames > 0 {
+                                    header.marker = true;
+                                    quiet_frames = 0;
+                                }
+                                Packet::new(header.clone(), encoder.encode(&samples))
+                            }
+                        };
+                        let bytes = match &mut srtp {
+                            Some(srtp) => srtp.protect(&packet.to_bytes())?,
+                            None => packet.to_bytes(),
+                        };
+                        sock.send(&bytes).await?;
+                        reporter.lock().unwrap().on_sent(
+                            packet.header.timestamp,
+                            packet.payload.len(),
+                            Instant::now(),
+                        );
+
+                        header.marker = false;
+                        header.seq = header.seq.wrapping_add(1);
+                        header.timestamp = header.timestamp.wrapping_add(samples.len() as u32);
                     }
                 }
             },
         ))
         .abort_handle();
         self.out_handle = Some(out_handle);
+    }
 
-        Ok(())
+    fn spawn_rtcp(&mut self, params: &MediaParams, addr: SocketAddr) {
+        let mut srtcp = params.srtp.as_ref().map(|keys| {
+            (
+                SrtcpContext::new(&keys.local),
+                SrtcpContext::new(&keys.remote),
+            )
+        });
+        let sock = self.rtcp_sock.clone();
+        let reporter = self.reporter.clone();
+        let rtcp_handle = tokio::spawn(and_log_err(
+            format!("rtcp socket {}", addr),
+            async move {
+                let mut buf = vec![0; RECV_BUF_SIZE];
+                let mut next_report = Instant::now() + report_interval();
+                loop {
+                    select! {
+                        n = sock.recv(&mut buf) => {
+                            let packets = match &mut srtcp {
+                                Some((_, inbound)) => inbound.unprotect(&buf[..n?]).and_then(|buf| Rtcp::parse_compound(&buf)),
+                                None => Rtcp::parse_compound(&buf[..n?]),
+                            };
+                            match packets {
+                                Ok(packets) => reporter.lock().unwrap().on_rtcp(packets, SystemTime::now(), Instant::now()),
+                                Err(e) => warn!("rtcp: dropping bad packet: {}", e),
+                            }
+                        },
+                        _ = sleep_until(next_report.into()) => {
+                            let report = reporter.lock().unwrap().report(SystemTime::now(), Instant::now());
+                            let report = match &mut srtcp {
+                                Some((outbound, _)) => outbound.protect(&report)?,
+                                None => report,
+                            };
+                            sock.send(&report).await?;
+                            next_report = Instant::now() + report_interval();
+                        },
+                    }
+                }
+            },
+        ))
+        .abort_handle();
+        self.rtcp_handle = Some(rtcp_handle);
+    }
+
+    fn abort(&mut self) {
+        if let Some(handle) = self.in_handle.take() {
+            debug!("aborting in task");
+            handle.abort();
+        }
+        if let Some(handle) = self.out_handle.take() {
+            debug!("aborting out task");
+            handle.abort();
+        }
+        if let Some(handle) = self.rtcp_handle.take() {
+            debug!("aborting rtcp task");
+            handle.abort();
+        }
     }
 }
 
+// Randomized so everyone on a call doesn't report in lockstep
+// https://www.rfc-editor.org/rfc/rfc3550 (6.2)
+fn report_interval() -> Duration {
+    RTCP_INTERVAL.mul_f64(rand::random_range(0.5..1.5))
+}
+
+fn resample_factor(codec: &dyn Codec) -> usize {
+    (AUDIO_SAMPLE_RATE / codec.clock_rate()) as usize
+}
+
 impl Drop for Socket {
     fn drop(&mut self) {
         debug!(
             "ope i dropped an RTP sock 🧦 connected to {}",
             self.remote.unwrap_or("0.0.0.0:0".parse().unwrap())
         );
-        match &self.in_handle {
-            Some(handle) => {
-                debug!("aborting in task");
-                handle.abort();
-            }
-            None => {}
-        }
-        match &self.out_handle {
-            Some(handle) => {
-                debug!("aborting out task");
-                handle.abort();
-            }
-            None => {}
-        }
+        self.abort();
     }
 }
@@ -0,0 +1,146 @@
+// Sequence number tracking for a remote synchronization source
+// https://www.rfc-editor.org/rfc/rfc3550 (A.1)
+const MAX_DROPOUT: u16 = 3000;
+const MAX_MISORDER: u16 = 100;
+const SEQ_MOD: u32 = 1 << 16;
+const HISTORY_LEN: u16 = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Arrival {
+    InOrder,
+    Late,
+    Duplicate,
+    // Too far off to count, unless the next one follows on from it
+    Stray,
+    Restart,
+}
+
+#[derive(Clone, Debug)]
+pub struct Source {
+    pub ssrc: u32,
+
+    max_seq: u16,
+    cycles: u32,
+    base_seq: u32,
+    received: u32,
+    // Where the sender would be next if it really did jump
+    bad_seq: Option<u16>,
+
+    // Bit n is set if we've seen max_seq - n
+    history: u64,
+}
+
+impl Source {
+    pub fn new(ssrc: u32, seq: u16) -> Self {
+        Self {
+            ssrc,
+
+            max_seq: seq,
+            cycles: 0,
+            base_seq: seq as u32,
+            received: 1,
+            bad_seq: None,
+
+            history: 1,
+        }
+    }
+
+    pub fn update(&mut self, seq: u16) -> Arrival {
+        let delta = seq.wrapping_sub(self.max_seq);
+        if delta == 0 {
+            Arrival::Duplicate
+        } else if delta < MAX_DROPOUT {
+            if seq < self.max_seq {
+                self.cycles += SEQ_MOD;
+            }
+            self.history = if delta < HISTORY_LEN {
+                (self.history << delta) | 1
+            } else {
+                1
+            };
+            self.max_seq = seq;
+            self.received += 1;
+            Arrival::InOrder
+        } else if delta <= u16::MAX - MAX_MISORDER {
+            // Too big a jump to be reordering. If the next packet follows on from it the sender
+            // restarted, otherwise it was a stray
+            if self.bad_seq == Some(seq) {
+                *self = Self::new(self.ssrc, seq);
+                Arrival::Restart
+            } else {
+                self.bad_seq = Some(seq.wrapping_add(1));
+                Arrival::Stray
+            }
+        } else {
+            let behind = self.max_seq.wrapping_sub(seq);
+            if behind < HISTORY_LEN {
+                let bit = 1 << behind;
+                if self.history & bit != 0 {
+                    return Arrival::Duplicate;
+                }
+                self.history |= bit;
+            }
+            self.received += 1;
+            Arrival::Late
+        }
+    }
+
+    pub fn extended_max_seq(&self) -> u32 {
+        self.cycles + self.max_seq as u32
+    }
+
+    pub fn expected(&self) -> u32 {
+        self.extended_max_seq() - self.base_seq + 1
+    }
+
+    pub fn received(&self) -> u32 {
+        self.received
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+
+    #[test]
+    fn successfully_classify_arrivals() {
+        let mut source = Source::new(1, 10);
+        assert_eq!(source.update(11), Arrival::InOrder);
+        assert_eq!(source.update(13), Arrival::InOrder);
+        assert_eq!(source.update(12), Arrival::Late);
+        assert_eq!(source.update(12), Arrival::Duplicate);
+        assert_eq!(source.update(13), Arrival::Duplicate);
+        assert_eq!(source.expected(), 4);
+        assert_eq!(source.received(), 4);
+    }
+
+    #[test]
+    fn successfully_track_wraparound() {
+        let mut source = Source::new(1, u16::MAX - 1);
+        assert_eq!(source.update(u16::MAX), Arrival::InOrder);
+        assert_eq!(source.update(0), Arrival::InOrder);
+        assert_eq!(source.update(1), Arrival::InOrder);
+        assert_eq!(source.extended_max_seq(), SEQ_MOD + 1);
+        assert_eq!(source.expected(), 4);
+    }
+
+    #[test]
+    fn successfully_restart_on_big_jump() {
+        let mut source = Source::new(1, 10);
+        assert_eq!(source.update(30000), Arrival::Stray);
+        assert_eq!(source.update(30001), Arrival::Restart);
+        assert_eq!(source.update(30002), Arrival::InOrder);
+        assert_eq!(source.expected(), 2);
+    }
+
+    #[test]
+    fn successfully_ignore_an_isolated_jump() {
+        let mut source = Source::new(1, 10);
+        assert_eq!(source.update(30000), Arrival::Stray);
+        assert_eq!(source.update(11), Arrival::InOrder);
+        assert_eq!(source.update(30002), Arrival::Stray);
+        assert_eq!(source.update(12), Arrival::InOrder);
+        assert_eq!(source.expected(), 3);
+        assert_eq!(source.received(), 3);
+    }
+}
@@ -0,0 +1,548 @@
+use std::fmt;
+
+use aes::cipher::{KeyIvInit, StreamCipher};
+use anyhow::{anyhow, Result};
+use base64::prelude::*;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+use super::packet::HEADER_SIZE;
+
+// Encrypted and authenticated RTP, with the only suite everyone supports
+// https://www.rfc-editor.org/rfc/rfc3711
+pub const CRYPTO_SUITE: &str = "AES_CM_128_HMAC_SHA1_80";
+
+const KEY_LEN: usize = 16;
+const SALT_LEN: usize = 14;
+const AUTH_KEY_LEN: usize = 20;
+// HMAC-SHA1 truncated to 80 bits
+const AUTH_TAG_LEN: usize = 10;
+
+// https://www.rfc-editor.org/rfc/rfc3711 (4.3.2)
+const RTP_ENCRYPTION_LABEL: u8 = 0x00;
+const RTP_AUTH_LABEL: u8 = 0x01;
+const RTP_SALT_LABEL: u8 = 0x02;
+const RTCP_ENCRYPTION_LABEL: u8 = 0x03;
+const RTCP_AUTH_LABEL: u8 = 0x04;
+const RTCP_SALT_LABEL: u8 = 0x05;
+
+const EXTENSION_BIT: u8 = 0x10;
+const CSRC_COUNT_MASK: u8 = 0x0f;
+
+// Header and sender SSRC stay in the clear
+// https://www.rfc-editor.org/rfc/rfc3711 (3.4)
+const RTCP_HEADER_SIZE: usize = 8;
+const RTCP_INDEX_SIZE: usize = 4;
+const RTCP_ENCRYPTED_BIT: u32 = 0x8000_0000;
+const RTCP_INDEX_MASK: u32 = 0x7fff_ffff;
+
+// https://www.rfc-editor.org/rfc/rfc3711 (3.3.2)
+const REPLAY_WINDOW_SIZE: u64 = 64;
+
+type Aes128Ctr = ctr::Ctr128BE<aes::Aes128>;
+type HmacSha1 = Hmac<Sha1>;
+
+#[derive(Clone, PartialEq)]
+pub struct MasterKey {
+    key: [u8; KEY_LEN],
+    salt: [u8; SALT_LEN],
+}
+
+impl MasterKey {
+    pub fn generate() -> Self {
+        Self {
+            key: rand::random(),
+            salt: rand::random(),
+        }
+    }
+
+    // The key-params of an a=crypto line, lifetime and MKI are optional and we don't use them
+    // https://www.rfc-editor.org/rfc/rfc4568 (6.1)
+    pub fn from_inline(params: &str) -> Result<Self> {
+        let encoded = params
+            .strip_prefix("inline:")
+            .ok_or(anyhow!("unsupported srtp key method: {}", params))?;
+        let encoded = encoded.split('|').next().unwrap_or_default();
+        let decoded = BASE64_STANDARD.decode(encoded)?;
+        if decoded.len() != KEY_LEN + SALT_LEN {
+            return Err(anyhow!("bad srtp master key length: {}", decoded.len()));
+        }
+        Ok(Self {
+            key: decoded[..KEY_LEN].try_into()?,
+            salt: decoded[KEY_LEN..].try_into()?,
+        })
+    }
+
+    pub fn to_inline(&self) -> String {
+        let mut key_salt = self.key.to_vec();
+        key_salt.extend_from_slice(&self.salt);
+        format!("inline:{}", BASE64_STANDARD.encode(key_salt))
+    }
+}
+
+// So keys don't end up in the logs alongside the rest of the media params
+impl fmt::Debug for MasterKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "MasterKey(..)")
+    }
+}
+
+// https://www.rfc-editor.org/rfc/rfc4568 (9.1)
+#[derive(Clone, Debug, PartialEq)]
+pub struct Crypto {
+    pub tag: u32,
+    pub key: MasterKey,
+}
+
+impl Crypto {
+    // Returns None for suites we don't do, so the caller can look at the next line
+    pub fn parse(value: &str) -> Result<Option<Self>> {
+        let mut parts = value.split_whitespace();
+        let tag = parts
+            .next()
+            .ok_or(anyhow!("empty crypto attribute"))?
+            .parse()?;
+        let suite = parts
+            .next()
+            .ok_or(anyhow!("crypto attribute missing suite"))?;
+        if suite != CRYPTO_SUITE {
+            return Ok(None);
+        }
+        let key_params = parts
+            .next()
+            .ok_or(anyhow!("crypto attribute missing key"))?;
+        // Several keys or session params are legal but nobody sends them to a phone
+        if key_params.contains(';') || parts.next().is_some() {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            tag,
+            key: MasterKey::from_inline(key_params)?,
+        }))
+    }
+}
+
+impl fmt::Display for Crypto {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.tag, CRYPTO_SUITE, self.key.to_inline())
+    }
+}
+
+// Our key for what we send, theirs for what we receive
+#[derive(Clone, Debug, PartialEq)]
+pub struct SrtpKeys {
+    pub local: MasterKey,
+    pub remote: MasterKey,
+}
+
+struct SessionKeys {
+    encryption: [u8; KEY_LEN],
+    salt: [u8; SALT_LEN],
+    auth: [u8; AUTH_KEY_LEN],
+}
+
+impl SessionKeys {
+    // With a key derivation rate of 0 the index drops out and it's just the label
+    // https://www.rfc-editor.org/rfc/rfc3711 (4.3.1)
+    fn derive(master: &MasterKey, labels: [u8; 3]) -> Self {
+        let prf = |label: u8, out: &mut [u8]| {
+            let mut iv = [0; 16];
+            iv[..SALT_LEN].copy_from_slice(&master.salt);
+            iv[7] ^= label;
+            Aes128Ctr::new(&master.key.into(), &iv.into()).apply_keystream(out);
+        };
+        let mut keys = Self {
+            encryption: [0; KEY_LEN],
+            salt: [0; SALT_LEN],
+            auth: [0; AUTH_KEY_LEN],
+        };
+        prf(labels[0], &mut keys.encryption);
+        prf(labels[1], &mut keys.auth);
+        prf(labels[2], &mut keys.salt);
+        keys
+    }
+
+    // https://www.rfc-editor.org/rfc/rfc3711 (4.1.1)
+    fn apply_keystream(&self, ssrc: u32, index: u64, buf: &mut [u8]) {
+        let mut iv = [0; 16];
+        iv[..SALT_LEN].copy_from_slice(&self.salt);
+        for (i, b) in ssrc.to_be_bytes().iter().enumerate() {
+            iv[4 + i] ^= b;
+        }
+        for (i, b) in index.to_be_bytes()[2..].iter().enumerate() {
+            iv[8 + i] ^= b;
+        }
+        Aes128Ctr::new(&self.encryption.into(), &iv.into()).apply_keystream(buf);
+    }
+
+    fn mac(&self, parts: &[&[u8]]) -> HmacSha1 {
+        let mut mac = HmacSha1::new_from_slice(&self.auth).expect("hmac takes any key length");
+        for part in parts {
+            mac.update(part);
+        }
+        mac
+    }
+}
+
+#[derive(Default)]
+struct ReplayWindow {
+    highest: Option<u64>,
+    // Bit n set means we've seen highest - n
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn check(&self, index: u64) -> Result<()> {
+        match self.highest {
+            Some(highest) if index <= highest => {
+                let delta = highest - index;
+                if delta >= REPLAY_WINDOW_SIZE {
+                    Err(anyhow!("srtp packet {} too old", index))
+                } else if self.seen & (1 << delta) != 0 {
+                    Err(anyhow!("srtp packet {} replayed", index))
+                } else {
+                    Ok(())
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn update(&mut self, index: u64) {
+        match self.highest {
+            Some(highest) if index <= highest => self.seen |= 1 << (highest - index),
+            Some(highest) => {
+                let delta = index - highest;
+                self.seen = if delta >= REPLAY_WINDOW_SIZE {
+                    0
+                } else {
+                    self.seen << delta
+                } | 1;
+                self.highest = Some(index);
+            }
+            None => {
+                self.seen = 1;
+                self.highest = Some(index);
+            }
+        }
+    }
+}
+
+// One direction of an RTP stream, we only ever have one source per direction
+pub struct SrtpContext {
+    keys: SessionKeys,
+    ssrc: Option<u32>,
+    // Rollover counter and highest seq so far, together they make the packet index
+    roc: u32,
+    s_l: Option<u16>,
+    replay: ReplayWindow,
+}
+
+impl SrtpContext {
+    pub fn new(master: &MasterKey) -> Self {
+        Self {
+            keys: SessionKeys::derive(
+                master,
+                [RTP_ENCRYPTION_LABEL, RTP_AUTH_LABEL, RTP_SALT_LABEL],
+            ),
+            ssrc: None,
+            roc: 0,
+            s_l: None,
+            replay: ReplayWindow::default(),
+        }
+    }
+
+    pub fn protect(&mut self, packet: &[u8]) -> Result<Vec<u8>> {
+        let (header_len, ssrc, seq) = parse_header(packet)?;
+        self.on_ssrc(ssrc);
+        let (roc, index) = self.estimate_index(seq);
+
+        let mut buf = packet.to_vec();
+        self.keys
+            .apply_keystream(ssrc, index, &mut buf[header_len..]);
+        let tag = self
+            .keys
+            .mac(&[&buf, &roc.to_be_bytes()])
+            .finalize()
+            .into_bytes();
+        buf.extend_from_slice(&tag[..AUTH_TAG_LEN]);
+
+        self.update_index(roc, seq);
+        Ok(buf)
+    }
+
+    pub fn unprotect(&mut self, packet: &[u8]) -> Result<Vec<u8>> {
+        if packet.len() < HEADER_SIZE + AUTH_TAG_LEN {
+            return Err(anyhow!("srtp packet too short: {}", packet.len()));
+        }
+        let (authed, tag) = packet.split_at(packet.len() - AUTH_TAG_LEN);
+        let (header_len, ssrc, seq) = parse_header(authed)?;
+        // Nothing about a new source counts until it authenticates, or anyone could reset us
+        let new_source = self.ssrc != Some(ssrc);
+        let (roc, index) = if new_source {
+            (0, seq as u64)
+        } else {
+            self.estimate_index(seq)
+        };
+
+        if !new_source {
+            self.replay.check(index)?;
+        }
+        self.keys
+            .mac(&[authed, &roc.to_be_bytes()])
+            .verify_truncated_left(tag)
+            .map_err(|_| anyhow!("srtp packet {} failed authentication", index))?;
+        self.on_ssrc(ssrc);
+        self.replay.update(index);
+
+        let mut buf = authed.to_vec();
+        self.keys
+            .apply_keystream(ssrc, index, &mut buf[header_len..]);
+
+        self.update_index(roc, seq);
+        Ok(buf)
+    }
+
+    // A new source starts its index over
+    fn on_ssrc(&mut self, ssrc: u32) {
+        if self.ssrc != Some(ssrc) {
+            self.ssrc = Some(ssrc);
+            self.roc = 0;
+            self.s_l = None;
+            self.replay = ReplayWindow::default();
+        }
+    }
+
+    // https://www.rfc-editor.org/rfc/rfc3711 (3.3.1)
+    fn estimate_index(&self, seq: u16) -> (u32, u64) {
+        let roc = match self.s_l {
+            None => self.roc,
+            Some(s_l) if s_l < 0x8000 && seq > s_l && seq - s_l > 0x8000 => {
+                self.roc.wrapping_sub(1)
+            }
+            Some(s_l) if s_l >= 0x8000 && seq < s_l - 0x8000 => self.roc.wrapping_add(1),
+            Some(_) => self.roc,
+        };
+        (roc, ((roc as u64) << 16) | seq as u64)
+    }
+
+    fn update_index(&mut self, roc: u32, seq: u16) {
+        match self.s_l {
+            _ if roc == self.roc.wrapping_add(1) => {
+                self.roc = roc;
+                self.s_l = Some(seq);
+            }
+            Some(s_l) if roc == self.roc && seq <= s_l => {}
+            _ if roc == self.roc => self.s_l = Some(seq),
+            _ => {}
+        }
+    }
+}
+
+pub struct SrtcpContext {
+    keys: SessionKeys,
+    // Ours to count up when sending, theirs to check when receiving
+    index: u32,
+    replay: ReplayWindow,
+}
+
+impl SrtcpContext {
+    pub fn new(master: &MasterKey) -> Self {
+        Self {
+            keys: SessionKeys::derive(
+                master,
+                [RTCP_ENCRYPTION_LABEL, RTCP_AUTH_LABEL, RTCP_SALT_LABEL],
+            ),
+            index: 0,
+            replay: ReplayWindow::default(),
+        }
+    }
+
+    // https://www.rfc-editor.org/rfc/rfc3711 (3.4)
+    pub fn protect(&mut self, packet: &[u8]) -> Result<Vec<u8>> {
+        let ssrc = rtcp_ssrc(packet)?;
+        let index = self.index;
+        self.index = (self.index + 1) & RTCP_INDEX_MASK;
+
+        let mut buf = packet.to_vec();
+        self.keys
+            .apply_keystream(ssrc, index as u64, &mut buf[RTCP_HEADER_SIZE..]);
+        buf.extend_from_slice(&(RTCP_ENCRYPTED_BIT | index).to_be_bytes());
+        let tag = self.keys.mac(&[&buf]).finalize().into_bytes();
+        buf.extend_from_slice(&tag[..AUTH_TAG_LEN]);
+        Ok(buf)
+    }
+
+    pub fn unprotect(&mut self, packet: &[u8]) -> Result<Vec<u8>> {
+        if packet.len() < RTCP_HEADER_SIZE + RTCP_INDEX_SIZE + AUTH_TAG_LEN {
+            return Err(anyhow!("srtcp packet too short: {}", packet.len()));
+        }
+        let (authed, tag) = packet.split_at(packet.len() - AUTH_TAG_LEN);
+        let (body, e_index) = authed.split_at(authed.len() - RTCP_INDEX_SIZE);
+        let e_index = u32::from_be_bytes(e_index.try_into()?);
+        let index = e_index & RTCP_INDEX_MASK;
+
+        self.replay.check(index as u64)?;
+        self.keys
+            .mac(&[authed])
+            .verify_truncated_left(tag)
+            .map_err(|_| anyhow!("srtcp packet {} failed authentication", index))?;
+        self.replay.update(index as u64);
+
+        let mut buf = body.to_vec();
+        if e_index & RTCP_ENCRYPTED_BIT != 0 {
+            let ssrc = rtcp_ssrc(&buf)?;
+            self.keys
+                .apply_keystream(ssrc, index as u64, &mut buf[RTCP_HEADER_SIZE..]);
+        }
+        Ok(buf)
+    }
+}
+
+// Where the payload starts, the SSRC and the seq
+fn parse_header(packet: &[u8]) -> Result<(usize, u32, u16)> {
+    if packet.len() < HEADER_SIZE {
+        return Err(anyhow!("rtp packet too short: {}", packet.len()));
+    }
+    let mut len = HEADER_SIZE + 4 * (packet[0] & CSRC_COUNT_MASK) as usize;
+    if packet[0] & EXTENSION_BIT != 0 {
+        let ext = packet
+            .get(len + 2..len + 4)
+            .ok_or(anyhow!("rtp packet truncated in extension"))?;
+        len += 4 + 4 * u16::from_be_bytes([ext[0], ext[1]]) as usize;
+    }
+    if packet.len() < len {
+        return Err(anyhow!("rtp packet truncated in header"));
+    }
+    let seq = u16::from_be_bytes([packet[2], packet[3]]);
+    let ssrc = u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]);
+    Ok((len, ssrc, seq))
+}
+
+fn rtcp_ssrc(packet: &[u8]) -> Result<u32> {
+    let ssrc = packet
+        .get(4..RTCP_HEADER_SIZE)
+        .ok_or(anyhow!("rtcp packet too short: {}", packet.len()))?;
+    Ok(u32::from_be_bytes(ssrc.try_into()?))
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use crate::rtp::packet::{Header, Packet};
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn packet(seq: u16) -> Vec<u8> {
+        let header = Header {
+            payload_type: 0,
+            seq,
+            timestamp: 160 * seq as u32,
+            ssrc: 0xdeadbeef,
+            ..Default::default()
+        };
+        Packet::new(header, vec![0x55; 160]).to_bytes()
+    }
+
+    // https://www.rfc-editor.org/rfc/rfc3711 (B.3)
+    #[test]
+    fn successfully_derive_session_keys() {
+        let master = MasterKey {
+            key: hex("E1F97A0D3E018BE0D64FA32C06DE4139").try_into().unwrap(),
+            salt: hex("0EC675AD498AFEEBB6960B3AABE6").try_into().unwrap(),
+        };
+        let keys = SessionKeys::derive(
+            &master,
+            [RTP_ENCRYPTION_LABEL, RTP_AUTH_LABEL, RTP_SALT_LABEL],
+        );
+        assert_eq!(
+            keys.encryption.to_vec(),
+            hex("C61E7A93744F39EE10734AFE3FF7A087")
+        );
+        assert_eq!(keys.salt.to_vec(), hex("30CBBC08863D8C85D49DB34A9AE1"));
+        assert_eq!(
+            keys.auth.to_vec(),
+            hex("CEBE321F6FF7716B6FD4AB49AF256A156D38BAA4")
+        );
+    }
+
+    #[test]
+    fn successfully_round_trip_across_rollover() -> Result<()> {
+        let key = MasterKey::generate();
+        let mut sender = SrtpContext::new(&key);
+        let mut receiver = SrtpContext::new(&key);
+        for seq in [65534, 65535, 0, 1] {
+            let plain = packet(seq);
+            let protected = sender.protect(&plain)?;
+            assert_eq!(protected.len(), plain.len() + AUTH_TAG_LEN);
+            assert_eq!(protected[..HEADER_SIZE], plain[..HEADER_SIZE]);
+            assert_ne!(protected[HEADER_SIZE..plain.len()], plain[HEADER_SIZE..]);
+            assert_eq!(receiver.unprotect(&protected)?, plain);
+        }
+        assert_eq!(receiver.roc, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn fail_to_unprotect_tampered_or_replayed_packets() -> Result<()> {
+        let key = MasterKey::generate();
+        let mut sender = SrtpContext::new(&key);
+        let mut receiver = SrtpContext::new(&key);
+
+        let protected = sender.protect(&packet(100))?;
+        let mut tampered = protected.clone();
+        tampered[HEADER_SIZE] ^= 1;
+        assert!(receiver.unprotect(&tampered).is_err());
+        receiver.unprotect(&protected)?;
+        assert!(receiver.unprotect(&protected).is_err());
+
+        let mut wrong_key = SrtpContext::new(&MasterKey::generate());
+        assert!(wrong_key.unprotect(&sender.protect(&packet(101))?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn fail_to_reset_the_source_with_a_forged_packet() -> Result<()> {
+        let key = MasterKey::generate();
+        let mut sender = SrtpContext::new(&key);
+        let mut receiver = SrtpContext::new(&key);
+        for seq in [65535, 0] {
+            receiver.unprotect(&sender.protect(&packet(seq))?)?;
+        }
+
+        let protected = sender.protect(&packet(1))?;
+        receiver.unprotect(&protected)?;
+        let mut forged = protected.clone();
+        forged[8..HEADER_SIZE].copy_from_slice(&0xcafef00d_u32.to_be_bytes());
+        assert!(receiver.unprotect(&forged).is_err());
+        assert!(receiver.unprotect(&protected).is_err());
+        assert_eq!(receiver.roc, 1);
+        receiver.unprotect(&sender.protect(&packet(2))?)?;
+        Ok(())
+    }
+
+    #[test]
+    fn successfully_round_trip_rtcp_and_crypto_lines() -> Result<()> {
+        let key = MasterKey::generate();
+        let mut sender = SrtcpContext::new(&key);
+        let mut receiver = SrtcpContext::new(&key);
+        let report = hex("80c90001deadbeef");
+        let protected = sender.protect(&report)?;
+        assert_eq!(receiver.unprotect(&protected)?, report);
+        assert!(receiver.unprotect(&protected).is_err());
+
+        let crypto = Crypto { tag: 1, key };
+        assert_eq!(Crypto::parse(&crypto.to_string())?, Some(crypto));
+        assert_eq!(
+            Crypto::parse("1 AES_CM_128_HMAC_SHA1_32 inline:AAAA")?,
+            None
+        );
+        assert!(Crypto::parse("1 AES_CM_128_HMAC_SHA1_80 inline:AAAA").is_err());
+        Ok(())
+    }
+}
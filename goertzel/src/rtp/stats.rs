@@ -0,0 +1,314 @@
+use std::time::{Duration, Instant, SystemTime};
+
+use tracing::debug;
+
+use super::jitter::JitterStats;
+use super::rtcp::{self, ReportBlock, Rtcp, SenderInfo};
+use super::source::Source;
+
+// Everything we know about how a call went, ours and what the far end told us over RTCP
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CallStats {
+    pub packets_sent: u32,
+    pub octets_sent: u32,
+
+    pub packets_received: u32,
+    pub packets_lost: i64,
+    pub jitter: Duration,
+    pub jitter_buffer: JitterStats,
+
+    // How our audio is arriving at the other end
+    pub remote_packets_lost: Option<i32>,
+    pub remote_fraction_lost: Option<f32>,
+    pub remote_jitter: Option<Duration>,
+
+    pub round_trip: Option<Duration>,
+}
+
+// Keeps the bookkeeping RTCP reports are built from
+// https://www.rfc-editor.org/rfc/rfc3550 (6.4)
+#[derive(Debug)]
+pub struct Reporter {
+    pub ssrc: u32,
+    cname: String,
+    clock_rate: u32,
+
+    packets_sent: u32,
+    octets_sent: u32,
+    // Lets us work out what RTP timestamp lines up with the wallclock in an SR
+    last_sent: Option<(u32, Instant)>,
+
+    source: Option<Source>,
+    jitter: u32,
+    jitter_buffer: JitterStats,
+    expected_prior: u32,
+    received_prior: u32,
+    // Middle of the NTP timestamp from their last SR, and when we got it
+    last_sr: Option<(u32, Instant)>,
+
+    remote_report: Option<ReportBlock>,
+    round_trip: Option<Duration>,
+}
+
+impl Reporter {
+    pub fn new(clock_rate: u32) -> Self {
+        let ssrc = rand::random();
+        Self {
+            ssrc,
+            // Random per call is fine, nothing needs to link our streams across calls
+            // https://www.rfc-editor.org/rfc/rfc7022 (4.2)
+            cname: format!("{:016x}@frandline", rand::random::<u64>()),
+            clock_rate,
+
+            packets_sent: 0,
+            octets_sent: 0,
+            last_sent: None,
+
+            source: None,
+            jitter: 0,
+            jitter_buffer: JitterStats::default(),
+            expected_prior: 0,
+            received_prior: 0,
+            last_sr: None,
+
+            remote_report: None,
+            round_trip: None,
+        }
+    }
+
+    pub fn set_clock_rate(&mut self, clock_rate: u32) {
+        self.clock_rate = clock_rate;
+    }
+
+    pub fn on_sent(&mut self, timestamp: u32, payload_len: usize, at: Instant) {
+        self.packets_sent = self.packets_sent.wrapping_add(1);
+        self.octets_sent = self.octets_sent.wrapping_add(payload_len as u32);
+        self.last_sent = Some((timestamp, at));
+    }
+
+    pub fn on_received(&mut self, source: &Source, jitter: u32, jitter_buffer: JitterStats) {
+        if self.source.as_ref().is_none_or(|s| s.ssrc != source.ssrc) {
+            self.expected_prior = 0;
+            self.received_prior = 0;
+            self.last_sr = None;
+        }
+        self.source = Some(source.clone());
+        self.jitter = jitter;
+        self.jitter_buffer = jitter_buffer;
+    }
+
+    pub fn on_rtcp(&mut self, packets: Vec<Rtcp>, now: SystemTime, at: Instant) {
+        for packet in packets {
+            let reports = match packet {
+                Rtcp::SenderReport {
+                    ssrc,
+                    info,
+                    reports,
+                } => {
+                    if self.source.as_ref().is_none_or(|s| s.ssrc == ssrc) {
+                        self.last_sr = Some((rtcp::ntp_middle(info.ntp_timestamp), at));
+                    }
+                    reports
+                }
+                Rtcp::ReceiverReport { reports, .. } => reports,
+                Rtcp::Bye { ssrcs } => {
+                    debug!("rtcp: remote said bye for {:08x?}", ssrcs);
+                    continue;
+                }
+                _ => continue,
+            };
+            for report in reports.into_iter().filter(|r| r.ssrc == self.ssrc) {
+                // https://www.rfc-editor.org/rfc/rfc3550 (6.4.1)
+                if report.last_sr != 0 {
+                    let rtt = rtcp::ntp_middle(rtcp::ntp_timestamp(now))
+                        .wrapping_sub(report.last_sr)
+                        .wrapping_sub(report.delay_since_last_sr);
+                    // Anything past a few seconds means the clocks are off, not the network
+                    if rtt < 1 << 18 {
+                        self.round_trip = Some(Duration::from_secs_f64(rtt as f64 / 65536.0));
+                    }
+                }
+                self.remote_report = Some(report);
+            }
+        }
+    }
+
+    // A compound packet with an SR if we've been sending, otherwise an RR
+    pub fn report(&mut self, now: SystemTime, at: Instant) -> Vec<u8> {
+        let reports: Vec<ReportBlock> = self.report_block(at).into_iter().collect();
+        let report = match self.last_sent {
+            Some((timestamp, sent_at)) => {
+                let since_sent = at.saturating_duration_since(sent_at);
+                let elapsed = (since_sent.as_secs_f64() * self.clock_rate as f64) as u32;
+                Rtcp::SenderReport {
+                    ssrc: self.ssrc,
+                    info: SenderInfo {
+                        ntp_timestamp: rtcp::ntp_timestamp(now),
+                        rtp_timestamp: timestamp.wrapping_add(elapsed),
+                        packet_count: self.packets_sent,
+                        octet_count: self.octets_sent,
+                    },
+                    reports,
+                }
+            }
+            None => Rtcp::ReceiverReport {
+                ssrc: self.ssrc,
+                reports,
+            },
+        };
+        // Every compound packet needs a CNAME
+        // https://www.rfc-editor.org/rfc/rfc3550 (6.1)
+        let sdes = Rtcp::SourceDescription {
+            ssrc: self.ssrc,
+            cname: self.cname.clone(),
+        };
+        let mut buf = report.to_bytes();
+        buf.extend(sdes.to_bytes());
+        buf
+    }
+
+    // https://www.rfc-editor.org/rfc/rfc3550 (A.3)
+    fn report_block(&mut self, at: Instant) -> Option<ReportBlock> {
+        let source = self.source.as_ref()?;
+        let expected = source.expected();
+        let received = source.received();
+
+        let expected_interval = expected.wrapping_sub(self.expected_prior);
+        let received_interval = received.wrapping_sub(self.received_prior);
+        self.expected_prior = expected;
+        self.received_prior = received;
+        let lost_interval = expected_interval as i64 - received_interval as i64;
+        let fraction_lost = if expected_interval == 0 || lost_interval <= 0 {
+            0
+        } else {
+            // Losing everything would come out as 256
+            ((lost_interval << 8) / expected_interval as i64).min(255) as u8
+        };
+
+        let (last_sr, delay_since_last_sr) = match self.last_sr {
+            Some((last_sr, got_at)) => {
+                let delay = at.saturating_duration_since(got_at).as_secs_f64() * 65536.0;
+                (last_sr, delay as u32)
+            }
+            None => (0, 0),
+        };
+
+        Some(ReportBlock {
+            ssrc: source.ssrc,
+            fraction_lost,
+            cumulative_lost: (expected as i64 - received as i64) as i32,
+            extended_max_seq: source.extended_max_seq(),
+            jitter: self.jitter,
+            last_sr,
+            delay_since_last_sr,
+        })
+    }
+
+    pub fn stats(&self) -> CallStats {
+        let to_duration = |ts: u32| Duration::from_secs_f64(ts as f64 / self.clock_rate as f64);
+        CallStats {
+            packets_sent: self.packets_sent,
+            octets_sent: self.octets_sent,
+
+            packets_received: self.source.as_ref().map_or(0, |s| s.received()),
+            packets_lost: self
+                .source
+                .as_ref()
+                .map_or(0, |s| s.expected() as i64 - s.received() as i64),
+            jitter: to_duration(self.jitter),
+            jitter_buffer: self.jitter_buffer,
+
+            remote_packets_lost: self.remote_report.as_ref().map(|r| r.cumulative_lost),
+            remote_fraction_lost: self
+                .remote_report
+                .as_ref()
+                .map(|r| r.fraction_lost as f32 / 256.0),
+            remote_jitter: self.remote_report.as_ref().map(|r| to_duration(r.jitter)),
+
+            round_trip: self.round_trip,
+        }
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+
+    const CLOCK_RATE: u32 = 8000;
+
+    #[test]
+    fn successfully_report_loss_from_received_packets() {
+        let mut reporter = Reporter::new(CLOCK_RATE);
+        let mut source = Source::new(7, 100);
+        for seq in [101, 102, 104] {
+            source.update(seq);
+        }
+        reporter.on_received(&source, 80, JitterStats::default());
+
+        let now = SystemTime::now();
+        let packets = Rtcp::parse_compound(&reporter.report(now, Instant::now())).unwrap();
+        let Rtcp::ReceiverReport { ssrc, reports } = &packets[0] else {
+            panic!("expected a receiver report, got {:?}", packets[0]);
+        };
+        assert_eq!(*ssrc, reporter.ssrc);
+        assert_eq!(reports[0].ssrc, 7);
+        assert_eq!(reports[0].cumulative_lost, 1);
+        // 1 in 5, in 256ths
+        assert_eq!(reports[0].fraction_lost, 51);
+        assert!(matches!(packets[1], Rtcp::SourceDescription { .. }));
+
+        let stats = reporter.stats();
+        assert_eq!(stats.packets_received, 4);
+        assert_eq!(stats.packets_lost, 1);
+        assert_eq!(stats.jitter, Duration::from_millis(10));
+    }
+
+    #[test]
+    fn successfully_report_total_loss() {
+        let mut reporter = Reporter::new(CLOCK_RATE);
+        let mut source = Source::new(7, 100);
+        source.update(104);
+        reporter.on_received(&source, 80, JitterStats::default());
+        // Nothing's come in since the last report
+        reporter.received_prior = source.received();
+
+        let packets =
+            Rtcp::parse_compound(&reporter.report(SystemTime::now(), Instant::now())).unwrap();
+        let Rtcp::ReceiverReport { reports, .. } = &packets[0] else {
+            panic!("expected a receiver report, got {:?}", packets[0]);
+        };
+        assert_eq!(reports[0].fraction_lost, 255);
+    }
+
+    #[test]
+    fn successfully_measure_round_trip_time() {
+        let mut reporter = Reporter::new(CLOCK_RATE);
+        reporter.on_sent(1000, 160, Instant::now());
+        let sent_at = SystemTime::now();
+        let packets = Rtcp::parse_compound(&reporter.report(sent_at, Instant::now())).unwrap();
+        let Rtcp::SenderReport { info, .. } = &packets[0] else {
+            panic!("expected a sender report, got {:?}", packets[0]);
+        };
+        assert_eq!(info.packet_count, 1);
+        assert_eq!(info.octet_count, 160);
+
+        // They held onto our SR for 50ms and it took 100ms total to get back to us
+        let reply = Rtcp::ReceiverReport {
+            ssrc: 7,
+            reports: vec![ReportBlock {
+                ssrc: reporter.ssrc,
+                fraction_lost: 0,
+                cumulative_lost: 0,
+                extended_max_seq: 0,
+                jitter: 0,
+                last_sr: rtcp::ntp_middle(info.ntp_timestamp),
+                delay_since_last_sr: 65536 / 20,
+            }],
+        };
+        let now = sent_at + Duration::from_millis(150);
+        reporter.on_rtcp(vec![reply], now, Instant::now());
+
+        let rtt = reporter.stats().round_trip.unwrap();
+        assert!(rtt.abs_diff(Duration::from_millis(100)) < Duration::from_millis(1));
+    }
+}
@@ -0,0 +1,792 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use itertools::Itertools;
+use sdp_rs::lines::attribute::Rtpmap;
+use sdp_rs::lines::common::{Addrtype, Nettype};
+use sdp_rs::lines::media::{MediaType, ProtoType};
+use sdp_rs::lines::{self, Attribute};
+use sdp_rs::{MediaDescription, SessionDescription};
+use tracing::debug;
+use vec1::Vec1;
+
+use crate::rtp::srtp::{Crypto, MasterKey, SrtpKeys};
+use crate::rtp::{cn, codec, event};
+
+// SDP offer/answer
+// https://www.rfc-editor.org/rfc/rfc3264
+const SESSION_NAME: &str = "Frandline";
+
+const DEFAULT_PTIME_MS: u32 = 20;
+const MIN_PTIME_MS: u32 = 10;
+const MAX_PTIME_MS: u32 = 140;
+
+// https://www.rfc-editor.org/rfc/rfc4568 (9.1)
+const CRYPTO_ATTRIBUTE: &str = "crypto";
+const CRYPTO_TAG: u32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    SendRecv,
+    SendOnly,
+    RecvOnly,
+    Inactive,
+}
+
+impl Direction {
+    fn new(sends: bool, recvs: bool) -> Self {
+        match (sends, recvs) {
+            (true, true) => Self::SendRecv,
+            (true, false) => Self::SendOnly,
+            (false, true) => Self::RecvOnly,
+            (false, false) => Self::Inactive,
+        }
+    }
+
+    pub fn sends(self) -> bool {
+        matches!(self, Self::SendRecv | Self::SendOnly)
+    }
+
+    pub fn recvs(self) -> bool {
+        matches!(self, Self::SendRecv | Self::RecvOnly)
+    }
+
+    // What the other side's direction means from our side of the stream
+    pub fn flip(self) -> Self {
+        Self::new(self.recvs(), self.sends())
+    }
+
+    pub fn and(self, other: Self) -> Self {
+        Self::new(self.sends() && other.sends(), self.recvs() && other.recvs())
+    }
+
+    fn from_attributes(attrs: &[Attribute]) -> Option<Self> {
+        attrs.iter().find_map(|attr| match attr {
+            Attribute::Sendrecv => Some(Self::SendRecv),
+            Attribute::Sendonly => Some(Self::SendOnly),
+            Attribute::Recvonly => Some(Self::RecvOnly),
+            Attribute::Inactive => Some(Self::Inactive),
+            // sdp-rs misspells the inactive key when parsing, so it shows up as Other
+            Attribute::Other(key, None) if key == "inactive" => Some(Self::Inactive),
+            _ => None,
+        })
+    }
+
+    fn attribute(self) -> Attribute {
+        match self {
+            Self::SendRecv => Attribute::Sendrecv,
+            Self::SendOnly => Attribute::Sendonly,
+            Self::RecvOnly => Attribute::Recvonly,
+            Self::Inactive => Attribute::Inactive,
+        }
+    }
+}
+
+// Whether the audio has to be encrypted or just should be
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SrtpPolicy {
+    Disabled,
+    // Offer keys on RTP/AVP and fall back to plain RTP if the other side ignores them
+    #[default]
+    Prefer,
+    // Offer RTP/SAVP and reject anything unencrypted
+    Require,
+}
+
+impl FromStr for SrtpPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "disabled" | "off" => Ok(Self::Disabled),
+            "prefer" => Ok(Self::Prefer),
+            "require" => Ok(Self::Require),
+            _ => Err(anyhow!("unknown srtp policy: {}", s)),
+        }
+    }
+}
+
+// The result of a negotiation, everything the RTP socket needs to talk to the other side
+#[derive(Clone, Debug, PartialEq)]
+pub struct MediaParams {
+    pub remote: SocketAddr,
+    pub payload_type: u8,
+    pub ptime: u32,
+    pub direction: Direction,
+    pub comfort_noise: bool,
+    // Payload type for DTMF events, whatever the other side called it
+    pub telephone_event: Option<u8>,
+    pub srtp: Option<SrtpKeys>,
+}
+
+#[derive(Debug)]
+pub struct Session {
+    client_ip: Ipv4Addr,
+    // Set once the RTP socket is bound
+    port: Option<u16>,
+
+    sess_id: u64,
+    sess_version: u64,
+
+    pub direction: Direction,
+
+    pub srtp_policy: SrtpPolicy,
+    // Fresh for every description we send so a new stream never reuses a keystream
+    local_key: MasterKey,
+}
+
+impl Session {
+    pub fn new(client_ip: Ipv4Addr) -> Self {
+        let sess_id = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_micros() as u64;
+        Self {
+            client_ip,
+            port: None,
+
+            sess_id,
+            sess_version: sess_id,
+
+            direction: Direction::SendRecv,
+
+            srtp_policy: SrtpPolicy::default(),
+            local_key: MasterKey::generate(),
+        }
+    }
+
+    pub fn set_port(&mut self, port: u16) {
+        self.port = Some(port);
+    }
+
+    pub fn offer(&mut self) -> Result<SessionDescription> {
+        self.local_key = MasterKey::generate();
+        let proto = match self.srtp_policy {
+            SrtpPolicy::Require => ProtoType::RtpSavp,
+            SrtpPolicy::Disabled | SrtpPolicy::Prefer => ProtoType::RtpAvp,
+        };
+        let crypto = (self.srtp_policy != SrtpPolicy::Disabled).then(|| self.crypto(CRYPTO_TAG));
+        let media = self.media_description(
+            &codec::SUPPORTED_PAYLOAD_TYPES,
+            DEFAULT_PTIME_MS,
+            true,
+            Some(event::TELEPHONE_EVENT_PAYLOAD_TYPE),
+            proto,
+            crypto,
+        )?;
+        self.sess_version += 1;
+        Ok(self.session_description(vec![media]))
+    }
+
+    // Answers an offer, or returns None if there's nothing in it we can use
+    pub fn answer(
+        &mut self,
+        offer: &SessionDescription,
+    ) -> Result<Option<(SessionDescription, MediaParams)>> {
+        self.local_key = MasterKey::generate();
+        let mut params = None;
+        let mut media = vec![];
+        // Every offered stream needs a line in the answer, in the same order
+        // https://www.rfc-editor.org/rfc/rfc3264 (6)
+        for desc in &offer.media_descriptions {
+            let remote_crypto = crypto(desc);
+            let encrypted = match (self.srtp_policy, &remote_crypto, &desc.media.proto) {
+                (SrtpPolicy::Disabled, _, ProtoType::RtpSavp) => None,
+                (SrtpPolicy::Disabled, _, _) => Some(false),
+                (_, Some(_), _) => Some(true),
+                // We can't do SAVP without keys, and won't do AVP if we need encryption
+                (SrtpPolicy::Require, None, _) | (_, None, ProtoType::RtpSavp) => None,
+                (SrtpPolicy::Prefer, None, _) => Some(false),
+            };
+            let usable = desc.media.media == MediaType::Audio
+                && matches!(desc.media.proto, ProtoType::RtpAvp | ProtoType::RtpSavp)
+                && desc.media.port != 0
+                && encrypted.is_some()
+                && params.is_none();
+            let payload_types = if usable {
+                common_payload_types(desc)
+            } else {
+                vec![]
+            };
+            if payload_types.is_empty() {
+                media.push(rejected(desc));
+                continue;
+            }
+
+            let ptime = ptime(&desc.attributes);
+            let direction = remote_direction(offer, desc)?.flip().and(self.direction);
+            let comfort_noise = has_comfort_noise(desc);
+            let telephone_event = telephone_event(desc);
+            // Answer with our key under their tag
+            // https://www.rfc-editor.org/rfc/rfc4568 (7.1.2)
+            let remote_crypto = remote_crypto.filter(|_| encrypted == Some(true));
+            let local_crypto = remote_crypto.as_ref().map(|c| self.crypto(c.tag));
+            params = Some(MediaParams {
+                remote: SocketAddr::new(connection_addr(offer, desc)?, desc.media.port),
+                payload_type: payload_types[0],
+                ptime,
+                direction,
+                comfort_noise,
+                telephone_event,
+                srtp: remote_crypto.map(|c| SrtpKeys {
+                    local: self.local_key.clone(),
+                    remote: c.key,
+                }),
+            });
+
+            let mut desc = self.media_description(
+                &payload_types,
+                ptime,
+                comfort_noise,
+                telephone_event,
+                desc.media.proto.clone(),
+                local_crypto,
+            )?;
+            set_direction(&mut desc, direction);
+            media.push(desc);
+        }
+
+        match params {
+            Some(params) => {
+                self.sess_version += 1;
+                debug!(?params, "sdp: answering offer");
+                Ok(Some((self.session_description(media), params)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn accept_answer(&self, answer: &SessionDescription) -> Result<MediaParams> {
+        let desc = answer
+            .media_descriptions
+            .iter()
+            .find(|desc| desc.media.media == MediaType::Audio && desc.media.port != 0)
+            .ok_or(anyhow!("answer rejected audio stream"))?;
+        let payload_type = common_payload_types(desc)
+            .into_iter()
+            .next()
+            .ok_or(anyhow!(
+                "answer has no codec we offered: {}",
+                desc.media.fmt
+            ))?;
+        let direction = remote_direction(answer, desc)?.flip().and(self.direction);
+
+        // They have to pick our tag, or answer in the clear to an optional offer
+        let remote_crypto = match crypto(desc) {
+            Some(c) if c.tag == CRYPTO_TAG && self.srtp_policy != SrtpPolicy::Disabled => Some(c),
+            _ if self.srtp_policy == SrtpPolicy::Require
+                || desc.media.proto == ProtoType::RtpSavp =>
+            {
+                return Err(anyhow!("answer didn't agree on srtp keys"));
+            }
+            _ => None,
+        };
+
+        let params = MediaParams {
+            remote: SocketAddr::new(connection_addr(answer, desc)?, desc.media.port),
+            payload_type,
+            ptime: ptime(&desc.attributes),
+            direction,
+            comfort_noise: has_comfort_noise(desc),
+            telephone_event: telephone_event(desc),
+            srtp: remote_crypto.map(|c| SrtpKeys {
+                local: self.local_key.clone(),
+                remote: c.key,
+            }),
+        };
+        debug!(?params, "sdp: accepted answer");
+        Ok(params)
+    }
+
+    fn media_description(
+        &self,
+        payload_types: &[u8],
+        ptime: u32,
+        comfort_noise: bool,
+        telephone_event: Option<u8>,
+        proto: ProtoType,
+        crypto: Option<Crypto>,
+    ) -> Result<MediaDescription> {
+        let port = self.port.ok_or(anyhow!("no RTP port set for session"))?;
+        let mut fmt = payload_types.to_vec();
+        let mut attributes: Vec<Attribute> = payload_types
+            .iter()
+            .filter_map(|pt| codec::from_payload_type(*pt))
+            .map(|codec| {
+                Attribute::Rtpmap(Rtpmap {
+                    payload_type: codec.payload_type().into(),
+                    encoding_name: codec.encoding_name().into(),
+                    clock_rate: codec.clock_rate() as i32,
+                    encoding_params: None,
+                })
+            })
+            .collect();
+        if comfort_noise {
+            fmt.push(cn::CN_PAYLOAD_TYPE);
+            attributes.push(Attribute::Rtpmap(Rtpmap {
+                payload_type: cn::CN_PAYLOAD_TYPE.into(),
+                encoding_name: cn::CN_ENCODING_NAME.into(),
+                clock_rate: cn::CN_CLOCK_RATE as i32,
+                encoding_params: None,
+            }));
+        }
+        if let Some(payload_type) = telephone_event {
+            fmt.push(payload_type);
+            attributes.push(Attribute::Rtpmap(Rtpmap {
+                payload_type: payload_type.into(),
+                encoding_name: event::TELEPHONE_EVENT_ENCODING_NAME.into(),
+                clock_rate: event::TELEPHONE_EVENT_CLOCK_RATE as i32,
+                encoding_params: None,
+            }));
+            // sdp-rs has an Fmtp type but no attribute for it
+            attributes.push(Attribute::Other(
+                "fmtp".into(),
+                Some(format!("{} {}", payload_type, event::TELEPHONE_EVENT_FMTP)),
+            ));
+        }
+        if let Some(crypto) = crypto {
+            attributes.push(Attribute::Other(
+                CRYPTO_ATTRIBUTE.into(),
+                Some(crypto.to_string()),
+            ));
+        }
+        attributes.push(Attribute::Ptime(ptime as f32));
+        attributes.push(Attribute::Maxptime(MAX_PTIME_MS as f32));
+        attributes.push(self.direction.attribute());
+
+        Ok(MediaDescription {
+            media: lines::Media {
+                media: MediaType::Audio,
+                port,
+                num_of_ports: None,
+                proto,
+                fmt: fmt.iter().map(|pt| pt.to_string()).join(" "),
+            },
+            info: None,
+            connections: vec![],
+            bandwidths: vec![],
+            key: None,
+            attributes,
+        })
+    }
+
+    fn crypto(&self, tag: u32) -> Crypto {
+        Crypto {
+            tag,
+            key: self.local_key.clone(),
+        }
+    }
+
+    fn session_description(&self, media: Vec<MediaDescription>) -> SessionDescription {
+        SessionDescription {
+            version: lines::Version::V0,
+            origin: lines::Origin {
+                username: "-".to_string(),
+                sess_id: self.sess_id.to_string(),
+                sess_version: self.sess_version.to_string(),
+                nettype: Nettype::In,
+                addrtype: Addrtype::Ip4,
+                unicast_address: self.client_ip.into(),
+            },
+            session_name: lines::SessionName::from(SESSION_NAME.to_string()),
+            session_info: None,
+            uri: None,
+            emails: vec![],
+            phones: vec![],
+            connection: Some(lines::Connection {
+                nettype: Nettype::In,
+                addrtype: Addrtype::Ip4,
+                connection_address: IpAddr::V4(self.client_ip).into(),
+            }),
+            bandwidths: vec![],
+            times: Vec1::new(sdp_rs::Time {
+                active: lines::Active { start: 0, stop: 0 },
+                repeat: vec![],
+                zone: None,
+            }),
+            key: None,
+            attributes: vec![],
+            media_descriptions: media,
+        }
+    }
+}
+
+pub fn parse(body: &[u8]) -> Result<SessionDescription> {
+    Ok(SessionDescription::from_str(std::str::from_utf8(body)?)?)
+}
+
+// Payload types from the m= line we can handle, in the other side's order of preference
+fn common_payload_types(desc: &MediaDescription) -> Vec<u8> {
+    desc.media
+        .fmt
+        .split_whitespace()
+        .filter_map(|fmt| fmt.parse::<u8>().ok())
+        .filter(|pt| match codec::from_payload_type(*pt) {
+            Some(codec) => desc.attributes.iter().all(|attr| match attr {
+                Attribute::Rtpmap(rtpmap) if rtpmap.payload_type == *pt as u32 => {
+                    rtpmap
+                        .encoding_name
+                        .eq_ignore_ascii_case(codec.encoding_name())
+                        && rtpmap.clock_rate as u32 == codec.clock_rate()
+                }
+                _ => true,
+            }),
+            None => false,
+        })
+        .collect()
+}
+
+fn has_comfort_noise(desc: &MediaDescription) -> bool {
+    desc.media
+        .fmt
+        .split_whitespace()
+        .any(|fmt| fmt.parse() == Ok(cn::CN_PAYLOAD_TYPE))
+        && desc.attributes.iter().all(|attr| match attr {
+            Attribute::Rtpmap(rtpmap) if rtpmap.payload_type == cn::CN_PAYLOAD_TYPE as u32 => {
+                rtpmap
+                    .encoding_name
+                    .eq_ignore_ascii_case(cn::CN_ENCODING_NAME)
+                    && rtpmap.clock_rate as u32 == cn::CN_CLOCK_RATE
+            }
+            _ => true,
+        })
+}
+
+// It's a dynamic payload type so we go by the rtpmap
+// https://www.rfc-editor.org/rfc/rfc4733 (7.1.1)
+fn telephone_event(desc: &MediaDescription) -> Option<u8> {
+    desc.attributes.iter().find_map(|attr| match attr {
+        Attribute::Rtpmap(rtpmap)
+            if rtpmap
+                .encoding_name
+                .eq_ignore_ascii_case(event::TELEPHONE_EVENT_ENCODING_NAME)
+                && rtpmap.clock_rate as u32 == event::TELEPHONE_EVENT_CLOCK_RATE =>
+        {
+            let payload_type = u8::try_from(rtpmap.payload_type).ok()?;
+            desc.media
+                .fmt
+                .split_whitespace()
+                .any(|fmt| fmt.parse() == Ok(payload_type))
+                .then_some(payload_type)
+        }
+        _ => None,
+    })
+}
+
+// The first a=crypto line with a suite we can do, skipping any we can't make sense of
+// https://www.rfc-editor.org/rfc/rfc4568 (6)
+fn crypto(desc: &MediaDescription) -> Option<Crypto> {
+    desc.attributes.iter().find_map(|attr| match attr {
+        Attribute::Other(key, Some(value)) if key == CRYPTO_ATTRIBUTE => {
+            Crypto::parse(value).ok().flatten()
+        }
+        _ => None,
+    })
+}
+
+fn ptime(attrs: &[Attribute]) -> u32 {
+    attrs
+        .iter()
+        .find_map(|attr| match attr {
+            Attribute::Ptime(ptime) => Some(*ptime as u32),
+            _ => None,
+        })
+        .map(|ptime| (ptime / MIN_PTIME_MS * MIN_PTIME_MS).clamp(MIN_PTIME_MS, MAX_PTIME_MS))
+        .unwrap_or(DEFAULT_PTIME_MS)
+}
+
+// Media-level c= lines take precedence over the session-level one
+fn connection_addr(sdp: &SessionDescription, desc: &MediaDescription) -> Result<IpAddr> {
+    desc.connections
+        .first()
+        .or(sdp.connection.as_ref())
+        .map(|conn| conn.connection_address.base)
+        .ok_or(anyhow!("connection line doesn't exist"))
+}
+
+// Older phones hold with c=0.0.0.0 instead of a direction, which means don't send them anything
+// https://www.rfc-editor.org/rfc/rfc3264 (8.4)
+fn remote_direction(sdp: &SessionDescription, desc: &MediaDescription) -> Result<Direction> {
+    let direction = Direction::from_attributes(&desc.attributes)
+        .or(Direction::from_attributes(&sdp.attributes))
+        .unwrap_or(Direction::SendRecv);
+    Ok(if connection_addr(sdp, desc)?.is_unspecified() {
+        direction.and(Direction::SendOnly)
+    } else {
+        direction
+    })
+}
+
+fn set_direction(desc: &mut MediaDescription, direction: Direction) {
+    for attr in desc.attributes.iter_mut() {
+        if Direction::from_attributes(std::slice::from_ref(attr)).is_some() {
+            *attr = direction.attribute();
+        }
+    }
+}
+
+fn rejected(desc: &MediaDescription) -> MediaDescription {
+    MediaDescription {
+        media: lines::Media {
+            port: 0,
+            ..desc.media.clone()
+        },
+        info: None,
+        connections: vec![],
+        bandwidths: vec![],
+        key: None,
+        attributes: vec![],
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+
+    const DUMMY_IP: &str = "192.168.1.1";
+    const DUMMY_PORT: u16 = 4000;
+
+    fn offer(media: &str) -> SessionDescription {
+        let sdp = format!(
+            "v=0\r\n\
+            o=- 1 1 IN IP4 10.0.0.1\r\n\
+            s=-\r\n\
+            c=IN IP4 10.0.0.1\r\n\
+            t=0 0\r\n\
+            {}\r\n",
+            media,
+        );
+        parse(sdp.as_bytes()).unwrap()
+    }
+
+    fn new_session() -> Session {
+        let mut session = Session::new(DUMMY_IP.parse().unwrap());
+        session.set_port(DUMMY_PORT);
+        session
+    }
+
+    #[test]
+    fn successfully_pick_the_offerers_preferred_codec() -> Result<()> {
+        let mut session = new_session();
+        let offer = offer(
+            "m=audio 5004 RTP/AVP 9 8 0 101\r\n\
+            a=rtpmap:9 G722/8000\r\n\
+            a=rtpmap:101 telephone-event/8000\r\n\
+            a=ptime:30",
+        );
+        let (answer, params) = session.answer(&offer)?.ok_or(anyhow!("rejected"))?;
+        assert_eq!(params.payload_type, codec::PCMA_PAYLOAD_TYPE);
+        assert_eq!(params.ptime, 30);
+        assert_eq!(params.remote, "10.0.0.1:5004".parse()?);
+        assert_eq!(params.direction, Direction::SendRecv);
+        assert_eq!(answer.media_descriptions[0].media.fmt, "8 0 101");
+        assert_eq!(answer.media_descriptions[0].media.port, DUMMY_PORT);
+        Ok(())
+    }
+
+    #[test]
+    fn successfully_reject_offer_without_common_codecs() -> Result<()> {
+        let mut session = new_session();
+        let offer = offer("m=audio 5004 RTP/AVP 9 18");
+        assert!(session.answer(&offer)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn successfully_reject_mismatched_rtpmap() -> Result<()> {
+        let mut session = new_session();
+        let offer = offer("m=audio 5004 RTP/AVP 0\r\na=rtpmap:0 opus/48000");
+        assert!(session.answer(&offer)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn successfully_answer_directions() -> Result<()> {
+        let mut session = new_session();
+        for (offered, expected) in [
+            ("sendonly", Direction::RecvOnly),
+            ("recvonly", Direction::SendOnly),
+            ("inactive", Direction::Inactive),
+            ("sendrecv", Direction::SendRecv),
+        ] {
+            let offer = offer(&format!("m=audio 5004 RTP/AVP 0\r\na={}", offered));
+            let (answer, params) = session.answer(&offer)?.ok_or(anyhow!("rejected"))?;
+            assert_eq!(params.direction, expected);
+            assert_eq!(
+                Direction::from_attributes(&answer.media_descriptions[0].attributes),
+                Some(expected)
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn successfully_reject_extra_streams_in_order() -> Result<()> {
+        let mut session = new_session();
+        let offer = offer(
+            "m=video 5006 RTP/AVP 96\r\n\
+            m=audio 5004 RTP/AVP 0\r\n\
+            c=IN IP4 10.0.0.2",
+        );
+        let (answer, params) = session.answer(&offer)?.ok_or(anyhow!("rejected"))?;
+        assert_eq!(answer.media_descriptions.len(), 2);
+        assert_eq!(answer.media_descriptions[0].media.port, 0);
+        assert_eq!(answer.media_descriptions[1].media.port, DUMMY_PORT);
+        assert_eq!(params.remote, "10.0.0.2:5004".parse()?);
+        Ok(())
+    }
+
+    #[test]
+    fn successfully_bump_version_per_description() -> Result<()> {
+        let mut session = new_session();
+        let first = session.offer()?;
+        let second = session.offer()?;
+        assert_eq!(first.origin.sess_id, second.origin.sess_id);
+        assert_ne!(first.origin.sess_version, second.origin.sess_version);
+        Ok(())
+    }
+
+    #[test]
+    fn fail_to_offer_without_a_port() {
+        let mut session = Session::new(DUMMY_IP.parse().unwrap());
+        assert!(session.offer().is_err());
+    }
+
+    #[test]
+    fn successfully_hold_with_legacy_connection_address() -> Result<()> {
+        let mut session = new_session();
+        let held = "m=audio 5004 RTP/AVP 0\r\nc=IN IP4 0.0.0.0";
+        let (answer, params) = session.answer(&offer(held))?.ok_or(anyhow!("rejected"))?;
+        assert_eq!(params.direction, Direction::RecvOnly);
+        assert_eq!(
+            Direction::from_attributes(&answer.media_descriptions[0].attributes),
+            Some(Direction::RecvOnly)
+        );
+        assert_eq!(
+            session.accept_answer(&offer(held))?.direction,
+            Direction::RecvOnly
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn successfully_accept_an_answer() -> Result<()> {
+        let session = new_session();
+        let answer = offer("m=audio 5004 RTP/AVP 0 101\r\na=recvonly");
+        let params = session.accept_answer(&answer)?;
+        assert_eq!(params.payload_type, codec::PCMU_PAYLOAD_TYPE);
+        assert_eq!(params.ptime, DEFAULT_PTIME_MS);
+        assert_eq!(params.direction, Direction::SendOnly);
+        assert!(!params.comfort_noise);
+        Ok(())
+    }
+
+    #[test]
+    fn successfully_negotiate_comfort_noise() -> Result<()> {
+        let mut session = new_session();
+        let offered = session.offer()?;
+        assert_eq!(offered.media_descriptions[0].media.fmt, "0 8 13 101");
+
+        let cn_offer = offer("m=audio 5004 RTP/AVP 8 13\r\na=rtpmap:13 CN/8000");
+        let (answer, params) = session.answer(&cn_offer)?.ok_or(anyhow!("rejected"))?;
+        assert!(params.comfort_noise);
+        assert_eq!(answer.media_descriptions[0].media.fmt, "8 13");
+
+        let wideband_offer = offer("m=audio 5004 RTP/AVP 8 13\r\na=rtpmap:13 CN/16000");
+        let (answer, params) = session
+            .answer(&wideband_offer)?
+            .ok_or(anyhow!("rejected"))?;
+        assert!(!params.comfort_noise);
+        assert_eq!(answer.media_descriptions[0].media.fmt, "8");
+        Ok(())
+    }
+
+    #[test]
+    fn successfully_negotiate_telephone_events() -> Result<()> {
+        let mut session = new_session();
+        let offer = offer(
+            "m=audio 5004 RTP/AVP 0 96\r\n\
+            a=rtpmap:96 telephone-event/8000\r\n\
+            a=fmtp:96 0-16",
+        );
+        let (answer, params) = session.answer(&offer)?.ok_or(anyhow!("rejected"))?;
+        assert_eq!(params.telephone_event, Some(96));
+        assert_eq!(answer.media_descriptions[0].media.fmt, "0 96");
+        assert!(answer.media_descriptions[0]
+            .attributes
+            .contains(&Attribute::Other("fmtp".into(), Some("96 0-15".into()))));
+        Ok(())
+    }
+
+    #[test]
+    fn successfully_negotiate_srtp() -> Result<()> {
+        let mut session = new_session();
+        let offered = session.offer()?;
+        assert_eq!(offered.media_descriptions[0].media.proto, ProtoType::RtpAvp);
+        let local = crypto(&offered.media_descriptions[0]).ok_or(anyhow!("no crypto"))?;
+
+        let remote = Crypto {
+            tag: 3,
+            key: MasterKey::generate(),
+        };
+        let srtp_offer = offer(&format!(
+            "m=audio 5004 RTP/SAVP 0\r\n\
+            a=crypto:2 AES_256_CM_HMAC_SHA1_80 inline:AAAA\r\n\
+            a=crypto:{}",
+            remote
+        ));
+        let (answer, params) = session.answer(&srtp_offer)?.ok_or(anyhow!("rejected"))?;
+        let desc = &answer.media_descriptions[0];
+        assert_eq!(desc.media.proto, ProtoType::RtpSavp);
+        let answered = crypto(desc).ok_or(anyhow!("no crypto in answer"))?;
+        assert_eq!(answered.tag, 3);
+        let keys = params.srtp.ok_or(anyhow!("no srtp"))?;
+        assert_eq!(keys.remote, remote.key);
+        assert_eq!(keys.local, answered.key);
+
+        // An answer has to come back with the key under our tag
+        session.offer()?;
+        let local = Crypto {
+            tag: local.tag,
+            key: remote.key.clone(),
+        };
+        let answer = offer(&format!("m=audio 5004 RTP/SAVP 0\r\na=crypto:{}", local));
+        let keys = session
+            .accept_answer(&answer)?
+            .srtp
+            .ok_or(anyhow!("no srtp"))?;
+        assert_eq!(keys.remote, remote.key);
+        Ok(())
+    }
+
+    #[test]
+    fn fail_to_negotiate_unencrypted_when_required() -> Result<()> {
+        let mut session = new_session();
+        session.srtp_policy = SrtpPolicy::Require;
+        let offered = session.offer()?;
+        assert_eq!(
+            offered.media_descriptions[0].media.proto,
+            ProtoType::RtpSavp
+        );
+        assert!(session.answer(&offer("m=audio 5004 RTP/AVP 0"))?.is_none());
+        assert!(session
+            .accept_answer(&offer("m=audio 5004 RTP/AVP 0"))
+            .is_err());
+
+        // And the other way round, we can't do SAVP if we won't do keys
+        session.srtp_policy = SrtpPolicy::Disabled;
+        let srtp_offer = offer(&format!(
+            "m=audio 5004 RTP/SAVP 0\r\na=crypto:{}",
+            Crypto {
+                tag: 1,
+                key: MasterKey::generate()
+            }
+        ));
+        assert!(session.answer(&srtp_offer)?.is_none());
+        let plain = session.accept_answer(&offer("m=audio 5004 RTP/AVP 0"))?;
+        assert_eq!(plain.srtp, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,65 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error, Result};
+
+use crate::sdp::SrtpPolicy;
+
+use super::transport::TransportKind;
+
+// How we send the digits dialed during a call
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DtmfMode {
+    // As telephone events in the RTP stream
+    // https://www.rfc-editor.org/rfc/rfc4733
+    #[default]
+    Rfc4733,
+    // As application/dtmf-relay bodies in SIP INFO requests
+    Info,
+}
+
+impl FromStr for DtmfMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "rfc4733" | "rfc2833" | "rtp" => Ok(Self::Rfc4733),
+            "info" | "sip-info" => Ok(Self::Info),
+            _ => Err(anyhow!("unknown dtmf mode: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Account {
+    pub username: String,
+    pub password: String,
+    pub dtmf_mode: DtmfMode,
+    pub srtp_policy: SrtpPolicy,
+    // Left to the server's DNS when unset
+    pub transport: Option<TransportKind>,
+}
+
+impl Account {
+    pub fn new(username: String, password: String) -> Self {
+        Self {
+            username,
+            password,
+            dtmf_mode: DtmfMode::default(),
+            srtp_policy: SrtpPolicy::default(),
+            transport: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+
+    #[test]
+    fn successfully_parse_dtmf_modes() -> Result<()> {
+        assert_eq!("rfc4733".parse::<DtmfMode>()?, DtmfMode::Rfc4733);
+        assert_eq!("INFO".parse::<DtmfMode>()?, DtmfMode::Info);
+        assert!("inband".parse::<DtmfMode>().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,737 @@
+use std::future::Future;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use rsip::prelude::{HeadersExt, ToTypedHeader, UntypedHeader};
+use rsip::{HostWithPort, Method, SipMessage, StatusCode};
+use tokio::net::lookup_host;
+use tokio::select;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, sleep_until};
+use tracing::{debug, info, trace, warn};
+use uuid::Uuid;
+
+use crate::asyncutil::{self, and_log_err};
+
+use super::locate::{self, Resolve};
+use super::outbound::{self, PONG_TIMEOUT};
+use super::registration::backoff;
+use super::router::{self, Destination, Routes};
+use super::transaction::{self, Action, Transactions};
+use super::transport::{self, ReadMessage, Received, TransportKind, WriteMessage};
+use super::websocket;
+use super::Dialog;
+
+const MESSAGE_CHANNEL_SIZE: usize = 64;
+
+// Whether there's a connection to the server under us right now
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flow {
+    Up,
+    Down,
+}
+
+pub struct SipConn {
+    transport: TransportKind,
+    client_ip: Ipv4Addr,
+    sip_instance_uuid: Uuid,
+
+    pub host: String,
+    pub port: u16,
+
+    pub tx_ch: mpsc::Sender<SipMessage>,
+    pub new_msg_ch: mpsc::Receiver<SipMessage>,
+
+    routes: Arc<Mutex<Routes>>,
+    flow: watch::Receiver<Flow>,
+}
+
+impl SipConn {
+    // Connects to a server we already know the transport and port of
+    pub async fn new(
+        transport: TransportKind,
+        client_ip: Ipv4Addr,
+        host: &str,
+        port: u16,
+    ) -> Result<Self> {
+        let addrs = lookup_host((host, port)).await?.collect();
+        Self::connect(transport, client_ip, host, port, addrs).await
+    }
+
+    // Finds the server for a domain in DNS, letting it pick the transport unless we have
+    // https://www.rfc-editor.org/rfc/rfc3263 (4)
+    pub async fn locate(
+        resolver: &impl Resolve,
+        transport: Option<TransportKind>,
+        client_ip: Ipv4Addr,
+        domain: &str,
+    ) -> Result<Self> {
+        let located = locate::locate(resolver, domain, transport, None).await?;
+        // Our URIs name the domain, not whichever server it sent us to
+        let port = located.transport.default_port();
+        Self::connect(located.transport, client_ip, domain, port, located.targets).await
+    }
+
+    async fn connect(
+        transport: TransportKind,
+        client_ip: Ipv4Addr,
+        host: &str,
+        port: u16,
+        addrs: Vec<SocketAddr>,
+    ) -> Result<Self> {
+        match transport {
+            TransportKind::Udp => {
+                Self::spawn(
+                    transport,
+                    client_ip,
+                    host,
+                    port,
+                    addrs,
+                    |_, addr| async move { transport::udp(addr).await },
+                )
+                .await
+            }
+            TransportKind::Tcp => {
+                Self::spawn(
+                    transport,
+                    client_ip,
+                    host,
+                    port,
+                    addrs,
+                    |_, addr| async move { transport::tcp(addr).await },
+                )
+                .await
+            }
+            TransportKind::Tls => {
+                Self::spawn(
+                    transport,
+                    client_ip,
+                    host,
+                    port,
+                    addrs,
+                    |host, addr| async move { transport::tls(&host, addr).await },
+                )
+                .await
+            }
+            TransportKind::Ws => {
+                Self::spawn(
+                    transport,
+                    client_ip,
+                    host,
+                    port,
+                    addrs,
+                    |host, addr| async move { websocket::ws(&host, addr).await },
+                )
+                .await
+            }
+            TransportKind::Wss => {
+                Self::spawn(
+                    transport,
+                    client_ip,
+                    host,
+                    port,
+                    addrs,
+                    |host, addr| async move { websocket::wss(&host, addr).await },
+                )
+                .await
+            }
+        }
+    }
+
+    // The first connection has to work, after that we keep trying for as long as we're around
+    async fn spawn<R, W, F, Fut>(
+        transport: TransportKind,
+        client_ip: Ipv4Addr,
+        host: &str,
+        port: u16,
+        addrs: Vec<SocketAddr>,
+        connect: F,
+    ) -> Result<Self>
+    where
+        R: ReadMessage,
+        W: WriteMessage,
+        F: Fn(String, SocketAddr) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<(R, W)>> + Send + 'static,
+    {
+        let sip_instance_uuid = Uuid::new_v4();
+
+        let routes = Arc::new(Mutex::new(Routes::default()));
+
+        let (send_send_ch, mut send_recv_ch) = mpsc::channel(MESSAGE_CHANNEL_SIZE);
+        let (new_msg_send_ch, new_msg_recv_ch) = mpsc::channel(MESSAGE_CHANNEL_SIZE);
+        let (flow_send_ch, flow_recv_ch) = watch::channel(Flow::Up);
+
+        let conn = SipConn {
+            transport,
+            client_ip,
+            sip_instance_uuid: sip_instance_uuid.clone(),
+
+            host: String::from(host),
+            port,
+
+            tx_ch: send_send_ch.clone(),
+            new_msg_ch: new_msg_recv_ch,
+
+            routes: routes.clone(),
+            flow: flow_recv_ch,
+        };
+
+        let (wire_send_ch, mut wire_recv_ch) = mpsc::channel(MESSAGE_CHANNEL_SIZE);
+        let mut link = Link {
+            transport,
+            connect,
+            host: String::from(host),
+            addrs,
+            reader: None,
+            writer: None,
+            generation: 0,
+            failures: 0,
+            reconnecting: None,
+            flow_timer: None,
+            next_ping: None,
+            pong_deadline: None,
+            wire_send_ch,
+            flow: flow_send_ch,
+        };
+        let (reader, writer) = connect_any(link.attempts()).await?;
+        link.up(reader, writer);
+
+        // Everything in and out goes through the transaction layer, which decides what
+        // actually hits the wire and what gets handed on to dialogs
+        tokio::spawn(and_log_err("sip transactions", async move {
+            // Only UDP needs the transaction layer to retransmit for it
+            let mut transactions = Transactions::new(transport.reliable());
+            loop {
+                let deadline = transactions.next_deadline();
+                let (next_ping, pong_deadline) = (link.next_ping, link.pong_deadline);
+                let actions = select! {
+                    msg = send_recv_ch.recv() => {
+                        // TODO: Maybe retry on this error
+                        let msg = msg.ok_or(anyhow!("got a none on the send"))?;
+                        transactions.on_send(msg, Instant::now())?
+                    },
+                    Some((generation, msg)) = wire_recv_ch.recv() => {
+                        // Whatever's left over from a connection we've already given up on
+                        if generation != link.generation {
+                            continue;
+                        }
+                        let msg = match msg {
+                            Ok(Received::Message(msg)) => *msg,
+                            Ok(Received::Pong) => {
+                                link.pong_deadline = None;
+                                continue;
+                            }
+                            Err(e) => {
+                                link.lost(e);
+                                continue;
+                            }
+                        };
+                        match transactions.on_recv(msg, Instant::now()) {
+                            Ok(actions) => actions,
+                            Err(e) => {
+                                warn!("sip: dropping message we couldn't match: {}", e);
+                                continue;
+                            }
+                        }
+                    },
+                    connected = asyncutil::join(&mut link.reconnecting) => {
+                        match connected.and_then(|connected| connected) {
+                            Ok((reader, writer)) => link.up(reader, writer),
+                            Err(e) => link.lost(e),
+                        }
+                        continue;
+                    },
+                    _ = sleep_until(deadline.unwrap_or_else(Instant::now).into()), if deadline.is_some() => {
+                        transactions.on_tick(Instant::now())
+                    },
+                    _ = asyncutil::sleep_until(next_ping.map(Into::into)) => {
+                        link.ping().await;
+                        continue;
+                    },
+                    _ = asyncutil::sleep_until(pong_deadline.map(Into::into)) => {
+                        link.lost(anyhow!("no pong in {:?}", PONG_TIMEOUT));
+                        continue;
+                    },
+                };
+
+                for action in actions {
+                    match action {
+                        Action::Transmit(msg) => link.transmit(&msg).await,
+                        Action::Deliver(msg) => {
+                            if let Some(flow_timer) = outbound::flow_timer(&msg) {
+                                link.keep_alive(flow_timer);
+                            }
+                            let destination = routes.lock().unwrap().destination(&msg);
+                            let rejection = match destination {
+                                // A dialog that's stopped reading can't hold up every other one
+                                Ok(Destination::Dialog(rx_send_ch)) => {
+                                    match rx_send_ch.try_send(msg) {
+                                        Ok(()) => None,
+                                        Err(TrySendError::Full(msg)) => {
+                                            warn!("sip: dialog's too far behind, turning a message away");
+                                            Some((msg, StatusCode::ServiceUnavailable))
+                                        }
+                                        Err(TrySendError::Closed(_)) => {
+                                            warn!(
+                                                "sip: dialog went away before its message arrived"
+                                            );
+                                            None
+                                        }
+                                    }
+                                }
+                                Ok(Destination::New) => {
+                                    debug!(
+                                        user=%msg.to_header().ok().and_then(|h| h.typed().ok()).and_then(|to| to.uri.auth).map(|auth| auth.user).unwrap_or_default(),
+                                        call_id=%call_id(&msg),
+                                        msg=%msg.clone().to_string().lines().next().unwrap_or("empty"),
+                                        "SIP New Recv",
+                                    );
+                                    // Whoever takes new requests might be busy with something else,
+                                    // and everything else here has to keep going meanwhile
+                                    match new_msg_send_ch.try_send(msg) {
+                                        Ok(()) => None,
+                                        Err(TrySendError::Full(msg)) => {
+                                            warn!("sip: too many new requests waiting, turning one away");
+                                            let busy = match &msg {
+                                                SipMessage::Request(req)
+                                                    if req.method == Method::Invite =>
+                                                {
+                                                    StatusCode::BusyHere
+                                                }
+                                                _ => StatusCode::ServiceUnavailable,
+                                            };
+                                            Some((msg, busy))
+                                        }
+                                        Err(TrySendError::Closed(msg)) => {
+                                            warn!("sip: nobody's taking new requests, turning one away");
+                                            Some((msg, StatusCode::ServiceUnavailable))
+                                        }
+                                    }
+                                }
+                                // https://www.rfc-editor.org/rfc/rfc3261 (12.2.2)
+                                Ok(Destination::Unknown) => {
+                                    Some((msg, StatusCode::CallTransactionDoesNotExist))
+                                }
+                                Err(e) => {
+                                    warn!("sip: couldn't route message: {}", e);
+                                    None
+                                }
+                            };
+                            match rejection {
+                                Some((SipMessage::Request(req), status_code))
+                                    if req.method != Method::Ack =>
+                                {
+                                    debug!(
+                                        "sip: rejecting {} {} with {}",
+                                        req.method,
+                                        call_id(&req),
+                                        status_code
+                                    );
+                                    let resp = transaction::response_to(&req, status_code);
+                                    for action in
+                                        transactions.on_send(resp.into(), Instant::now())?
+                                    {
+                                        if let Action::Transmit(msg) = action {
+                                            link.transmit(&msg).await
+                                        }
+                                    }
+                                }
+                                Some((msg, _)) => debug!(
+                                    msg=%msg.to_string().lines().next().unwrap_or("empty"),
+                                    "sip: dropping message nobody's taking",
+                                ),
+                                None => {}
+                            }
+                        }
+                    }
+                }
+            }
+        }));
+
+        Ok(conn)
+    }
+
+    pub async fn dialog(&self, username: String) -> Dialog {
+        let host_with_port = HostWithPort::from((self.host.clone(), self.port));
+
+        let (rx_send_ch, rx_recv_ch) = mpsc::channel(MESSAGE_CHANNEL_SIZE);
+        let mut dialog = Dialog::new(
+            host_with_port,
+            self.transport,
+            self.client_ip,
+            self.sip_instance_uuid,
+            username,
+            self.tx_ch.clone(),
+            rx_recv_ch,
+        );
+        let route = router::register(
+            &self.routes,
+            dialog.call_id.value().to_string(),
+            dialog.local_tag(),
+            None,
+            rx_send_ch,
+        );
+        dialog.set_route(route);
+        dialog
+    }
+
+    pub async fn dialog_from_req(&self, msg: &SipMessage) -> Result<Dialog> {
+        let (rx_send_ch, rx_recv_ch) = mpsc::channel(MESSAGE_CHANNEL_SIZE);
+        let mut dialog = Dialog::from_request(
+            (self.host.clone(), self.port).into(),
+            self.transport,
+            self.client_ip,
+            self.sip_instance_uuid,
+            self.tx_ch.clone(),
+            rx_recv_ch,
+            &msg,
+        )?;
+        let route = router::register(
+            &self.routes,
+            dialog.call_id.value().to_string(),
+            dialog.local_tag(),
+            dialog.remote_tag(),
+            rx_send_ch,
+        );
+        dialog.set_route(route);
+        Ok(dialog)
+    }
+
+    pub fn flow(&self) -> watch::Receiver<Flow> {
+        self.flow.clone()
+    }
+}
+
+fn call_id(msg: &impl HeadersExt) -> String {
+    msg.call_id_header()
+        .map(|h| h.value().to_string())
+        .unwrap_or_default()
+}
+
+// The connection under the transaction layer, and getting a new one whenever it goes. Transactions
+// outlive it, so whatever was in flight times out like it would have anyway
+// https://www.rfc-editor.org/rfc/rfc5626 (4.5)
+struct Link<R, W, F> {
+    transport: TransportKind,
+    connect: F,
+    host: String,
+    // Everywhere the server can be reached, in the order to try it
+    addrs: Vec<SocketAddr>,
+
+    reader: Option<JoinHandle<()>>,
+    writer: Option<W>,
+    // Which connection the reader's messages came off of
+    generation: u32,
+
+    failures: u32,
+    reconnecting: Option<JoinHandle<Result<(R, W)>>>,
+
+    // Keepalives, once the registrar's said it does outbound and how often it wants them
+    // https://www.rfc-editor.org/rfc/rfc5626 (4.4.1)
+    flow_timer: Option<Duration>,
+    next_ping: Option<Instant>,
+    pong_deadline: Option<Instant>,
+
+    wire_send_ch: mpsc::Sender<(u32, Result<Received>)>,
+    flow: watch::Sender<Flow>,
+}
+
+impl<R, W, F, Fut> Link<R, W, F>
+where
+    R: ReadMessage,
+    W: WriteMessage,
+    F: Fn(String, SocketAddr) -> Fut,
+    Fut: Future<Output = Result<(R, W)>> + Send + 'static,
+{
+    fn attempts(&self) -> Vec<(SocketAddr, Fut)> {
+        self.addrs
+            .iter()
+            .map(|addr| (*addr, (self.connect)(self.host.clone(), *addr)))
+            .collect()
+    }
+
+    fn up(&mut self, mut reader: R, writer: W) {
+        self.generation += 1;
+        let generation = self.generation;
+        let wire_send_ch = self.wire_send_ch.clone();
+        self.reader = Some(tokio::spawn(async move {
+            loop {
+                let msg = reader.read_message().await;
+                let lost = msg.is_err();
+                if wire_send_ch.send((generation, msg)).await.is_err() || lost {
+                    return;
+                }
+            }
+        }));
+        self.writer = Some(writer);
+        // The registrar gets asked again on a new flow, so wait to hear whether it still wants
+        // keepalives
+        self.flow_timer = None;
+        self.next_ping = None;
+        self.pong_deadline = None;
+        if self.failures > 0 {
+            info!("sip: reconnected to {}", self.host);
+        }
+        self.failures = 0;
+        self.flow.send_replace(Flow::Up);
+    }
+
+    fn lost(&mut self, e: anyhow::Error) {
+        if let Some(reader) = self.reader.take() {
+            reader.abort();
+        }
+        self.writer = None;
+        self.next_ping = None;
+        self.pong_deadline = None;
+        self.failures += 1;
+        let wait = backoff(self.failures);
+        warn!(
+            "sip: lost the connection to {}, retrying in {:?}: {}",
+            self.host, wait, e
+        );
+        if *self.flow.borrow() == Flow::Up {
+            self.flow.send_replace(Flow::Down);
+        }
+        let attempts = self.attempts();
+        self.reconnecting = Some(tokio::spawn(async move {
+            sleep(wait).await;
+            connect_any(attempts).await
+        }));
+    }
+
+    // Without a connection there's nowhere to send it, and anything that wanted an answer will
+    // time out waiting
+    async fn transmit(&mut self, msg: &SipMessage) {
+        let Some(writer) = &mut self.writer else {
+            debug!(
+                msg=%msg.to_string().lines().next().unwrap_or("empty"),
+                "sip: no connection, dropping message",
+            );
+            return;
+        };
+        if let Err(e) = writer.write_message(msg).await {
+            self.lost(e);
+        }
+    }
+
+    // Only connections get CRLF keepalives. Every re-registration says how often again, but
+    // there's no need to move a ping that's already coming
+    fn keep_alive(&mut self, flow_timer: Duration) {
+        if !self.transport.reliable() || self.writer.is_none() {
+            return;
+        }
+        if self.flow_timer.is_none() {
+            debug!("sip: keeping the flow alive every {:?}", flow_timer);
+            self.next_ping = Some(Instant::now() + outbound::next_keepalive(flow_timer));
+        }
+        self.flow_timer = Some(flow_timer);
+    }
+
+    async fn ping(&mut self) {
+        let (Some(writer), Some(flow_timer)) = (&mut self.writer, self.flow_timer) else {
+            self.next_ping = None;
+            return;
+        };
+        trace!("sip: ping");
+        if let Err(e) = writer.ping().await {
+            self.lost(e);
+            return;
+        }
+        let now = Instant::now();
+        self.next_ping = Some(now + outbound::next_keepalive(flow_timer));
+        if self.pong_deadline.is_none() {
+            self.pong_deadline = Some(now + PONG_TIMEOUT);
+        }
+    }
+}
+
+// Each of the server's addresses in turn until one of them takes
+// https://www.rfc-editor.org/rfc/rfc3263 (4.3)
+async fn connect_any<R, W, Fut>(attempts: Vec<(SocketAddr, Fut)>) -> Result<(R, W)>
+where
+    Fut: Future<Output = Result<(R, W)>>,
+{
+    let mut last = anyhow!("nowhere to connect to");
+    for (addr, attempt) in attempts {
+        match attempt.await {
+            Ok(connected) => return Ok(connected),
+            Err(e) => {
+                warn!("sip: couldn't connect to {}: {}", addr, e);
+                last = e;
+            }
+        }
+    }
+    Err(last)
+}
+
+impl<R, W, F> Drop for Link<R, W, F> {
+    fn drop(&mut self) {
+        if let Some(reader) = &self.reader {
+            reader.abort();
+        }
+        if let Some(reconnecting) = &self.reconnecting {
+            reconnecting.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use std::net::IpAddr;
+
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    use super::locate::{Naptr, Srv};
+    use super::*;
+
+    // A domain whose best SRV target has nobody listening
+    struct FailingOver {
+        dead: u16,
+        live: u16,
+    }
+
+    impl Resolve for FailingOver {
+        async fn naptr(&self, _: &str) -> Result<Vec<Naptr>> {
+            Ok(vec![])
+        }
+
+        async fn srv(&self, name: &str) -> Result<Vec<Srv>> {
+            if name != "_sip._tcp.pbx.frandline.com" {
+                return Ok(vec![]);
+            }
+            Ok([(10, self.dead), (20, self.live)]
+                .into_iter()
+                .map(|(priority, port)| Srv {
+                    priority,
+                    weight: 0,
+                    port,
+                    target: "localhost.frandline.com".into(),
+                })
+                .collect())
+        }
+
+        async fn ip(&self, _: &str) -> Result<Vec<IpAddr>> {
+            Ok(vec![IpAddr::V4(Ipv4Addr::LOCALHOST)])
+        }
+    }
+
+    #[tokio::test]
+    async fn successfully_notice_the_connection_going_down() -> Result<()> {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+        let port = listener.local_addr()?.port();
+        let server = tokio::spawn(async move { listener.accept().await });
+
+        let conn = SipConn::new(TransportKind::Tcp, Ipv4Addr::LOCALHOST, "127.0.0.1", port).await?;
+        let mut flow = conn.flow();
+        assert_eq!(*flow.borrow_and_update(), Flow::Up);
+
+        drop(server.await??);
+        flow.changed().await?;
+        assert_eq!(*flow.borrow(), Flow::Down);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn successfully_ping_once_the_registrar_does_outbound() -> Result<()> {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+        let port = listener.local_addr()?.port();
+        let server = tokio::spawn(async move {
+            let (recv_stream, mut send_stream) = listener.accept().await?.0.into_split();
+            let mut reader = transport::StreamReader::new(recv_stream);
+            let Received::Message(msg) = reader.read_message().await? else {
+                return Err(anyhow!("expected a REGISTER"));
+            };
+            let register: rsip::Request = (*msg).try_into()?;
+            let mut ok = transaction::response_to(&register, StatusCode::OK);
+            ok.headers
+                .push(rsip::headers::Require::new("outbound").into());
+            ok.headers
+                .push(rsip::Header::Other("Flow-Timer".into(), "1".into()));
+            send_stream.write_all(ok.to_string().as_bytes()).await?;
+            reader.read_message().await
+        });
+
+        let conn = SipConn::new(TransportKind::Tcp, Ipv4Addr::LOCALHOST, "127.0.0.1", port).await?;
+        let mut dialog = conn.dialog("1102".into()).await;
+        let register = dialog.new_request(Method::Register, vec![]);
+        dialog.send(register).await?;
+        assert_eq!(server.await??, Received::Pong);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn successfully_fail_over_to_the_next_srv_target() -> Result<()> {
+        let dead = TcpListener::bind(("127.0.0.1", 0)).await?;
+        let live = TcpListener::bind(("127.0.0.1", 0)).await?;
+        let resolver = FailingOver {
+            dead: dead.local_addr()?.port(),
+            live: live.local_addr()?.port(),
+        };
+        drop(dead);
+        let server = tokio::spawn(async move { live.accept().await });
+
+        let conn =
+            SipConn::locate(&resolver, None, Ipv4Addr::LOCALHOST, "pbx.frandline.com").await?;
+        assert_eq!(conn.transport, TransportKind::Tcp);
+        server.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn successfully_turn_away_requests_nobody_is_taking() -> Result<()> {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+        let port = listener.local_addr()?.port();
+        let server = tokio::spawn(async move {
+            let (recv_stream, mut send_stream) = listener.accept().await?.0.into_split();
+            for n in 0..=MESSAGE_CHANNEL_SIZE {
+                let options = format!(
+                    "OPTIONS sip:1102@127.0.0.1 SIP/2.0\r\n\
+                    Via: SIP/2.0/TCP 127.0.0.1:5060;branch=z9hG4bKopt{}\r\n\
+                    From: <sip:pbx@127.0.0.1>;tag=pbx\r\n\
+                    To: <sip:1102@127.0.0.1>\r\n\
+                    Call-ID: options-{}\r\n\
+                    CSeq: 1 OPTIONS\r\n\
+                    Content-Length: 0\r\n\r\n",
+                    n, n,
+                );
+                send_stream.write_all(options.as_bytes()).await?;
+            }
+            transport::StreamReader::new(recv_stream)
+                .read_message()
+                .await
+        });
+
+        let _conn =
+            SipConn::new(TransportKind::Tcp, Ipv4Addr::LOCALHOST, "127.0.0.1", port).await?;
+        let Received::Message(msg) =
+            tokio::time::timeout(Duration::from_secs(5), server).await???
+        else {
+            return Err(anyhow!("expected a response"));
+        };
+        let resp: rsip::Response = (*msg).try_into()?;
+        assert_eq!(resp.status_code, StatusCode::ServiceUnavailable);
+        assert_eq!(
+            resp.call_id_header()?.value(),
+            format!("options-{}", MESSAGE_CHANNEL_SIZE)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fail_to_connect_when_nobody_is_listening() -> Result<()> {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+        let port = listener.local_addr()?.port();
+        drop(listener);
+        assert!(
+            SipConn::new(TransportKind::Tcp, Ipv4Addr::LOCALHOST, "127.0.0.1", port)
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+}
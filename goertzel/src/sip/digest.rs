@@ -0,0 +1,420 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use anyhow::{anyhow, Result};
+use md5::{Digest, Md5};
+use rand::distr::{Alphanumeric, SampleString};
+use rsip::prelude::*;
+use rsip::{headers, Header, Request, Response, StatusCode};
+use sha2::Sha256;
+use tracing::debug;
+
+// https://www.rfc-editor.org/rfc/rfc8760 (2)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    Md5,
+    Md5Sess,
+    Sha256,
+    Sha256Sess,
+}
+
+impl Algorithm {
+    fn parse(s: &str) -> Option<Self> {
+        match s.to_ascii_uppercase().as_str() {
+            "MD5" => Some(Self::Md5),
+            "MD5-SESS" => Some(Self::Md5Sess),
+            "SHA-256" => Some(Self::Sha256),
+            "SHA-256-SESS" => Some(Self::Sha256Sess),
+            _ => None,
+        }
+    }
+
+    fn hash(&self, s: &str) -> String {
+        match self {
+            Self::Md5 | Self::Md5Sess => format!("{:x}", Md5::digest(s)),
+            Self::Sha256 | Self::Sha256Sess => format!("{:x}", Sha256::digest(s)),
+        }
+    }
+
+    fn hash_bytes(&self, b: &[u8]) -> String {
+        match self {
+            Self::Md5 | Self::Md5Sess => format!("{:x}", Md5::digest(b)),
+            Self::Sha256 | Self::Sha256Sess => format!("{:x}", Sha256::digest(b)),
+        }
+    }
+
+    fn is_sess(&self) -> bool {
+        matches!(self, Self::Md5Sess | Self::Sha256Sess)
+    }
+
+    // When a server offers more than one, take the strongest
+    // https://www.rfc-editor.org/rfc/rfc8760 (2.4)
+    fn strength(&self) -> u8 {
+        match self {
+            Self::Md5 | Self::Md5Sess => 0,
+            Self::Sha256 | Self::Sha256Sess => 1,
+        }
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Md5 => write!(f, "MD5"),
+            Self::Md5Sess => write!(f, "MD5-sess"),
+            Self::Sha256 => write!(f, "SHA-256"),
+            Self::Sha256Sess => write!(f, "SHA-256-sess"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Qop {
+    Auth,
+    AuthInt,
+}
+
+impl fmt::Display for Qop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Auth => write!(f, "auth"),
+            Self::AuthInt => write!(f, "auth-int"),
+        }
+    }
+}
+
+// 401s come from the registrar or UAS, 407s from proxies along the way, and each wants its
+// answer in its own header
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Challenger {
+    Server,
+    Proxy,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Challenge {
+    challenger: Challenger,
+    pub realm: String,
+    pub nonce: String,
+    pub opaque: Option<String>,
+    pub algorithm: Algorithm,
+    pub qop: Option<Qop>,
+    pub stale: bool,
+}
+
+impl Challenge {
+    // None for anything that isn't a digest we know how to answer
+    // https://www.rfc-editor.org/rfc/rfc3261 (25.1)
+    fn parse(value: &str, challenger: Challenger) -> Result<Option<Self>> {
+        let (scheme, rest) = value.trim().split_once(' ').unwrap_or((value.trim(), ""));
+        if !scheme.eq_ignore_ascii_case("digest") {
+            return Ok(None);
+        }
+        let params = auth_params(rest);
+        let param = |name: &str| params.get(name).cloned();
+
+        let algorithm = match param("algorithm") {
+            Some(algorithm) => match Algorithm::parse(&algorithm) {
+                Some(algorithm) => algorithm,
+                None => return Ok(None),
+            },
+            None => Algorithm::Md5,
+        };
+        // Plain auth if we get the choice, we'd have to sign every body otherwise
+        let qop = param("qop").and_then(|qops| {
+            let qops: Vec<&str> = qops.split(',').map(str::trim).collect();
+            if qops.iter().any(|qop| qop.eq_ignore_ascii_case("auth")) {
+                Some(Qop::Auth)
+            } else if qops.iter().any(|qop| qop.eq_ignore_ascii_case("auth-int")) {
+                Some(Qop::AuthInt)
+            } else {
+                None
+            }
+        });
+        Ok(Some(Self {
+            challenger,
+            realm: param("realm").ok_or(anyhow!("challenge missing realm"))?,
+            nonce: param("nonce").ok_or(anyhow!("challenge missing nonce"))?,
+            opaque: param("opaque"),
+            algorithm,
+            qop,
+            stale: param("stale").is_some_and(|stale| stale.eq_ignore_ascii_case("true")),
+        }))
+    }
+
+    // https://www.rfc-editor.org/rfc/rfc7616 (3.4.2)
+    fn ha1(&self, username: &str, password: &str, cnonce: &str) -> String {
+        let ha1 = self
+            .algorithm
+            .hash(&format!("{}:{}:{}", username, self.realm, password));
+        if self.algorithm.is_sess() {
+            self.algorithm
+                .hash(&format!("{}:{}:{}", ha1, self.nonce, cnonce))
+        } else {
+            ha1
+        }
+    }
+
+    // https://www.rfc-editor.org/rfc/rfc7616 (3.4.3)
+    fn ha2(&self, method: &str, uri: &str, body: &[u8]) -> String {
+        match self.qop {
+            Some(Qop::AuthInt) => self.algorithm.hash(&format!(
+                "{}:{}:{}",
+                method,
+                uri,
+                self.algorithm.hash_bytes(body)
+            )),
+            _ => self.algorithm.hash(&format!("{}:{}", method, uri)),
+        }
+    }
+
+    // https://www.rfc-editor.org/rfc/rfc7616 (3.4.1)
+    fn response(&self, ha1: &str, ha2: &str, nc: u32, cnonce: &str) -> String {
+        match self.qop {
+            Some(qop) => self.algorithm.hash(&format!(
+                "{}:{}:{:08x}:{}:{}:{}",
+                ha1, self.nonce, nc, cnonce, qop, ha2
+            )),
+            None => self
+                .algorithm
+                .hash(&format!("{}:{}:{}", ha1, self.nonce, ha2)),
+        }
+    }
+}
+
+// Remembers the challenges we've been given so later requests can answer them up front, counting
+// how many times we've used each nonce
+#[derive(Debug, Default)]
+pub struct DigestAuth {
+    challenges: HashMap<String, (Challenge, u32)>,
+}
+
+impl DigestAuth {
+    // Takes in the challenges from a 401/407. False means retrying won't help, either nothing
+    // in there is answerable or it's the same nonce we just answered, so it's our credentials
+    pub fn on_challenge(&mut self, resp: &Response) -> Result<bool> {
+        let challenger = match resp.status_code {
+            StatusCode::Unauthorized => Challenger::Server,
+            StatusCode::ProxyAuthenticationRequired => Challenger::Proxy,
+            _ => return Ok(false),
+        };
+        let mut offered: HashMap<String, Challenge> = HashMap::new();
+        for header in resp.headers.iter() {
+            let value = match (challenger, header) {
+                (Challenger::Server, Header::WwwAuthenticate(h)) => h.value(),
+                (Challenger::Proxy, Header::ProxyAuthenticate(h)) => h.value(),
+                _ => continue,
+            };
+            let Some(challenge) = Challenge::parse(value, challenger)? else {
+                continue;
+            };
+            match offered.get(&challenge.realm) {
+                Some(best) if best.algorithm.strength() >= challenge.algorithm.strength() => {}
+                _ => {
+                    offered.insert(challenge.realm.clone(), challenge);
+                }
+            }
+        }
+
+        let mut retry = false;
+        for (realm, challenge) in offered {
+            let fresh = match self.challenges.get(&realm) {
+                Some((prev, _)) => challenge.stale || prev.nonce != challenge.nonce,
+                None => true,
+            };
+            debug!(
+                "sip: {} challenge for {} (stale={}, fresh={})",
+                challenge.algorithm, realm, challenge.stale, fresh
+            );
+            retry |= fresh;
+            self.challenges.insert(realm, (challenge, 0));
+        }
+        Ok(retry)
+    }
+
+    // Answers every challenge we're holding onto
+    // https://www.rfc-editor.org/rfc/rfc3261 (22.4)
+    pub fn authorize(&mut self, req: &mut Request, username: &str, password: &str) {
+        let method = req.method.to_string();
+        let uri = req.uri.to_string();
+        for (challenge, nc) in self.challenges.values_mut() {
+            *nc += 1;
+            let cnonce = Alphanumeric.sample_string(&mut rand::rng(), 16);
+            let ha1 = challenge.ha1(username, password, &cnonce);
+            let ha2 = challenge.ha2(&method, &uri, &req.body);
+            let response = challenge.response(&ha1, &ha2, *nc, &cnonce);
+
+            let mut value = format!(
+                "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", response=\"{}\", algorithm={}",
+                username, challenge.realm, challenge.nonce, uri, response, challenge.algorithm,
+            );
+            if let Some(qop) = challenge.qop {
+                value.push_str(&format!(
+                    ", qop={}, nc={:08x}, cnonce=\"{}\"",
+                    qop, nc, cnonce
+                ));
+            } else if challenge.algorithm.is_sess() {
+                // HA1 has the cnonce in it, so the server needs it even without qop
+                // https://www.rfc-editor.org/rfc/rfc2617 (3.2.2.2)
+                value.push_str(&format!(", cnonce=\"{}\"", cnonce));
+            }
+            if let Some(opaque) = &challenge.opaque {
+                value.push_str(&format!(", opaque=\"{}\"", opaque));
+            }
+            req.headers.push(match challenge.challenger {
+                Challenger::Server => headers::Authorization::new(value).into(),
+                Challenger::Proxy => headers::ProxyAuthorization::new(value).into(),
+            });
+        }
+    }
+}
+
+// Comma separated name=value pairs, where quoted values can have commas of their own
+fn auth_params(s: &str) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    let mut rest = s.trim();
+    while !rest.is_empty() {
+        let Some((name, after)) = rest.split_once('=') else {
+            break;
+        };
+        let name = name
+            .trim()
+            .trim_start_matches(',')
+            .trim()
+            .to_ascii_lowercase();
+        let after = after.trim_start();
+        let (value, after) = match after.strip_prefix('"') {
+            Some(quoted) => match quoted.split_once('"') {
+                Some((value, after)) => (value, after),
+                None => (quoted, ""),
+            },
+            None => after.split_once(',').unwrap_or((after, "")),
+        };
+        params.insert(name, value.trim().to_string());
+        rest = after.trim_start().trim_start_matches(',').trim_start();
+    }
+    params
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use crate::sip::testing;
+
+    fn challenge(status: &str, headers: &str) -> Response {
+        testing::message(
+            &format!("SIP/2.0 {}", status),
+            "abc",
+            Some("def"),
+            "1 REGISTER",
+            headers,
+        )
+    }
+
+    fn register() -> Request {
+        Request::try_from(
+            "REGISTER sips:pbx.frandline.com SIP/2.0\r\n\
+            Via: SIP/2.0/TLS 10.0.0.1:5061;branch=z9hG4bKabc\r\n\
+            From: <sips:1102@pbx.frandline.com>;tag=abc\r\n\
+            To: <sips:1102@pbx.frandline.com>\r\n\
+            Call-ID: call-1\r\n\
+            CSeq: 2 REGISTER\r\n\
+            Content-Length: 0\r\n\r\n",
+        )
+        .unwrap()
+    }
+
+    fn authorization(req: &Request) -> String {
+        req.headers
+            .iter()
+            .find_map(|h| match h {
+                Header::Authorization(h) => Some(h.to_string()),
+                Header::ProxyAuthorization(h) => Some(h.to_string()),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    // https://www.rfc-editor.org/rfc/rfc7616 (3.9.1)
+    #[test]
+    fn successfully_compute_rfc7616_responses() -> Result<()> {
+        let mut challenge = Challenge::parse(
+            "Digest realm=\"http-auth@example.org\", qop=\"auth, auth-int\", algorithm=SHA-256, \
+            nonce=\"7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v\", \
+            opaque=\"FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS\"",
+            Challenger::Server,
+        )?
+        .unwrap();
+        assert_eq!(challenge.qop, Some(Qop::Auth));
+        let cnonce = "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ";
+        let respond = |challenge: &Challenge| {
+            let ha1 = challenge.ha1("Mufasa", "Circle of Life", cnonce);
+            let ha2 = challenge.ha2("GET", "/dir/index.html", &[]);
+            challenge.response(&ha1, &ha2, 1, cnonce)
+        };
+        assert_eq!(
+            respond(&challenge),
+            "753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1"
+        );
+        challenge.algorithm = Algorithm::Md5;
+        assert_eq!(respond(&challenge), "8ca523f5e9506fed4657c9700eebdbec");
+        Ok(())
+    }
+
+    #[test]
+    fn successfully_answer_proxy_challenges_and_count_nonces() -> Result<()> {
+        let mut auth = DigestAuth::default();
+        let resp = challenge(
+            "407 Proxy Authentication Required",
+            "Proxy-Authenticate: Digest realm=\"asterisk\", nonce=\"n1\", algorithm=MD5, qop=\"auth\"\r\n\
+            Proxy-Authenticate: Digest realm=\"asterisk\", nonce=\"n1\", algorithm=SHA-256, qop=\"auth\"\r\n",
+        );
+        assert!(auth.on_challenge(&resp)?);
+
+        let mut req = register();
+        auth.authorize(&mut req, "1102", "hunter2");
+        let first = authorization(&req);
+        assert!(first.starts_with("Proxy-Authorization: Digest username=\"1102\""));
+        assert!(first.contains("algorithm=SHA-256"));
+        assert!(first.contains("uri=\"sips:pbx.frandline.com\""));
+        assert!(first.contains("nc=00000001"));
+
+        let mut req = register();
+        auth.authorize(&mut req, "1102", "hunter2");
+        assert!(authorization(&req).contains("nc=00000002"));
+        Ok(())
+    }
+
+    #[test]
+    fn fail_to_retry_the_same_nonce_unless_stale() -> Result<()> {
+        let mut auth = DigestAuth::default();
+        let resp = challenge(
+            "401 Unauthorized",
+            "WWW-Authenticate: Digest realm=\"asterisk\", nonce=\"n1\", algorithm=MD5-sess\r\n",
+        );
+        assert!(auth.on_challenge(&resp)?);
+        assert!(!auth.on_challenge(&resp)?);
+
+        let mut req = register();
+        auth.authorize(&mut req, "1102", "hunter2");
+        let answer = authorization(&req);
+        assert!(answer.contains("algorithm=MD5-sess"));
+        assert!(answer.contains("cnonce=\""));
+        assert!(!answer.contains("qop="));
+
+        let stale = challenge(
+            "401 Unauthorized",
+            "WWW-Authenticate: Digest realm=\"asterisk\", nonce=\"n1\", stale=TRUE\r\n",
+        );
+        assert!(auth.on_challenge(&stale)?);
+
+        let mut req = register();
+        auth.authorize(&mut req, "1102", "hunter2");
+        let answer = authorization(&req);
+        assert!(answer.starts_with("Authorization: Digest"));
+        assert!(answer.ends_with("algorithm=MD5"));
+        assert!(!answer.contains("qop="));
+        assert!(!answer.contains("cnonce="));
+        Ok(())
+    }
+}
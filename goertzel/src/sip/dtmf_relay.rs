@@ -0,0 +1,75 @@
+use anyhow::{anyhow, Result};
+
+use crate::dtmf::{OCTOTHORPE, SEXTILE};
+
+// DTMF carried in SIP INFO bodies, for PBXes that want digits out of band on the signalling
+// path instead of as RTP events. There's no RFC for the body, everyone follows Cisco's format:
+//   Signal=5
+//   Duration=160
+pub const DTMF_RELAY_MEDIA_TYPE: &str = "application/dtmf-relay";
+
+// In ms
+pub const DIGIT_DURATION_MS: u32 = 100;
+
+const STAR_SIGNAL: &str = "*";
+const POUND_SIGNAL: &str = "#";
+// Some gateways send the RFC 4733 event codes for * and # instead
+const STAR_EVENT_SIGNAL: &str = "10";
+const POUND_EVENT_SIGNAL: &str = "11";
+
+pub fn to_body(digit: u8, duration_ms: u32) -> Result<Vec<u8>> {
+    let signal = match digit {
+        0..=9 => digit.to_string(),
+        SEXTILE => STAR_SIGNAL.into(),
+        OCTOTHORPE => POUND_SIGNAL.into(),
+        _ => Err(anyhow!("no dtmf relay signal for digit {}", digit))?,
+    };
+    Ok(format!("Signal={}\r\nDuration={}\r\n", signal, duration_ms).into_bytes())
+}
+
+// Returns the digit in the body, Duration is optional and we don't use it anyway
+pub fn parse(body: &[u8]) -> Result<u8> {
+    let body = std::str::from_utf8(body)?;
+    let signal = body
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case("signal"))
+        .map(|(_, value)| value.trim())
+        .ok_or(anyhow!("dtmf relay body missing signal"))?;
+    match signal {
+        STAR_SIGNAL | STAR_EVENT_SIGNAL => Ok(SEXTILE),
+        POUND_SIGNAL | POUND_EVENT_SIGNAL => Ok(OCTOTHORPE),
+        s if s.len() == 1 && s.as_bytes()[0].is_ascii_digit() => Ok(s.as_bytes()[0] - b'0'),
+        s => Err(anyhow!("unsupported dtmf relay signal: {}", s)),
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+
+    #[test]
+    fn successfully_round_trip_digits() -> Result<()> {
+        assert_eq!(to_body(5, 100)?, b"Signal=5\r\nDuration=100\r\n");
+        for digit in [0, 5, 9, SEXTILE, OCTOTHORPE] {
+            assert_eq!(parse(&to_body(digit, DIGIT_DURATION_MS)?)?, digit);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn successfully_parse_loose_bodies() -> Result<()> {
+        assert_eq!(parse(b"signal= 7\nduration=250\n")?, 7);
+        assert_eq!(parse(b"Signal=10\r\nDuration=160\r\n")?, SEXTILE);
+        assert_eq!(parse(b"Signal=#")?, OCTOTHORPE);
+        Ok(())
+    }
+
+    #[test]
+    fn fail_to_relay_unknown_signals() {
+        assert!(to_body(11, 100).is_err());
+        assert!(parse(b"Duration=160\r\n").is_err());
+        assert!(parse(b"Signal=A\r\n").is_err());
+        assert!(parse(b"Signal=12\r\n").is_err());
+    }
+}
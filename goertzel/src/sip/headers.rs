@@ -0,0 +1,20 @@
+use rsip::{Header, Headers};
+
+// rsip doesn't know every header we use, so the rest come through as Other. Takes the long name
+// and the compact one if there is one
+// https://www.rfc-editor.org/rfc/rfc3261 (7.3.3)
+pub(super) fn other_header<'a>(headers: &'a Headers, names: &[&str]) -> Option<&'a str> {
+    headers.iter().find_map(|header| match header {
+        Header::Other(name, value) if names.iter().any(|n| name.eq_ignore_ascii_case(n)) => {
+            Some(value.as_str())
+        }
+        _ => None,
+    })
+}
+
+// Whether a comma separated header like Supported or Allow lists token
+pub(super) fn has_token(value: &str, token: &str) -> bool {
+    value
+        .split(',')
+        .any(|t| t.trim().eq_ignore_ascii_case(token))
+}
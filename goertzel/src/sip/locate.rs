@@ -0,0 +1,466 @@
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::{anyhow, Result};
+use itertools::Itertools;
+use rand::Rng;
+use tokio::net::UdpSocket;
+use tracing::{debug, warn};
+use trust_dns_client::client::{AsyncClient, ClientHandle};
+use trust_dns_client::rr::{DNSClass, Name, RData, RecordType};
+use trust_dns_client::udp::UdpClientStream;
+
+use super::transport::TransportKind;
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+const DNS_PORT: u16 = 53;
+
+// The NAPTR services we can speak, best first for when there's no NAPTR to say
+// https://www.rfc-editor.org/rfc/rfc3263 (4.1), https://www.rfc-editor.org/rfc/rfc7118 (9)
+const SERVICES: &[(&str, TransportKind)] = &[
+    ("SIPS+D2T", TransportKind::Tls),
+    ("SIP+D2T", TransportKind::Tcp),
+    ("SIP+D2U", TransportKind::Udp),
+    ("SIPS+D2W", TransportKind::Wss),
+    ("SIP+D2W", TransportKind::Ws),
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Naptr {
+    pub order: u16,
+    pub preference: u16,
+    pub flags: String,
+    pub service: String,
+    pub replacement: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Srv {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+// Where to find a server, in the order to try it
+#[derive(Clone, Debug, PartialEq)]
+pub struct Located {
+    pub transport: TransportKind,
+    pub targets: Vec<SocketAddr>,
+}
+
+// The lookups finding a server takes. Names that don't exist come back empty
+pub trait Resolve: Send + Sync {
+    fn naptr(&self, domain: &str) -> impl Future<Output = Result<Vec<Naptr>>> + Send;
+    fn srv(&self, name: &str) -> impl Future<Output = Result<Vec<Srv>>> + Send;
+    fn ip(&self, host: &str) -> impl Future<Output = Result<Vec<IpAddr>>> + Send;
+}
+
+// Asks a nameserver directly, since the system resolver only does addresses
+pub struct DnsResolver {
+    nameserver: SocketAddr,
+}
+
+impl DnsResolver {
+    pub fn new(nameserver: SocketAddr) -> Self {
+        DnsResolver { nameserver }
+    }
+
+    // Whatever nameserver the system's been told to use first
+    pub fn system() -> Result<Self> {
+        let conf = std::fs::read_to_string(RESOLV_CONF)?;
+        let nameserver = conf
+            .lines()
+            .find_map(|line| match line.split_whitespace().collect_vec()[..] {
+                ["nameserver", ip, ..] => ip.parse::<IpAddr>().ok(),
+                _ => None,
+            })
+            .ok_or(anyhow!("no nameserver in {}", RESOLV_CONF))?;
+        Ok(DnsResolver::new(SocketAddr::new(nameserver, DNS_PORT)))
+    }
+
+    async fn query(&self, name: &str, record_type: RecordType) -> Result<Vec<RData>> {
+        let stream = UdpClientStream::<UdpSocket>::new(self.nameserver);
+        let (mut client, bg) = AsyncClient::connect(stream).await?;
+        tokio::spawn(bg);
+        let resp = client
+            .query(Name::from_ascii(name)?, DNSClass::IN, record_type)
+            .await?;
+        Ok(resp
+            .answers()
+            .iter()
+            .map(|record| record.rdata().clone())
+            .collect())
+    }
+}
+
+impl Resolve for DnsResolver {
+    async fn naptr(&self, domain: &str) -> Result<Vec<Naptr>> {
+        Ok(self
+            .query(domain, RecordType::NAPTR)
+            .await?
+            .into_iter()
+            .filter_map(|rdata| match rdata {
+                RData::NAPTR(naptr) => Some(Naptr {
+                    order: naptr.order(),
+                    preference: naptr.preference(),
+                    flags: String::from_utf8_lossy(naptr.flags()).into(),
+                    service: String::from_utf8_lossy(naptr.services()).into(),
+                    replacement: host_name(naptr.replacement()),
+                }),
+                _ => None,
+            })
+            .collect())
+    }
+
+    async fn srv(&self, name: &str) -> Result<Vec<Srv>> {
+        Ok(self
+            .query(name, RecordType::SRV)
+            .await?
+            .into_iter()
+            .filter_map(|rdata| match rdata {
+                RData::SRV(srv) => Some(Srv {
+                    priority: srv.priority(),
+                    weight: srv.weight(),
+                    port: srv.port(),
+                    target: host_name(srv.target()),
+                }),
+                _ => None,
+            })
+            .collect())
+    }
+
+    async fn ip(&self, host: &str) -> Result<Vec<IpAddr>> {
+        let mut ips = vec![];
+        for record_type in [RecordType::A, RecordType::AAAA] {
+            ips.extend(
+                self.query(host, record_type)
+                    .await?
+                    .into_iter()
+                    .filter_map(|rdata| match rdata {
+                        RData::A(ip) => Some(IpAddr::V4(ip)),
+                        RData::AAAA(ip) => Some(IpAddr::V6(ip)),
+                        _ => None,
+                    }),
+            );
+        }
+        Ok(ips)
+    }
+}
+
+fn host_name(name: &Name) -> String {
+    name.to_ascii().trim_end_matches('.').to_string()
+}
+
+// https://www.rfc-editor.org/rfc/rfc3263 (4.1)
+fn srv_name(transport: TransportKind, domain: &str) -> Option<String> {
+    match transport {
+        TransportKind::Udp => Some(format!("_sip._udp.{}", domain)),
+        TransportKind::Tcp => Some(format!("_sip._tcp.{}", domain)),
+        TransportKind::Tls => Some(format!("_sips._tcp.{}", domain)),
+        // WebSocket servers are only ever found by their URL
+        // https://www.rfc-editor.org/rfc/rfc7118 (6)
+        TransportKind::Ws | TransportKind::Wss => None,
+    }
+}
+
+// Finds the server for a domain: NAPTR picks the transport unless we already have one, SRV the
+// hosts and ports, and A/AAAA the addresses. An explicit port or address skips straight past
+// the lookups that would have found it
+// https://www.rfc-editor.org/rfc/rfc3263 (4)
+pub async fn locate(
+    resolver: &impl Resolve,
+    domain: &str,
+    transport: Option<TransportKind>,
+    port: Option<u16>,
+) -> Result<Located> {
+    if let Ok(ip) = domain.parse::<IpAddr>() {
+        let transport = transport.unwrap_or_default();
+        let port = port.unwrap_or(transport.default_port());
+        return Ok(Located {
+            transport,
+            targets: vec![SocketAddr::new(ip, port)],
+        });
+    }
+    if let Some(port) = port {
+        let transport = transport.unwrap_or_default();
+        return addresses(resolver, domain, transport, port).await;
+    }
+
+    let candidates = match transport {
+        Some(transport) => srv_name(transport, domain)
+            .map(|name| (transport, name))
+            .into_iter()
+            .collect(),
+        None => naptr_candidates(resolver, domain).await,
+    };
+    for (transport, name) in candidates {
+        let srvs = match resolver.srv(&name).await {
+            Ok(srvs) => srvs,
+            Err(e) => {
+                warn!("sip: couldn't look up {}: {}", name, e);
+                continue;
+            }
+        };
+        let mut targets = vec![];
+        for srv in srv_order(srvs, &mut rand::rng()) {
+            match resolver.ip(&srv.target).await {
+                Ok(ips) => targets.extend(ips.into_iter().map(|ip| SocketAddr::new(ip, srv.port))),
+                Err(e) => warn!("sip: couldn't look up {}: {}", srv.target, e),
+            }
+        }
+        if !targets.is_empty() {
+            debug!(
+                "sip: found {} over {:?} at {:?}",
+                domain, transport, targets
+            );
+            return Ok(Located { transport, targets });
+        }
+    }
+
+    let transport = transport.unwrap_or_default();
+    addresses(resolver, domain, transport, transport.default_port()).await
+}
+
+// What NAPTR says to try, or every SRV we know how to use if there isn't any
+// https://www.rfc-editor.org/rfc/rfc3263 (4.1)
+async fn naptr_candidates(resolver: &impl Resolve, domain: &str) -> Vec<(TransportKind, String)> {
+    let naptrs = resolver.naptr(domain).await.unwrap_or_else(|e| {
+        warn!("sip: couldn't look up NAPTR for {}: {}", domain, e);
+        vec![]
+    });
+    let from_naptr = naptrs
+        .into_iter()
+        .filter(|naptr| naptr.flags.eq_ignore_ascii_case("s"))
+        .sorted_by_key(|naptr| (naptr.order, naptr.preference))
+        .filter_map(|naptr| {
+            SERVICES
+                .iter()
+                .find(|(service, _)| naptr.service.eq_ignore_ascii_case(service))
+                .map(|(_, transport)| (*transport, naptr.replacement))
+        })
+        .collect_vec();
+    if !from_naptr.is_empty() {
+        return from_naptr;
+    }
+    SERVICES
+        .iter()
+        .filter_map(|(_, transport)| srv_name(*transport, domain).map(|name| (*transport, name)))
+        .collect()
+}
+
+async fn addresses(
+    resolver: &impl Resolve,
+    host: &str,
+    transport: TransportKind,
+    port: u16,
+) -> Result<Located> {
+    let targets = resolver
+        .ip(host)
+        .await?
+        .into_iter()
+        .map(|ip| SocketAddr::new(ip, port))
+        .collect_vec();
+    if targets.is_empty() {
+        return Err(anyhow!("couldn't find {}", host));
+    }
+    Ok(Located { transport, targets })
+}
+
+// Lowest priority first, and within a priority a weighted shuffle so the heavier ones tend to
+// come first. Weight zero goes up front so it still gets a chance
+// https://www.rfc-editor.org/rfc/rfc2782
+fn srv_order(mut srvs: Vec<Srv>, rng: &mut impl Rng) -> Vec<Srv> {
+    srvs.sort_by_key(|srv| (srv.priority, srv.weight != 0));
+    let mut ordered = Vec::with_capacity(srvs.len());
+    for (_, group) in &srvs.into_iter().chunk_by(|srv| srv.priority) {
+        let mut group = group.collect_vec();
+        while !group.is_empty() {
+            let total: u32 = group.iter().map(|srv| u32::from(srv.weight)).sum();
+            let pick = rng.random_range(0..=total);
+            let mut running = 0;
+            let next = group
+                .iter()
+                .position(|srv| {
+                    running += u32::from(srv.weight);
+                    running >= pick
+                })
+                .unwrap_or(0);
+            ordered.push(group.remove(next));
+        }
+    }
+    ordered
+}
+
+#[cfg(test)]
+mod should {
+    use std::collections::HashMap;
+    use std::str::FromStr;
+
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use trust_dns_client::op::{Message, MessageType};
+    use trust_dns_client::rr::rdata::SRV;
+    use trust_dns_client::rr::Record;
+
+    use super::*;
+
+    // Stands in for DNS with whatever records a test needs
+    #[derive(Default)]
+    struct Records {
+        naptr: HashMap<String, Vec<Naptr>>,
+        srv: HashMap<String, Vec<Srv>>,
+        ip: HashMap<String, Vec<IpAddr>>,
+    }
+
+    impl Resolve for Records {
+        async fn naptr(&self, domain: &str) -> Result<Vec<Naptr>> {
+            Ok(self.naptr.get(domain).cloned().unwrap_or_default())
+        }
+
+        async fn srv(&self, name: &str) -> Result<Vec<Srv>> {
+            Ok(self.srv.get(name).cloned().unwrap_or_default())
+        }
+
+        async fn ip(&self, host: &str) -> Result<Vec<IpAddr>> {
+            Ok(self.ip.get(host).cloned().unwrap_or_default())
+        }
+    }
+
+    fn srv(priority: u16, weight: u16, port: u16, target: &str) -> Srv {
+        Srv {
+            priority,
+            weight,
+            port,
+            target: target.into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn successfully_pick_the_transport_from_naptr() -> Result<()> {
+        let mut records = Records::default();
+        records.naptr.insert(
+            "pbx.frandline.com".into(),
+            vec![
+                Naptr {
+                    order: 20,
+                    preference: 0,
+                    flags: "s".into(),
+                    service: "SIPS+D2T".into(),
+                    replacement: "_sips._tcp.pbx.frandline.com".into(),
+                },
+                Naptr {
+                    order: 10,
+                    preference: 0,
+                    flags: "S".into(),
+                    service: "SIP+D2U".into(),
+                    replacement: "_sip._udp.pbx.frandline.com".into(),
+                },
+            ],
+        );
+        records.srv.insert(
+            "_sip._udp.pbx.frandline.com".into(),
+            vec![
+                srv(20, 0, 5080, "backup.frandline.com"),
+                srv(10, 0, 5060, "primary.frandline.com"),
+            ],
+        );
+        records
+            .ip
+            .insert("primary.frandline.com".into(), vec!["10.0.0.1".parse()?]);
+        records
+            .ip
+            .insert("backup.frandline.com".into(), vec!["10.0.0.2".parse()?]);
+
+        let located = locate(&records, "pbx.frandline.com", None, None).await?;
+        assert_eq!(
+            located,
+            Located {
+                transport: TransportKind::Udp,
+                targets: vec!["10.0.0.1:5060".parse()?, "10.0.0.2:5080".parse()?],
+            }
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn successfully_fall_back_to_addresses_without_srv() -> Result<()> {
+        let mut records = Records::default();
+        records
+            .ip
+            .insert("pbx.frandline.com".into(), vec!["10.0.0.1".parse()?]);
+
+        let located = locate(
+            &records,
+            "pbx.frandline.com",
+            Some(TransportKind::Tcp),
+            None,
+        )
+        .await?;
+        assert_eq!(located.targets, vec!["10.0.0.1:5060".parse()?]);
+        let located = locate(&records, "pbx.frandline.com", None, Some(5070)).await?;
+        assert_eq!(located.transport, TransportKind::Tls);
+        assert_eq!(located.targets, vec!["10.0.0.1:5070".parse()?]);
+        let located = locate(&records, "10.0.0.9", Some(TransportKind::Udp), None).await?;
+        assert_eq!(located.targets, vec!["10.0.0.9:5060".parse()?]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fail_to_locate_a_domain_with_no_records() {
+        let records = Records::default();
+        assert!(locate(&records, "nowhere.invalid", None, None)
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn successfully_order_srv_by_priority_then_weight() {
+        let mut rng = StdRng::seed_from_u64(3261);
+        let mut heavy_first = 0;
+        for _ in 0..1000 {
+            let ordered = srv_order(
+                vec![
+                    srv(20, 0, 5060, "last"),
+                    srv(10, 1, 5060, "light"),
+                    srv(10, 99, 5060, "heavy"),
+                ],
+                &mut rng,
+            );
+            assert_eq!(ordered[2].target, "last");
+            if ordered[0].target == "heavy" {
+                heavy_first += 1;
+            }
+        }
+        assert!(heavy_first > 900, "heavy came first {} times", heavy_first);
+    }
+
+    #[tokio::test]
+    async fn successfully_ask_a_nameserver_for_srv() -> Result<()> {
+        let server = UdpSocket::bind(("127.0.0.1", 0)).await?;
+        let nameserver = server.local_addr()?;
+        tokio::spawn(async move {
+            let mut buf = vec![0; 512];
+            let (len, client) = server.recv_from(&mut buf).await?;
+            let query = Message::from_vec(&buf[..len])?;
+            let mut resp = Message::new();
+            resp.set_id(query.id())
+                .set_message_type(MessageType::Response)
+                .add_query(query.queries()[0].clone())
+                .add_answer(Record::from_rdata(
+                    query.queries()[0].name().clone(),
+                    60,
+                    RData::SRV(SRV::new(10, 5, 5061, Name::from_str("pbx.frandline.com.")?)),
+                ));
+            server.send_to(&resp.to_vec()?, client).await?;
+            anyhow::Ok(())
+        });
+
+        let resolver = DnsResolver::new(nameserver);
+        assert_eq!(
+            resolver.srv("_sips._tcp.frandline.com").await?,
+            vec![srv(10, 5, 5061, "pbx.frandline.com")]
+        );
+        Ok(())
+    }
+}
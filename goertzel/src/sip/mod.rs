@@ -1,4 +1,20 @@
 mod sip;
 pub use sip::*;
 
-pub mod tlssocket;
+pub mod account;
+pub mod conn;
+pub mod digest;
+pub mod dtmf_relay;
+mod headers;
+pub mod locate;
+pub mod outbound;
+pub mod prack;
+pub mod refer;
+pub mod registration;
+pub mod router;
+pub mod session_timer;
+#[cfg(test)]
+mod testing;
+pub mod transaction;
+pub mod transport;
+pub mod websocket;
@@ -0,0 +1,85 @@
+use std::time::Duration;
+
+use rand::Rng;
+use rsip::prelude::*;
+use rsip::{Header, Method, SipMessage, StatusCodeKind};
+
+use super::headers::has_token;
+
+pub const OUTBOUND_OPTION_TAG: &str = "outbound";
+
+// A double CRLF asks whether the flow's still there, and a single one answers
+// https://www.rfc-editor.org/rfc/rfc5626 (3.5.1)
+pub const PING: &[u8] = b"\r\n\r\n";
+
+// What we go with when the registrar doesn't say, and how long a pong gets to show up
+// https://www.rfc-editor.org/rfc/rfc5626 (4.4.1)
+const DEFAULT_FLOW_TIMER: Duration = Duration::from_secs(120);
+pub const PONG_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How often the registrar wants keepalives on this flow, if it does outbound at all. Only a 2xx to
+// a REGISTER with Require: outbound says it does
+// https://www.rfc-editor.org/rfc/rfc5626 (4.4.1)
+pub fn flow_timer(msg: &SipMessage) -> Option<Duration> {
+    let SipMessage::Response(resp) = msg else {
+        return None;
+    };
+    if resp.status_code.kind() != StatusCodeKind::Successful
+        || !matches!(
+            resp.cseq_header().and_then(|h| h.method()),
+            Ok(Method::Register)
+        )
+    {
+        return None;
+    }
+    let mut outbound = false;
+    let mut seconds = None;
+    for header in resp.headers.iter() {
+        match header {
+            Header::Require(h) if has_token(h.value(), OUTBOUND_OPTION_TAG) => outbound = true,
+            Header::Other(name, value) if name.eq_ignore_ascii_case("Flow-Timer") => {
+                seconds = value.trim().parse::<u64>().ok()
+            }
+            _ => {}
+        }
+    }
+    outbound.then(|| seconds.map_or(DEFAULT_FLOW_TIMER, Duration::from_secs))
+}
+
+// Somewhere between 80% and all of the flow timer, so keepalives don't line up
+// https://www.rfc-editor.org/rfc/rfc5626 (4.4.1)
+pub fn next_keepalive(flow_timer: Duration) -> Duration {
+    flow_timer.mul_f64(rand::rng().random_range(0.8..=1.0))
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use crate::sip::testing;
+
+    fn ok(cseq: &str, headers: &str) -> SipMessage {
+        testing::message("SIP/2.0 200 OK", "abc", Some("def"), cseq, headers)
+    }
+
+    #[test]
+    fn successfully_read_the_flow_timer() {
+        assert_eq!(
+            flow_timer(&ok("2 REGISTER", "Require: outbound\r\nFlow-Timer: 25\r\n")),
+            Some(Duration::from_secs(25))
+        );
+        assert_eq!(
+            flow_timer(&ok("2 REGISTER", "Require: outbound\r\n")),
+            Some(DEFAULT_FLOW_TIMER)
+        );
+        assert_eq!(flow_timer(&ok("2 REGISTER", "Flow-Timer: 25\r\n")), None);
+        assert_eq!(flow_timer(&ok("1 INVITE", "Require: outbound\r\n")), None);
+    }
+
+    #[test]
+    fn successfully_jitter_keepalives_under_the_flow_timer() {
+        for _ in 0..100 {
+            let wait = next_keepalive(Duration::from_secs(100));
+            assert!(wait >= Duration::from_secs(80) && wait <= Duration::from_secs(100));
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use anyhow::{anyhow, Result};
+use rsip::prelude::*;
+use rsip::{Header, Headers, Method, SipMessage};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+use tracing::warn;
+
+use super::headers::{has_token, other_header};
+use super::transaction::T1;
+
+pub const RELIABLE_OPTION_TAG: &str = "100rel";
+
+pub fn supports_100rel(headers: &Headers) -> bool {
+    headers.iter().any(|header| match header {
+        Header::Supported(h) => has_token(h.value(), RELIABLE_OPTION_TAG),
+        Header::Require(h) => has_token(h.value(), RELIABLE_OPTION_TAG),
+        _ => false,
+    })
+}
+
+pub fn requires_100rel(headers: &Headers) -> bool {
+    headers.iter().any(|header| match header {
+        Header::Require(h) => has_token(h.value(), RELIABLE_OPTION_TAG),
+        _ => false,
+    })
+}
+
+pub fn rseq(headers: &Headers) -> Result<Option<u32>> {
+    match other_header(headers, &["RSeq"]) {
+        Some(value) => Ok(Some(value.trim().parse()?)),
+        None => Ok(None),
+    }
+}
+
+// RAck: 776656 1 INVITE, the RSeq and CSeq of the response it's acknowledging
+// https://www.rfc-editor.org/rfc/rfc3262 (7.2)
+pub fn rack(headers: &Headers) -> Result<Option<(u32, u32, Method)>> {
+    let Some(value) = other_header(headers, &["RAck"]) else {
+        return Ok(None);
+    };
+    let mut parts = value.split_whitespace();
+    let (Some(rseq), Some(cseq), Some(method)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(anyhow!("bad RAck: {}", value));
+    };
+    Ok(Some((rseq.parse()?, cseq.parse()?, method.parse()?)))
+}
+
+pub fn rseq_header(rseq: u32) -> Header {
+    Header::Other("RSeq".into(), rseq.to_string())
+}
+
+pub fn rack_header(rseq: u32, cseq: u32, method: Method) -> Header {
+    Header::Other("RAck".into(), format!("{} {} {}", rseq, cseq, method))
+}
+
+// A reliable provisional we keep sending until it's PRACKed or we give up on it
+// https://www.rfc-editor.org/rfc/rfc3262 (3)
+#[derive(Debug)]
+pub struct Unacked {
+    pub rseq: u32,
+    pub cseq: u32,
+    handle: JoinHandle<()>,
+}
+
+impl Unacked {
+    pub fn spawn(rseq: u32, cseq: u32, resp: SipMessage, tx_ch: mpsc::Sender<SipMessage>) -> Self {
+        let handle = tokio::spawn(async move {
+            let mut interval = T1;
+            while interval < T1 * 64 {
+                sleep(interval).await;
+                if tx_ch.send(resp.clone()).await.is_err() {
+                    return;
+                }
+                interval *= 2;
+            }
+            warn!("sip: reliable provisional {} never got a PRACK", rseq);
+        });
+        Unacked { rseq, cseq, handle }
+    }
+}
+
+impl Drop for Unacked {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+
+    #[test]
+    fn successfully_read_rack() -> Result<()> {
+        let mut headers: Headers = Default::default();
+        headers.push(rack_header(776656, 1, Method::Invite));
+        headers.push(rseq_header(776656));
+        assert_eq!(rack(&headers)?, Some((776656, 1, Method::Invite)));
+        assert_eq!(rseq(&headers)?, Some(776656));
+        Ok(())
+    }
+
+    #[test]
+    fn fail_to_read_a_short_rack() {
+        let mut headers: Headers = Default::default();
+        headers.push(Header::Other("RAck".into(), "776656 1".into()));
+        assert!(rack(&headers).is_err());
+    }
+}
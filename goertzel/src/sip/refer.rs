@@ -0,0 +1,195 @@
+use std::fmt;
+
+use anyhow::{anyhow, Result};
+use rsip::{Header, Headers, StatusCode, Uri};
+
+use super::headers::other_header;
+
+pub const SIPFRAG_MEDIA_TYPE: &str = "message/sipfrag";
+pub const REFER_EVENT: &str = "refer";
+
+// rsip has 202 down as 201
+pub fn accepted() -> StatusCode {
+    StatusCode::Other(202, "Accepted".into())
+}
+
+// Which dialog an INVITE takes the place of, from the side of whoever gets it
+// https://www.rfc-editor.org/rfc/rfc3891 (6.1)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Replaces {
+    pub call_id: String,
+    pub to_tag: String,
+    pub from_tag: String,
+}
+
+impl Replaces {
+    pub fn parse(value: &str) -> Result<Self> {
+        let mut parts = value.split(';');
+        let call_id = parts.next().unwrap_or_default().trim().to_string();
+        let (mut to_tag, mut from_tag) = (None, None);
+        for param in parts {
+            match param.split_once('=') {
+                Some((name, tag)) if name.trim().eq_ignore_ascii_case("to-tag") => {
+                    to_tag = Some(tag.trim().to_string())
+                }
+                Some((name, tag)) if name.trim().eq_ignore_ascii_case("from-tag") => {
+                    from_tag = Some(tag.trim().to_string())
+                }
+                _ => {}
+            }
+        }
+        match (call_id.is_empty(), to_tag, from_tag) {
+            (false, Some(to_tag), Some(from_tag)) => Ok(Replaces {
+                call_id,
+                to_tag,
+                from_tag,
+            }),
+            _ => Err(anyhow!("bad Replaces: {}", value)),
+        }
+    }
+
+    pub fn from_headers(headers: &Headers) -> Result<Option<Self>> {
+        match other_header(headers, &["Replaces"]) {
+            Some(value) => Ok(Some(Replaces::parse(value)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+impl fmt::Display for Replaces {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{};to-tag={};from-tag={}",
+            self.call_id, self.to_tag, self.from_tag
+        )
+    }
+}
+
+pub fn replaces_header(replaces: &Replaces) -> Header {
+    Header::Other("Replaces".into(), replaces.to_string())
+}
+
+// Replaces rides along as an escaped header in the Refer-To URI
+// https://www.rfc-editor.org/rfc/rfc3891 (5)
+pub fn refer_to_header(target: &Uri, replaces: Option<&Replaces>) -> Header {
+    let value = match replaces {
+        Some(replaces) => format!("<{}?Replaces={}>", target, escape(&replaces.to_string())),
+        None => format!("<{}>", target),
+    };
+    Header::Other("Refer-To".into(), value)
+}
+
+// Refer-To, or r for short
+pub fn refer_to(headers: &Headers) -> Result<Option<(Uri, Option<Replaces>)>> {
+    let Some(value) = other_header(headers, &["Refer-To", "r"]) else {
+        return Ok(None);
+    };
+    let value = value.trim();
+    let inner = match (value.find('<'), value.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &value[start + 1..end],
+        _ => value,
+    };
+    let (uri, embedded) = match inner.split_once('?') {
+        Some((uri, embedded)) => (uri, Some(embedded)),
+        None => (inner, None),
+    };
+    let replaces = embedded
+        .into_iter()
+        .flat_map(|embedded| embedded.split('&'))
+        .find_map(|header| match header.split_once('=') {
+            Some((name, value)) if name.eq_ignore_ascii_case("Replaces") => Some(value),
+            _ => None,
+        })
+        .map(|value| Replaces::parse(&unescape(value)?))
+        .transpose()?;
+    Ok(Some((Uri::try_from(uri)?, replaces)))
+}
+
+fn escape(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            ';' | '=' | '@' | '%' | '&' | '?' | ' ' => format!("%{:02X}", c as u32),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+fn unescape(value: &str) -> Result<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        match b {
+            b'%' if tail.len() >= 2 => {
+                bytes.push(u8::from_str_radix(std::str::from_utf8(&tail[..2])?, 16)?);
+                rest = &tail[2..];
+            }
+            b => {
+                bytes.push(b);
+                rest = tail;
+            }
+        }
+    }
+    Ok(String::from_utf8(bytes)?)
+}
+
+// NOTIFYs for a REFER carry just the status line of how the new call is going
+// https://www.rfc-editor.org/rfc/rfc3515 (2.4.5)
+pub fn sipfrag(status_code: &StatusCode) -> Vec<u8> {
+    format!("SIP/2.0 {}\r\n", status_code).into_bytes()
+}
+
+pub fn sipfrag_status(body: &[u8]) -> Result<StatusCode> {
+    let body = std::str::from_utf8(body)?;
+    let code = body
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .ok_or(anyhow!("empty sipfrag"))?;
+    Ok(StatusCode::from(code.parse::<u16>()?))
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+
+    #[test]
+    fn successfully_carry_replaces_in_refer_to() -> Result<()> {
+        let replaces = Replaces {
+            call_id: "12345@10.0.0.9".into(),
+            to_tag: "far".into(),
+            from_tag: "near".into(),
+        };
+        let target = Uri::try_from("sips:1103@pbx.frandline.com")?;
+        let mut headers: Headers = Default::default();
+        headers.push(refer_to_header(&target, Some(&replaces)));
+        assert_eq!(
+            headers.iter().next().map(|h| h.to_string()),
+            Some(
+                "Refer-To: <sips:1103@pbx.frandline.com?Replaces=12345%4010.0.0.9%3Bto-tag%3Dfar%3Bfrom-tag%3Dnear>"
+                    .into()
+            )
+        );
+        assert_eq!(refer_to(&headers)?, Some((target, Some(replaces))));
+        Ok(())
+    }
+
+    #[test]
+    fn successfully_read_sipfrag_status() -> Result<()> {
+        assert_eq!(
+            sipfrag_status(&sipfrag(&StatusCode::Ringing))?,
+            StatusCode::Ringing
+        );
+        assert_eq!(
+            sipfrag_status(b"SIP/2.0 503 Service Unavailable\r\n")?.code(),
+            503
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn fail_to_parse_replaces_without_tags() {
+        assert!(Replaces::parse("12345@10.0.0.9;to-tag=far").is_err());
+    }
+}
@@ -0,0 +1,215 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use rand::Rng;
+use rsip::prelude::*;
+use rsip::{Header, Response};
+use tokio::select;
+use tokio::sync::watch;
+use tokio::time::sleep;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::asyncutil::and_log_err;
+
+use super::conn::Flow;
+use super::Dialog;
+
+// What we ask the registrar for, it's free to give us less
+pub const REGISTER_EXPIRES: u32 = 3600;
+
+// https://www.rfc-editor.org/rfc/rfc5626 (4.5)
+const RETRY_BASE: Duration = Duration::from_secs(30);
+const RETRY_MAX: Duration = Duration::from_secs(1800);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Registration {
+    Pending,
+    Registered,
+    Failed,
+}
+
+// Keeps a registration alive for as long as someone's watching it. A new connection needs
+// registering all over again
+// https://www.rfc-editor.org/rfc/rfc5626 (4.4.1)
+pub fn spawn(
+    mut dialog: Dialog,
+    password: String,
+    mut flow: watch::Receiver<Flow>,
+) -> watch::Receiver<Registration> {
+    let (state_tx, state_rx) = watch::channel(Registration::Pending);
+    tokio::spawn(and_log_err("sip registration", async move {
+        let mut failures = 0;
+        loop {
+            if *flow.borrow_and_update() == Flow::Down {
+                debug!("sip: connection's down, waiting on it to register");
+                state_tx.send_replace(Registration::Failed);
+                select! {
+                    changed = flow.changed() => if changed.is_err() {
+                        return Ok(());
+                    },
+                    _ = state_tx.closed() => return Ok(()),
+                }
+                failures = 0;
+                continue;
+            }
+            let wait = match dialog.register(password.clone()).await {
+                Ok(expires) => {
+                    debug!("sip: registered for {:?}", expires);
+                    failures = 0;
+                    state_tx.send_replace(Registration::Registered);
+                    refresh_after(expires)
+                }
+                Err(e) => {
+                    failures += 1;
+                    let wait = backoff(failures);
+                    warn!("sip: registration failed, retrying in {:?}: {}", wait, e);
+                    state_tx.send_replace(Registration::Failed);
+                    wait
+                }
+            };
+            select! {
+                _ = sleep(wait) => {},
+                Ok(()) = flow.changed() => debug!("sip: connection changed, registering again"),
+                _ = state_tx.closed() => return Ok(()),
+            }
+        }
+    }));
+    state_rx
+}
+
+// The expiry the registrar actually granted our contact, falling back to the Expires header
+// https://www.rfc-editor.org/rfc/rfc3261 (10.2.4)
+pub fn granted_expiry(resp: &Response, sip_instance_uuid: &Uuid) -> Result<Option<Duration>> {
+    let instance = sip_instance_uuid.to_string();
+    // rsip can't tokenize the quoted <urn:uuid:...> in +sip.instance, so pick the params out
+    // of the raw values ourselves
+    let contacts: Vec<Vec<(String, String)>> = resp
+        .headers()
+        .iter()
+        .filter_map(|header| match header {
+            Header::Contact(contact) => Some(contact_params(contact.value())),
+            _ => None,
+        })
+        .collect();
+    // Other devices on the same AOR show up too, ours is the one with our instance ID
+    let ours = match contacts.len() {
+        1 => contacts.first(),
+        _ => contacts.iter().find(|params| {
+            params
+                .iter()
+                .any(|(name, value)| name == "+sip.instance" && value.contains(&instance))
+        }),
+    };
+    let from_contact = ours.and_then(|params| {
+        params
+            .iter()
+            .find(|(name, _)| name == "expires")
+            .and_then(|(_, value)| value.parse::<u32>().ok())
+    });
+    let seconds = match from_contact {
+        Some(seconds) => Some(seconds),
+        None => match resp.expires_header() {
+            Some(expires) => Some(expires.seconds()?),
+            None => None,
+        },
+    };
+    Ok(seconds.map(|seconds| Duration::from_secs(seconds.into())))
+}
+
+fn contact_params(value: &str) -> Vec<(String, String)> {
+    let params = match value.split_once('>') {
+        Some((_, params)) => params,
+        None => value
+            .split_once(';')
+            .map(|(_, params)| params)
+            .unwrap_or(""),
+    };
+    params
+        .split(';')
+        .filter(|param| !param.trim().is_empty())
+        .map(|param| match param.split_once('=') {
+            Some((name, value)) => (name.trim().to_lowercase(), value.trim().to_string()),
+            None => (param.trim().to_lowercase(), String::new()),
+        })
+        .collect()
+}
+
+// Leaves enough slack for a challenge round trip and a retry or two before it lapses
+// https://www.rfc-editor.org/rfc/rfc5626 (4.4.1)
+pub fn refresh_after(expires: Duration) -> Duration {
+    if expires > Duration::from_secs(1200) {
+        expires - Duration::from_secs(600)
+    } else {
+        expires / 2
+    }
+}
+
+// Exponential up to a cap, then somewhere between half and all of that so a whole building
+// of phones doesn't come back at once
+// https://www.rfc-editor.org/rfc/rfc5626 (4.5)
+pub fn backoff(failures: u32) -> Duration {
+    let wait = RETRY_BASE
+        .saturating_mul(1 << failures.saturating_sub(1).min(16))
+        .min(RETRY_MAX);
+    wait.mul_f64(rand::rng().random_range(0.5..=1.0))
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use crate::sip::testing;
+
+    fn ok(headers: &str) -> Response {
+        testing::message("SIP/2.0 200 OK", "abc", Some("def"), "2 REGISTER", headers)
+    }
+
+    #[test]
+    fn successfully_read_granted_expiry() -> Result<()> {
+        let ours = Uuid::new_v4();
+        let theirs = Uuid::new_v4();
+        let resp = ok(&format!(
+            "Contact: <sips:1102@10.0.0.2>;expires=3600;+sip.instance=\"<urn:uuid:{}>\"\r\n\
+            Contact: <sips:1102@10.0.0.1>;expires=120;+sip.instance=\"<urn:uuid:{}>\"\r\n\
+            Expires: 3600\r\n",
+            theirs, ours,
+        ));
+        assert_eq!(
+            granted_expiry(&resp, &ours)?,
+            Some(Duration::from_secs(120))
+        );
+
+        let resp = ok("Expires: 600\r\n");
+        assert_eq!(
+            granted_expiry(&resp, &ours)?,
+            Some(Duration::from_secs(600))
+        );
+        assert_eq!(granted_expiry(&ok(""), &ours)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn successfully_refresh_before_expiry() {
+        assert_eq!(
+            refresh_after(Duration::from_secs(3600)),
+            Duration::from_secs(3000)
+        );
+        assert_eq!(
+            refresh_after(Duration::from_secs(120)),
+            Duration::from_secs(60)
+        );
+    }
+
+    #[test]
+    fn successfully_back_off_up_to_a_cap() {
+        for failures in 1..40 {
+            let wait = backoff(failures);
+            let cap = RETRY_BASE
+                .saturating_mul(1 << (failures - 1).min(16))
+                .min(RETRY_MAX);
+            assert!(wait >= cap / 2 && wait <= cap);
+        }
+        assert!(backoff(1) <= RETRY_BASE);
+        assert!(backoff(30) >= RETRY_MAX / 2);
+    }
+}
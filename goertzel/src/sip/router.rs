@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use rsip::prelude::*;
+use rsip::SipMessage;
+use tokio::sync::mpsc;
+use tracing::debug;
+
+// Where a message off the transaction layer should go
+// https://www.rfc-editor.org/rfc/rfc3261 (12.2.2)
+#[derive(Debug)]
+pub enum Destination {
+    Dialog(mpsc::Sender<SipMessage>),
+    // A request that starts something, for whoever's waiting on new requests
+    New,
+    // In-dialog request or response for a dialog we don't have (any more)
+    Unknown,
+}
+
+// Our tag is the one thing that's unique to our end of a dialog, the remote tag isn't known
+// until they answer
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct Key {
+    call_id: String,
+    local_tag: String,
+}
+
+#[derive(Debug)]
+struct Entry {
+    remote_tag: Option<String>,
+    tx_ch: mpsc::Sender<SipMessage>,
+}
+
+#[derive(Debug, Default)]
+pub struct Routes {
+    dialogs: HashMap<Key, Entry>,
+}
+
+impl Routes {
+    pub fn destination(&self, msg: &SipMessage) -> Result<Destination> {
+        let call_id = msg.call_id_header()?.value().to_string();
+        let from_tag = msg.from_header()?.tag()?.map(|t| t.to_string());
+        let to_tag = msg.to_header()?.tag()?.map(|t| t.to_string());
+
+        let local_tag = match msg {
+            // We sent the request, so we're From
+            SipMessage::Response(_) => from_tag,
+            SipMessage::Request(_) => match to_tag {
+                Some(to_tag) => Some(to_tag),
+                // No To tag is a new dialog, unless it's something like a CANCEL for a request
+                // we've already made a dialog for
+                None => {
+                    let found = self.dialogs.iter().find(|(key, entry)| {
+                        key.call_id == call_id && from_tag.is_some() && entry.remote_tag == from_tag
+                    });
+                    return Ok(match found {
+                        Some((_, entry)) => Destination::Dialog(entry.tx_ch.clone()),
+                        None => Destination::New,
+                    });
+                }
+            },
+        };
+        let Some(local_tag) = local_tag else {
+            return Ok(Destination::Unknown);
+        };
+        Ok(match self.dialogs.get(&Key { call_id, local_tag }) {
+            Some(entry) => Destination::Dialog(entry.tx_ch.clone()),
+            None => Destination::Unknown,
+        })
+    }
+}
+
+// Registers a dialog, and takes it out again once the dialog goes away
+pub fn register(
+    routes: &Arc<Mutex<Routes>>,
+    call_id: String,
+    local_tag: String,
+    remote_tag: Option<String>,
+    tx_ch: mpsc::Sender<SipMessage>,
+) -> RouteLease {
+    let key = Key { call_id, local_tag };
+    debug!("sip: routing {}/{} to dialog", key.call_id, key.local_tag);
+    routes
+        .lock()
+        .unwrap()
+        .dialogs
+        .insert(key.clone(), Entry { remote_tag, tx_ch });
+    RouteLease {
+        key,
+        routes: routes.clone(),
+    }
+}
+
+#[derive(Debug)]
+pub struct RouteLease {
+    key: Key,
+    routes: Arc<Mutex<Routes>>,
+}
+
+impl Drop for RouteLease {
+    fn drop(&mut self) {
+        debug!(
+            "sip: dialog {}/{} ended",
+            self.key.call_id, self.key.local_tag
+        );
+        self.routes.lock().unwrap().dialogs.remove(&self.key);
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use crate::sip::testing;
+
+    fn msg(first_line: &str, from_tag: &str, to_tag: Option<&str>) -> SipMessage {
+        testing::message(first_line, from_tag, to_tag, "1 INVITE", "")
+    }
+
+    const REQUEST: &str = "INVITE sips:b@pbx.frandline.com SIP/2.0";
+    const RESPONSE: &str = "SIP/2.0 180 Ringing";
+
+    #[test]
+    fn successfully_route_by_call_id_and_tags() -> Result<()> {
+        let routes = Arc::new(Mutex::new(Routes::default()));
+        let (ours, _ours_rx) = mpsc::channel(1);
+        let (theirs, _theirs_rx) = mpsc::channel(1);
+        // One we called out on, and one they called us on
+        let _outbound = register(&routes, "call-1".into(), "us".into(), None, ours.clone());
+        let _inbound = register(
+            &routes,
+            "call-1".into(),
+            "me".into(),
+            Some("them".into()),
+            theirs.clone(),
+        );
+        let routes = routes.lock().unwrap();
+
+        let Destination::Dialog(ch) = routes.destination(&msg(RESPONSE, "us", Some("x")))? else {
+            panic!("response didn't go to its dialog");
+        };
+        assert!(ch.same_channel(&ours));
+        let Destination::Dialog(ch) = routes.destination(&msg(REQUEST, "them", Some("me")))? else {
+            panic!("in-dialog request didn't go to its dialog");
+        };
+        assert!(ch.same_channel(&theirs));
+        let Destination::Dialog(ch) = routes.destination(&msg(REQUEST, "them", None))? else {
+            panic!("cancel didn't go to its dialog");
+        };
+        assert!(ch.same_channel(&theirs));
+
+        assert!(matches!(
+            routes.destination(&msg(REQUEST, "someone", None))?,
+            Destination::New
+        ));
+        assert!(matches!(
+            routes.destination(&msg(REQUEST, "them", Some("nope")))?,
+            Destination::Unknown
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn successfully_deregister_ended_dialogs() -> Result<()> {
+        let routes = Arc::new(Mutex::new(Routes::default()));
+        let (tx_ch, _rx_ch) = mpsc::channel(1);
+        let lease = register(&routes, "call-1".into(), "us".into(), None, tx_ch);
+        let response = msg(RESPONSE, "us", None);
+        assert!(matches!(
+            routes.lock().unwrap().destination(&response)?,
+            Destination::Dialog(_)
+        ));
+        drop(lease);
+        assert!(matches!(
+            routes.lock().unwrap().destination(&response)?,
+            Destination::Unknown
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,211 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use rsip::prelude::*;
+use rsip::{Header, Headers, Method};
+use tokio::time::Instant;
+
+use super::headers::{has_token, other_header};
+
+// What we ask for, and the smallest the RFC lets anyone go. Since ours is the floor we never have
+// to turn a request down with a 422
+// https://www.rfc-editor.org/rfc/rfc4028 (4)
+pub const SESSION_EXPIRES: u32 = 1800;
+pub const MIN_SE: u32 = 90;
+
+pub const TIMER_OPTION_TAG: &str = "timer";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Refresher {
+    Us,
+    Them,
+}
+
+impl Refresher {
+    // The refresher param is relative to the transaction it's in, so it depends on which side of
+    // that transaction we were
+    fn from_param(param: &str, we_are_uac: bool) -> Result<Self> {
+        match (param.to_ascii_lowercase().as_str(), we_are_uac) {
+            ("uac", true) | ("uas", false) => Ok(Refresher::Us),
+            ("uac", false) | ("uas", true) => Ok(Refresher::Them),
+            (param, _) => Err(anyhow!("bad refresher: {}", param)),
+        }
+    }
+
+    pub fn to_param(self, we_are_uac: bool) -> &'static str {
+        match (self, we_are_uac) {
+            (Refresher::Us, true) | (Refresher::Them, false) => "uac",
+            (Refresher::Us, false) | (Refresher::Them, true) => "uas",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SessionTimer {
+    pub expires: u32,
+    pub refresher: Refresher,
+    refreshed: Instant,
+}
+
+impl SessionTimer {
+    pub fn new(expires: u32, refresher: Refresher) -> Self {
+        SessionTimer {
+            expires,
+            refresher,
+            refreshed: Instant::now(),
+        }
+    }
+
+    // Halfway through for the refresher. The other side gives it until a little before the end
+    // so the BYE gets there while the far end might still care
+    // https://www.rfc-editor.org/rfc/rfc4028 (10)
+    pub fn deadline(&self) -> Instant {
+        let expires = Duration::from_secs(self.expires.into());
+        self.refreshed
+            + match self.refresher {
+                Refresher::Us => expires / 2,
+                Refresher::Them => expires - (expires / 3).min(Duration::from_secs(32)),
+            }
+    }
+
+    // The timer the far end agreed to in their 2xx. Without a Session-Expires there isn't one
+    // https://www.rfc-editor.org/rfc/rfc4028 (7.2)
+    pub fn from_response(headers: &Headers) -> Result<Option<Self>> {
+        let Some((expires, refresher)) = session_expires(headers)? else {
+            return Ok(None);
+        };
+        let refresher = match refresher {
+            Some(refresher) => Refresher::from_param(&refresher, true)?,
+            None => Refresher::Us,
+        };
+        Ok(Some(SessionTimer::new(expires, refresher)))
+    }
+
+    // The timer we'll agree to for their request, and it's up to us who refreshes if they left it
+    // open. We leave it to them when they know how
+    // https://www.rfc-editor.org/rfc/rfc4028 (9)
+    pub fn from_request(headers: &Headers) -> Result<Option<Self>> {
+        let Some((expires, refresher)) = session_expires(headers)? else {
+            return Ok(None);
+        };
+        let refresher = match refresher {
+            Some(refresher) => Refresher::from_param(&refresher, false)?,
+            None if supports_timer(headers) => Refresher::Them,
+            None => Refresher::Us,
+        };
+        Ok(Some(SessionTimer::new(expires.max(MIN_SE), refresher)))
+    }
+}
+
+// Session-Expires: 1800;refresher=uac, or x for short
+pub fn session_expires(headers: &Headers) -> Result<Option<(u32, Option<String>)>> {
+    let Some(value) = other_header(headers, &["Session-Expires", "x"]) else {
+        return Ok(None);
+    };
+    let mut parts = value.split(';');
+    let expires = parts.next().unwrap_or_default().trim().parse()?;
+    let refresher = parts.find_map(|param| match param.split_once('=') {
+        Some((name, value)) if name.trim().eq_ignore_ascii_case("refresher") => {
+            Some(value.trim().to_string())
+        }
+        _ => None,
+    });
+    Ok(Some((expires, refresher)))
+}
+
+pub fn min_se(headers: &Headers) -> Result<Option<u32>> {
+    match other_header(headers, &["Min-SE"]) {
+        Some(value) => {
+            let seconds = value.split(';').next().unwrap_or_default().trim();
+            Ok(Some(seconds.parse()?))
+        }
+        None => Ok(None),
+    }
+}
+
+pub fn supports_timer(headers: &Headers) -> bool {
+    headers.iter().any(|header| match header {
+        Header::Supported(h) => has_token(h.value(), TIMER_OPTION_TAG),
+        Header::Require(h) => has_token(h.value(), TIMER_OPTION_TAG),
+        _ => false,
+    })
+}
+
+pub fn allows(headers: &Headers, method: Method) -> bool {
+    headers.iter().any(|header| match header {
+        Header::Allow(h) => has_token(h.value(), &method.to_string()),
+        _ => false,
+    })
+}
+
+pub fn session_expires_header(expires: u32, refresher: Option<&str>) -> Header {
+    let value = match refresher {
+        Some(refresher) => format!("{};refresher={}", expires, refresher),
+        None => expires.to_string(),
+    };
+    Header::Other("Session-Expires".into(), value)
+}
+
+pub fn min_se_header(min_se: u32) -> Header {
+    Header::Other("Min-SE".into(), min_se.to_string())
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+
+    fn headers(lines: &[(&str, &str)]) -> Headers {
+        let mut headers: Headers = Default::default();
+        for (name, value) in lines {
+            let header = match *name {
+                "Supported" => Header::Supported(rsip::headers::Supported::new(*value)),
+                name => Header::Other(name.into(), value.to_string()),
+            };
+            headers.push(header);
+        }
+        headers
+    }
+
+    #[test]
+    fn successfully_pick_the_refresher_from_our_side_of_the_transaction() -> Result<()> {
+        let uac = headers(&[("Session-Expires", "600;refresher=uac")]);
+        let ours = SessionTimer::from_response(&uac)?.ok_or(anyhow!("no timer"))?;
+        assert_eq!(ours.expires, 600);
+        assert_eq!(ours.refresher, Refresher::Us);
+        let theirs = SessionTimer::from_request(&uac)?.ok_or(anyhow!("no timer"))?;
+        assert_eq!(theirs.refresher, Refresher::Them);
+
+        let open = headers(&[("x", "1800"), ("Supported", "replaces, timer")]);
+        let theirs = SessionTimer::from_request(&open)?.ok_or(anyhow!("no timer"))?;
+        assert_eq!(theirs.refresher, Refresher::Them);
+        let ours =
+            SessionTimer::from_request(&headers(&[("x", "1800")]))?.ok_or(anyhow!("no timer"))?;
+        assert_eq!(ours.refresher, Refresher::Us);
+
+        assert!(SessionTimer::from_response(&headers(&[]))?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn successfully_refresh_before_the_far_end_gives_up() {
+        let ours = SessionTimer::new(1800, Refresher::Us);
+        assert_eq!(ours.deadline() - ours.refreshed, Duration::from_secs(900));
+        let theirs = SessionTimer::new(1800, Refresher::Them);
+        assert_eq!(
+            theirs.deadline() - theirs.refreshed,
+            Duration::from_secs(1768)
+        );
+        let theirs = SessionTimer::new(90, Refresher::Them);
+        assert_eq!(
+            theirs.deadline() - theirs.refreshed,
+            Duration::from_secs(60)
+        );
+    }
+
+    #[test]
+    fn fail_to_parse_a_bad_refresher() {
+        let bad = headers(&[("Session-Expires", "600;refresher=both")]);
+        assert!(SessionTimer::from_response(&bad).is_err());
+        assert!(min_se(&headers(&[("Min-SE", "soon")])).is_err());
+    }
+}
@@ -1,46 +1,51 @@
-use std::net::{IpAddr, Ipv4Addr};
-use std::str::FromStr;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::collections::VecDeque;
+use std::net::Ipv4Addr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::{anyhow, Result};
-use itertools::Itertools;
-use md5::{Digest, Md5};
 use rand::distr::Alphanumeric;
 use rand::prelude::*;
 use rand::rngs::StdRng;
-use rand::{rng, Rng};
-use rsip::headers::auth::{Algorithm, AuthQop};
-use rsip::headers::{auth, CallId, ContentLength, Expires, MaxForwards, UserAgent};
+use rand::Rng;
+use rsip::headers::{self, CallId, ContentLength, Expires, MaxForwards, UserAgent};
 use rsip::param::OtherParam;
-use rsip::typed::{Authorization, CSeq, Contact, ContentType, From, MediaType, To, Via};
+use rsip::typed::{CSeq, Contact, ContentType, From, MediaType, To, Via};
 use rsip::{prelude::*, StatusCodeKind};
 use rsip::{
     Auth, Header, Headers, HostWithPort, Method, Param, Request, Response, Scheme, SipMessage,
-    StatusCode, Transport, Uri, Version,
+    StatusCode, Uri, Version,
 };
-use sdp_rs::{MediaDescription, SessionDescription};
+use sdp_rs::SessionDescription;
 use tokio::sync::mpsc;
+use tokio::time::{sleep, Instant};
 use tracing::{debug, trace};
 use uuid::Uuid;
-use vec1::Vec1;
 
-const REALM: &str = "asterisk";
+use super::account::DtmfMode;
+use super::digest::DigestAuth;
+use super::dtmf_relay::{self, DTMF_RELAY_MEDIA_TYPE};
+use super::outbound::OUTBOUND_OPTION_TAG;
+use super::prack::{self, Unacked};
+use super::refer::{self, Replaces, REFER_EVENT, SIPFRAG_MEDIA_TYPE};
+use super::registration::{granted_expiry, REGISTER_EXPIRES};
+use super::router::RouteLease;
+use super::session_timer::{self, Refresher, SessionTimer, MIN_SE, SESSION_EXPIRES};
+use super::transport::TransportKind;
+use crate::sdp::{self, MediaParams};
+
 const USER_AGENT: &str = "Frandline";
 const UA_VERSION: &str = "0.1.0";
 // Branch should always be prefixed with magic string z9hG4bK
 // https://www.ietf.org/rfc/rfc3261.txt (8.1.1.7)
 const BRANCH_PREFIX: &str = "z9hG4bK";
 const MAX_FORWARDS: u32 = 70;
+// One for the challenge, one for a stale nonce, one more in case a reused nonce had gone bad
+const MAX_AUTH_ATTEMPTS: usize = 4;
+const ALLOW: &str = "INVITE, ACK, CANCEL, BYE, INFO, UPDATE, REFER, NOTIFY, PRACK";
 
 pub const SERVER_NAME: &str = "pbx.frandline.com";
 pub const SERVER_PORT: u16 = 5061;
 
-fn md5(s: String) -> String {
-    let mut hasher = Md5::new();
-    hasher.update(s);
-    format!("{:x}", hasher.finalize())
-}
-
 // TODO(peter): Ask about &mut impl Rng vs &mut ThreadRng (this didn't work)
 fn rand_chars(rng: &mut impl Rng, len: usize) -> String {
     rng.sample_iter(&Alphanumeric)
@@ -56,13 +61,6 @@ fn ms_since_epoch() -> u128 {
         .as_millis()
 }
 
-fn micros_since_epoch() -> u128 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_micros()
-}
-
 pub fn assert_status(resp: &Response) -> Result<()> {
     match resp.status_code.kind() {
         StatusCodeKind::Successful | StatusCodeKind::Provisional => Ok(()),
@@ -70,9 +68,76 @@ pub fn assert_status(resp: &Response) -> Result<()> {
     }
 }
 
-fn uri(user: String, host_with_port: HostWithPort) -> Uri {
+// CANCEL has to look like the request it's cancelling so the far end can match them up
+// https://www.rfc-editor.org/rfc/rfc3261 (9.1)
+fn cancel_for(req: &Request) -> Result<Request> {
+    let mut headers: Headers = Default::default();
+    for header in req.headers.iter() {
+        match header {
+            Header::Via(_) if headers.iter().any(|h| matches!(h, Header::Via(_))) => {}
+            h @ (Header::Via(_)
+            | Header::CallId(_)
+            | Header::From(_)
+            | Header::To(_)
+            | Header::Route(_)) => headers.push(h.clone()),
+            _ => {}
+        }
+    }
+    headers.push(
+        CSeq {
+            seq: req.cseq_header()?.seq()?,
+            method: Method::Cancel,
+        }
+        .into(),
+    );
+    headers.push(MaxForwards::from(MAX_FORWARDS).into());
+    headers.push(ContentLength::from(0).into());
+    Ok(Request {
+        method: Method::Cancel,
+        uri: req.uri.clone(),
+        version: req.version.clone(),
+        headers,
+        body: vec![],
+    })
+}
+
+fn is_ack(msg: &SipMessage) -> bool {
+    matches!(msg, SipMessage::Request(req) if req.method == Method::Ack)
+}
+
+// Contact can carry params rsip won't tokenize (like +sip.instance), so only the URI gets parsed
+fn contact_uri(headers: &Headers) -> Result<Option<Uri>> {
+    let Some(contact) = headers.iter().find_map(|h| match h {
+        Header::Contact(contact) => Some(contact.value()),
+        _ => None,
+    }) else {
+        return Ok(None);
+    };
+    let uri = match contact.split_once('<') {
+        Some((_, rest)) => rest.split_once('>').map(|(uri, _)| uri).unwrap_or(rest),
+        // Without brackets anything after a ; belongs to the header, not the URI
+        None => contact.split(';').next().unwrap_or(contact),
+    };
+    Ok(Some(Uri::try_from(uri.trim())?))
+}
+
+fn record_route(headers: &Headers) -> Result<Vec<Uri>> {
+    let mut routes = vec![];
+    for header in headers.iter() {
+        if let Header::RecordRoute(record_route) = header {
+            for entry in record_route.value().split('>') {
+                if let Some((_, uri)) = entry.split_once('<') {
+                    routes.push(Uri::try_from(uri.trim())?);
+                }
+            }
+        }
+    }
+    Ok(routes)
+}
+
+fn uri(transport: TransportKind, user: String, host_with_port: HostWithPort) -> Uri {
     Uri {
-        scheme: Some(Scheme::Sips),
+        scheme: Some(transport.scheme()),
         auth: Some(Auth {
             user,
             password: None,
@@ -98,13 +163,22 @@ fn flip_to(to: To) -> From {
     }
 }
 
+// What our INVITE gets built from, so it can go out again answering a challenge
+#[derive(Clone, Debug)]
+struct Outgoing {
+    to: To,
+    replaces: Option<Replaces>,
+    body: String,
+    attempts: usize,
+}
+
 #[derive(Debug)]
 pub struct Dialog {
     pub tx_ch: mpsc::Sender<SipMessage>,
     pub rx_ch: mpsc::Receiver<SipMessage>,
 
     server_host: HostWithPort,
-    client_ip: Ipv4Addr,
+    transport: TransportKind,
     sip_instance_uuid: Uuid,
 
     username: String,
@@ -115,12 +189,55 @@ pub struct Dialog {
     from: From,
     to: Option<To>,
 
+    pub session: sdp::Session,
+    pub dtmf_mode: DtmfMode,
+
+    // The transaction layer hands us 2xx retransmissions to ACK again
+    // https://www.rfc-editor.org/rfc/rfc3261 (13.2.2.4)
+    last_ack: Option<Request>,
+
+    // Where in-dialog requests go, and the proxies they have to go through to get there
+    // https://www.rfc-editor.org/rfc/rfc3261 (12.1)
+    remote_target: Option<Uri>,
+    route_set: Vec<Uri>,
+    confirmed: bool,
+    // What CANCEL and the ACK for its 2xx get built from
+    invite: Option<Request>,
+    outgoing: Option<Outgoing>,
+
+    // Keeps messages for this dialog coming to rx_ch until we're dropped
+    route: Option<RouteLease>,
+
+    auth: DigestAuth,
+    // Messages we had to read early but aren't ours to act on
+    unread: VecDeque<SipMessage>,
+    // Whoever picked the Call-ID waits longer after glare
+    // https://www.rfc-editor.org/rfc/rfc3261 (14.1)
+    owns_call_id: bool,
+
+    // What we ask for, and the least the far end told us they'd take
+    // https://www.rfc-editor.org/rfc/rfc4028 (6)
+    session_expires: u32,
+    min_se: u32,
+    session_timer: Option<SessionTimer>,
+    // UPDATE refreshes without a round of SDP, but only if they take it
+    // https://www.rfc-editor.org/rfc/rfc4028 (7.4)
+    update_allowed: bool,
+
+    // The last reliable provisional we PRACKed as (CSeq, RSeq), and the one of ours waiting on a
+    // PRACK
+    // https://www.rfc-editor.org/rfc/rfc3262 (3)
+    pracked: Option<(u32, u32)>,
+    rseq: u32,
+    unacked: Option<Unacked>,
+
     rng: StdRng,
 }
 
 impl Dialog {
     pub fn new(
         server_host: HostWithPort,
+        transport: TransportKind,
         client_ip: Ipv4Addr,
         sip_instance_uuid: Uuid,
         username: String,
@@ -132,7 +249,7 @@ impl Dialog {
 
         let from = From {
             display_name: Some(username.clone()),
-            uri: uri(username.clone(), server_host.clone()),
+            uri: uri(transport, username.clone(), server_host.clone()),
             params: vec![],
         }
         .with_tag(rand_chars(&mut rng, 16).into());
@@ -142,7 +259,7 @@ impl Dialog {
             rx_ch,
 
             server_host,
-            client_ip,
+            transport,
             sip_instance_uuid,
 
             username,
@@ -153,12 +270,38 @@ impl Dialog {
             from,
             to: None,
 
+            session: sdp::Session::new(client_ip),
+            dtmf_mode: DtmfMode::default(),
+
+            last_ack: None,
+
+            remote_target: None,
+            route_set: vec![],
+            confirmed: false,
+            invite: None,
+            outgoing: None,
+
+            route: None,
+            auth: Default::default(),
+            unread: VecDeque::new(),
+            owns_call_id: true,
+
+            session_expires: SESSION_EXPIRES,
+            min_se: MIN_SE,
+            session_timer: None,
+            update_allowed: false,
+
+            pracked: None,
+            rseq: rng.random_range(1..1 << 31),
+            unacked: None,
+
             rng,
         }
     }
 
     pub fn from_request(
         server_host: HostWithPort,
+        transport: TransportKind,
         client_ip: Ipv4Addr,
         sip_instance_uuid: Uuid,
         tx_ch: mpsc::Sender<SipMessage>,
@@ -181,7 +324,7 @@ impl Dialog {
             rx_ch,
 
             server_host,
-            client_ip,
+            transport,
             sip_instance_uuid,
 
             username,
@@ -192,70 +335,103 @@ impl Dialog {
             from: flip_to(to),
             to: Some(flip_from(from)),
 
+            session: sdp::Session::new(client_ip),
+            dtmf_mode: DtmfMode::default(),
+
+            last_ack: None,
+
+            // We're the UAS, so the route set's in the order the request came through
+            // https://www.rfc-editor.org/rfc/rfc3261 (12.1.1)
+            remote_target: contact_uri(msg.headers())?,
+            route_set: record_route(msg.headers())?,
+            confirmed: true,
+            invite: None,
+            outgoing: None,
+
+            route: None,
+            auth: Default::default(),
+            unread: VecDeque::new(),
+            owns_call_id: false,
+
+            session_expires: SESSION_EXPIRES,
+            min_se: MIN_SE,
+            session_timer: None,
+            update_allowed: session_timer::allows(msg.headers(), Method::Update),
+
+            pracked: None,
+            rseq: rng.random_range(1..1 << 31),
+            unacked: None,
+
             rng,
         })
     }
 
-    pub fn sdp(&self, sess_id: String) -> SessionDescription {
-        SessionDescription {
-            version: sdp_rs::lines::Version::V0,
-            origin: sdp_rs::lines::Origin {
-                username: "-".to_string(),
-                sess_id: sess_id.clone(),
-                sess_version: sess_id,
-                nettype: sdp_rs::lines::common::Nettype::In,
-                addrtype: sdp_rs::lines::common::Addrtype::Ip4,
-                unicast_address: self.client_ip.into(),
-            },
-            session_name: sdp_rs::lines::SessionName::from(USER_AGENT.to_string()),
-            session_info: None,
-            uri: None,
-            emails: vec![],
-            phones: vec![],
-            connection: Some(sdp_rs::lines::Connection {
-                nettype: sdp_rs::lines::common::Nettype::In,
-                addrtype: sdp_rs::lines::common::Addrtype::Ip4,
-                connection_address: IpAddr::V4(self.client_ip).into(),
-            }),
-            bandwidths: vec![],
-            times: Vec1::new(sdp_rs::Time {
-                active: sdp_rs::lines::Active { start: 0, stop: 0 },
-                repeat: vec![],
-                zone: None,
-            }),
-            key: None,
-            attributes: vec![],
-            media_descriptions: vec![MediaDescription {
-                media: sdp_rs::lines::Media {
-                    media: sdp_rs::lines::media::MediaType::Audio,
-                    port: 19512, // TODO(peter): Choose and set up an RTP port
-                    num_of_ports: None,
-                    proto: sdp_rs::lines::media::ProtoType::RtpAvp,
-                    fmt: vec![0].into_iter().map(|v| v.to_string()).join(" "),
-                },
-                info: None,
-                connections: vec![],
-                bandwidths: vec![],
-                key: None,
-                attributes: vec![
-                    sdp_rs::lines::Attribute::Rtpmap(sdp_rs::lines::attribute::Rtpmap {
-                        payload_type: 0,
-                        encoding_name: "PCMU".into(),
-                        clock_rate: 8000,
-                        encoding_params: None,
-                    }),
-                    sdp_rs::lines::Attribute::Ptime(20.0),
-                    sdp_rs::lines::Attribute::Maxptime(140.0),
-                    sdp_rs::lines::Attribute::Sendrecv,
-                ],
-            }],
+    // We always tag our side when the dialog's made
+    pub fn local_tag(&self) -> String {
+        self.from
+            .tag()
+            .map(|tag| tag.to_string())
+            .unwrap_or_default()
+    }
+
+    pub fn remote_tag(&self) -> Option<String> {
+        self.to.as_ref()?.tag().map(|tag| tag.to_string())
+    }
+
+    pub fn set_route(&mut self, route: RouteLease) {
+        self.route = Some(route);
+    }
+
+    // Answers the offer in req, or returns None if we can't agree on media
+    pub fn negotiate(
+        &mut self,
+        req: &Request,
+    ) -> Result<Option<(SessionDescription, MediaParams)>> {
+        self.session.answer(&sdp::parse(&req.body)?)
+    }
+
+    // Like negotiate, but an INVITE that starts a call can leave the offer to us. Then ours goes
+    // in the 200 and there's no media until the answer comes back in the ACK
+    // https://www.rfc-editor.org/rfc/rfc3261 (13.2.1)
+    pub fn negotiate_invite(
+        &mut self,
+        req: &Request,
+    ) -> Result<Option<(SessionDescription, Option<MediaParams>)>> {
+        if req.body.is_empty() {
+            return Ok(Some((self.session.offer()?, None)));
         }
+        Ok(self
+            .negotiate(req)?
+            .map(|(answer, params)| (answer, Some(params))))
     }
 
-    pub fn sdp_from(&self, req: Request) -> Result<SessionDescription> {
-        let sdp = SessionDescription::from_str(std::str::from_utf8(&req.body)?)?;
-        let sess_id = sdp.origin.sess_id;
-        Ok(self.sdp(sess_id))
+    // Takes the answer to an offer we put in a 200
+    pub fn accept_delayed_answer(&self, ack: &Request) -> Result<MediaParams> {
+        self.session.accept_answer(&sdp::parse(&ack.body)?)
+    }
+
+    pub fn accept_answer(&self, resp: &Response) -> Result<MediaParams> {
+        self.session.accept_answer(&sdp::parse(&resp.body)?)
+    }
+
+    // Ringback, announcements and the like the far end plays us before anyone answers. We only
+    // listen until they do
+    // https://www.rfc-editor.org/rfc/rfc3960 (3.1)
+    pub fn early_media(&self, resp: &Response) -> Result<Option<MediaParams>> {
+        let is_sdp = resp.headers.iter().any(|h| match h {
+            Header::ContentType(ct) => ct
+                .value()
+                .to_ascii_lowercase()
+                .starts_with("application/sdp"),
+            _ => false,
+        });
+        if resp.status_code.kind() != StatusCodeKind::Provisional || !is_sdp || resp.body.is_empty()
+        {
+            return Ok(None);
+        }
+        let mut params = self.accept_answer(resp)?;
+        params.direction = params.direction.and(sdp::Direction::RecvOnly);
+        Ok(Some(params))
     }
 
     pub fn sdp_response_to(
@@ -288,11 +464,33 @@ impl Dialog {
     }
 
     pub async fn recv(&mut self) -> Result<SipMessage> {
-        let msg = self
-            .rx_ch
-            .recv()
-            .await
-            .ok_or(anyhow!("failed recv dialog rx"))?;
+        if let Some(msg) = self.unread.pop_front() {
+            return Ok(msg);
+        }
+        let msg = loop {
+            let msg = self
+                .rx_ch
+                .recv()
+                .await
+                .ok_or(anyhow!("failed recv dialog rx"))?;
+            if let Some(msg) = self.screen(msg).await? {
+                break msg;
+            }
+        };
+        match &msg {
+            SipMessage::Response(resp) => {
+                self.update_from_response(resp)?;
+                self.update_session_timer(resp)?;
+            }
+            // A re-INVITE can move the far end somewhere else
+            // https://www.rfc-editor.org/rfc/rfc3261 (12.2.2)
+            SipMessage::Request(req) if req.method == Method::Invite => {
+                if let Some(target) = contact_uri(&req.headers)? {
+                    self.remote_target = Some(target);
+                }
+            }
+            SipMessage::Request(_) => {}
+        }
         trace!(
             user=%self.username,
             call_id=%self.call_id.value().to_string(),
@@ -308,18 +506,188 @@ impl Dialog {
         Ok(msg)
     }
 
+    // Takes care of the messages nobody driving the call needs to see
+    async fn screen(&mut self, msg: SipMessage) -> Result<Option<SipMessage>> {
+        if let (SipMessage::Response(resp), Some(ack)) = (&msg, &self.last_ack) {
+            if resp.status_code.kind() == StatusCodeKind::Successful
+                && resp.cseq_header()?.method()? == Method::Invite
+                && resp.cseq_header()?.seq()? == ack.cseq_header()?.seq()?
+            {
+                debug!(call_id=%self.call_id.value().to_string(), "SIP Resend ACK");
+                self.send(ack.clone()).await?;
+                return Ok(None);
+            }
+        }
+        match &msg {
+            SipMessage::Response(resp) if resp.cseq_header()?.method()? == Method::PRack => {
+                debug!(call_id=%self.call_id.value().to_string(), status=%resp.status_code, "SIP PRACK answered");
+                Ok(None)
+            }
+            SipMessage::Response(resp)
+                if resp.status_code.kind() == StatusCodeKind::Provisional
+                    && prack::requires_100rel(&resp.headers) =>
+            {
+                match self.prack(resp).await? {
+                    true => Ok(Some(msg)),
+                    false => Ok(None),
+                }
+            }
+            SipMessage::Request(req) if req.method == Method::PRack => {
+                self.answer_prack(req.clone()).await?;
+                Ok(None)
+            }
+            _ => Ok(Some(msg)),
+        }
+    }
+
+    // PRACKs the next reliable provisional in sequence. Anything else is a retransmission or out
+    // of order, and we don't act on it at all
+    // https://www.rfc-editor.org/rfc/rfc3262 (4)
+    async fn prack(&mut self, resp: &Response) -> Result<bool> {
+        let rseq =
+            prack::rseq(&resp.headers)?.ok_or(anyhow!("reliable provisional without RSeq"))?;
+        let cseq = resp.cseq_header()?.seq()?;
+        if let Some((pracked_cseq, pracked_rseq)) = self.pracked {
+            if pracked_cseq == cseq && rseq != pracked_rseq + 1 {
+                debug!(call_id=%self.call_id.value().to_string(), rseq, "SIP Drop reliable provisional");
+                return Ok(false);
+            }
+        }
+        self.pracked = Some((cseq, rseq));
+
+        // The PRACK's in the dialog the provisional made
+        self.update_from_response(resp)?;
+        let mut req = self.new_request(Method::PRack, vec![]);
+        req.headers
+            .push(prack::rack_header(rseq, cseq, Method::Invite));
+        self.add_invite_credentials(&mut req);
+        self.send(req).await?;
+        Ok(true)
+    }
+
+    async fn answer_prack(&mut self, req: Request) -> Result<()> {
+        let acked = match (prack::rack(&req.headers)?, &self.unacked) {
+            (Some((rseq, cseq, Method::Invite)), Some(unacked)) => {
+                rseq == unacked.rseq && cseq == unacked.cseq
+            }
+            _ => false,
+        };
+        let status_code = match acked {
+            true => {
+                self.unacked = None;
+                StatusCode::OK
+            }
+            // https://www.rfc-editor.org/rfc/rfc3262 (3)
+            false => StatusCode::CallTransactionDoesNotExist,
+        };
+        let resp = self.response_to(req, status_code, vec![])?;
+        self.send(resp).await
+    }
+
+    // Sends 180 for the INVITE, reliably if the caller can do that
+    // https://www.rfc-editor.org/rfc/rfc3262 (3)
+    pub async fn ringing(&mut self, invite: Request) -> Result<()> {
+        let reliable = prack::supports_100rel(&invite.headers);
+        let cseq = invite.cseq_header()?.seq()?;
+        let mut resp = self.response_to(invite, StatusCode::Ringing, vec![])?;
+        if reliable {
+            self.rseq += 1;
+            let headers = resp.headers_mut();
+            headers.push(headers::Require::new(prack::RELIABLE_OPTION_TAG).into());
+            headers.push(prack::rseq_header(self.rseq));
+            self.unacked = Some(Unacked::spawn(
+                self.rseq,
+                cseq,
+                resp.clone(),
+                self.tx_ch.clone(),
+            ));
+        }
+        self.send(resp).await
+    }
+
+    // Provisionals with a tag and 2xxs to our INVITE fill the dialog in, and the 2xx settles it
+    // https://www.rfc-editor.org/rfc/rfc3261 (12.1.2)
+    fn update_from_response(&mut self, resp: &Response) -> Result<()> {
+        let establishes = match resp.status_code.kind() {
+            StatusCodeKind::Successful => true,
+            StatusCodeKind::Provisional => resp.status_code != StatusCode::Trying,
+            _ => false,
+        };
+        if !establishes
+            || resp.cseq_header()?.method()? != Method::Invite
+            || resp.to_header()?.tag()?.is_none()
+        {
+            return Ok(());
+        }
+        if let Some(target) = contact_uri(&resp.headers)? {
+            self.remote_target = Some(target);
+        }
+        // Once it's confirmed a re-INVITE can only refresh the target
+        if !self.confirmed {
+            self.to = Some(resp.to_header()?.typed()?);
+            self.route_set = record_route(&resp.headers)?.into_iter().rev().collect();
+            self.confirmed = resp.status_code.kind() == StatusCodeKind::Successful;
+        }
+        Ok(())
+    }
+
+    // A 2xx to anything that can refresh the session sets the timer again, and no Session-Expires
+    // in it means the far end doesn't want one
+    // https://www.rfc-editor.org/rfc/rfc4028 (7.2)
+    fn update_session_timer(&mut self, resp: &Response) -> Result<()> {
+        if resp.status_code.kind() != StatusCodeKind::Successful
+            || !matches!(
+                resp.cseq_header()?.method()?,
+                Method::Invite | Method::Update
+            )
+        {
+            return Ok(());
+        }
+        self.session_timer = SessionTimer::from_response(&resp.headers)?;
+        if resp.headers.iter().any(|h| matches!(h, Header::Allow(_))) {
+            self.update_allowed = session_timer::allows(&resp.headers, Method::Update);
+        }
+        Ok(())
+    }
+
+    // When the session has to be refreshed by, if anyone's keeping it alive
+    pub fn session_deadline(&self) -> Option<Instant> {
+        self.session_timer.as_ref().map(SessionTimer::deadline)
+    }
+
+    // https://www.rfc-editor.org/rfc/rfc3261 (12.2.1.1)
+    fn request_target(&self) -> (Uri, Vec<Uri>) {
+        let Some(remote_target) = self.remote_target.clone() else {
+            let server = Uri {
+                scheme: Some(self.transport.scheme()),
+                host_with_port: self.server_host.clone(),
+                ..Default::default()
+            };
+            return (server, vec![]);
+        };
+        match self.route_set.split_first() {
+            // A strict router wants to be the Request-URI, and the remote target goes on the end
+            Some((first, rest)) if !first.params.contains(&Param::Lr) => {
+                let mut routes = rest.to_vec();
+                routes.push(remote_target);
+                (first.clone(), routes)
+            }
+            _ => (remote_target, self.route_set.clone()),
+        }
+    }
+
     fn contact(&self) -> Contact {
         Contact {
             display_name: Some(self.username.clone()),
             uri: Uri {
-                scheme: Some(Scheme::Sips),
+                scheme: Some(self.transport.scheme()),
                 host_with_port: self.server_host.clone(),
                 auth: Some(Auth {
                     user: self.username.clone(),
                     password: None,
                 }),
                 params: vec![
-                    Param::Transport(Transport::Tls),
+                    Param::Transport(self.transport.via()),
                     Param::Other("ob".into(), None),
                 ],
                 ..Default::default()
@@ -337,20 +705,19 @@ impl Dialog {
 
     pub fn new_request(&mut self, method: Method, body: Vec<u8>) -> Request {
         self.cseq += 1;
+        self.request_with_seq(method, self.cseq, body)
+    }
+
+    fn request_with_seq(&mut self, method: Method, seq: u32, body: Vec<u8>) -> Request {
+        let (uri, routes) = self.request_target();
         let branch: String = format!("{}{}", BRANCH_PREFIX, rand_chars(&mut self.rng, 32));
 
         let mut headers: Headers = Default::default();
-        headers.push(
-            CSeq {
-                seq: self.cseq,
-                method,
-            }
-            .into(),
-        );
+        headers.push(CSeq { seq, method }.into());
         headers.push(
             Via {
                 version: Version::V2,
-                transport: Transport::Tls,
+                transport: self.transport.via(),
                 uri: Uri {
                     host_with_port: self.server_host.clone(),
                     ..Default::default()
@@ -369,16 +736,39 @@ impl Dialog {
         if let Some(to) = &self.to {
             headers.push(to.clone().into());
         }
+        for route in routes {
+            headers.push(headers::Route::new(format!("<{}>", route)).into());
+        }
         headers.push(MaxForwards::from(MAX_FORWARDS).into());
+        if let Method::Invite | Method::Update = method {
+            headers.push(
+                headers::Supported::new(match method {
+                    Method::Invite => {
+                        format!(
+                            "{}, {}",
+                            prack::RELIABLE_OPTION_TAG,
+                            session_timer::TIMER_OPTION_TAG
+                        )
+                    }
+                    _ => session_timer::TIMER_OPTION_TAG.into(),
+                })
+                .into(),
+            );
+            headers.push(headers::Allow::new(ALLOW).into());
+            headers.push(match &self.session_timer {
+                Some(timer) => session_timer::session_expires_header(
+                    timer.expires,
+                    Some(timer.refresher.to_param(true)),
+                ),
+                None => session_timer::session_expires_header(self.session_expires, None),
+            });
+            headers.push(session_timer::min_se_header(self.min_se));
+        }
         headers.push(ContentLength::from(body.len() as u32).into());
 
         Request {
             method,
-            uri: Uri {
-                scheme: Some(Scheme::Sips),
-                host_with_port: self.server_host.clone(),
-                ..Default::default()
-            },
+            uri,
             version: Version::V2,
             headers,
             body,
@@ -396,6 +786,11 @@ impl Dialog {
             params: vec![],
         };
         req.headers_mut().push(to_header.into());
+        req.headers_mut()
+            .push(Expires::from(REGISTER_EXPIRES).into());
+        // https://www.rfc-editor.org/rfc/rfc5626 (4.2.1)
+        req.headers_mut()
+            .push(headers::Supported::new(OUTBOUND_OPTION_TAG).into());
         Ok(req)
     }
 
@@ -405,15 +800,22 @@ impl Dialog {
         status_code: StatusCode,
         body: Vec<u8>,
     ) -> Result<SipMessage> {
-        self.cseq = req.cseq_header()?.seq()?;
+        // Their CSeq space is their own, but ours can't go backwards
+        self.cseq = self.cseq.max(req.cseq_header()?.seq()?);
+        // No use retransmitting a provisional once there's a final answer
+        // https://www.rfc-editor.org/rfc/rfc3262 (3)
+        if req.method == Method::Invite && status_code.kind() != StatusCodeKind::Provisional {
+            self.unacked = None;
+        }
 
         let mut headers: Headers = Default::default();
-        for header in req.headers().clone() {
+        for header in req.headers.clone() {
             match header {
                 h @ Header::CallId(_)
                 | h @ Header::CSeq(_)
                 | h @ Header::From(_)
-                | h @ Header::Via(_) => headers.push(h),
+                | h @ Header::Via(_)
+                | h @ Header::RecordRoute(_) => headers.push(h),
                 ref h @ Header::To(ref to) => match (to.tag()?, self.to.clone()) {
                     (Some(_), _) => headers.push(h.clone()),
                     (None, Some(to)) => headers.push(to.into()),
@@ -427,6 +829,26 @@ impl Dialog {
             }
         }
         headers.push(self.contact().into());
+        if status_code.kind() == StatusCodeKind::Successful
+            && matches!(req.method, Method::Invite | Method::Update)
+        {
+            headers.push(headers::Allow::new(ALLOW).into());
+            self.session_timer = SessionTimer::from_request(&req.headers)?;
+            if let Some(timer) = &self.session_timer {
+                headers.push(session_timer::session_expires_header(
+                    timer.expires,
+                    Some(timer.refresher.to_param(false)),
+                ));
+                // They have to know to refresh if it's on them
+                // https://www.rfc-editor.org/rfc/rfc4028 (9)
+                if session_timer::supports_timer(&req.headers) {
+                    headers.push(headers::Require::new(session_timer::TIMER_OPTION_TAG).into());
+                }
+            }
+            if req.headers.iter().any(|h| matches!(h, Header::Allow(_))) {
+                self.update_allowed = session_timer::allows(&req.headers, Method::Update);
+            }
+        }
         headers.push(ContentLength::from(body.len() as u32).into());
 
         Ok(SipMessage::Response(Response {
@@ -437,114 +859,473 @@ impl Dialog {
         }))
     }
 
-    pub fn add_auth_to_request(
-        &self,
-        req: &mut Request,
-        password: String,
-        opaque: Option<String>,
-        nonce: String,
-    ) {
-        let cnonce = format!("{}/{}", ms_since_epoch(), rand_chars(&mut rng(), 16));
-        // TODO(peter): Actually track this?
-        let nc = 1;
-
-        let ha1 = md5(format!("{}:{}:{}", self.username, REALM, password));
-        let ha2 = md5(format!(
-            "{}:{}:{}",
-            req.method,
-            req.uri.scheme.as_ref().unwrap_or(&Scheme::Sips),
-            self.server_host,
-        ));
-        let response = md5(format!(
-            "{}:{}:{:08x}:{}:auth:{}",
-            ha1, nonce, nc, cnonce, ha2
-        ));
+    pub fn set_to(&mut self, to: To) {
+        self.to = Some(to);
+    }
 
-        req.headers.push(
-            Authorization {
-                scheme: auth::Scheme::Digest,
-                username: self.username.clone(),
-                realm: REALM.into(),
-                nonce,
-                uri: Uri {
-                    scheme: Some(Scheme::Sips),
-                    host_with_port: self.server_host.clone(),
-                    ..Default::default()
-                },
-                response,
-                algorithm: Some(Algorithm::Md5),
-                opaque,
-                qop: Some(AuthQop::Auth { cnonce, nc }),
+    // Reads until wanted turns up, saving everything else for the next recv. An INVITE from the
+    // far end while we're waiting on our own is glare
+    // https://www.rfc-editor.org/rfc/rfc3261 (14.2)
+    async fn wait_for(&mut self, wanted: impl Fn(&SipMessage) -> bool) -> Result<SipMessage> {
+        let mut skipped = VecDeque::new();
+        let msg = loop {
+            let msg = self.recv().await?;
+            match msg {
+                ref msg if wanted(msg) => break msg.clone(),
+                SipMessage::Request(req) if req.method == Method::Invite => {
+                    let resp = self.response_to(req, StatusCode::RequestPending, vec![])?;
+                    self.send(resp).await?;
+                }
+                msg => skipped.push_back(msg),
             }
-            .into(),
-        );
-        req.headers.push(Expires::from(3600).into());
+        };
+        skipped.append(&mut self.unread);
+        self.unread = skipped;
+        Ok(msg)
     }
 
-    pub fn set_to(&mut self, to: To) {
-        self.to = Some(to);
+    // Skips over provisional responses, which are just the other side saying it's working on it
+    pub async fn final_response(&mut self) -> Result<Response> {
+        let msg = self
+            .wait_for(|msg| match msg {
+                SipMessage::Response(resp) => {
+                    resp.status_code.kind() != StatusCodeKind::Provisional
+                }
+                SipMessage::Request(_) => false,
+            })
+            .await?;
+        Ok(msg.try_into()?)
     }
 
-    pub async fn register(&mut self, password: String) -> Result<()> {
-        let req = self.new_register_request()?;
-        self.send(req).await?;
-
-        let resp: Response = self.recv().await?.try_into()?;
-        let www_auth = resp
-            .www_authenticate_header()
-            .ok_or(anyhow!("missing www auth header"))?
-            .typed()?;
+    // Sends whatever make_req builds, answering digest challenges and session intervals that are
+    // too short with a fresh one until it gets through or retrying stops making sense
+    // https://www.rfc-editor.org/rfc/rfc3261 (22.2)
+    // https://www.rfc-editor.org/rfc/rfc4028 (6)
+    async fn send_authed(
+        &mut self,
+        password: &str,
+        make_req: impl Fn(&mut Self) -> Result<Request>,
+    ) -> Result<Response> {
+        for _ in 0..MAX_AUTH_ATTEMPTS {
+            let mut req = make_req(self)?;
+            self.auth.authorize(&mut req, &self.username, password);
+            if req.method == Method::Invite {
+                self.invite = Some(req.clone());
+            }
+            self.send(req).await?;
+
+            // Trying is hop-by-hop, the challenge can still come after it
+            let resp: Response = self
+                .wait_for(|msg| match msg {
+                    SipMessage::Response(resp) => resp.status_code != StatusCode::Trying,
+                    SipMessage::Request(_) => false,
+                })
+                .await?
+                .try_into()?;
+            // The transaction layer ACKs an INVITE's challenge for us
+            match resp.status_code {
+                StatusCode::Unauthorized | StatusCode::ProxyAuthenticationRequired
+                    if self.auth.on_challenge(&resp)? => {}
+                StatusCode::SessionIntervalTooSmall if self.on_too_small(&resp)? => {}
+                _ => return Ok(resp),
+            }
+        }
+        Err(anyhow!("gave up after {} challenges", MAX_AUTH_ATTEMPTS))
+    }
 
-        let mut authed_req = self.new_register_request()?;
-        self.add_auth_to_request(&mut authed_req, password, www_auth.opaque, www_auth.nonce);
-        self.send(authed_req.clone()).await?;
+    fn on_too_small(&mut self, resp: &Response) -> Result<bool> {
+        let Some(min_se) = session_timer::min_se(&resp.headers)? else {
+            return Ok(false);
+        };
+        if min_se <= self.session_expires {
+            return Ok(false);
+        }
+        debug!("sip: session interval too small, asking for {}s", min_se);
+        self.min_se = min_se;
+        self.session_expires = min_se;
+        Ok(true)
+    }
 
-        let resp: Response = self.recv().await?.try_into()?;
+    // Returns how long the registrar will keep us around for
+    pub async fn register(&mut self, password: String) -> Result<Duration> {
+        let mut resp = self
+            .send_authed(&password, Self::new_register_request)
+            .await?;
+        if resp.status_code.kind() == StatusCodeKind::Provisional {
+            resp = self.final_response().await?;
+        }
         assert_status(&resp)?;
 
-        Ok(())
+        Ok(granted_expiry(&resp, &self.sip_instance_uuid)?
+            .unwrap_or(Duration::from_secs(REGISTER_EXPIRES.into())))
     }
 
     pub async fn invite(&mut self, password: String, to: To) -> Result<()> {
-        let sess_id = micros_since_epoch().to_string();
-        let body = self.sdp(sess_id).to_string();
-        let mut req = self.new_request(Method::Invite, body.clone().into());
-        req.uri = to.clone().uri;
-        req.headers.push(to.clone().into());
-        req.headers.push(ContentType(MediaType::Sdp(vec![])).into());
-        self.send(req).await?;
-
-        let resp: Response = self.recv().await?.try_into()?;
-        let www_auth = resp
-            .www_authenticate_header()
-            .ok_or(anyhow!("missing www auth header"))?
-            .typed()?;
+        self.invite_replacing(password, to, None).await
+    }
 
-        let mut req = self.new_request(Method::Invite, body.into());
-        req.uri = to.clone().uri;
-        req.headers.push(to.into());
-        req.headers.push(ContentType(MediaType::Sdp(vec![])).into());
-        self.add_auth_to_request(&mut req, password, www_auth.opaque, www_auth.nonce);
-        self.send(req).await?;
+    // Calls to, taking the place of a call they're already on if we were handed Replaces. Returns
+    // once it's sent, whatever comes back is for whoever's driving the call, who hands challenges
+    // back to retry_invite
+    // https://www.rfc-editor.org/rfc/rfc3891 (5)
+    pub async fn invite_replacing(
+        &mut self,
+        password: String,
+        mut to: To,
+        replaces: Option<Replaces>,
+    ) -> Result<()> {
+        // Contacts are written down as sips:, which we can't reach over a plain transport
+        // https://www.rfc-editor.org/rfc/rfc3261 (26.2.2)
+        if matches!(to.uri.scheme, Some(Scheme::Sip | Scheme::Sips)) {
+            to.uri.scheme = Some(self.transport.scheme());
+        }
+        let body = self.session.offer()?.to_string();
+        self.outgoing = Some(Outgoing {
+            to,
+            replaces,
+            body,
+            attempts: 0,
+        });
+        self.send_invite(&password).await
+    }
 
-        let resp = self.recv().await?;
-        assert_status(&resp.clone().try_into()?)?;
+    // Sends our INVITE again if resp is a challenge we can answer or a session interval that's too
+    // small for them. False means it's a real answer, or retrying won't get us anywhere
+    // https://www.rfc-editor.org/rfc/rfc3261 (22.2)
+    // https://www.rfc-editor.org/rfc/rfc4028 (6)
+    pub async fn retry_invite(&mut self, password: &str, resp: &Response) -> Result<bool> {
+        let (Some(outgoing), Some(invite)) = (&self.outgoing, &self.invite) else {
+            return Ok(false);
+        };
+        // Only the answer to the INVITE we sent last counts, and re-INVITEs answer their own
+        if self.confirmed
+            || outgoing.attempts >= MAX_AUTH_ATTEMPTS
+            || resp.cseq_header()?.seq()? != invite.cseq_header()?.seq()?
+        {
+            return Ok(false);
+        }
+        let retry = match resp.status_code {
+            StatusCode::Unauthorized | StatusCode::ProxyAuthenticationRequired => {
+                self.auth.on_challenge(resp)?
+            }
+            StatusCode::SessionIntervalTooSmall => self.on_too_small(resp)?,
+            _ => false,
+        };
+        if retry {
+            self.send_invite(password).await?;
+        }
+        Ok(retry)
+    }
 
-        Ok(())
+    async fn send_invite(&mut self, password: &str) -> Result<()> {
+        let outgoing = self.outgoing.as_mut().ok_or(anyhow!("no invite to send"))?;
+        outgoing.attempts += 1;
+        let outgoing = outgoing.clone();
+        let mut req = self.new_request(Method::Invite, outgoing.body.into());
+        req.uri = outgoing.to.uri.clone();
+        req.headers.push(outgoing.to.into());
+        req.headers.push(ContentType(MediaType::Sdp(vec![])).into());
+        if let Some(replaces) = &outgoing.replaces {
+            req.headers.push(refer::replaces_header(replaces));
+        }
+        self.auth.authorize(&mut req, &self.username, password);
+        self.invite = Some(req.clone());
+        self.send(req).await
     }
 
+    // Only for 2xx, the transaction layer ACKs everything else. It goes to the remote target with
+    // the INVITE's CSeq and credentials
+    // https://www.rfc-editor.org/rfc/rfc3261 (13.2.2.4)
     pub async fn ack(&mut self, resp: Response) -> Result<()> {
-        let mut req = self.new_request(Method::Ack, vec![]);
-
         let cseq = resp.cseq_header()?.seq()?;
-        req.cseq_header_mut()?.mut_seq(cseq)?;
+        let mut req = self.request_with_seq(Method::Ack, cseq, vec![]);
+        self.add_invite_credentials(&mut req);
+        self.last_ack = Some(req.clone());
         self.send(req).await?;
 
         Ok(())
     }
 
+    pub fn is_holding(&self) -> bool {
+        !self.session.direction.sends()
+    }
+
+    // Puts the far end on hold, or takes them off it. We've no music to play them, so hold is
+    // inactive rather than sendonly. Returns None and leaves things as they were if the far end
+    // turns it down
+    // https://www.rfc-editor.org/rfc/rfc3264 (8.4)
+    pub async fn hold(&mut self, password: String, hold: bool) -> Result<Option<MediaParams>> {
+        let direction = self.session.direction;
+        self.session.direction = match hold {
+            true => sdp::Direction::Inactive,
+            false => sdp::Direction::SendRecv,
+        };
+        let params = self.reinvite(&password).await;
+        if !matches!(params, Ok(Some(_))) {
+            self.session.direction = direction;
+        }
+        params
+    }
+
+    // Offers our session as it stands now to the far end
+    // https://www.rfc-editor.org/rfc/rfc3261 (14.1)
+    async fn reinvite(&mut self, password: &str) -> Result<Option<MediaParams>> {
+        loop {
+            let body = self.session.offer()?.to_string();
+            let mut resp = self
+                .send_authed(password, |dialog| {
+                    let mut req = dialog.new_request(Method::Invite, body.clone().into());
+                    req.headers.push(ContentType(MediaType::Sdp(vec![])).into());
+                    Ok(req)
+                })
+                .await?;
+            if resp.status_code.kind() == StatusCodeKind::Provisional {
+                resp = self.final_response().await?;
+            }
+            match resp.status_code {
+                _ if resp.status_code.kind() == StatusCodeKind::Successful => {
+                    let params = self.accept_answer(&resp);
+                    self.ack(resp).await?;
+                    return params.map(Some);
+                }
+                // They were sending us one at the same time, back off and try again
+                StatusCode::RequestPending => {
+                    let wait = match self.owns_call_id {
+                        true => self.rng.random_range(2100..=4000),
+                        false => self.rng.random_range(0..=2000),
+                    };
+                    debug!("sip: re-INVITE glare, retrying in {}ms", wait);
+                    sleep(Duration::from_millis(wait)).await;
+                }
+                // https://www.rfc-editor.org/rfc/rfc3261 (14.1)
+                StatusCode::CallTransactionDoesNotExist | StatusCode::RequestTimeout => {
+                    return Err(anyhow!("dialog is gone: {}", resp.status_code));
+                }
+                _ => return Ok(None),
+            }
+        }
+    }
+
+    // Answers a re-INVITE and returns the media to move to, or None if we turned it down. With
+    // no offer in it, we offer in the 200 and the answer comes back in the ACK
+    // https://www.rfc-editor.org/rfc/rfc3261 (14.2)
+    pub async fn answer_reinvite(&mut self, req: Request) -> Result<Option<MediaParams>> {
+        if req.body.is_empty() {
+            let offer = self.session.offer()?;
+            let resp = self.sdp_response_to(req, StatusCode::OK, offer)?;
+            self.send(resp).await?;
+            let ack: Request = self.wait_for(is_ack).await?.try_into()?;
+            return Ok(Some(self.accept_delayed_answer(&ack)?));
+        }
+
+        let (resp, params) = match self.negotiate(&req)? {
+            Some((answer, params)) => (
+                self.sdp_response_to(req, StatusCode::OK, answer)?,
+                Some(params),
+            ),
+            // Rejecting the offer leaves the session as it was
+            None => (
+                self.response_to(req, StatusCode::NotAcceptableHere, vec![])?,
+                None,
+            ),
+        };
+        self.send(resp).await?;
+        // The transaction layer soaks up the ACK for anything but a 2xx
+        if params.is_some() {
+            self.wait_for(is_ack).await?;
+        }
+        Ok(params)
+    }
+
+    // Keeps the session alive if it's on us, or gives up on it if it was on them and they never
+    // did. Returns new media if a re-INVITE moved it. Either way an error means the call's dead
+    // https://www.rfc-editor.org/rfc/rfc4028 (10)
+    pub async fn refresh_session(&mut self, password: String) -> Result<Option<MediaParams>> {
+        let Some(timer) = &self.session_timer else {
+            return Ok(None);
+        };
+        if timer.refresher == Refresher::Them {
+            return Err(anyhow!("far end never refreshed the session"));
+        }
+        if !self.update_allowed {
+            return match self.reinvite(&password).await? {
+                Some(params) => Ok(Some(params)),
+                None => Err(anyhow!("far end turned down the session refresh")),
+            };
+        }
+
+        let mut resp = self
+            .send_authed(&password, |dialog| {
+                Ok(dialog.new_request(Method::Update, vec![]))
+            })
+            .await?;
+        if resp.status_code.kind() == StatusCodeKind::Provisional {
+            resp = self.final_response().await?;
+        }
+        assert_status(&resp)?;
+        Ok(None)
+    }
+
+    // Answers an UPDATE, which is mostly the far end refreshing the session. Returns the media to
+    // move to if it had an offer in it we could take
+    // https://www.rfc-editor.org/rfc/rfc3311 (5.2)
+    pub async fn answer_update(&mut self, req: Request) -> Result<Option<MediaParams>> {
+        if req.body.is_empty() {
+            let resp = self.response_to(req, StatusCode::OK, vec![])?;
+            self.send(resp).await?;
+            return Ok(None);
+        }
+        let (resp, params) = match self.negotiate(&req)? {
+            Some((answer, params)) => (
+                self.sdp_response_to(req, StatusCode::OK, answer)?,
+                Some(params),
+            ),
+            None => (
+                self.response_to(req, StatusCode::NotAcceptableHere, vec![])?,
+                None,
+            ),
+        };
+        self.send(resp).await?;
+        Ok(params)
+    }
+
+    fn add_invite_credentials(&self, req: &mut Request) {
+        if let Some(invite) = &self.invite {
+            for header in invite.headers.iter() {
+                if let h @ (Header::Authorization(_) | Header::ProxyAuthorization(_)) = header {
+                    req.headers.push(h.clone());
+                }
+            }
+        }
+    }
+
+    // What the far end of this call would need to match it, for handing it off to someone else
+    pub fn replaces(&self) -> Result<Replaces> {
+        Ok(Replaces {
+            call_id: self.call_id.value().to_string(),
+            to_tag: self
+                .remote_tag()
+                .ok_or(anyhow!("no remote tag to replace"))?,
+            from_tag: self.local_tag(),
+        })
+    }
+
+    // Whether msg is an INVITE out to take this call over
+    // https://www.rfc-editor.org/rfc/rfc3891 (3)
+    pub fn replaced_by(&self, msg: &SipMessage) -> Result<bool> {
+        let SipMessage::Request(req) = msg else {
+            return Ok(false);
+        };
+        if req.method != Method::Invite {
+            return Ok(false);
+        }
+        Ok(match Replaces::from_headers(&req.headers)? {
+            Some(replaces) => {
+                replaces.call_id == self.call_id.value()
+                    && replaces.to_tag == self.local_tag()
+                    && Some(replaces.from_tag) == self.remote_tag()
+            }
+            None => false,
+        })
+    }
+
+    // Asks the far end to call target instead, in place of the call we have with them if there's
+    // something to replace
+    // https://www.rfc-editor.org/rfc/rfc3515 (2.4.1)
+    pub async fn refer(
+        &mut self,
+        password: String,
+        target: Uri,
+        replaces: Option<Replaces>,
+    ) -> Result<()> {
+        let referred_by = format!("<{}>", self.from.uri);
+        let mut resp = self
+            .send_authed(&password, |dialog| {
+                let mut req = dialog.new_request(Method::Refer, vec![]);
+                req.headers
+                    .push(refer::refer_to_header(&target, replaces.as_ref()));
+                req.headers
+                    .push(Header::Other("Referred-By".into(), referred_by.clone()));
+                Ok(req)
+            })
+            .await?;
+        if resp.status_code.kind() == StatusCodeKind::Provisional {
+            resp = self.final_response().await?;
+        }
+        match resp.status_code.kind() {
+            StatusCodeKind::Successful => Ok(()),
+            _ => Err(anyhow!(
+                "far end wouldn't take the transfer: {}",
+                resp.status_code
+            )),
+        }
+    }
+
+    // Takes on a transfer, returning who to call and what call of theirs to replace. They hear
+    // how it goes over NOTIFY, starting straight away
+    // https://www.rfc-editor.org/rfc/rfc3515 (2.4.4)
+    pub async fn answer_refer(&mut self, req: Request) -> Result<Option<(Uri, Option<Replaces>)>> {
+        let target = match refer::refer_to(&req.headers) {
+            Ok(Some(target)) => target,
+            Ok(None) | Err(_) => {
+                let resp = self.response_to(req, StatusCode::BadRequest, vec![])?;
+                self.send(resp).await?;
+                return Ok(None);
+            }
+        };
+        let resp = self.response_to(req, refer::accepted(), vec![])?;
+        self.send(resp).await?;
+        self.notify_refer(StatusCode::Trying).await?;
+        Ok(Some(target))
+    }
+
+    // A final status ends the implicit subscription the REFER made
+    // https://www.rfc-editor.org/rfc/rfc3515 (2.4.5)
+    pub async fn notify_refer(&mut self, status_code: StatusCode) -> Result<()> {
+        let state = match status_code.kind() {
+            StatusCodeKind::Provisional => "active;expires=60",
+            _ => "terminated;reason=noresource",
+        };
+        let mut req = self.new_request(Method::Notify, refer::sipfrag(&status_code));
+        req.headers.push(headers::Event::new(REFER_EVENT).into());
+        req.headers
+            .push(headers::SubscriptionState::new(state).into());
+        req.headers.push(
+            ContentType(MediaType::Other(
+                SIPFRAG_MEDIA_TYPE.into(),
+                vec![("version", "2.0").into()],
+            ))
+            .into(),
+        );
+        self.send(req).await
+    }
+
+    // Answers a NOTIFY and returns how the transfer it's reporting on is going
+    pub async fn answer_notify(&mut self, req: Request) -> Result<Option<StatusCode>> {
+        let is_refer = req.headers.iter().any(|h| match h {
+            Header::Event(event) => event
+                .value()
+                .trim()
+                .to_ascii_lowercase()
+                .starts_with(REFER_EVENT),
+            _ => false,
+        });
+        let status_code = match is_refer {
+            true => refer::sipfrag_status(&req.body).ok(),
+            false => None,
+        };
+        let resp = match is_refer {
+            true => self.response_to(req, StatusCode::OK, vec![])?,
+            // https://www.rfc-editor.org/rfc/rfc6665 (4.1.3)
+            false => self.response_to(req, StatusCode::BadEvent, vec![])?,
+        };
+        self.send(resp).await?;
+        Ok(status_code)
+    }
+
     pub async fn cancel(&mut self) -> Result<()> {
-        let req = self.new_request(Method::Cancel, vec![]);
+        let invite = self.invite.as_ref().ok_or(anyhow!("no invite to cancel"))?;
+        let req = cancel_for(invite)?;
         self.send(req).await?;
 
         Ok(())
@@ -556,6 +1337,44 @@ impl Dialog {
 
         Ok(())
     }
+
+    // The response comes back on recv like any other in-dialog message
+    pub async fn info_digit(&mut self, digit: u8) -> Result<()> {
+        let body = dtmf_relay::to_body(digit, dtmf_relay::DIGIT_DURATION_MS)?;
+        let mut req = self.new_request(Method::Info, body);
+        req.headers
+            .push(ContentType(MediaType::Other(DTMF_RELAY_MEDIA_TYPE.into(), vec![])).into());
+        self.send(req).await?;
+
+        Ok(())
+    }
+
+    // Responds to an INFO and returns the digit it carried, if it was DTMF at all
+    // https://www.rfc-editor.org/rfc/rfc6086 (4.2.2)
+    pub async fn answer_info(&mut self, req: Request) -> Result<Option<u8>> {
+        let is_dtmf_relay = req.headers.iter().any(|h| match h {
+            Header::ContentType(ct) => ct
+                .value()
+                .to_ascii_lowercase()
+                .starts_with(DTMF_RELAY_MEDIA_TYPE),
+            _ => false,
+        });
+        if !is_dtmf_relay {
+            let resp = self.response_to(req, StatusCode::UnsupportedMediaType, vec![])?;
+            self.send(resp).await?;
+            return Ok(None);
+        }
+
+        let digit = dtmf_relay::parse(&req.body);
+        let status_code = match digit {
+            Ok(_) => StatusCode::OK,
+            Err(_) => StatusCode::BadRequest,
+        };
+        let resp = self.response_to(req, status_code, vec![])?;
+        self.send(resp).await?;
+
+        Ok(Some(digit?))
+    }
 }
 
 #[cfg(test)]
@@ -573,11 +1392,18 @@ mod should {
 
     fn new_dummy_dialog() -> Result<(Dialog, mpsc::Sender<SipMessage>, mpsc::Receiver<SipMessage>)>
     {
+        new_dummy_dialog_over(TransportKind::Tls)
+    }
+
+    fn new_dummy_dialog_over(
+        transport: TransportKind,
+    ) -> Result<(Dialog, mpsc::Sender<SipMessage>, mpsc::Receiver<SipMessage>)> {
         let server_host = HostWithPort::from((DUMMY_HOST, DUMMY_PORT));
         let (mock_sender, mock_rx_ch) = mpsc::channel(CHANNEL_SIZE);
         let (mock_tx_ch, mock_receiver) = mpsc::channel(CHANNEL_SIZE);
         let dialog = Dialog::new(
             server_host,
+            transport,
             DUMMY_IP.parse()?,
             DUMMY_UUID,
             DUMMY_USERNAME.into(),
@@ -595,4 +1421,480 @@ mod should {
         println!("{}", req);
         Err(anyhow!("asdf"))
     }
+
+    fn response_to_invite(status: &str, from_tag: &str, headers: &str) -> Result<SipMessage> {
+        Ok(SipMessage::try_from(format!(
+            "SIP/2.0 {}\r\n\
+            Via: SIP/2.0/TLS {}:{};branch=z9hG4bKabc\r\n\
+            From: <sips:me@{}>;tag={}\r\n\
+            To: <sips:1102@{}>;tag=far\r\n\
+            Call-ID: call-1\r\n\
+            CSeq: 1 INVITE\r\n\
+            {}\
+            Content-Length: 0\r\n\r\n",
+            status, DUMMY_HOST, DUMMY_PORT, DUMMY_HOST, from_tag, DUMMY_HOST, headers,
+        ))?)
+    }
+
+    #[tokio::test]
+    async fn successfully_send_in_dialog_requests_to_the_remote_target() -> Result<()> {
+        let (mut dialog, mock_sender, mut mock_receiver) = new_dummy_dialog()?;
+        let resp = response_to_invite(
+            "200 OK",
+            &dialog.local_tag(),
+            "Contact: <sips:1102@10.0.0.9:5061;transport=TLS>;+sip.instance=\"<urn:uuid:x>\"\r\n\
+            Record-Route: <sips:p1.example;lr>, <sips:p2.example;lr>\r\n",
+        )?;
+        mock_sender.send(resp).await?;
+        let resp: Response = dialog.recv().await?.try_into()?;
+
+        dialog.ack(resp).await?;
+        let ack: Request = mock_receiver.recv().await.unwrap().try_into()?;
+        assert_eq!(ack.method, Method::Ack);
+        assert_eq!(ack.cseq_header()?.seq()?, 1);
+        assert_eq!(ack.uri.to_string(), "sips:1102@10.0.0.9:5061;transport=TLS");
+        let froms = ack
+            .headers
+            .iter()
+            .filter(|h| matches!(h, Header::From(_)))
+            .count();
+        assert_eq!(froms, 1);
+
+        dialog.bye().await?;
+        let bye: Request = mock_receiver.recv().await.unwrap().try_into()?;
+        assert_eq!(bye.uri, ack.uri);
+        assert_eq!(bye.cseq_header()?.seq()?, 1);
+        assert_eq!(
+            bye.to_header()?.tag()?.map(|t| t.to_string()),
+            Some("far".into())
+        );
+        // We're the UAC, so the route set is backwards from how it came in
+        let routes: Vec<String> = bye
+            .headers
+            .iter()
+            .filter_map(|h| match h {
+                Header::Route(route) => Some(route.value().to_string()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(routes, vec!["<sips:p2.example;lr>", "<sips:p1.example;lr>"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn successfully_cancel_with_the_invites_branch() -> Result<()> {
+        let (mut dialog, _, mut mock_receiver) = new_dummy_dialog()?;
+        dialog.session.set_port(10000);
+        let to = To {
+            display_name: None,
+            uri: Uri::try_from("sips:1102@testy.pbx.corm")?,
+            params: vec![],
+        };
+        dialog.invite("hunter2".into(), to).await?;
+        dialog.cancel().await?;
+
+        let invite: Request = mock_receiver.recv().await.unwrap().try_into()?;
+        let cancel: Request = mock_receiver.recv().await.unwrap().try_into()?;
+        assert_eq!(cancel.method, Method::Cancel);
+        assert_eq!(cancel.uri, invite.uri);
+        assert_eq!(
+            cancel.via_header()?.typed()?.branch(),
+            invite.via_header()?.typed()?.branch()
+        );
+        assert_eq!(cancel.cseq_header()?.seq()?, invite.cseq_header()?.seq()?);
+        assert_eq!(cancel.to_header()?.tag()?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn successfully_put_the_far_end_on_hold() -> Result<()> {
+        let (mut dialog, mock_sender, mut mock_receiver) = new_dummy_dialog()?;
+        let contact = "Contact: <sips:1102@10.0.0.9:5061;transport=TLS>\r\n";
+        let ok = response_to_invite("200 OK", &dialog.local_tag(), contact)?;
+        mock_sender.send(ok).await?;
+        let ok: Response = dialog.recv().await?.try_into()?;
+        dialog.ack(ok).await?;
+        mock_receiver.recv().await.unwrap();
+
+        let ok = response_to_invite(
+            "200 OK",
+            &dialog.local_tag(),
+            &format!("{}Content-Type: application/sdp\r\n", contact),
+        )?
+        .to_string()
+        .replace("CSeq: 1 INVITE", "CSeq: 2 INVITE");
+        let mut ok = Response::try_from(ok)?;
+        ok.body = "v=0\r\n\
+            o=- 1 2 IN IP4 10.0.0.9\r\n\
+            s=-\r\n\
+            c=IN IP4 10.0.0.9\r\n\
+            t=0 0\r\n\
+            m=audio 5004 RTP/AVP 0\r\n\
+            a=inactive\r\n"
+            .into();
+        mock_sender.send(ok.into()).await?;
+        dialog.session.set_port(10000);
+
+        let params = dialog
+            .hold("hunter2".into(), true)
+            .await?
+            .ok_or(anyhow!("hold was turned down"))?;
+        assert_eq!(params.direction, sdp::Direction::Inactive);
+        assert!(dialog.is_holding());
+
+        let invite: Request = mock_receiver.recv().await.unwrap().try_into()?;
+        assert_eq!(invite.method, Method::Invite);
+        assert_eq!(
+            invite.uri.to_string(),
+            "sips:1102@10.0.0.9:5061;transport=TLS"
+        );
+        assert!(String::from_utf8(invite.body)?.contains("a=inactive"));
+        let ack: Request = mock_receiver.recv().await.unwrap().try_into()?;
+        assert_eq!(ack.method, Method::Ack);
+        assert_eq!(ack.cseq_header()?.seq()?, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn successfully_ask_again_when_the_session_interval_is_too_small() -> Result<()> {
+        let (mut dialog, mock_sender, mut mock_receiver) = new_dummy_dialog()?;
+        let too_small = response_to_invite(
+            "422 Session Interval Too Small",
+            &dialog.local_tag(),
+            "Min-SE: 3600\r\n",
+        )?;
+        mock_sender.send(too_small).await?;
+        let ringing = response_to_invite("180 Ringing", &dialog.local_tag(), "")?;
+        mock_sender.send(ringing).await?;
+        dialog.session.set_port(10000);
+        let to = To {
+            display_name: None,
+            uri: Uri::try_from("sips:1102@testy.pbx.corm")?,
+            params: vec![],
+        };
+        dialog.invite("hunter2".into(), to).await?;
+        let too_small: Response = dialog.recv().await?.try_into()?;
+        assert!(dialog.retry_invite("hunter2", &too_small).await?);
+        let ringing: Response = dialog.recv().await?.try_into()?;
+        assert!(!dialog.retry_invite("hunter2", &ringing).await?);
+
+        let first: Request = mock_receiver.recv().await.unwrap().try_into()?;
+        assert_eq!(
+            session_timer::session_expires(&first.headers)?,
+            Some((SESSION_EXPIRES, None))
+        );
+        let second: Request = mock_receiver.recv().await.unwrap().try_into()?;
+        assert_eq!(
+            session_timer::session_expires(&second.headers)?,
+            Some((3600, None))
+        );
+        assert_eq!(session_timer::min_se(&second.headers)?, Some(3600));
+        assert!(session_timer::supports_timer(&second.headers));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn successfully_answer_a_challenge_to_an_invite() -> Result<()> {
+        let (mut dialog, mock_sender, mut mock_receiver) = new_dummy_dialog()?;
+        let challenge = "WWW-Authenticate: Digest realm=\"asterisk\", nonce=\"n1\"\r\n";
+        let unauthorized = response_to_invite("401 Unauthorized", &dialog.local_tag(), challenge)?;
+        mock_sender.send(unauthorized.clone()).await?;
+        let again = unauthorized
+            .to_string()
+            .replace("CSeq: 1 INVITE", "CSeq: 2 INVITE");
+        mock_sender.send(SipMessage::try_from(again)?).await?;
+        dialog.session.set_port(10000);
+        let to = To {
+            display_name: None,
+            uri: Uri::try_from("sips:1102@testy.pbx.corm")?,
+            params: vec![],
+        };
+        dialog.invite("hunter2".into(), to).await?;
+
+        let first: Request = mock_receiver.recv().await.unwrap().try_into()?;
+        assert!(first.authorization_header().is_none());
+        let resp: Response = dialog.recv().await?.try_into()?;
+        assert!(dialog.retry_invite("hunter2", &resp).await?);
+        let second: Request = mock_receiver.recv().await.unwrap().try_into()?;
+        assert_eq!(second.cseq_header()?.seq()?, 2);
+        assert!(second
+            .authorization_header()
+            .ok_or(anyhow!("no authorization"))?
+            .value()
+            .contains("nonce=\"n1\""));
+
+        // The same nonce again means it's the password that's wrong
+        let resp: Response = dialog.recv().await?.try_into()?;
+        assert!(!dialog.retry_invite("hunter2", &resp).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn successfully_leave_refreshing_to_a_caller_that_supports_timers() -> Result<()> {
+        let (mut dialog, _, _) = new_dummy_dialog()?;
+        let invite = Request::try_from(format!(
+            "INVITE sips:me@{} SIP/2.0\r\n\
+            Via: SIP/2.0/TLS 10.0.0.9:5061;branch=z9hG4bKabc\r\n\
+            From: <sips:1102@{}>;tag=far\r\n\
+            To: <sips:me@{}>\r\n\
+            Call-ID: call-1\r\n\
+            CSeq: 1 INVITE\r\n\
+            Supported: timer\r\n\
+            Allow: INVITE, ACK, BYE, UPDATE\r\n\
+            Session-Expires: 600\r\n\
+            Content-Length: 0\r\n\r\n",
+            DUMMY_HOST, DUMMY_HOST, DUMMY_HOST,
+        ))?;
+        assert!(dialog.session_deadline().is_none());
+
+        let ok: Response = dialog
+            .response_to(invite, StatusCode::OK, vec![])?
+            .try_into()?;
+        assert_eq!(
+            session_timer::session_expires(&ok.headers)?,
+            Some((600, Some("uac".into())))
+        );
+        assert!(ok.headers.iter().any(|h| matches!(h, Header::Require(_))));
+        assert!(dialog.session_deadline().is_some());
+        assert!(dialog.update_allowed);
+        Ok(())
+    }
+
+    #[test]
+    fn successfully_offer_to_an_invite_without_one() -> Result<()> {
+        let (mut dialog, _, _) = new_dummy_dialog()?;
+        dialog.session.set_port(10000);
+        let invite = |body: &str| {
+            Request::try_from(format!(
+                "INVITE sips:me@{} SIP/2.0\r\n\
+                Via: SIP/2.0/TLS 10.0.0.9:5061;branch=z9hG4bKabc\r\n\
+                From: <sips:1102@{}>;tag=far\r\n\
+                To: <sips:me@{}>\r\n\
+                Call-ID: call-1\r\n\
+                CSeq: 1 INVITE\r\n\
+                Content-Length: {}\r\n\r\n{}",
+                DUMMY_HOST,
+                DUMMY_HOST,
+                DUMMY_HOST,
+                body.len(),
+                body,
+            ))
+        };
+
+        let (offer, params) = dialog
+            .negotiate_invite(&invite("")?)?
+            .ok_or(anyhow!("no offer"))?;
+        assert!(params.is_none());
+        assert!(!offer.to_string().is_empty());
+
+        let mut ack = invite("")?;
+        ack.method = Method::Ack;
+        ack.body = "v=0\r\n\
+            o=- 1 1 IN IP4 10.0.0.9\r\n\
+            s=-\r\n\
+            c=IN IP4 10.0.0.9\r\n\
+            t=0 0\r\n\
+            m=audio 5004 RTP/AVP 0\r\n"
+            .into();
+        let params = dialog.accept_delayed_answer(&ack)?;
+        assert_eq!(params.remote, "10.0.0.9:5004".parse()?);
+
+        assert!(dialog.negotiate_invite(&invite("not sdp")?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn successfully_listen_to_early_media() -> Result<()> {
+        let (mut dialog, _, _) = new_dummy_dialog()?;
+        dialog.session.set_port(10000);
+        dialog.session.offer()?;
+        let sdp = "v=0\r\n\
+            o=- 1 1 IN IP4 10.0.0.9\r\n\
+            s=-\r\n\
+            c=IN IP4 10.0.0.9\r\n\
+            t=0 0\r\n\
+            m=audio 5004 RTP/AVP 0\r\n";
+
+        let mut progress: Response = response_to_invite(
+            "183 Session Progress",
+            &dialog.local_tag(),
+            "Content-Type: application/sdp\r\n",
+        )?
+        .try_into()?;
+        progress.body = sdp.into();
+        let params = dialog
+            .early_media(&progress)?
+            .ok_or(anyhow!("no early media"))?;
+        assert_eq!(params.direction, sdp::Direction::RecvOnly);
+        assert_eq!(params.remote, "10.0.0.9:5004".parse()?);
+
+        let ringing: Response =
+            response_to_invite("180 Ringing", &dialog.local_tag(), "")?.try_into()?;
+        assert!(dialog.early_media(&ringing)?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn successfully_prack_reliable_provisionals_once() -> Result<()> {
+        let (mut dialog, mock_sender, mut mock_receiver) = new_dummy_dialog()?;
+        let reliable = |status: &str, rseq: u32| {
+            response_to_invite(
+                status,
+                &dialog.local_tag(),
+                &format!(
+                    "Contact: <sips:1102@10.0.0.9:5061;transport=TLS>\r\n\
+                    Require: 100rel\r\n\
+                    RSeq: {}\r\n",
+                    rseq
+                ),
+            )
+        };
+        mock_sender.send(reliable("180 Ringing", 5)?).await?;
+        mock_sender.send(reliable("180 Ringing", 5)?).await?;
+        mock_sender
+            .send(reliable("183 Session Progress", 6)?)
+            .await?;
+
+        let ringing: Response = dialog.recv().await?.try_into()?;
+        assert_eq!(ringing.status_code, StatusCode::Ringing);
+        let progress: Response = dialog.recv().await?.try_into()?;
+        assert_eq!(progress.status_code, StatusCode::SessionProgress);
+
+        for rseq in [5, 6] {
+            let prack: Request = mock_receiver.recv().await.unwrap().try_into()?;
+            assert_eq!(prack.method, Method::PRack);
+            assert_eq!(
+                prack.uri.to_string(),
+                "sips:1102@10.0.0.9:5061;transport=TLS"
+            );
+            assert_eq!(
+                prack::rack(&prack.headers)?,
+                Some((rseq, 1, Method::Invite))
+            );
+        }
+        assert!(mock_receiver.try_recv().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn successfully_ring_reliably_until_pracked() -> Result<()> {
+        let (mut dialog, mock_sender, mut mock_receiver) = new_dummy_dialog()?;
+        let local_tag = dialog.local_tag();
+        let request = |method: &str, cseq: u32, headers: &str| {
+            Request::try_from(format!(
+                "{} sips:me@{} SIP/2.0\r\n\
+                Via: SIP/2.0/TLS 10.0.0.9:5061;branch=z9hG4bK{}\r\n\
+                From: <sips:1102@{}>;tag=far\r\n\
+                To: <sips:me@{}>{}\r\n\
+                Call-ID: call-1\r\n\
+                CSeq: {} {}\r\n\
+                {}\
+                Content-Length: 0\r\n\r\n",
+                method,
+                DUMMY_HOST,
+                cseq,
+                DUMMY_HOST,
+                DUMMY_HOST,
+                match method {
+                    "INVITE" => String::new(),
+                    _ => format!(";tag={}", local_tag),
+                },
+                cseq,
+                method,
+                headers,
+            ))
+        };
+        let invite = request("INVITE", 1, "Supported: 100rel, timer\r\n")?;
+        dialog.ringing(invite).await?;
+        let ringing: Response = mock_receiver.recv().await.unwrap().try_into()?;
+        assert!(prack::requires_100rel(&ringing.headers));
+        let rseq = prack::rseq(&ringing.headers)?.ok_or(anyhow!("no RSeq"))?;
+
+        let prack = request("PRACK", 2, &format!("RAck: {} 1 INVITE\r\n", rseq))?;
+        mock_sender.send(prack.into()).await?;
+        let bye = request("BYE", 3, "")?;
+        mock_sender.send(bye.into()).await?;
+        let bye: Request = dialog.recv().await?.try_into()?;
+        assert_eq!(bye.method, Method::Bye);
+
+        let ok: Response = mock_receiver.recv().await.unwrap().try_into()?;
+        assert_eq!(ok.status_code, StatusCode::OK);
+        assert_eq!(ok.cseq_header()?.method()?, Method::PRack);
+        assert!(dialog.unacked.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn successfully_refer_with_replaces() -> Result<()> {
+        let (mut consult, consult_sender, mut consult_receiver) = new_dummy_dialog()?;
+        let ok = response_to_invite("200 OK", &consult.local_tag(), "")?;
+        consult_sender.send(ok).await?;
+        let ok: Response = consult.recv().await?.try_into()?;
+        consult.ack(ok).await?;
+        consult_receiver.recv().await.unwrap();
+        let replaces = consult.replaces()?;
+        assert_eq!(replaces.to_tag, "far");
+        assert_eq!(replaces.from_tag, consult.local_tag());
+
+        let (mut held, held_sender, mut held_receiver) = new_dummy_dialog()?;
+        let ok = response_to_invite("200 OK", &held.local_tag(), "")?;
+        held_sender.send(ok).await?;
+        held.recv().await?;
+        let accepted = response_to_invite("202 Accepted", &held.local_tag(), "")?
+            .to_string()
+            .replace("CSeq: 1 INVITE", "CSeq: 1 REFER");
+        held_sender
+            .send(Response::try_from(accepted)?.into())
+            .await?;
+        let target = Uri::try_from("sips:1103@testy.pbx.corm")?;
+        held.refer("hunter2".into(), target.clone(), Some(replaces.clone()))
+            .await?;
+
+        let refer: Request = held_receiver.recv().await.unwrap().try_into()?;
+        assert_eq!(refer.method, Method::Refer);
+        assert_eq!(
+            refer::refer_to(&refer.headers)?,
+            Some((target, Some(replaces)))
+        );
+
+        // What the transfer target would get, as seen from their side
+        let invite = SipMessage::try_from(format!(
+            "INVITE sips:me@{} SIP/2.0\r\n\
+            Via: SIP/2.0/TLS 10.0.0.9:5061;branch=z9hG4bKdef\r\n\
+            From: <sips:1102@{}>;tag=other\r\n\
+            To: <sips:me@{}>\r\n\
+            Call-ID: new-call\r\n\
+            CSeq: 1 INVITE\r\n\
+            Replaces: {};to-tag={};from-tag=far\r\n\
+            Content-Length: 0\r\n\r\n",
+            DUMMY_HOST,
+            DUMMY_HOST,
+            DUMMY_HOST,
+            consult.call_id.value(),
+            consult.local_tag(),
+        ))?;
+        assert!(consult.replaced_by(&invite)?);
+        assert!(!held.replaced_by(&invite)?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn successfully_invite_over_udp_with_plain_sip_uris() -> Result<()> {
+        let (mut dialog, _, mut mock_receiver) = new_dummy_dialog_over(TransportKind::Udp)?;
+        dialog.session.set_port(10000);
+        let to = To {
+            display_name: None,
+            uri: Uri::try_from("sips:1102@testy.pbx.corm")?,
+            params: vec![],
+        };
+        dialog.invite("hunter2".into(), to).await?;
+
+        let invite: Request = mock_receiver.recv().await.unwrap().try_into()?;
+        assert_eq!(invite.uri.to_string(), "sip:1102@testy.pbx.corm");
+        assert!(invite.via_header()?.value().starts_with("SIP/2.0/UDP "));
+        let contact = invite.contact_header()?.value().to_string();
+        assert!(contact.contains("<sip:Testy Guy@testy.pbx.corm:5060;transport=UDP;ob>"));
+        assert!(invite.from_header()?.value().contains("<sip:"));
+        Ok(())
+    }
 }
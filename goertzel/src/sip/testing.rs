@@ -0,0 +1,28 @@
+use std::fmt::Debug;
+
+// A message to or from the PBX with just enough in it to parse. Tests fill in what they're after
+pub(super) fn message<T>(
+    first_line: &str,
+    from_tag: &str,
+    to_tag: Option<&str>,
+    cseq: &str,
+    headers: &str,
+) -> T
+where
+    T: TryFrom<String>,
+    T::Error: Debug,
+{
+    let to_tag = to_tag.map(|t| format!(";tag={}", t)).unwrap_or_default();
+    T::try_from(format!(
+        "{}\r\n\
+        Via: SIP/2.0/TLS 10.0.0.1:5061;branch=z9hG4bKabc\r\n\
+        From: <sips:1102@pbx.frandline.com>;tag={}\r\n\
+        To: <sips:1102@pbx.frandline.com>{}\r\n\
+        Call-ID: call-1\r\n\
+        CSeq: {}\r\n\
+        {}\
+        Content-Length: 0\r\n\r\n",
+        first_line, from_tag, to_tag, cseq, headers,
+    ))
+    .unwrap()
+}
@@ -0,0 +1,770 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use rsip::headers::{ContentLength, MaxForwards};
+use rsip::prelude::*;
+use rsip::typed::CSeq;
+use rsip::{Header, Headers, Method, Request, Response, SipMessage, StatusCode, StatusCodeKind};
+use tracing::{debug, warn};
+
+// Transactions between the connection and dialogs, so retransmissions and stray messages get
+// dealt with here instead of showing up as whatever a dialog recv's next
+// https://www.rfc-editor.org/rfc/rfc3261 (17)
+pub const T1: Duration = Duration::from_millis(500);
+pub const T2: Duration = Duration::from_secs(4);
+pub const T4: Duration = Duration::from_secs(5);
+// Timers B, F, H, J, L and M
+const TRANSACTION_TIMEOUT: Duration = Duration::from_millis(64 * 500);
+// Timer D
+const INVITE_COMPLETED_TIMEOUT: Duration = Duration::from_secs(32);
+// How long a request can go without a final response from us before we forget it. An INVITE
+// gets as long as a proxy would give it to ring (Timer C)
+// https://www.rfc-editor.org/rfc/rfc3261 (16.6)
+const UNANSWERED_INVITE_TIMEOUT: Duration = Duration::from_secs(180);
+// How long we give a dialog to say something before we send 100 Trying for it
+// https://www.rfc-editor.org/rfc/rfc3261 (17.2.1)
+const TRYING_DELAY: Duration = Duration::from_millis(200);
+
+const BRANCH_PREFIX: &str = "z9hG4bK";
+const MAX_FORWARDS: u32 = 70;
+
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    // Put it on the wire
+    Transmit(SipMessage),
+    // Hand it up to whichever dialog it belongs to
+    Deliver(SipMessage),
+}
+
+// https://www.rfc-editor.org/rfc/rfc3261 (17.1.3)
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct ClientKey {
+    branch: String,
+    method: String,
+}
+
+// https://www.rfc-editor.org/rfc/rfc3261 (17.2.3)
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct ServerKey {
+    branch: String,
+    sent_by: String,
+    method: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    // INVITE client
+    Calling,
+    // Non-INVITE client and server
+    Trying,
+    Proceeding,
+    // 2xx to an INVITE, the dialog deals with those from here
+    // https://www.rfc-editor.org/rfc/rfc6026 (7.1)
+    Accepted,
+    Completed,
+    // INVITE server
+    Confirmed,
+}
+
+struct Timers {
+    // Timer A, E or G, or 2xx retransmission for an INVITE server
+    retransmit_at: Option<Instant>,
+    retransmit_interval: Duration,
+    // Whichever of the others applies to the state we're in
+    timeout_at: Option<Instant>,
+}
+
+impl Timers {
+    fn new(now: Instant, retransmit: bool, timeout: Option<Duration>) -> Self {
+        Self {
+            retransmit_at: retransmit.then_some(now + T1),
+            retransmit_interval: T1,
+            timeout_at: timeout.map(|timeout| now + timeout),
+        }
+    }
+
+    fn retransmit(&mut self, now: Instant, max_interval: Duration) {
+        self.retransmit_interval = (self.retransmit_interval * 2).min(max_interval);
+        self.retransmit_at = Some(now + self.retransmit_interval);
+    }
+}
+
+struct ClientTransaction {
+    request: Request,
+    state: State,
+    timers: Timers,
+    // For a non-2xx final response, sent again if they retransmit it
+    ack: Option<Request>,
+}
+
+struct ServerTransaction {
+    request: Request,
+    state: State,
+    timers: Timers,
+    last_response: Option<Response>,
+    trying_at: Option<Instant>,
+}
+
+pub struct Transactions {
+    // Nothing gets retransmitted over TCP and TLS, and there's no lingering to soak up
+    // retransmissions that can't happen
+    reliable: bool,
+    clients: HashMap<ClientKey, ClientTransaction>,
+    servers: HashMap<ServerKey, ServerTransaction>,
+}
+
+impl Transactions {
+    pub fn new(reliable: bool) -> Self {
+        Self {
+            reliable,
+            clients: HashMap::new(),
+            servers: HashMap::new(),
+        }
+    }
+
+    // Something a dialog wants to send
+    pub fn on_send(&mut self, msg: SipMessage, now: Instant) -> Result<Vec<Action>> {
+        match &msg {
+            // ACKs to 2xx are end to end and aren't part of any transaction
+            // https://www.rfc-editor.org/rfc/rfc3261 (17.1.1.3)
+            SipMessage::Request(req) if req.method == Method::Ack => {}
+            SipMessage::Request(req) => {
+                if let Some(key) = client_key(req, req.method)? {
+                    let state = match req.method {
+                        Method::Invite => State::Calling,
+                        _ => State::Trying,
+                    };
+                    self.clients.insert(
+                        key,
+                        ClientTransaction {
+                            request: req.clone(),
+                            state,
+                            timers: Timers::new(now, !self.reliable, Some(TRANSACTION_TIMEOUT)),
+                            ack: None,
+                        },
+                    );
+                }
+            }
+            SipMessage::Response(resp) => {
+                if let Some(key) = server_key(resp, resp.cseq_header()?.method()?)? {
+                    if let Some(txn) = self.servers.get_mut(&key) {
+                        txn.on_response(resp.clone(), self.reliable, now);
+                    }
+                }
+            }
+        }
+        Ok(vec![Action::Transmit(msg)])
+    }
+
+    // Something that came in off the wire
+    pub fn on_recv(&mut self, msg: SipMessage, now: Instant) -> Result<Vec<Action>> {
+        match msg {
+            SipMessage::Response(resp) => self.on_recv_response(resp, now),
+            SipMessage::Request(req) => self.on_recv_request(req, now),
+        }
+    }
+
+    fn on_recv_response(&mut self, resp: Response, now: Instant) -> Result<Vec<Action>> {
+        let Some(key) = client_key(&resp, resp.cseq_header()?.method()?)? else {
+            return Ok(vec![Action::Deliver(resp.into())]);
+        };
+        let Some(txn) = self.clients.get_mut(&key) else {
+            debug!(
+                "sip: dropping stray response {} for {}",
+                resp.status_code, key.method
+            );
+            return Ok(vec![]);
+        };
+        let kind = resp.status_code.kind();
+        let is_invite = txn.request.method == Method::Invite;
+        let actions = match (txn.state, kind) {
+            (State::Calling | State::Trying | State::Proceeding, StatusCodeKind::Provisional) => {
+                txn.state = State::Proceeding;
+                if is_invite {
+                    // Timer B only runs while Calling, it can ring for as long as it likes
+                    // https://www.rfc-editor.org/rfc/rfc3261 (17.1.1.2)
+                    txn.timers.retransmit_at = None;
+                    txn.timers.timeout_at = None;
+                } else if txn.timers.retransmit_at.is_some() {
+                    // https://www.rfc-editor.org/rfc/rfc3261 (17.1.2.2)
+                    txn.timers.retransmit_interval = T2;
+                }
+                vec![Action::Deliver(resp.into())]
+            }
+            (State::Calling | State::Proceeding, StatusCodeKind::Successful) if is_invite => {
+                txn.state = State::Accepted;
+                txn.timers = Timers::new(now, false, Some(TRANSACTION_TIMEOUT));
+                vec![Action::Deliver(resp.into())]
+            }
+            // Retransmitted 2xx go up to the dialog so it can ACK them again
+            (State::Accepted, StatusCodeKind::Successful) => vec![Action::Deliver(resp.into())],
+            (State::Accepted, _) => vec![],
+            (State::Calling | State::Proceeding, _) if is_invite => {
+                let ack = ack_for(&txn.request, &resp)?;
+                txn.ack = Some(ack.clone());
+                txn.state = State::Completed;
+                let timeout = (!self.reliable).then_some(INVITE_COMPLETED_TIMEOUT);
+                txn.timers = Timers::new(now, false, Some(timeout.unwrap_or_default()));
+                vec![Action::Transmit(ack.into()), Action::Deliver(resp.into())]
+            }
+            (State::Calling | State::Trying | State::Proceeding, _) => {
+                txn.state = State::Completed;
+                let timeout = (!self.reliable).then_some(T4);
+                txn.timers = Timers::new(now, false, Some(timeout.unwrap_or_default()));
+                vec![Action::Deliver(resp.into())]
+            }
+            // They didn't get our ACK
+            (State::Completed, StatusCodeKind::Provisional) => vec![],
+            (State::Completed, _) => txn
+                .ack
+                .clone()
+                .map(|ack| Action::Transmit(ack.into()))
+                .into_iter()
+                .collect(),
+            (State::Confirmed, _) => vec![],
+        };
+        Ok(actions)
+    }
+
+    fn on_recv_request(&mut self, req: Request, now: Instant) -> Result<Vec<Action>> {
+        // ACKs to non-2xx belong to the INVITE transaction
+        let method = match req.method {
+            Method::Ack => Method::Invite,
+            method => method,
+        };
+        let Some(key) = server_key(&req, method)? else {
+            return Ok(vec![Action::Deliver(req.into())]);
+        };
+
+        if let Some(txn) = self.servers.get_mut(&key) {
+            let actions = match (req.method, txn.state) {
+                (Method::Ack, State::Completed) => {
+                    txn.state = State::Confirmed;
+                    let timeout = (!self.reliable).then_some(T4);
+                    txn.timers = Timers::new(now, false, Some(timeout.unwrap_or_default()));
+                    vec![]
+                }
+                (Method::Ack, _) => vec![],
+                // Retransmissions get whatever we last said, if anything
+                (_, State::Proceeding | State::Completed) => txn
+                    .last_response
+                    .clone()
+                    .map(|resp| Action::Transmit(resp.into()))
+                    .into_iter()
+                    .collect(),
+                (_, _) => vec![],
+            };
+            return Ok(actions);
+        }
+
+        match req.method {
+            // We answer CANCEL ourselves, and the INVITE it's for if nobody has yet, so the dialog
+            // only has to hear about it
+            // https://www.rfc-editor.org/rfc/rfc3261 (9.2)
+            Method::Cancel => {
+                let invite_key = ServerKey {
+                    method: Method::Invite.to_string(),
+                    ..key.clone()
+                };
+                let mut actions = vec![];
+                let status_code = match self.servers.get_mut(&invite_key) {
+                    Some(invite) => {
+                        if invite.state == State::Proceeding {
+                            let mut terminated =
+                                response_to(&invite.request, StatusCode::RequestTerminated);
+                            // With the To tag of whatever provisional they've had from us
+                            if let Some(to) = invite
+                                .last_response
+                                .as_ref()
+                                .and_then(|resp| resp.to_header().ok())
+                            {
+                                *terminated.to_header_mut()? = to.clone();
+                            }
+                            invite.on_response(terminated.clone(), self.reliable, now);
+                            actions.push(Action::Transmit(terminated.into()));
+                            actions.push(Action::Deliver(req.clone().into()));
+                        }
+                        StatusCode::OK
+                    }
+                    None => StatusCode::CallTransactionDoesNotExist,
+                };
+                let resp = response_to(&req, status_code);
+                let mut cancel = ServerTransaction {
+                    request: req,
+                    state: State::Trying,
+                    timers: Timers::new(now, false, Some(TRANSACTION_TIMEOUT)),
+                    last_response: None,
+                    trying_at: None,
+                };
+                cancel.on_response(resp.clone(), self.reliable, now);
+                self.servers.insert(key, cancel);
+                actions.insert(0, Action::Transmit(resp.into()));
+                return Ok(actions);
+            }
+            Method::Ack => {
+                // The ACK for a 2xx, which gets its own branch, so stop resending the 2xx
+                let call_id = req.call_id_header()?.value().to_string();
+                let seq = req.cseq_header()?.seq()?;
+                for txn in self.servers.values_mut() {
+                    if txn.state == State::Accepted
+                        && txn.request.call_id_header()?.value() == call_id
+                        && txn.request.cseq_header()?.seq()? == seq
+                    {
+                        txn.timers.retransmit_at = None;
+                    }
+                }
+            }
+            method => {
+                let (state, trying_at, timeout) = match method {
+                    Method::Invite => (
+                        State::Proceeding,
+                        Some(now + TRYING_DELAY),
+                        UNANSWERED_INVITE_TIMEOUT,
+                    ),
+                    _ => (State::Trying, None, TRANSACTION_TIMEOUT),
+                };
+                self.servers.insert(
+                    key,
+                    ServerTransaction {
+                        request: req.clone(),
+                        state,
+                        timers: Timers::new(now, false, Some(timeout)),
+                        last_response: None,
+                        trying_at,
+                    },
+                );
+            }
+        }
+        Ok(vec![Action::Deliver(req.into())])
+    }
+
+    pub fn on_tick(&mut self, now: Instant) -> Vec<Action> {
+        let mut actions = vec![];
+        let reliable = self.reliable;
+
+        self.clients.retain(|key, txn| {
+            if txn.timers.retransmit_at.is_some_and(|at| at <= now) {
+                let max_interval = match txn.request.method {
+                    Method::Invite => Duration::MAX,
+                    _ => T2,
+                };
+                txn.timers.retransmit(now, max_interval);
+                actions.push(Action::Transmit(txn.request.clone().into()));
+            }
+            if txn.timers.timeout_at.is_some_and(|at| at <= now) {
+                if matches!(
+                    txn.state,
+                    State::Calling | State::Trying | State::Proceeding
+                ) {
+                    warn!("sip: {} transaction {} timed out", key.method, key.branch);
+                    // Timeouts look like a 408 to the dialog
+                    // https://www.rfc-editor.org/rfc/rfc3261 (8.1.3.1)
+                    let resp = response_to(&txn.request, StatusCode::RequestTimeout);
+                    actions.push(Action::Deliver(resp.into()));
+                }
+                return false;
+            }
+            true
+        });
+
+        self.servers.retain(|key, txn| {
+            if txn.trying_at.is_some_and(|at| at <= now) {
+                txn.trying_at = None;
+                let trying = response_to(&txn.request, StatusCode::Trying);
+                txn.last_response = Some(trying.clone());
+                actions.push(Action::Transmit(trying.into()));
+            }
+            if txn.timers.retransmit_at.is_some_and(|at| at <= now) {
+                txn.timers.retransmit(now, T2);
+                if let Some(resp) = &txn.last_response {
+                    actions.push(Action::Transmit(resp.clone().into()));
+                }
+            }
+            if txn.timers.timeout_at.is_some_and(|at| at <= now) {
+                match txn.state {
+                    State::Completed if !reliable => {
+                        warn!("sip: never got an ACK for {}", key.branch)
+                    }
+                    State::Trying | State::Proceeding => {
+                        warn!("sip: never answered {} {}", key.method, key.branch)
+                    }
+                    _ => {}
+                }
+                return false;
+            }
+            true
+        });
+
+        actions
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let clients = self.clients.values().flat_map(|txn| {
+            [txn.timers.retransmit_at, txn.timers.timeout_at]
+                .into_iter()
+                .flatten()
+        });
+        let servers = self.servers.values().flat_map(|txn| {
+            [
+                txn.timers.retransmit_at,
+                txn.timers.timeout_at,
+                txn.trying_at,
+            ]
+            .into_iter()
+            .flatten()
+        });
+        clients.chain(servers).min()
+    }
+}
+
+impl ServerTransaction {
+    fn on_response(&mut self, resp: Response, reliable: bool, now: Instant) {
+        self.trying_at = None;
+        let is_invite = self.request.method == Method::Invite;
+        match resp.status_code.kind() {
+            StatusCodeKind::Provisional => self.state = State::Proceeding,
+            // We keep sending the 2xx until the ACK turns up
+            // https://www.rfc-editor.org/rfc/rfc3261 (13.3.1.4)
+            StatusCodeKind::Successful if is_invite => {
+                self.state = State::Accepted;
+                self.timers = Timers::new(now, true, Some(TRANSACTION_TIMEOUT));
+            }
+            // Timers G and H
+            _ if is_invite => {
+                self.state = State::Completed;
+                self.timers = Timers::new(now, !reliable, Some(TRANSACTION_TIMEOUT));
+            }
+            // Timer J
+            _ => {
+                self.state = State::Completed;
+                let timeout = (!reliable).then_some(TRANSACTION_TIMEOUT);
+                self.timers = Timers::new(now, false, Some(timeout.unwrap_or_default()));
+            }
+        }
+        self.last_response = Some(resp);
+    }
+}
+
+// Only RFC 3261 branches are unique enough to match on, anything else goes straight through
+fn branch(msg: &impl HeadersExt) -> Result<Option<String>> {
+    Ok(msg
+        .via_header()?
+        .typed()?
+        .branch()
+        .map(|branch| branch.to_string())
+        .filter(|branch| branch.starts_with(BRANCH_PREFIX)))
+}
+
+fn client_key(msg: &impl HeadersExt, method: Method) -> Result<Option<ClientKey>> {
+    Ok(branch(msg)?.map(|branch| ClientKey {
+        branch,
+        method: method.to_string(),
+    }))
+}
+
+fn server_key(msg: &impl HeadersExt, method: Method) -> Result<Option<ServerKey>> {
+    let sent_by = msg
+        .via_header()?
+        .typed()?
+        .sent_by()
+        .host_with_port
+        .to_string();
+    Ok(branch(msg)?.map(|branch| ServerKey {
+        branch,
+        sent_by,
+        method: method.to_string(),
+    }))
+}
+
+// https://www.rfc-editor.org/rfc/rfc3261 (17.1.1.3)
+fn ack_for(req: &Request, resp: &Response) -> Result<Request> {
+    let mut headers: Headers = Default::default();
+    for header in req.headers.iter() {
+        match header {
+            Header::Via(_) if headers.iter().any(|h| matches!(h, Header::Via(_))) => {}
+            h @ (Header::Via(_) | Header::CallId(_) | Header::From(_) | Header::Route(_)) => {
+                headers.push(h.clone())
+            }
+            _ => {}
+        }
+    }
+    headers.push(resp.to_header()?.clone().into());
+    headers.push(
+        CSeq {
+            seq: req.cseq_header()?.seq()?,
+            method: Method::Ack,
+        }
+        .into(),
+    );
+    headers.push(MaxForwards::from(MAX_FORWARDS).into());
+    headers.push(ContentLength::from(0).into());
+    Ok(Request {
+        method: Method::Ack,
+        uri: req.uri.clone(),
+        version: req.version.clone(),
+        headers,
+        body: vec![],
+    })
+}
+
+// For the responses we make up ourselves, without a dialog behind them
+// https://www.rfc-editor.org/rfc/rfc3261 (8.2.6.2)
+pub fn response_to(req: &Request, status_code: StatusCode) -> Response {
+    let mut headers: Headers = Default::default();
+    for header in req.headers.iter() {
+        if let h @ (Header::Via(_)
+        | Header::CallId(_)
+        | Header::From(_)
+        | Header::To(_)
+        | Header::CSeq(_)) = header
+        {
+            headers.push(h.clone());
+        }
+    }
+    headers.push(ContentLength::from(0).into());
+    Response {
+        status_code,
+        version: req.version.clone(),
+        headers,
+        body: vec![],
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+
+    fn request(method: Method, branch: &str) -> Request {
+        let msg = format!(
+            "{} sips:pbx.frandline.com SIP/2.0\r\n\
+            Via: SIP/2.0/TLS 10.0.0.1:5061;branch={}\r\n\
+            From: <sips:me@pbx.frandline.com>;tag=abc\r\n\
+            To: <sips:you@pbx.frandline.com>\r\n\
+            Call-ID: call-1\r\n\
+            CSeq: 1 {}\r\n\
+            Content-Length: 0\r\n\r\n",
+            method, branch, method,
+        );
+        Request::try_from(msg).unwrap()
+    }
+
+    fn response(req: &Request, status_code: StatusCode) -> SipMessage {
+        let mut resp = response_to(req, status_code);
+        *resp.to_header_mut().unwrap() =
+            rsip::headers::To::new("<sips:you@pbx.frandline.com>;tag=xyz");
+        resp.into()
+    }
+
+    fn transmits(actions: &[Action]) -> usize {
+        actions
+            .iter()
+            .filter(|a| matches!(a, Action::Transmit(_)))
+            .count()
+    }
+
+    #[test]
+    fn successfully_retransmit_and_time_out_non_invite() -> Result<()> {
+        let mut txns = Transactions::new(false);
+        let start = Instant::now();
+        let req = request(Method::Register, "z9hG4bKreg");
+        txns.on_send(req.clone().into(), start)?;
+
+        // Timer E doubles from T1 up to T2
+        let mut retransmits = vec![];
+        let mut now = start;
+        while let Some(deadline) = txns.next_deadline() {
+            now = deadline;
+            let actions = txns.on_tick(now);
+            if transmits(&actions) > 0 {
+                retransmits.push(now - start);
+            }
+            if let Some(Action::Deliver(SipMessage::Response(resp))) = actions.last() {
+                assert_eq!(resp.status_code, StatusCode::RequestTimeout);
+                break;
+            }
+        }
+        assert_eq!(
+            retransmits[..5],
+            [500, 1500, 3500, 7500, 11500].map(Duration::from_millis)
+        );
+        assert_eq!(now - start, TRANSACTION_TIMEOUT);
+        assert!(txns.next_deadline().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn successfully_deliver_each_response_once() -> Result<()> {
+        let mut txns = Transactions::new(true);
+        let now = Instant::now();
+        let req = request(Method::Register, "z9hG4bKreg");
+        txns.on_send(req.clone().into(), now)?;
+        assert!(txns.next_deadline().is_some());
+
+        let trying = response(&req, StatusCode::Trying);
+        assert_eq!(
+            txns.on_recv(trying.clone(), now)?,
+            vec![Action::Deliver(trying)]
+        );
+        let ok = response(&req, StatusCode::OK);
+        assert_eq!(
+            txns.on_recv(ok.clone(), now)?,
+            vec![Action::Deliver(ok.clone())]
+        );
+        assert_eq!(txns.on_recv(ok.clone(), now)?, vec![]);
+
+        // Reliable transports don't hang around in Completed
+        txns.on_tick(now);
+        assert_eq!(txns.on_recv(ok, now)?, vec![]);
+        let stray = response(&request(Method::Bye, "z9hG4bKother"), StatusCode::OK);
+        assert_eq!(txns.on_recv(stray, now)?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn successfully_ack_invite_failures_and_pass_on_2xx_retransmissions() -> Result<()> {
+        let mut txns = Transactions::new(true);
+        let now = Instant::now();
+        let req = request(Method::Invite, "z9hG4bKinv1");
+        txns.on_send(req.clone().into(), now)?;
+
+        let busy = response(&req, StatusCode::BusyHere);
+        let actions = txns.on_recv(busy.clone(), now)?;
+        let Action::Transmit(SipMessage::Request(ack)) = &actions[0] else {
+            panic!("expected an ack, got {:?}", actions);
+        };
+        assert_eq!(ack.method, Method::Ack);
+        assert_eq!(ack.cseq_header()?.seq()?, 1);
+        assert_eq!(ack.to_header()?.tag()?, busy.to_header()?.tag()?);
+        assert_eq!(branch(ack)?, branch(&req)?);
+        assert_eq!(actions[1], Action::Deliver(busy));
+
+        let req = request(Method::Invite, "z9hG4bKinv2");
+        txns.on_send(req.clone().into(), now)?;
+        let ok = response(&req, StatusCode::OK);
+        assert_eq!(
+            txns.on_recv(ok.clone(), now)?,
+            vec![Action::Deliver(ok.clone())]
+        );
+        assert_eq!(txns.on_recv(ok.clone(), now)?, vec![Action::Deliver(ok)]);
+        Ok(())
+    }
+
+    #[test]
+    fn successfully_keep_ringing_past_timer_b() -> Result<()> {
+        let mut txns = Transactions::new(true);
+        let start = Instant::now();
+        let req = request(Method::Invite, "z9hG4bKring");
+        txns.on_send(req.clone().into(), start)?;
+
+        let ringing = response(&req, StatusCode::Ringing);
+        assert_eq!(
+            txns.on_recv(ringing.clone(), start)?,
+            vec![Action::Deliver(ringing)]
+        );
+        assert!(txns.next_deadline().is_none());
+        assert!(txns.on_tick(start + TRANSACTION_TIMEOUT * 2).is_empty());
+
+        let ok = response(&req, StatusCode::OK);
+        assert_eq!(
+            txns.on_recv(ok.clone(), start + TRANSACTION_TIMEOUT * 2)?,
+            vec![Action::Deliver(ok)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn successfully_forget_requests_we_never_answer() -> Result<()> {
+        let mut txns = Transactions::new(true);
+        let start = Instant::now();
+        txns.on_recv(request(Method::Options, "z9hG4bKopt").into(), start)?;
+        txns.on_recv(request(Method::Invite, "z9hG4bKinv").into(), start)?;
+
+        txns.on_tick(start + TRANSACTION_TIMEOUT);
+        assert_eq!(txns.servers.len(), 1);
+        txns.on_tick(start + UNANSWERED_INVITE_TIMEOUT);
+        assert!(txns.servers.is_empty());
+        assert!(txns.next_deadline().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn successfully_terminate_an_invite_when_its_cancelled() -> Result<()> {
+        let mut txns = Transactions::new(false);
+        let now = Instant::now();
+        let invite = request(Method::Invite, "z9hG4bKinv");
+        txns.on_recv(invite.clone().into(), now)?;
+        let ringing = response(&invite, StatusCode::Ringing);
+        txns.on_send(ringing, now)?;
+
+        let mut cancel = invite.clone();
+        cancel.method = Method::Cancel;
+        *cancel.cseq_header_mut()? = rsip::headers::CSeq::new("1 CANCEL");
+        let actions = txns.on_recv(cancel.clone().into(), now)?;
+        let [Action::Transmit(SipMessage::Response(ok)), Action::Transmit(SipMessage::Response(terminated)), Action::Deliver(delivered)] =
+            &actions[..]
+        else {
+            panic!("expected a 200, a 487 and the CANCEL, got {:?}", actions);
+        };
+        assert_eq!(ok.status_code, StatusCode::OK);
+        assert_eq!(ok.cseq_header()?.method()?, Method::Cancel);
+        assert_eq!(terminated.status_code, StatusCode::RequestTerminated);
+        assert_eq!(terminated.cseq_header()?.method()?, Method::Invite);
+        assert_eq!(
+            terminated.to_header()?.tag()?.map(|t| t.to_string()),
+            Some("xyz".into())
+        );
+        assert_eq!(delivered, &SipMessage::from(cancel.clone()));
+
+        // Retransmissions of either get the same answer, and the INVITE's over
+        assert_eq!(
+            txns.on_recv(cancel.clone().into(), now)?,
+            vec![Action::Transmit(ok.clone().into())]
+        );
+        assert_eq!(
+            txns.on_recv(invite.into(), now)?,
+            vec![Action::Transmit(terminated.clone().into())]
+        );
+
+        let mut stray = request(Method::Cancel, "z9hG4bKother");
+        *stray.cseq_header_mut()? = rsip::headers::CSeq::new("1 CANCEL");
+        let actions = txns.on_recv(stray.into(), now)?;
+        assert!(matches!(
+            &actions[..],
+            [Action::Transmit(SipMessage::Response(r))] if r.status_code == StatusCode::CallTransactionDoesNotExist
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn successfully_absorb_server_retransmissions() -> Result<()> {
+        let mut txns = Transactions::new(false);
+        let start = Instant::now();
+        let invite = request(Method::Invite, "z9hG4bKinv");
+        assert_eq!(
+            txns.on_recv(invite.clone().into(), start)?,
+            vec![Action::Deliver(invite.clone().into())]
+        );
+
+        // Nobody said anything, so we say we're on it
+        let actions = txns.on_tick(start + TRYING_DELAY);
+        assert!(matches!(
+            &actions[..],
+            [Action::Transmit(SipMessage::Response(r))] if r.status_code == StatusCode::Trying
+        ));
+
+        let busy = response(&invite, StatusCode::BusyHere);
+        txns.on_send(busy.clone(), start)?;
+        assert_eq!(
+            txns.on_recv(invite.clone().into(), start)?,
+            vec![Action::Transmit(busy.clone())]
+        );
+        // Timer G until the ACK
+        assert_eq!(txns.on_tick(start + T1), vec![Action::Transmit(busy)]);
+        let mut ack = invite.clone();
+        ack.method = Method::Ack;
+        assert_eq!(txns.on_recv(ack.into(), start + T1)?, vec![]);
+        assert!(txns.on_tick(start + T1 * 2).is_empty());
+        Ok(())
+    }
+}
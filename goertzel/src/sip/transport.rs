@@ -0,0 +1,326 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Error, Result};
+use rsip::prelude::*;
+use rsip::{header_opt, Header, Scheme, SipMessage};
+use rustls::pki_types::ServerName;
+use rustls::RootCertStore;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio_rustls::TlsConnector;
+use tracing::trace;
+
+use super::outbound::PING;
+
+// Big enough for anything that fits in a datagram
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+// Which transport an account talks to its server over
+// https://www.rfc-editor.org/rfc/rfc3261 (18)
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TransportKind {
+    Udp,
+    Tcp,
+    #[default]
+    Tls,
+    // https://www.rfc-editor.org/rfc/rfc7118
+    Ws,
+    Wss,
+}
+
+impl TransportKind {
+    // Only the secure transports get sips:, the others are plain sip:
+    // https://www.rfc-editor.org/rfc/rfc3261 (19.1)
+    pub fn scheme(self) -> Scheme {
+        match self {
+            Self::Udp | Self::Tcp | Self::Ws => Scheme::Sip,
+            Self::Tls | Self::Wss => Scheme::Sips,
+        }
+    }
+
+    pub fn via(self) -> rsip::Transport {
+        match self {
+            Self::Udp => rsip::Transport::Udp,
+            Self::Tcp => rsip::Transport::Tcp,
+            Self::Tls => rsip::Transport::Tls,
+            Self::Ws => rsip::Transport::Ws,
+            Self::Wss => rsip::Transport::Wss,
+        }
+    }
+
+    // Whether the transaction layer gets to skip retransmitting
+    pub fn reliable(self) -> bool {
+        !matches!(self, Self::Udp)
+    }
+
+    // The WebSocket ones are where Asterisk's HTTP server listens
+    pub fn default_port(self) -> u16 {
+        match self {
+            Self::Udp | Self::Tcp => 5060,
+            Self::Tls => 5061,
+            Self::Ws => 8088,
+            Self::Wss => 8089,
+        }
+    }
+}
+
+impl FromStr for TransportKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "udp" => Ok(Self::Udp),
+            "tcp" => Ok(Self::Tcp),
+            "tls" => Ok(Self::Tls),
+            "ws" => Ok(Self::Ws),
+            "wss" => Ok(Self::Wss),
+            _ => Err(anyhow!("unknown sip transport: {}", s)),
+        }
+    }
+}
+
+// What comes off the wire, a whole message or the answer to one of our keepalives
+#[derive(Debug, PartialEq)]
+pub enum Received {
+    Message(Box<SipMessage>),
+    Pong,
+}
+
+// The halves of a connection the SIP connection's tasks drive, one reading whole messages off the
+// wire and one putting them on it
+pub trait ReadMessage: Send + 'static {
+    fn read_message(&mut self) -> impl Future<Output = Result<Received>> + Send;
+}
+
+pub trait WriteMessage: Send + 'static {
+    fn write_message(&mut self, msg: &SipMessage) -> impl Future<Output = Result<()>> + Send;
+
+    // Asks the far end whether the connection's still there
+    fn ping(&mut self) -> impl Future<Output = Result<()>> + Send;
+}
+
+// TCP and TLS are byte streams, so messages are framed by the blank line after the headers and
+// Content-Length
+// https://www.rfc-editor.org/rfc/rfc3261 (18.3)
+pub struct StreamReader<R>(BufReader<R>);
+
+impl<R: AsyncRead + Unpin + Send + 'static> StreamReader<R> {
+    pub fn new(stream: R) -> Self {
+        StreamReader(BufReader::new(stream))
+    }
+}
+
+impl<R: AsyncRead + Unpin + Send + 'static> ReadMessage for StreamReader<R> {
+    async fn read_message(&mut self) -> Result<Received> {
+        let mut msg_str = String::new();
+        loop {
+            let mut line = String::new();
+            if self.0.read_line(&mut line).await? == 0 {
+                return Err(anyhow!("sip stream closed"));
+            }
+            let line = line.trim_end_matches(['\r', '\n']);
+            trace!(
+                line=%line,
+                "SIP New Recv line"
+            );
+            // A CRLF between messages is a pong
+            // https://www.rfc-editor.org/rfc/rfc5626 (3.5.1)
+            if line.is_empty() && msg_str.is_empty() {
+                return Ok(Received::Pong);
+            }
+            msg_str.push_str(line);
+            msg_str.push_str("\r\n");
+            if line.is_empty() {
+                break;
+            }
+        }
+        let mut msg = SipMessage::try_from(msg_str)?;
+        let content_length = match header_opt!(msg.headers().iter(), Header::ContentLength) {
+            Some(h) => h.length()?.try_into()?,
+            None => 0,
+        };
+        if content_length > 0 {
+            let mut buf = vec![0; content_length];
+            self.0.read_exact(&mut buf).await?;
+            msg.body_mut().extend_from_slice(&buf);
+        }
+        Ok(Received::Message(Box::new(msg)))
+    }
+}
+
+pub struct StreamWriter<W>(W);
+
+impl<W: AsyncWrite + Unpin + Send + 'static> StreamWriter<W> {
+    pub fn new(stream: W) -> Self {
+        StreamWriter(stream)
+    }
+}
+
+impl<W: AsyncWrite + Unpin + Send + 'static> WriteMessage for StreamWriter<W> {
+    async fn write_message(&mut self, msg: &SipMessage) -> Result<()> {
+        self.0.write_all(msg.to_string().as_bytes()).await?;
+        Ok(())
+    }
+
+    async fn ping(&mut self) -> Result<()> {
+        self.0.write_all(PING).await?;
+        Ok(())
+    }
+}
+
+// Every datagram is one whole message
+// https://www.rfc-editor.org/rfc/rfc3261 (18.3)
+pub struct DatagramReader(Arc<UdpSocket>);
+
+impl ReadMessage for DatagramReader {
+    async fn read_message(&mut self) -> Result<Received> {
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        loop {
+            let len = self.0.recv(&mut buf).await?;
+            let datagram = &buf[..len];
+            if datagram.iter().all(|b| b.is_ascii_whitespace()) {
+                continue;
+            }
+            trace!(len, "SIP New Recv datagram");
+            return Ok(Received::Message(Box::new(SipMessage::try_from(datagram)?)));
+        }
+    }
+}
+
+pub struct DatagramWriter(Arc<UdpSocket>);
+
+impl WriteMessage for DatagramWriter {
+    async fn write_message(&mut self, msg: &SipMessage) -> Result<()> {
+        self.0.send(msg.to_string().as_bytes()).await?;
+        Ok(())
+    }
+
+    // Keepalives over UDP are STUN, which we don't do
+    // https://www.rfc-editor.org/rfc/rfc5626 (4.4.2)
+    async fn ping(&mut self) -> Result<()> {
+        Err(anyhow!("no keepalives over udp"))
+    }
+}
+
+pub async fn udp(addr: SocketAddr) -> Result<(DatagramReader, DatagramWriter)> {
+    let local: SocketAddr = match addr {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0; 16], 0).into(),
+    };
+    let sock = UdpSocket::bind(local).await?;
+    sock.connect(addr).await?;
+    let sock = Arc::new(sock);
+    Ok((DatagramReader(sock.clone()), DatagramWriter(sock)))
+}
+
+pub async fn tcp(
+    addr: SocketAddr,
+) -> Result<(
+    StreamReader<tokio::net::tcp::OwnedReadHalf>,
+    StreamWriter<tokio::net::tcp::OwnedWriteHalf>,
+)> {
+    let (recv_stream, send_stream) = TcpStream::connect(addr).await?.into_split();
+    Ok((
+        StreamReader::new(recv_stream),
+        StreamWriter::new(send_stream),
+    ))
+}
+
+pub type TlsStream = tokio_rustls::client::TlsStream<TcpStream>;
+
+pub async fn tls(
+    host: &str,
+    addr: SocketAddr,
+) -> Result<(
+    StreamReader<tokio::io::ReadHalf<TlsStream>>,
+    StreamWriter<tokio::io::WriteHalf<TlsStream>>,
+)> {
+    let (recv_stream, send_stream) = tokio::io::split(tls_stream(host, addr).await?);
+    Ok((
+        StreamReader::new(recv_stream),
+        StreamWriter::new(send_stream),
+    ))
+}
+
+// The certificate's checked against the domain we looked up, whichever of its addresses we reach
+// https://www.rfc-editor.org/rfc/rfc5922 (4)
+pub async fn tls_stream(host: &str, addr: SocketAddr) -> Result<TlsStream> {
+    let connector = get_tls_connector();
+    let sock = TcpStream::connect(addr).await?;
+    Ok(connector
+        .connect(ServerName::try_from(host)?.to_owned(), sock)
+        .await?)
+}
+
+fn get_tls_connector() -> TlsConnector {
+    let root_store = RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    let tls_config = rustls::ClientConfig::builder()
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(tls_config))
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+
+    const OPTIONS: &str = "OPTIONS sip:pbx.frandline.com SIP/2.0\r\n\
+        Via: SIP/2.0/UDP 10.0.0.1:5060;branch=z9hG4bKabc\r\n\
+        From: <sip:1102@pbx.frandline.com>;tag=abc\r\n\
+        To: <sip:pbx.frandline.com>\r\n\
+        Call-ID: call-1\r\n\
+        CSeq: 1 OPTIONS\r\n\
+        Content-Length: 4\r\n\r\n\
+        body";
+
+    #[test]
+    fn successfully_parse_transports() -> Result<()> {
+        assert_eq!("UDP".parse::<TransportKind>()?, TransportKind::Udp);
+        assert_eq!("tls".parse::<TransportKind>()?.scheme(), Scheme::Sips);
+        assert!(!TransportKind::Udp.reliable());
+        assert_eq!("ws".parse::<TransportKind>()?.scheme(), Scheme::Sip);
+        assert!("sctp".parse::<TransportKind>().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn successfully_frame_messages_on_a_stream() -> Result<()> {
+        let wire = format!("{}\r\n{}", OPTIONS, OPTIONS);
+        let mut reader = StreamReader::new(std::io::Cursor::new(wire.into_bytes()));
+        let msg = SipMessage::try_from(OPTIONS)?;
+        assert_eq!(
+            reader.read_message().await?,
+            Received::Message(Box::new(msg.clone()))
+        );
+        assert_eq!(reader.read_message().await?, Received::Pong);
+        assert_eq!(
+            reader.read_message().await?,
+            Received::Message(Box::new(msg))
+        );
+        assert!(reader.read_message().await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn successfully_send_messages_over_udp() -> Result<()> {
+        let server = UdpSocket::bind(("127.0.0.1", 0)).await?;
+        let (mut reader, mut writer) = udp(server.local_addr()?).await?;
+
+        let msg = SipMessage::try_from(OPTIONS)?;
+        writer.write_message(&msg).await?;
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        let (len, client) = server.recv_from(&mut buf).await?;
+        assert_eq!(SipMessage::try_from(&buf[..len])?, msg);
+
+        server.send_to(b"\r\n\r\n", client).await?;
+        server.send_to(&buf[..len], client).await?;
+        assert_eq!(
+            reader.read_message().await?,
+            Received::Message(Box::new(msg))
+        );
+        Ok(())
+    }
+}
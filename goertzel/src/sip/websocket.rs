@@ -0,0 +1,357 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use base64::prelude::*;
+use rsip::SipMessage;
+use sha1::{Digest, Sha1};
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadHalf,
+    WriteHalf,
+};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tracing::{debug, trace};
+
+use super::transport::{tls_stream, ReadMessage, Received, TlsStream, WriteMessage};
+
+// Where Asterisk's res_http_websocket takes SIP
+pub const WEBSOCKET_PATH: &str = "/ws";
+// https://www.rfc-editor.org/rfc/rfc7118 (4.1)
+pub const WEBSOCKET_PROTOCOL: &str = "sip";
+// https://www.rfc-editor.org/rfc/rfc6455 (1.3)
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// https://www.rfc-editor.org/rfc/rfc6455 (5.2)
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+type Shared<W> = Arc<Mutex<W>>;
+
+pub async fn ws(
+    host: &str,
+    addr: SocketAddr,
+) -> Result<(
+    WebSocketReader<ReadHalf<BufReader<TcpStream>>, WriteHalf<BufReader<TcpStream>>>,
+    WebSocketWriter<WriteHalf<BufReader<TcpStream>>>,
+)> {
+    connect(TcpStream::connect(addr).await?, host, addr.port()).await
+}
+
+pub async fn wss(
+    host: &str,
+    addr: SocketAddr,
+) -> Result<(
+    WebSocketReader<ReadHalf<BufReader<TlsStream>>, WriteHalf<BufReader<TlsStream>>>,
+    WebSocketWriter<WriteHalf<BufReader<TlsStream>>>,
+)> {
+    connect(tls_stream(host, addr).await?, host, addr.port()).await
+}
+
+// Upgrades the stream to a WebSocket speaking the sip subprotocol
+// https://www.rfc-editor.org/rfc/rfc6455 (4.1)
+pub async fn connect<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+    stream: S,
+    host: &str,
+    port: u16,
+) -> Result<(
+    WebSocketReader<ReadHalf<BufReader<S>>, WriteHalf<BufReader<S>>>,
+    WebSocketWriter<WriteHalf<BufReader<S>>>,
+)> {
+    let key = BASE64_STANDARD.encode(rand::random::<[u8; 16]>());
+    let mut stream = BufReader::new(stream);
+    stream
+        .write_all(
+            format!(
+                "GET {} HTTP/1.1\r\n\
+                Host: {}:{}\r\n\
+                Upgrade: websocket\r\n\
+                Connection: Upgrade\r\n\
+                Sec-WebSocket-Key: {}\r\n\
+                Sec-WebSocket-Version: 13\r\n\
+                Sec-WebSocket-Protocol: {}\r\n\r\n",
+                WEBSOCKET_PATH, host, port, key, WEBSOCKET_PROTOCOL,
+            )
+            .as_bytes(),
+        )
+        .await?;
+
+    let mut status = String::new();
+    stream.read_line(&mut status).await?;
+    if status.split_whitespace().nth(1) != Some("101") {
+        return Err(anyhow!("websocket upgrade refused: {}", status.trim_end()));
+    }
+    let (mut accept, mut protocol) = (None, None);
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line).await? == 0 {
+            return Err(anyhow!("websocket closed during upgrade"));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        match line.split_once(':') {
+            Some((name, value)) if name.eq_ignore_ascii_case("Sec-WebSocket-Accept") => {
+                accept = Some(value.trim().to_string())
+            }
+            Some((name, value)) if name.eq_ignore_ascii_case("Sec-WebSocket-Protocol") => {
+                protocol = Some(value.trim().to_ascii_lowercase())
+            }
+            _ => {}
+        }
+    }
+    if accept != Some(accept_key(&key)) {
+        return Err(anyhow!("websocket upgrade had a bad accept key"));
+    }
+    // https://www.rfc-editor.org/rfc/rfc7118 (4.1)
+    if protocol.as_deref() != Some(WEBSOCKET_PROTOCOL) {
+        return Err(anyhow!("websocket server won't speak sip"));
+    }
+    debug!("sip: websocket up to {}:{}", host, port);
+
+    let (recv_stream, send_stream) = tokio::io::split(stream);
+    let send_stream = Arc::new(Mutex::new(send_stream));
+    Ok((
+        WebSocketReader {
+            stream: recv_stream,
+            pong: send_stream.clone(),
+            partial: vec![],
+        },
+        WebSocketWriter(send_stream),
+    ))
+}
+
+fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(ACCEPT_GUID.as_bytes());
+    BASE64_STANDARD.encode(sha1.finalize())
+}
+
+// Everything a client sends is masked
+// https://www.rfc-editor.org/rfc/rfc6455 (5.3)
+fn frame(opcode: u8, payload: &[u8], mask: Option<[u8; 4]>) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+    match payload.len() {
+        len if len < 126 => frame.push(mask_bit | len as u8),
+        len if len <= u16::MAX.into() => {
+            frame.push(mask_bit | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(mask_bit | 127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    match mask {
+        Some(mask) => {
+            frame.extend_from_slice(&mask);
+            frame.extend(payload.iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+        }
+        None => frame.extend_from_slice(payload),
+    }
+    frame
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(stream: &mut R) -> Result<(bool, u8, Vec<u8>)> {
+    let mut head = [0; 2];
+    stream.read_exact(&mut head).await?;
+    let fin = head[0] & 0x80 != 0;
+    let opcode = head[0] & 0x0f;
+    let len = match head[1] & 0x7f {
+        126 => stream.read_u16().await?.into(),
+        127 => stream.read_u64().await?,
+        len => len.into(),
+    };
+    let mask = match head[1] & 0x80 {
+        0 => None,
+        _ => {
+            let mut mask = [0; 4];
+            stream.read_exact(&mut mask).await?;
+            Some(mask)
+        }
+    };
+    let mut payload = vec![0; len.try_into()?];
+    stream.read_exact(&mut payload).await?;
+    if let Some(mask) = mask {
+        for (b, m) in payload.iter_mut().zip(mask.iter().cycle()) {
+            *b ^= m;
+        }
+    }
+    Ok((fin, opcode, payload))
+}
+
+// Each SIP message is a whole WebSocket message, which may come in fragments
+// https://www.rfc-editor.org/rfc/rfc7118 (5.1)
+pub struct WebSocketReader<R, W> {
+    stream: R,
+    // Pings get answered from the read side
+    pong: Shared<W>,
+    // Whatever's come of a fragmented message so far
+    partial: Vec<u8>,
+}
+
+impl<R, W> ReadMessage for WebSocketReader<R, W>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    async fn read_message(&mut self) -> Result<Received> {
+        loop {
+            let (fin, opcode, payload) = read_frame(&mut self.stream).await?;
+            match opcode {
+                OPCODE_TEXT | OPCODE_BINARY | OPCODE_CONTINUATION => {
+                    self.partial.extend_from_slice(&payload);
+                    if !fin {
+                        continue;
+                    }
+                    let data = std::mem::take(&mut self.partial);
+                    if data.iter().all(|b| b.is_ascii_whitespace()) {
+                        continue;
+                    }
+                    trace!(len = data.len(), "SIP New Recv websocket message");
+                    return Ok(Received::Message(Box::new(SipMessage::try_from(
+                        data.as_slice(),
+                    )?)));
+                }
+                OPCODE_PING => {
+                    let pong = frame(OPCODE_PONG, &payload, Some(rand::random()));
+                    self.pong.lock().await.write_all(&pong).await?;
+                }
+                OPCODE_PONG => return Ok(Received::Pong),
+                OPCODE_CLOSE => {
+                    let close = frame(OPCODE_CLOSE, &payload, Some(rand::random()));
+                    self.pong.lock().await.write_all(&close).await?;
+                    return Err(anyhow!("websocket closed"));
+                }
+                opcode => return Err(anyhow!("unknown websocket opcode: {}", opcode)),
+            }
+        }
+    }
+}
+
+pub struct WebSocketWriter<W>(Shared<W>);
+
+impl<W: AsyncWrite + Unpin + Send + 'static> WriteMessage for WebSocketWriter<W> {
+    async fn write_message(&mut self, msg: &SipMessage) -> Result<()> {
+        let frame = frame(
+            OPCODE_TEXT,
+            msg.to_string().as_bytes(),
+            Some(rand::random()),
+        );
+        self.0.lock().await.write_all(&frame).await?;
+        Ok(())
+    }
+
+    // WebSockets have their own keepalives instead of CRLFs
+    // https://www.rfc-editor.org/rfc/rfc6455 (5.5.2)
+    async fn ping(&mut self) -> Result<()> {
+        let frame = frame(OPCODE_PING, &[], Some(rand::random()));
+        self.0.lock().await.write_all(&frame).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+
+    const OPTIONS: &str = "OPTIONS sip:pbx.frandline.com SIP/2.0\r\n\
+        Via: SIP/2.0/WS df7jal23ls0d.invalid;branch=z9hG4bKabc\r\n\
+        From: <sip:1102@pbx.frandline.com>;tag=abc\r\n\
+        To: <sip:pbx.frandline.com>\r\n\
+        Call-ID: call-1\r\n\
+        CSeq: 1 OPTIONS\r\n\
+        Content-Length: 0\r\n\r\n";
+
+    // Answers the upgrade the way a server would, with whatever protocol it's given
+    async fn upgrade<S: AsyncRead + AsyncWrite + Unpin>(
+        server: &mut BufReader<S>,
+        protocol: &str,
+    ) -> Result<()> {
+        let mut key = None;
+        loop {
+            let mut line = String::new();
+            server.read_line(&mut line).await?;
+            match line.trim_end().split_once(": ") {
+                Some(("Sec-WebSocket-Key", value)) => key = Some(value.to_string()),
+                None if line.trim_end().is_empty() => break,
+                _ => {}
+            }
+        }
+        let key = key.ok_or(anyhow!("no key"))?;
+        server
+            .write_all(
+                format!(
+                    "HTTP/1.1 101 Switching Protocols\r\n\
+                    Upgrade: websocket\r\n\
+                    Connection: Upgrade\r\n\
+                    Sec-WebSocket-Accept: {}\r\n\
+                    Sec-WebSocket-Protocol: {}\r\n\r\n",
+                    accept_key(&key),
+                    protocol,
+                )
+                .as_bytes(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    #[test]
+    fn successfully_compute_the_accept_key() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[tokio::test]
+    async fn successfully_exchange_sip_over_a_websocket() -> Result<()> {
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let mut server = BufReader::new(server);
+        let server_task = tokio::spawn(async move {
+            upgrade(&mut server, "sip").await?;
+            // A ping, then the message split across two frames
+            server.write_all(&frame(OPCODE_PING, b"hi", None)).await?;
+            let (first, rest) = OPTIONS.as_bytes().split_at(20);
+            let mut fragment = frame(OPCODE_TEXT, first, None);
+            fragment[0] &= 0x7f;
+            server.write_all(&fragment).await?;
+            server
+                .write_all(&frame(OPCODE_CONTINUATION, rest, None))
+                .await?;
+
+            let pong = read_frame(&mut server).await?;
+            let msg = read_frame(&mut server).await?;
+            anyhow::Ok((pong, msg))
+        });
+
+        let (mut reader, mut writer) = connect(client, "pbx.frandline.com", 8088).await?;
+        let msg = SipMessage::try_from(OPTIONS)?;
+        assert_eq!(
+            reader.read_message().await?,
+            Received::Message(Box::new(msg.clone()))
+        );
+        writer.write_message(&msg).await?;
+
+        let (pong, sent) = server_task.await??;
+        assert_eq!(pong, (true, OPCODE_PONG, b"hi".to_vec()));
+        assert_eq!(sent, (true, OPCODE_TEXT, OPTIONS.as_bytes().to_vec()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fail_to_upgrade_without_the_sip_protocol() -> Result<()> {
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let mut server = BufReader::new(server);
+        tokio::spawn(async move { upgrade(&mut server, "chat").await });
+        assert!(connect(client, "pbx.frandline.com", 8088).await.is_err());
+        Ok(())
+    }
+}
@@ -54,6 +54,12 @@ impl TwoToneGen {
             .beep(Duration::from_millis(500), Duration::from_millis(500))
     }
 
+    // Reorder, the fast busy for when the network can't take the call
+    pub fn no_service(rate: u32) -> Self {
+        Self::new(rate, BUSY_TONES.0, BUSY_TONES.1)
+            .beep(Duration::from_millis(250), Duration::from_millis(250))
+    }
+
     pub fn busy(rate: u32) -> Self {
         Self::new(rate, BUSY_TONES.0, BUSY_TONES.1)
             .beep(Duration::from_millis(500), Duration::from_millis(500))